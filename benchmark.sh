@@ -2,7 +2,12 @@
 set -eo pipefail
 
 test_data="$1"
-cp "${test_data}" ./hyper_data.txt
+if [ -n "${test_data}" ]; then
+    cp "${test_data}" ./hyper_data.txt
+else
+    # No data given, generate a reproducible file with the same shape as the HIGGS-style data
+    hck gen --cols 'label:int,float*27' --rows 1e6 --seed 7 -d, --header -o ./hyper_data.txt
+fi
 
 # Single Character Delimiter tests
 
@@ -0,0 +1,77 @@
+#ifndef HCKLIB_H
+#define HCKLIB_H
+
+/* Generated by cbindgen from src/lib/ffi.rs, do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A compiled field selection, see [`hck_extractor_new`].
+ */
+typedef struct HckExtractor HckExtractor;
+
+/**
+ * The `[start, end)` byte offsets of a field within the buffer given to [`hck_extractor_feed`].
+ */
+typedef struct HckSpan {
+  size_t start;
+  size_t end;
+} HckSpan;
+
+/**
+ * Called with the spans of the selected fields of a line, returning anything but 0 stops.
+ */
+typedef int (*HckRowCallback)(void *user_data, const struct HckSpan *spans, size_t len);
+
+/**
+ * Create an extractor selecting `fields`, ex: `"1,3-5"`, from lines split on `delimiter`.
+ *
+ * The delimiter is a regex unless `literal` is set, and escapes like `\t` are resolved. Returns
+ * null on failure, see [`hck_last_error`]. Free it with [`hck_extractor_free`].
+ *
+ * # Safety
+ *
+ * `fields` and `delimiter` must be null or valid NUL terminated strings.
+ */
+struct HckExtractor *hck_extractor_new(const char *fields, const char *delimiter, bool literal);
+
+/**
+ * Split the `len` bytes at `buffer` into lines and call `callback` with the spans of the selected
+ * fields of each, passing `user_data` through.
+ *
+ * A final line without a terminator is processed like any other, so feed whole lines. Returns 0
+ * once every line is processed, -1 if an argument is null, or the non-zero value `callback`
+ * stopped with. The spans are only valid for the call to `callback`.
+ *
+ * # Safety
+ *
+ * `extractor` must come from [`hck_extractor_new`] and `buffer` must point to `len` readable
+ * bytes.
+ */
+int hck_extractor_feed(const struct HckExtractor *extractor,
+                       const uint8_t *buffer,
+                       size_t len,
+                       HckRowCallback callback,
+                       void *user_data);
+
+/**
+ * Free an extractor, null is ignored.
+ *
+ * # Safety
+ *
+ * `extractor` must come from [`hck_extractor_new`] and not have been freed already.
+ */
+void hck_extractor_free(struct HckExtractor *extractor);
+
+/**
+ * The message for the last error on this thread, or null if there hasn't been one.
+ *
+ * The string is owned by hcklib and is valid until the next error on the same thread.
+ */
+const char *hck_last_error(void);
+
+#endif  /* HCKLIB_H */
@@ -0,0 +1,148 @@
+//! Adaptive reads from streams like stdin.
+//!
+//! A pipe from a firehose like `zcat` should be read in large chunks to keep syscalls down, while a
+//! pipe from something interactive like `tail -f` delivers a few lines at a time and its results
+//! should show up as soon as they are ready. An [`AdaptiveReader`] grows its reads while they keep
+//! coming back full and shrinks them when they don't, and reports through its [`Pace`] whether
+//! the stream looks interactive so that output can be flushed instead of held in a buffer.
+use std::{
+    cell::Cell,
+    io::{self, Read},
+    rc::Rc,
+};
+
+/// The smallest read an [`AdaptiveReader`] makes, reads shorter than this mean the stream is
+/// interactive.
+pub const MIN_READ_SIZE: usize = 4 * (1 << 10);
+
+/// The default largest read an `AdaptiveReader` makes, the same as the default line buffer capacity.
+pub const DEFAULT_MAX_READ_SIZE: usize = 64 * (1 << 10);
+
+/// Whether an [`AdaptiveReader`] is seeing an interactive stream, shared with whatever writes the
+/// results.
+#[derive(Debug, Clone, Default)]
+pub struct Pace(Rc<Cell<bool>>);
+
+impl Pace {
+    /// Whether the last read came back short, so output shouldn't wait for more input.
+    #[inline]
+    pub fn is_interactive(&self) -> bool {
+        self.0.get()
+    }
+}
+
+/// A [`Read`] wrapper that sizes each read to how fast the inner reader delivers data.
+#[derive(Debug)]
+pub struct AdaptiveReader<R> {
+    inner: R,
+    /// The size of the next read
+    size: usize,
+    /// The largest read to make
+    max: usize,
+    pace: Pace,
+}
+
+impl<R: Read> AdaptiveReader<R> {
+    /// Create an [`AdaptiveReader`] that reads at most `max` bytes at a time, or
+    /// [`DEFAULT_MAX_READ_SIZE`] if `None`.
+    pub fn new(inner: R, max: Option<usize>) -> Self {
+        let max = max.unwrap_or(DEFAULT_MAX_READ_SIZE).max(MIN_READ_SIZE);
+        Self {
+            inner,
+            size: MIN_READ_SIZE,
+            max,
+            pace: Pace::default(),
+        }
+    }
+
+    /// A handle on whether this reader is seeing an interactive stream.
+    pub fn pace(&self) -> Pace {
+        self.pace.clone()
+    }
+
+    /// The size of the next read, before it is capped by the buffer it reads into.
+    #[cfg(test)]
+    pub fn read_size(&self) -> usize {
+        self.size
+    }
+}
+
+impl<R: Read> Read for AdaptiveReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let want = buf.len().min(self.size);
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 {
+            return Ok(0);
+        }
+        if n == want {
+            // The stream keeps up, so ask for more at a time
+            self.size = (self.size * 2).min(self.max);
+        } else if n * 2 <= want {
+            self.size = (self.size / 2).max(MIN_READ_SIZE);
+        }
+        self.pace.0.set(n < MIN_READ_SIZE);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Returns the given number of bytes from each read.
+    struct Scripted(Vec<usize>);
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let n = self.0.remove(0).min(buf.len());
+            buf[..n].fill(b'x');
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_adaptive_reader() {
+        let reads = vec![
+            usize::MAX,
+            usize::MAX,
+            usize::MAX,
+            usize::MAX,
+            10,
+            10,
+            10,
+            usize::MAX,
+        ];
+        let mut reader = AdaptiveReader::new(Scripted(reads), Some(4 * MIN_READ_SIZE));
+        let pace = reader.pace();
+        let mut buf = vec![0; 1 << 20];
+
+        // Full reads grow up to the max
+        let sizes: Vec<usize> = (0..4).map(|_| reader.read(&mut buf).unwrap()).collect();
+        assert_eq!(
+            sizes,
+            vec![
+                MIN_READ_SIZE,
+                2 * MIN_READ_SIZE,
+                4 * MIN_READ_SIZE,
+                4 * MIN_READ_SIZE
+            ]
+        );
+        assert!(!pace.is_interactive());
+
+        // Short reads shrink back down and mark the stream as interactive
+        assert_eq!(reader.read(&mut buf).unwrap(), 10);
+        assert_eq!(reader.read_size(), 2 * MIN_READ_SIZE);
+        assert!(pace.is_interactive());
+        assert_eq!(reader.read(&mut buf).unwrap(), 10);
+        assert_eq!(reader.read(&mut buf).unwrap(), 10);
+        assert_eq!(reader.read_size(), MIN_READ_SIZE);
+
+        // Reads are capped by the buffer, and EOF leaves the pace alone
+        assert_eq!(reader.read(&mut buf[..10]).unwrap(), 10);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(pace.is_interactive());
+    }
+}
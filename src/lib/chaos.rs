@@ -0,0 +1,186 @@
+//! A [`Read`] wrapper that misbehaves on purpose.
+//!
+//! [`ChaosReader`] injects short reads, interrupted reads, and an early end of input into an
+//! otherwise well behaved reader. It exists to validate that the reader paths in
+//! [`Core`](crate::core::Core) produce the same output no matter how the bytes trickle in.
+//!
+//! This module is only available with the `test-util` feature.
+use std::io::{self, Read};
+
+use crate::gen::Rng;
+
+/// A [`Read`] wrapper that injects short reads, interrupts, and early EOFs.
+#[derive(Debug)]
+pub struct ChaosReader<R> {
+    inner: R,
+    rng: Rng,
+    /// Probability that a read is cut short
+    short_reads: f64,
+    /// Probability that a read fails with [`io::ErrorKind::Interrupted`]
+    interrupts: f64,
+    /// Number of bytes to allow before reporting EOF
+    eof_after: Option<u64>,
+    /// Number of bytes read so far
+    read: u64,
+}
+
+impl<R: Read> ChaosReader<R> {
+    /// Create a [`ChaosReader`] with the default amount of chaos.
+    ///
+    /// By default half of all reads are short and a tenth are interrupted.
+    pub fn new(inner: R, seed: u64) -> Self {
+        Self {
+            inner,
+            rng: Rng::new(seed),
+            short_reads: 0.5,
+            interrupts: 0.1,
+            eof_after: None,
+            read: 0,
+        }
+    }
+
+    /// The probability (0-1) that a read returns fewer bytes than requested.
+    pub fn short_reads(mut self, probability: f64) -> Self {
+        self.short_reads = probability;
+        self
+    }
+
+    /// The probability (0-1) that a read fails with [`io::ErrorKind::Interrupted`].
+    pub fn interrupts(mut self, probability: f64) -> Self {
+        self.interrupts = probability;
+        self
+    }
+
+    /// Report EOF after `bytes` bytes, regardless of where that falls in the input.
+    pub fn eof_after(mut self, bytes: u64) -> Self {
+        self.eof_after = Some(bytes);
+        self
+    }
+
+    /// Consume the [`ChaosReader`], returning the wrapped reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ChaosReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.rng.next_f64() < self.interrupts {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "chaos: interrupted read",
+            ));
+        }
+
+        let mut len = buf.len();
+        if self.rng.next_f64() < self.short_reads {
+            len = self.rng.below(len as u64) as usize + 1;
+        }
+        if let Some(eof_after) = self.eof_after {
+            len = len.min(eof_after.saturating_sub(self.read) as usize);
+            if len == 0 {
+                return Ok(0);
+            }
+        }
+
+        let n = self.inner.read(&mut buf[..len])?;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        core::{Core, CoreConfigBuilder, HckInput},
+        gen::{generate, ColumnSpec, GenConfig},
+        line_parser::SubStrLineParser,
+    };
+    use ripline::line_buffer::LineBufferBuilder;
+
+    fn data() -> Vec<u8> {
+        let config = GenConfig {
+            columns: ColumnSpec::from_list("id:seq,int,word*3,float").unwrap(),
+            rows: 500,
+            seed: 3,
+            delimiter: b",".to_vec(),
+            ragged: 0.2,
+            ..GenConfig::default()
+        };
+        let mut data = vec![];
+        generate(&config, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_chaos_reader_yields_all_bytes() {
+        let data = data();
+        let mut reader = ChaosReader::new(&data[..], 1);
+        let mut out = vec![];
+        let mut buf = [0; 100];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => out.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(out, data);
+
+        let mut truncated = vec![];
+        ChaosReader::new(&data[..], 1)
+            .interrupts(0.0)
+            .eof_after(42)
+            .read_to_end(&mut truncated)
+            .unwrap();
+        assert_eq!(truncated, &data[..42]);
+    }
+
+    #[test]
+    fn test_reader_paths_under_chaos() {
+        let data = data();
+        for list in ["1,3-4", "2-", "5", "1-2,4-"] {
+            let conf = CoreConfigBuilder::new()
+                .delimiter(b",")
+                .fields(Some(list))
+                .build()
+                .unwrap();
+            let (_, fields) = conf.parse_fields(&HckInput::<&str>::Stdin).unwrap();
+
+            let mut expected = vec![];
+            let mut line_buffer = LineBufferBuilder::new().build();
+            let mut core = Core::new(
+                &conf,
+                &fields,
+                SubStrLineParser::new(&fields, b","),
+                &mut line_buffer,
+            );
+            core.hck_bytes(&data, &mut expected).unwrap();
+
+            for seed in 0..10 {
+                // A tiny buffer forces many refills with lines split across reads
+                let mut line_buffer = LineBufferBuilder::new().capacity(16).build();
+                let mut core = Core::new(
+                    &conf,
+                    &fields,
+                    SubStrLineParser::new(&fields, b","),
+                    &mut line_buffer,
+                );
+                let mut slow = vec![];
+                core.hck_reader(ChaosReader::new(&data[..], seed), &mut slow)
+                    .unwrap();
+                assert_eq!(slow, expected, "slow path: {} seed {}", list, seed);
+
+                let mut fast = vec![];
+                core.hck_reader_fast(ChaosReader::new(&data[..], seed), &mut fast)
+                    .unwrap();
+                assert_eq!(fast, expected, "fast path: {} seed {}", list, seed);
+            }
+        }
+    }
+}
@@ -0,0 +1,478 @@
+//! Escaping and unescaping field contents.
+//!
+//! Fields embedded in other formats often carry that format's escaping, ex: a percent encoded URL
+//! query value or a JSON string. A [`Codec`] decodes or encodes one field at a time so they can be
+//! normalized while they are selected. Decoding is lenient, any malformed escape is left as is.
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::field_range::{FieldError, FieldRange};
+
+/// Errors for parsing a [`Codec`] or [`CodecSpec`].
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum CodecError {
+    #[error("Unknown codec `{0}`, expected `url`, `backslash`, `tsv`, `json`, or `base64`")]
+    Unknown(String),
+    #[error("Invalid codec spec `{0}`, expected FIELDS:CODEC like `3:url`")]
+    InvalidSpec(String),
+    #[error(transparent)]
+    InvalidField(#[from] FieldError),
+}
+
+/// An escaping scheme for field contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Codec {
+    /// Percent encoding, ex: `a%20b`. A `+` is left as is.
+    Url,
+    /// Backslash escapes `\t`, `\n`, `\r`, `\0`, `\\`, and `\xNN`.
+    Backslash,
+    /// The TSV escapes `\t`, `\n`, `\r`, and `\\` used by Postgres `COPY` and many TSV dialects.
+    /// Unlike [`Codec::Backslash`] nothing else is escaped, so other bytes round trip as they are.
+    Tsv,
+    /// The contents of a JSON string, without the surrounding quotes.
+    Json,
+    /// Standard base64 with padding. Decoding also accepts the URL safe alphabet and missing padding,
+    /// and leaves the field as is if it isn't valid base64.
+    Base64,
+}
+
+impl Codec {
+    /// Append the decoded `field` to `out`.
+    pub fn decode_into(&self, field: &[u8], out: &mut Vec<u8>) {
+        match self {
+            Codec::Url => url_decode(field, out),
+            Codec::Backslash => backslash_decode(field, out),
+            Codec::Tsv => tsv_decode(field, out),
+            Codec::Json => json_decode(field, out),
+            Codec::Base64 => base64_decode(field, out),
+        }
+    }
+
+    /// Append the encoded `field` to `out`.
+    pub fn encode_into(&self, field: &[u8], out: &mut Vec<u8>) {
+        match self {
+            Codec::Url => url_encode(field, out),
+            Codec::Backslash => backslash_encode(field, out),
+            Codec::Tsv => tsv_encode(field, out),
+            Codec::Json => json_encode(field, out),
+            Codec::Base64 => base64_encode(field, out),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = CodecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "url" => Ok(Codec::Url),
+            "backslash" => Ok(Codec::Backslash),
+            "tsv" => Ok(Codec::Tsv),
+            "json" => Ok(Codec::Json),
+            "base64" => Ok(Codec::Base64),
+            _ => Err(CodecError::Unknown(s.to_owned())),
+        }
+    }
+}
+
+/// A [`Codec`] to apply to some output positions, parsed from `FIELDS:CODEC` like `3:url` or `1,4-5:json`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecSpec {
+    pub fields: Vec<FieldRange>,
+    pub codec: Codec,
+}
+
+impl CodecSpec {
+    /// Whether the 0-based output position `index` is covered by this spec.
+    #[inline]
+    pub fn contains(&self, index: usize) -> bool {
+        self.fields
+            .iter()
+            .any(|r| r.low <= index && index <= r.high)
+    }
+}
+
+impl FromStr for CodecSpec {
+    type Err = CodecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (fields, codec) = s
+            .rsplit_once(':')
+            .filter(|(fields, _)| !fields.is_empty())
+            .ok_or_else(|| CodecError::InvalidSpec(s.to_owned()))?;
+        Ok(Self {
+            fields: FieldRange::from_list(fields)?,
+            codec: codec.parse()?,
+        })
+    }
+}
+
+/// The value of an ASCII hex digit.
+#[inline]
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|d| d as u8)
+}
+
+/// The value of the two hex digits at the start of `bytes`.
+#[inline]
+fn hex_pair(bytes: &[u8]) -> Option<u8> {
+    match bytes {
+        [hi, lo, ..] => Some(hex_value(*hi)? << 4 | hex_value(*lo)?),
+        _ => None,
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+fn url_decode(field: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < field.len() {
+        match field[i] {
+            b'%' => match hex_pair(&field[i + 1..]) {
+                Some(byte) => {
+                    out.push(byte);
+                    i += 3;
+                    continue;
+                }
+                None => out.push(b'%'),
+            },
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+}
+
+fn url_encode(field: &[u8], out: &mut Vec<u8>) {
+    for &byte in field {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            out.push(byte);
+        } else {
+            out.extend_from_slice(&[b'%', HEX[(byte >> 4) as usize], HEX[(byte & 0xF) as usize]]);
+        }
+    }
+}
+
+fn backslash_decode(field: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < field.len() {
+        if field[i] != b'\\' || i + 1 == field.len() {
+            out.push(field[i]);
+            i += 1;
+            continue;
+        }
+        let (byte, len) = match field[i + 1] {
+            b't' => (b'\t', 2),
+            b'n' => (b'\n', 2),
+            b'r' => (b'\r', 2),
+            b'0' => (b'\0', 2),
+            b'\\' => (b'\\', 2),
+            b'x' => match hex_pair(&field[i + 2..]) {
+                Some(byte) => (byte, 4),
+                None => (b'\\', 1),
+            },
+            _ => (b'\\', 1),
+        };
+        out.push(byte);
+        i += len;
+    }
+}
+
+fn backslash_encode(field: &[u8], out: &mut Vec<u8>) {
+    for &byte in field {
+        match byte {
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\0' => out.extend_from_slice(b"\\0"),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            byte if byte.is_ascii_control() => out.extend_from_slice(&[
+                b'\\',
+                b'x',
+                HEX[(byte >> 4) as usize],
+                HEX[(byte & 0xF) as usize],
+            ]),
+            byte => out.push(byte),
+        }
+    }
+}
+
+fn tsv_decode(field: &[u8], out: &mut Vec<u8>) {
+    let mut rest = field;
+    while let Some(i) = memchr::memchr(b'\\', rest) {
+        out.extend_from_slice(&rest[..i]);
+        let byte = match rest.get(i + 1) {
+            Some(b't') => b'\t',
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b'\\') => b'\\',
+            _ => {
+                out.push(b'\\');
+                rest = &rest[i + 1..];
+                continue;
+            }
+        };
+        out.push(byte);
+        rest = &rest[i + 2..];
+    }
+    out.extend_from_slice(rest);
+}
+
+fn tsv_encode(field: &[u8], out: &mut Vec<u8>) {
+    for &byte in field {
+        match byte {
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            byte => out.push(byte),
+        }
+    }
+}
+
+/// The UTF-16 code unit of a `\uXXXX` escape at the start of `bytes`.
+#[inline]
+fn json_code_unit(bytes: &[u8]) -> Option<u16> {
+    match bytes {
+        [b'\\', b'u', a, b, c, d, ..] => {
+            Some(u16::from(hex_pair(&[*a, *b])?) << 8 | u16::from(hex_pair(&[*c, *d])?))
+        }
+        _ => None,
+    }
+}
+
+fn json_decode(field: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < field.len() {
+        if field[i] != b'\\' || i + 1 == field.len() {
+            out.push(field[i]);
+            i += 1;
+            continue;
+        }
+        let (byte, len) = match field[i + 1] {
+            b'"' => (b'"', 2),
+            b'\\' => (b'\\', 2),
+            b'/' => (b'/', 2),
+            b'b' => (0x08, 2),
+            b'f' => (0x0C, 2),
+            b'n' => (b'\n', 2),
+            b'r' => (b'\r', 2),
+            b't' => (b'\t', 2),
+            b'u' => match json_code_unit(&field[i..]) {
+                Some(unit) => {
+                    // A high surrogate followed by a low one is a single character
+                    let low = json_code_unit(&field[i + 6..]);
+                    let (units, len) = match low {
+                        Some(low) if (0xD800..0xDC00).contains(&unit) => (vec![unit, low], 12),
+                        _ => (vec![unit], 6),
+                    };
+                    for c in char::decode_utf16(units) {
+                        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+                        out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    }
+                    i += len;
+                    continue;
+                }
+                None => (b'\\', 1),
+            },
+            _ => (b'\\', 1),
+        };
+        out.push(byte);
+        i += len;
+    }
+}
+
+fn json_encode(field: &[u8], out: &mut Vec<u8>) {
+    for &byte in field {
+        match byte {
+            b'"' => out.extend_from_slice(b"\\\""),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            0x08 => out.extend_from_slice(b"\\b"),
+            0x0C => out.extend_from_slice(b"\\f"),
+            byte if byte < 0x20 => out.extend_from_slice(&[
+                b'\\',
+                b'u',
+                b'0',
+                b'0',
+                HEX[(byte >> 4) as usize],
+                HEX[(byte & 0xF) as usize],
+            ]),
+            byte => out.push(byte),
+        }
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The 6 bit value of a base64 character in either the standard or URL safe alphabet.
+#[inline]
+fn base64_value(byte: u8) -> Option<u32> {
+    let value = match byte {
+        b'A'..=b'Z' => byte - b'A',
+        b'a'..=b'z' => byte - b'a' + 26,
+        b'0'..=b'9' => byte - b'0' + 52,
+        b'+' | b'-' => 62,
+        b'/' | b'_' => 63,
+        _ => return None,
+    };
+    Some(u32::from(value))
+}
+
+fn base64_decode(field: &[u8], out: &mut Vec<u8>) {
+    let start = out.len();
+    let data = field
+        .strip_suffix(b"==")
+        .or_else(|| field.strip_suffix(b"="))
+        .unwrap_or(field);
+    for chunk in data.chunks(4) {
+        let mut bits = 0u32;
+        for &byte in chunk {
+            match base64_value(byte) {
+                Some(value) => bits = bits << 6 | value,
+                None => {
+                    out.truncate(start);
+                    out.extend_from_slice(field);
+                    return;
+                }
+            }
+        }
+        match chunk.len() {
+            4 => out.extend_from_slice(&bits.to_be_bytes()[1..]),
+            3 => out.extend_from_slice(&(bits << 6).to_be_bytes()[1..3]),
+            2 => out.push((bits >> 4) as u8),
+            _ => {
+                out.truncate(start);
+                out.extend_from_slice(field);
+                return;
+            }
+        }
+    }
+}
+
+fn base64_encode(field: &[u8], out: &mut Vec<u8>) {
+    for chunk in field.chunks(3) {
+        let mut bytes = [0; 3];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * i) & 0x3F) as usize]);
+            } else {
+                out.push(b'=');
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(codec: Codec, field: &str) -> String {
+        let mut out = vec![];
+        codec.decode_into(field.as_bytes(), &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    fn encode(codec: Codec, field: &str) -> String {
+        let mut out = vec![];
+        codec.encode_into(field.as_bytes(), &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let spec: CodecSpec = "1,3-4:json".parse().unwrap();
+        assert_eq!(spec.codec, Codec::Json);
+        assert!(spec.contains(0) && spec.contains(3) && !spec.contains(1));
+        assert_eq!(
+            "3:hex".parse::<CodecSpec>(),
+            Err(CodecError::Unknown(String::from("hex")))
+        );
+        assert_eq!(
+            "url".parse::<CodecSpec>(),
+            Err(CodecError::InvalidSpec(String::from("url")))
+        );
+        assert!(matches!(
+            "0:url".parse::<CodecSpec>(),
+            Err(CodecError::InvalidField(_))
+        ));
+    }
+
+    #[test]
+    fn test_url() {
+        assert_eq!(decode(Codec::Url, "a%20b%2Fc+d"), "a b/c+d");
+        assert_eq!(decode(Codec::Url, "caf%C3%A9"), "café");
+        assert_eq!(decode(Codec::Url, "100%, %zz, %4"), "100%, %zz, %4");
+        assert_eq!(encode(Codec::Url, "a b/café~"), "a%20b%2Fcaf%C3%A9~");
+    }
+
+    #[test]
+    fn test_backslash() {
+        assert_eq!(decode(Codec::Backslash, r"a\tb\\n\x41\q\"), "a\tb\\nA\\q\\");
+        assert_eq!(encode(Codec::Backslash, "a\tb\\\n\x01é"), r"a\tb\\\n\x01é");
+    }
+
+    #[test]
+    fn test_tsv() {
+        assert_eq!(decode(Codec::Tsv, r"a\tb\\n\x41\q\"), "a\tb\\n\\x41\\q\\");
+        assert_eq!(
+            encode(Codec::Tsv, "a\tb\\\n\r\x01é"),
+            "a\\tb\\\\\\n\\r\x01é"
+        );
+        let text = "tab \t newline \n slash \\ \\t bell \x07";
+        assert_eq!(decode(Codec::Tsv, &encode(Codec::Tsv, text)), text);
+    }
+
+    #[test]
+    fn test_base64() {
+        for (text, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(encode(Codec::Base64, text), encoded);
+            assert_eq!(decode(Codec::Base64, encoded), text);
+        }
+        assert_eq!(decode(Codec::Base64, "Zm8"), "fo");
+        let (mut url_safe, mut standard) = (vec![], vec![]);
+        Codec::Base64.decode_into(b"-_-_", &mut url_safe);
+        Codec::Base64.decode_into(b"+/+/", &mut standard);
+        assert_eq!(url_safe, standard);
+        assert_eq!(standard, [0xFB, 0xFF, 0xBF]);
+        // Not base64, so left alone
+        for field in ["Zm9v!", "Z", "Zm9vY", "a=b"] {
+            assert_eq!(decode(Codec::Base64, field), field);
+        }
+        let mut out = vec![];
+        Codec::Base64.decode_into(b"/w==", &mut out);
+        assert_eq!(out, [0xFF]);
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            decode(Codec::Json, r#"say \"hi\"\né😀\ud800x\/"#),
+            "say \"hi\"\né😀\u{FFFD}x/"
+        );
+        assert_eq!(decode(Codec::Json, r"\u12\x"), r"\u12\x");
+        assert_eq!(
+            encode(Codec::Json, "say \"hi\"\n\té\x01"),
+            r#"say \"hi\"\n\té\u0001"#
+        );
+        let text = "quote \" slash \\ tab \t bell \x07 😀";
+        assert_eq!(decode(Codec::Json, &encode(Codec::Json, text)), text);
+        assert_eq!(
+            decode(Codec::Backslash, &encode(Codec::Backslash, text)),
+            text
+        );
+        assert_eq!(decode(Codec::Url, &encode(Codec::Url, text)), text);
+    }
+}
@@ -0,0 +1,445 @@
+//! Field comparators for ordering records.
+//!
+//! A [`SortKey`] pairs a 1-based field with a [`Comparator`], and a list of keys is parsed from a
+//! spec like `1V,2N,3nr`. Records are compared key by key with [`compare_keys`], the first key that
+//! differs decides the order.
+//!
+//! | Suffix | Comparator                | Ordering                         |
+//! | ------ | ------------------------- | -------------------------------- |
+//! | none   | [`Comparator::Bytes`]     | `file10` < `file9`               |
+//! | `n`    | [`Comparator::Numeric`]   | `9` < `10`, `-1.5` < `2e3`       |
+//! | `N`    | [`Comparator::Natural`]   | `file9` < `file10`               |
+//! | `V`    | [`Comparator::Version`]   | `1.2.0-rc.1` < `1.2.0` < `1.10.0`|
+//!
+//! Any key can also be suffixed with `r` to reverse it.
+//!
+//! All of the comparators work on raw bytes and never allocate. When byte order isn't acceptable for
+//! human facing text, the `collate` feature adds [`Collation`] for locale aware ordering with ICU,
+//! at a considerable cost in speed. Byte order remains the default.
+//!
+//! Nothing orders records yet, so the module stays private until a sort subcommand uses it.
+#![allow(dead_code)]
+use std::{borrow::Cow, cmp::Ordering, str::FromStr};
+
+use bstr::ByteSlice;
+use thiserror::Error;
+
+/// Errors for parsing a [`SortKey`].
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+#[allow(clippy::enum_variant_names)]
+pub enum SortKeyError {
+    #[error("Sort keys must be a field number followed by any of `n`, `N`, `V`, `r`: {0}")]
+    InvalidKey(String),
+    #[error("Fields are numbered from 1: {0}")]
+    InvalidField(String),
+    #[error("Collations must be given as `icu:LOCALE`, ex: `icu:en_US`: {0}")]
+    InvalidCollation(String),
+}
+
+/// How two field values are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Comparator {
+    /// Plain byte order.
+    #[default]
+    Bytes,
+    /// As floating point numbers, values that aren't numbers sort first in byte order.
+    Numeric,
+    /// Runs of digits are compared as numbers and everything else as bytes.
+    Natural,
+    /// As semantic versions, with an optional leading `v`. Pre-releases sort before their release
+    /// and build metadata is ignored. Values that aren't versions fall back to natural order.
+    Version,
+}
+
+impl Comparator {
+    /// Compare two values.
+    pub fn compare(self, a: &[u8], b: &[u8]) -> Ordering {
+        match self {
+            Comparator::Bytes => a.cmp(b),
+            Comparator::Numeric => compare_numeric(a, b),
+            Comparator::Natural => compare_natural(a, b),
+            Comparator::Version => compare_version(a, b),
+        }
+    }
+}
+
+/// A field to order records by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortKey {
+    /// The 0-based field index.
+    pub field: usize,
+    pub comparator: Comparator,
+    pub reverse: bool,
+}
+
+impl SortKey {
+    /// Parse a comma separated list of keys, ex: `1V,2N`.
+    pub fn from_list(list: &str) -> Result<Vec<SortKey>, SortKeyError> {
+        list.split(',').map(SortKey::from_str).collect()
+    }
+
+    /// Compare the key fields of two records, a missing field sorts before any value.
+    #[inline]
+    pub fn compare(&self, a: &[Cow<[u8]>], b: &[Cow<[u8]>]) -> Ordering {
+        let ordering = match (a.get(self.field), b.get(self.field)) {
+            (Some(a), Some(b)) => self.comparator.compare(a, b),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        };
+        if self.reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = SortKeyError;
+
+    /// Convert a [`str`] like `2`, `1V`, or `3nr` into a [`SortKey`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let field = match s[..digits].parse::<usize>() {
+            Ok(0) => return Err(SortKeyError::InvalidField(s.to_owned())),
+            Ok(field) => field - 1,
+            Err(_) => return Err(SortKeyError::InvalidKey(s.to_owned())),
+        };
+
+        let mut key = SortKey {
+            field,
+            comparator: Comparator::Bytes,
+            reverse: false,
+        };
+        for (i, c) in s[digits..].chars().enumerate() {
+            match c {
+                'r' if !key.reverse => key.reverse = true,
+                'n' | 'N' | 'V' if i == 0 => {
+                    key.comparator = match c {
+                        'n' => Comparator::Numeric,
+                        'N' => Comparator::Natural,
+                        _ => Comparator::Version,
+                    }
+                }
+                _ => return Err(SortKeyError::InvalidKey(s.to_owned())),
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// Compare two records by each key in turn.
+#[inline]
+pub fn compare_keys(keys: &[SortKey], a: &[Cow<[u8]>], b: &[Cow<[u8]>]) -> Ordering {
+    keys.iter()
+        .map(|key| key.compare(a, b))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Locale aware ordering of text, ex: `é` sorts between `e` and `f` in most locales.
+///
+/// Invalid UTF-8 is compared as if it were replaced with U+FFFD.
+#[cfg(feature = "collate")]
+pub(crate) struct Collation {
+    collator: icu_collator::Collator,
+}
+
+#[cfg(feature = "collate")]
+impl Collation {
+    /// Create a [`Collation`] from a spec like `icu:en_US` or `icu:de-DE`.
+    pub(crate) fn from_spec(spec: &str) -> Result<Self, SortKeyError> {
+        let invalid = || SortKeyError::InvalidCollation(spec.to_owned());
+        let locale = spec
+            .strip_prefix("icu:")
+            .and_then(|locale| icu_locid::Locale::try_from_bytes(locale.as_bytes()).ok())
+            .ok_or_else(invalid)?;
+        let collator = icu_collator::Collator::try_new(
+            &(&locale).into(),
+            icu_collator::CollatorOptions::new(),
+        )
+        .map_err(|_| invalid())?;
+        Ok(Self { collator })
+    }
+
+    /// Compare two values.
+    #[inline]
+    pub(crate) fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.collator.compare_utf8(a, b)
+    }
+}
+
+fn compare_numeric(a: &[u8], b: &[u8]) -> Ordering {
+    let parse = |v: &[u8]| v.to_str().ok().and_then(|v| v.trim().parse::<f64>().ok());
+    match (parse(a), parse(b)) {
+        (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal).then(a.cmp(b)),
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+/// Split off the leading run of bytes that are all digits, or all not digits.
+#[inline]
+fn next_run(value: &[u8]) -> (&[u8], &[u8]) {
+    let digits = value.first().is_some_and(u8::is_ascii_digit);
+    let end = value
+        .iter()
+        .position(|b| b.is_ascii_digit() != digits)
+        .unwrap_or(value.len());
+    value.split_at(end)
+}
+
+/// Compare two runs of ascii digits by value, without overflowing on long runs.
+#[inline]
+fn compare_digits(a: &[u8], b: &[u8]) -> Ordering {
+    fn trim(v: &[u8]) -> &[u8] {
+        let start = v.iter().position(|b| *b != b'0').unwrap_or(v.len());
+        &v[start..]
+    }
+    let (x, y) = (trim(a), trim(b));
+    x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+}
+
+fn compare_natural(mut a: &[u8], mut b: &[u8]) -> Ordering {
+    while !a.is_empty() && !b.is_empty() {
+        let (run_a, rest_a) = next_run(a);
+        let (run_b, rest_b) = next_run(b);
+        let ordering = if run_a[0].is_ascii_digit() && run_b[0].is_ascii_digit() {
+            compare_digits(run_a, run_b)
+        } else {
+            run_a.cmp(run_b)
+        };
+        if ordering.is_ne() {
+            return ordering;
+        }
+        a = rest_a;
+        b = rest_b;
+    }
+    a.len().cmp(&b.len())
+}
+
+/// The parts of a semantic version, borrowed from the value.
+struct Version<'a> {
+    core: Vec<&'a [u8]>,
+    pre: Option<&'a [u8]>,
+}
+
+impl<'a> Version<'a> {
+    fn parse(value: &'a [u8]) -> Option<Self> {
+        let value = value.strip_prefix(b"v").unwrap_or(value);
+        let value = value.split_str("+").next().unwrap_or(value);
+        let (core, pre) = match value.find_byte(b'-') {
+            Some(i) => (&value[..i], Some(&value[i + 1..])),
+            None => (value, None),
+        };
+        let core: Vec<&[u8]> = core.split_str(".").collect();
+        let valid = core
+            .iter()
+            .all(|part| !part.is_empty() && part.iter().all(u8::is_ascii_digit));
+        valid.then_some(Version { core, pre })
+    }
+}
+
+fn compare_version(a: &[u8], b: &[u8]) -> Ordering {
+    let (x, y) = match (Version::parse(a), Version::parse(b)) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return compare_natural(a, b),
+    };
+    // Missing trailing parts count as 0, so `1.2` == `1.2.0`
+    let len = x.core.len().max(y.core.len());
+    for i in 0..len {
+        let ordering = compare_digits(
+            x.core.get(i).copied().unwrap_or(b"0"),
+            y.core.get(i).copied().unwrap_or(b"0"),
+        );
+        if ordering.is_ne() {
+            return ordering;
+        }
+    }
+    match (x.pre, y.pre) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(x), Some(y)) => x
+            .split_str(".")
+            .zip(y.split_str("."))
+            .map(|(x, y)| {
+                let numeric = |v: &[u8]| !v.is_empty() && v.iter().all(u8::is_ascii_digit);
+                match (numeric(x), numeric(y)) {
+                    (true, true) => compare_digits(x, y),
+                    // Numeric identifiers have lower precedence than alphanumeric ones
+                    (true, false) => Ordering::Less,
+                    (false, true) => Ordering::Greater,
+                    (false, false) => x.cmp(y),
+                }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| x.split_str(".").count().cmp(&y.split_str(".").count())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sorted(comparator: Comparator, values: &[&str]) -> Vec<String> {
+        let mut values: Vec<&str> = values.to_vec();
+        values.sort_by(|a, b| comparator.compare(a.as_bytes(), b.as_bytes()));
+        values.into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(
+            SortKey::from_list("1V,2N,3nr,4").unwrap(),
+            vec![
+                SortKey {
+                    field: 0,
+                    comparator: Comparator::Version,
+                    reverse: false
+                },
+                SortKey {
+                    field: 1,
+                    comparator: Comparator::Natural,
+                    reverse: false
+                },
+                SortKey {
+                    field: 2,
+                    comparator: Comparator::Numeric,
+                    reverse: true
+                },
+                SortKey {
+                    field: 3,
+                    comparator: Comparator::Bytes,
+                    reverse: false
+                },
+            ]
+        );
+        assert!(matches!(
+            SortKey::from_list("0N"),
+            Err(SortKeyError::InvalidField(_))
+        ));
+        for bad in ["", "N", "1x", "1NV", "1rr"] {
+            assert!(
+                matches!(SortKey::from_str(bad), Err(SortKeyError::InvalidKey(_))),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_natural() {
+        assert_eq!(
+            sorted(
+                Comparator::Natural,
+                &[
+                    "file10",
+                    "file9",
+                    "file",
+                    "file09b",
+                    "file1",
+                    "a100000000000000000000000"
+                ]
+            ),
+            vec![
+                "a100000000000000000000000",
+                "file",
+                "file1",
+                "file9",
+                "file09b",
+                "file10"
+            ]
+        );
+        assert_eq!(
+            sorted(Comparator::Bytes, &["file10", "file9"]),
+            vec!["file10", "file9"]
+        );
+    }
+
+    #[test]
+    fn test_numeric() {
+        assert_eq!(
+            sorted(Comparator::Numeric, &["10", "9", "-1.5", "x", "2e3"]),
+            vec!["x", "-1.5", "9", "10", "2e3"]
+        );
+    }
+
+    #[test]
+    fn test_version() {
+        assert_eq!(
+            sorted(
+                Comparator::Version,
+                &[
+                    "1.10.0",
+                    "v1.2.0",
+                    "1.2.0-rc.1",
+                    "1.2.0-alpha",
+                    "1.2.0-alpha.1",
+                    "1.2.0-1",
+                    "1.9",
+                    "1.2.0-rc.10",
+                    "1.2.0-rc.2",
+                ]
+            ),
+            vec![
+                "1.2.0-1",
+                "1.2.0-alpha",
+                "1.2.0-alpha.1",
+                "1.2.0-rc.1",
+                "1.2.0-rc.2",
+                "1.2.0-rc.10",
+                "v1.2.0",
+                "1.9",
+                "1.10.0",
+            ]
+        );
+        assert_eq!(
+            Comparator::Version.compare(b"1.2+build.5", b"1.2.0"),
+            Ordering::Equal
+        );
+        // Not versions, so natural order
+        assert_eq!(
+            Comparator::Version.compare(b"release-9", b"release-10"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "collate")]
+    fn test_collation() {
+        let collation = Collation::from_spec("icu:en_US").unwrap();
+        let mut values = vec!["f", "é", "E", "e"];
+        values.sort_by(|a, b| collation.compare(a.as_bytes(), b.as_bytes()));
+        assert_eq!(values, vec!["e", "E", "é", "f"]);
+        assert!(Collation::from_spec("en_US").is_err());
+        assert!(Collation::from_spec("icu:not a locale").is_err());
+    }
+
+    #[test]
+    fn test_compare_keys() {
+        let record = |fields: &[&'static str]| -> Vec<Cow<'static, [u8]>> {
+            fields.iter().map(|f| Cow::Borrowed(f.as_bytes())).collect()
+        };
+        let keys = SortKey::from_list("1V,2nr").unwrap();
+        let mut records = vec![
+            record(&["1.10", "1"]),
+            record(&["1.9", "1"]),
+            record(&["1.9", "5"]),
+            record(&["1.9"]),
+        ];
+        records.sort_by(|a, b| compare_keys(&keys, a, b));
+        assert_eq!(
+            records,
+            vec![
+                record(&["1.9", "5"]),
+                record(&["1.9", "1"]),
+                record(&["1.9"]),
+                record(&["1.10", "1"]),
+            ]
+        );
+    }
+}
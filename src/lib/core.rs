@@ -5,69 +5,274 @@
 //!
 //! If we go with a dyn trait on the line splitter function it is appreciably slower.
 use crate::{
+    adaptive::{AdaptiveReader, Pace},
+    decompress::Decompressors,
+    direct_io::DirectReader,
     field_range::{FieldRange, RegexOrString},
-    line_parser::LineParser,
-    mmap::MmapChoice,
+    limit::{line_too_long, LineLimitReader},
+    line_parser::{
+        is_whitespace_regex, squeeze_delimiters, trim_blanks, DelimLineParser,
+        FixedWidthLineParser, LineParser, RegexLineParser, SubStrLineParser, WhitespaceLineParser,
+    },
+    metrics::{Metrics, Span},
+    mmap::{advise_sequential_file, advise_sequential_map, MmapChoice},
+    output::OutputFormat,
+    pattern::ColumnPattern,
+    pipeline::{Pipeline, Record, RecordContext, StageAction},
     single_byte_delim_parser::SingleByteDelimParser,
+    truncate::TruncatingReader,
 };
-use anyhow::Result;
-use bstr::ByteSlice;
-use flate2::read::MultiGzDecoder;
-use grep_cli::DecompressionReaderBuilder;
+use anyhow::{bail, Result};
+use bstr::{ByteSlice, ByteVec};
+use encoding_rs::{Encoding, UTF_8};
+use encoding_rs_io::DecodeReaderBytesBuilder;
 use regex::bytes::Regex;
 use ripline::{
-    line_buffer::{LineBuffer, LineBufferReader},
+    line_buffer::{LineBuffer, LineBufferBuilder, LineBufferReader},
     lines::{self, LineIter},
     LineTerminator,
 };
 use std::{
+    borrow::Cow,
+    fmt,
     fs::File,
-    io::{self, BufRead, BufReader, Read, Write},
+    io::{self, BufRead, BufReader, IoSlice, Read, Write},
+    iter, mem,
+    ops::Range,
     path::Path,
+    slice,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The default for [`CoreConfigBuilder::stdin_buffer_size`].
+pub use crate::adaptive::DEFAULT_MAX_READ_SIZE as DEFAULT_STDIN_BUFFER_SIZE;
 
 const DEFAULT_DELIM: &[u8] = b"\t";
 
+/// The delimiter for [`CoreConfigBuilder::awk_fields`], runs of the blanks awk's default `FS` splits on.
+const AWK_DELIM: &str = r"[ \t]+";
+
+/// The byte a regex delimiter matches if it is nothing but an escaped ASCII byte, ex: `\t`, `\0`, or
+/// `\x1f`, so that it can be split on as a literal instead.
+fn escaped_byte(pattern: &str) -> Option<u8> {
+    if !pattern.starts_with('\\') {
+        return None;
+    }
+    match Vec::unescape_bytes(pattern).as_slice() {
+        &[byte] if byte.is_ascii() => Some(byte),
+        _ => None,
+    }
+}
+
+/// A regex matching exactly `bytes`, which need not be UTF-8.
+fn literal_regex(bytes: &[u8]) -> Result<Regex> {
+    let pattern: String = bytes.iter().map(|b| format!("\\x{:02X}", b)).collect();
+    Ok(Regex::new(&format!("(?-u){}", pattern))?)
+}
+
+/// How much to read at a time when splitting records on a [`RecordSeparator`].
+const RECORD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How much to read at a time from an async reader, see [`Core::hck_async_reader`].
+#[cfg(feature = "async")]
+const ASYNC_CHUNK_SIZE: usize = 128 * 1024;
+
 /// The input types that `hck` can parse.
 pub enum HckInput<P: AsRef<Path>> {
     Stdin,
     Path(P),
 }
 
+/// What to do with rows whose field count differs from the first row of an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum RaggedRows {
+    /// Don't count fields at all.
+    #[default]
+    Ignore,
+    /// Write a line to the diagnostics for each ragged row and keep going.
+    Report,
+    /// Fail with an [`io::ErrorKind::InvalidData`] error on the first ragged row.
+    Error,
+}
+
+/// Writing the number of fields on each line, see [`CoreConfigBuilder::field_count`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FieldCount {
+    /// Write only the number of fields on each line, in place of the selected fields.
+    Only,
+    /// Write the number of fields on each line as a last column after the selected fields.
+    Column,
+}
+
+/// Column names for inputs that have no header line, see [`CoreConfigBuilder::header_names`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HeaderNames {
+    /// The names of the columns, in order.
+    Names(Vec<String>),
+    /// `c1`, `c2`, ... for as many columns as the first line of the input has.
+    Generated,
+}
+
+/// How lines are split into fields, see [`Explanation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParserKind {
+    /// A single byte delimiter scanned for along with the line terminator in one pass.
+    Fast,
+    /// A literal delimiter split out of each line.
+    SubStr,
+    /// Runs of whitespace, the default delimiter, split without the regex engine.
+    Whitespace,
+    /// A regex delimiter.
+    Regex,
+    /// Fixed width columns.
+    FixedWidth,
+    /// Lines aren't split at all, every field is selected and written back out as it was read.
+    Passthrough,
+}
+
+impl fmt::Display for ParserKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParserKind::Fast => "fast",
+            ParserKind::SubStr => "substr",
+            ParserKind::Whitespace => "whitespace",
+            ParserKind::Regex => "regex",
+            ParserKind::FixedWidth => "fixed-width",
+            ParserKind::Passthrough => "passthrough",
+        })
+    }
+}
+
+/// How a [`Core`] would process an input, see [`Core::explain`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    /// The parser lines are split with.
+    pub parser: ParserKind,
+    /// Whether the input would be memory mapped instead of read.
+    pub mmap: bool,
+    /// The name of the decompression backend the input would be read through, if any.
+    pub decompressor: Option<String>,
+}
+
+/// A record separator that a [`LineTerminator`] can't represent, which is only handled in slow mode.
+#[derive(Debug, Clone)]
+struct RecordSeparator {
+    /// Matches the separator in the input
+    regex: Regex,
+    /// What to write after each record, unless an output record separator is set
+    output: Vec<u8>,
+}
+
 /// The config object for [`Core`].
+///
+/// The [`CoreConfigBuilder`] takes borrowed or owned values, so a `CoreConfig<'static>` can be
+/// built from owned ones and kept around independently of where its inputs came from.
 #[derive(Debug, Clone)]
 pub struct CoreConfig<'a> {
-    delimiter: &'a [u8],
-    output_delimiter: &'a [u8],
+    delimiter: Cow<'a, [u8]>,
+    output_delimiter: Cow<'a, [u8]>,
     line_terminator: LineTerminator,
+    record_separator: Option<Cow<'a, [u8]>>,
+    record_separator_is_regex: bool,
+    output_record_separator: Option<Cow<'a, [u8]>>,
     mmap_choice: MmapChoice,
     is_parser_regex: bool,
     try_decompress: bool,
-    raw_fields: Option<&'a str>,
-    raw_header_fields: Option<&'a [Regex]>,
-    raw_exclude: Option<&'a str>,
-    raw_exclude_headers: Option<&'a [Regex]>,
+    decompressors: Decompressors,
+    encoding: Option<&'static Encoding>,
+    keep_bom: bool,
+    raw_fields: Option<Cow<'a, str>>,
+    raw_header_fields: Option<Cow<'a, [Regex]>>,
+    raw_exclude: Option<Cow<'a, str>>,
+    raw_exclude_headers: Option<Cow<'a, [Regex]>>,
     header_is_regex: bool,
+    header_names: Option<HeaderNames>,
+    reuse_input_delim: bool,
+    mirror_missing_terminator: bool,
+    count_only: bool,
+    fill: Option<Cow<'a, [u8]>>,
+    ragged_rows: RaggedRows,
+    field_count: Option<FieldCount>,
+    column_matches: Vec<ColumnPattern>,
+    invert_match: bool,
+    widths: Option<Cow<'a, [usize]>>,
+    awk_fields: bool,
+    squeeze: bool,
+    stdin_buffer_size: Option<usize>,
+    read_buffer_size: Option<usize>,
+    vectored_writes: bool,
+    direct_io: bool,
+    read_hints: bool,
+    truncate_lines: bool,
+    max_line_length: Option<usize>,
+    output_format: OutputFormat,
+    #[cfg(feature = "test-util")]
+    chaos_seed: Option<u64>,
+    #[cfg(feature = "uring")]
+    uring: bool,
     parsed_delim: RegexOrString,
+    parsed_record_separator: Option<RecordSeparator>,
+    parsed_output_terminator: Option<LineTerminator>,
 }
 
 impl<'a> Default for CoreConfig<'a> {
     fn default() -> Self {
         Self {
-            delimiter: DEFAULT_DELIM,
-            output_delimiter: DEFAULT_DELIM,
+            delimiter: Cow::Borrowed(DEFAULT_DELIM),
+            output_delimiter: Cow::Borrowed(DEFAULT_DELIM),
             line_terminator: LineTerminator::default(),
+            record_separator: None,
+            record_separator_is_regex: false,
+            output_record_separator: None,
             mmap_choice: unsafe { MmapChoice::auto() },
             is_parser_regex: false,
             try_decompress: false,
-            raw_fields: Some("1-"),
+            decompressors: Decompressors::default(),
+            encoding: None,
+            keep_bom: false,
+            raw_fields: Some(Cow::Borrowed("1-")),
             raw_header_fields: None,
             raw_exclude: None,
             raw_exclude_headers: None,
             header_is_regex: false,
+            header_names: None,
+            reuse_input_delim: false,
+            mirror_missing_terminator: false,
+            count_only: false,
+            fill: None,
+            ragged_rows: RaggedRows::default(),
+            field_count: None,
+            column_matches: vec![],
+            invert_match: false,
+            widths: None,
+            awk_fields: false,
+            squeeze: false,
+            stdin_buffer_size: None,
+            read_buffer_size: None,
+            vectored_writes: false,
+            direct_io: false,
+            read_hints: true,
+            truncate_lines: false,
+            max_line_length: None,
+            output_format: OutputFormat::default(),
+            #[cfg(feature = "test-util")]
+            chaos_seed: None,
+            #[cfg(feature = "uring")]
+            uring: false,
             parsed_delim: RegexOrString::String(
                 std::str::from_utf8(DEFAULT_DELIM).unwrap().to_string(),
             ),
+            parsed_record_separator: None,
+            parsed_output_terminator: None,
         }
     }
 }
@@ -78,6 +283,270 @@ impl<'a> CoreConfig<'a> {
         &self.parsed_delim
     }
 
+    /// Get the [`OutputFormat`] records are written with
+    pub fn output_format(&self) -> &OutputFormat {
+        &self.output_format
+    }
+
+    /// Get the output delimiter, with any escapes already resolved
+    pub fn output_delimiter(&self) -> &[u8] {
+        &self.output_delimiter
+    }
+
+    /// The input delimiter as bytes, if it is a literal rather than a regex or fixed widths
+    #[inline]
+    fn literal_delimiter(&self) -> Option<&[u8]> {
+        match &self.parsed_delim {
+            RegexOrString::String(delim) => Some(delim.as_bytes()),
+            _ => None,
+        }
+    }
+
+    /// The fast mode parser for selecting `fields`, set up the way [`Core`] uses it, if the
+    /// delimiter is a single byte literal and records are split on lines.
+    ///
+    /// Buffers handed to it have to end on a line terminator or at the end of the input, see
+    /// [`SingleByteDelimParser::process_buffer`]. Header handling, transcoding, and pipelines are
+    /// left to the caller.
+    pub fn single_byte_parser<'c>(
+        &'c self,
+        fields: &'c [FieldRange],
+    ) -> Option<SingleByteDelimParser<'c>> {
+        let &[sep] = self.literal_delimiter()? else {
+            return None;
+        };
+        if self.parsed_record_separator.is_some() {
+            return None;
+        }
+        let parser =
+            SingleByteDelimParser::new(self.line_terminator, &self.output_delimiter, fields, sep)
+                .output_terminator(self.output_line_terminator())
+                .count_fields(self.field_count == Some(FieldCount::Only))
+                .mirror_missing_terminator(self.mirrors_missing_terminator())
+                .count_only(self.count_only)
+                .vectored(self.vectored_writes)
+                .squeeze(self.squeeze)
+                .fill(self.fill());
+        Some(parser)
+    }
+
+    /// Get the line terminator
+    pub fn line_terminator(&self) -> LineTerminator {
+        self.line_terminator
+    }
+
+    /// Get the line terminator written after each record, the input's unless
+    /// [`CoreConfigBuilder::output_record_separator`] is set
+    pub fn output_line_terminator(&self) -> LineTerminator {
+        self.parsed_output_terminator
+            .unwrap_or(self.line_terminator)
+    }
+
+    /// Whether records are only counted, not written
+    pub fn count_only(&self) -> bool {
+        self.count_only
+    }
+
+    /// The value written in place of selected fields that are missing from a line
+    pub fn fill(&self) -> Option<&[u8]> {
+        self.fill.as_deref()
+    }
+
+    /// How rows with a differing number of fields are handled
+    pub fn ragged_rows(&self) -> RaggedRows {
+        self.ragged_rows
+    }
+
+    /// Whether the number of fields on each line is written, and how
+    pub fn field_count(&self) -> Option<FieldCount> {
+        self.field_count
+    }
+
+    /// The regexes input columns must match for a line to be kept
+    pub fn column_matches(&self) -> &[ColumnPattern] {
+        &self.column_matches
+    }
+
+    /// Whether fields are split the way awk's default field separator splits them, in which case
+    /// the line parser has to ignore leading and trailing blanks, see
+    /// [`RegexLineParser::trim_blanks`].
+    pub fn awk_fields(&self) -> bool {
+        self.awk_fields
+    }
+
+    /// Whether runs of a literal delimiter are treated as one, in which case the line parser has to
+    /// skip empty fields, see [`SubStrLineParser::squeeze`].
+    pub fn squeeze(&self) -> bool {
+        self.squeeze
+    }
+
+    /// The line parser for the delimiter, selecting `fields` with the fill, awk style fields, and
+    /// squeezing of this config. The default `\s+` delimiter is split without the regex engine.
+    pub fn line_parser<'p>(&'p self, fields: &'p [FieldRange]) -> DelimLineParser<'p> {
+        match &self.parsed_delim {
+            RegexOrString::Regex(regex) if is_whitespace_regex(regex) => {
+                DelimLineParser::Whitespace(
+                    WhitespaceLineParser::new(fields)
+                        .fill(self.fill())
+                        .trim_blanks(self.awk_fields),
+                )
+            }
+            RegexOrString::Regex(regex) => DelimLineParser::Regex(
+                RegexLineParser::new(fields, regex)
+                    .fill(self.fill())
+                    .trim_blanks(self.awk_fields),
+            ),
+            RegexOrString::String(delim) => DelimLineParser::SubStr(
+                SubStrLineParser::new(fields, delim.as_bytes())
+                    .fill(self.fill())
+                    .squeeze(self.squeeze),
+            ),
+            RegexOrString::Widths(widths) => DelimLineParser::FixedWidth(
+                FixedWidthLineParser::new(fields, widths).fill(self.fill()),
+            ),
+        }
+    }
+
+    /// The output delimiter to use for `line`, which is the first delimiter found on the line if
+    /// `reuse_input_delim` is set and the delimiter is a regex.
+    #[inline]
+    fn output_delimiter_for<'b>(&'b self, line: &'b [u8]) -> &'b [u8] {
+        match &self.parsed_delim {
+            RegexOrString::Regex(regex) if self.reuse_input_delim => regex
+                .find(line)
+                .map(|m| m.as_bytes())
+                .unwrap_or(&self.output_delimiter),
+            _ => &self.output_delimiter,
+        }
+    }
+
+    /// Whether a final line without a terminator should be written without one as well.
+    ///
+    /// Graphviz output always terminates its records since it has an epilogue to follow them.
+    #[inline]
+    fn mirrors_missing_terminator(&self) -> bool {
+        self.mirror_missing_terminator && !matches!(self.output_format, OutputFormat::Dot { .. })
+    }
+
+    /// A copy of this config that writes every field back out exactly as it was read, if the
+    /// delimiter allows it.
+    ///
+    /// Fixed width inputs have no delimiter to write back out, so they can't round trip.
+    pub(crate) fn roundtrip_config(&self) -> Option<CoreConfig<'a>> {
+        let mut config = self.clone();
+        match &self.parsed_delim {
+            RegexOrString::String(delim) => {
+                config.output_delimiter = Cow::Owned(delim.as_bytes().to_vec());
+            }
+            RegexOrString::Regex(_) => config.reuse_input_delim = true,
+            RegexOrString::Widths(_) => return None,
+        }
+        config.mirror_missing_terminator = true;
+        config.count_only = false;
+        config.squeeze = false;
+        config.awk_fields = false;
+        config.fill = None;
+        config.ragged_rows = RaggedRows::Ignore;
+        config.output_format = OutputFormat::Delimited;
+        config.keep_bom = true;
+        Some(config)
+    }
+
+    /// Wrap `reader` to transcode it to UTF-8 if an encoding is set.
+    ///
+    /// Otherwise, unless `keep_bom` is set, a UTF-8 byte order mark is stripped and an input
+    /// starting with a UTF-16 byte order mark is transcoded.
+    fn decode<'r, R: Read + 'r>(&self, reader: R) -> Box<dyn Read + 'r> {
+        match self.encoding {
+            Some(encoding) => Box::new(
+                DecodeReaderBytesBuilder::new()
+                    .encoding(Some(encoding))
+                    .strip_bom(true)
+                    .build(reader),
+            ),
+            None if self.keep_bom => Box::new(reader),
+            None => Box::new(
+                DecodeReaderBytesBuilder::new()
+                    .utf8_passthru(true)
+                    .strip_bom(true)
+                    .build(reader),
+            ),
+        }
+    }
+
+    /// The bytes of a memory mapped input past any UTF-8 byte order mark, or `None` if it has to be
+    /// read through [`CoreConfig::decode`] instead.
+    fn mapped_bytes<'m>(&self, bytes: &'m [u8]) -> Option<&'m [u8]> {
+        if self.encoding.is_some() {
+            return None;
+        }
+        if self.keep_bom {
+            return Some(bytes);
+        }
+        match Encoding::for_bom(bytes) {
+            Some((encoding, len)) if encoding == UTF_8 => Some(&bytes[len..]),
+            Some(_) => None,
+            None => Some(bytes),
+        }
+    }
+
+    /// Whether input files are read with io_uring, see [`CoreConfigBuilder::uring`].
+    fn uses_uring(&self) -> bool {
+        #[cfg(feature = "uring")]
+        return self.uring;
+        #[cfg(not(feature = "uring"))]
+        false
+    }
+
+    /// Why `input` can't be memory mapped, if memory maps are required, see [`MmapChoice::always`].
+    fn mmap_required_but_unavailable<P: AsRef<Path>>(
+        &self,
+        input: &HckInput<P>,
+    ) -> Option<&'static str> {
+        if !self.mmap_choice.is_required() {
+            return None;
+        }
+        match input {
+            HckInput::Stdin => Some("stdin"),
+            HckInput::Path(_) if self.try_decompress => Some("input read with `try_decompress`"),
+            HckInput::Path(_) if self.encoding.is_some() => Some("transcoded input"),
+            HckInput::Path(_) if self.direct_io => Some("input read with direct I/O"),
+            HckInput::Path(_) if self.uses_uring() => Some("input read with io_uring"),
+            HckInput::Path(_) => None,
+        }
+    }
+
+    /// Open a reader over the raw bytes of `input`, decompressing it if `try_decompress` is set.
+    ///
+    /// Compressed bytes read are added to `compressed`, if given.
+    pub(crate) fn open_input<P: AsRef<Path>>(
+        &self,
+        input: &HckInput<P>,
+        compressed: Option<&Arc<AtomicU64>>,
+    ) -> Result<Box<dyn Read>, io::Error> {
+        match input {
+            HckInput::Stdin if self.try_decompress => match compressed {
+                Some(count) => self.decompressors.open_stdin_counted(count),
+                None => self.decompressors.open_stdin(),
+            },
+            HckInput::Stdin => Ok(Box::new(io::stdin())),
+            HckInput::Path(path) if self.try_decompress => match compressed {
+                Some(count) => self.decompressors.open_counted(path.as_ref(), count),
+                None => self.decompressors.open(path.as_ref()),
+            },
+            HckInput::Path(path) if self.direct_io => Ok(Box::new(DirectReader::open(path)?)),
+            #[cfg(feature = "uring")]
+            HckInput::Path(path) if self.uring => crate::uring::open(path.as_ref()),
+            HckInput::Path(path) => {
+                let file = File::open(path)?;
+                if self.read_hints {
+                    advise_sequential_file(&file);
+                }
+                Ok(Box::new(file))
+            }
+        }
+    }
+
     /// Read the first line of an input and return it.
     ///
     /// It's up to the user to make sure that any consumed bytes are properly handed
@@ -86,40 +555,50 @@ impl<'a> CoreConfig<'a> {
         &self,
         input: &HckInput<P>,
     ) -> Result<Vec<u8>, io::Error> {
-        let mut buffer = String::new();
         match input {
             HckInput::Stdin => {
+                if self.encoding.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Header selections not supported when transcoding stdin, pass it as a file instead",
+                    ));
+                }
                 // TODO: work out how to decode just a byte slice
                 if self.try_decompress {
                     unimplemented!("Header selections not supported when piping gzipped stdin")
                 }
-                io::stdin().read_line(&mut buffer)?;
-            }
-
-            HckInput::Path(path) => {
-                if self.try_decompress {
-                    let reader: Box<dyn Read> = if path
-                        .as_ref()
-                        .to_str()
-                        .map(|p| p.ends_with(".gz"))
-                        .unwrap_or(false)
-                    {
-                        Box::new(MultiGzDecoder::new(File::open(path)?))
-                    } else {
-                        Box::new(
-                            DecompressionReaderBuilder::new()
-                                // .matcher(matcher)
-                                .build(path)?,
-                        )
-                    };
-                    let mut reader = BufReader::new(reader);
-                    reader.read_line(&mut buffer)?;
-                } else {
-                    BufReader::new(File::open(path)?).read_line(&mut buffer)?;
+                let mut stdin = io::stdin().lock();
+                if !self.keep_bom {
+                    // Stdin can't be reopened, so the byte order mark is skipped here and not again
+                    // when the rest of the input is processed
+                    match Encoding::for_bom(stdin.fill_buf()?) {
+                        Some((encoding, len)) if encoding == UTF_8 => stdin.consume(len),
+                        Some((encoding, _)) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!(
+                                    "Header selections not supported on {} stdin, pass it as a file instead",
+                                    encoding.name()
+                                ),
+                            ))
+                        }
+                        None => (),
+                    }
                 }
+                self.read_first_record(stdin)
+            }
+            HckInput::Path(_) => {
+                self.read_first_record(BufReader::new(self.decode(self.open_input(input, None)?)))
             }
         }
-        Ok(lines::without_terminator(buffer.as_bytes(), self.line_terminator).to_owned())
+    }
+
+    /// Read the first line, or record if there is a record separator, of `reader`.
+    fn read_first_record<R: BufRead>(&self, reader: R) -> Result<Vec<u8>, io::Error> {
+        match &self.parsed_record_separator {
+            Some(sep) => read_record(reader, &sep.regex),
+            None => read_line(reader, self.line_terminator),
+        }
     }
 
     /// Parse the raw user input fields and header fields. Returns any header bytes read and the parsed fields
@@ -127,15 +606,128 @@ impl<'a> CoreConfig<'a> {
     where
         P: AsRef<Path>,
     {
+        match &self.header_names {
+            Some(names) => self.resolve_named_fields(names, || self.peek_first_line(input)),
+            None => self.resolve_fields(&self.parsed_delim, || self.peek_first_line(input)),
+        }
+    }
+
+    /// Parse the raw user input fields and header fields against a list of column names.
+    ///
+    /// This is for inputs like Parquet that carry their column names out of band instead of in a header line.
+    pub fn parse_fields_from_names<S: AsRef<str>>(&self, names: &[S]) -> Result<Vec<FieldRange>> {
+        // Column names can't contain a NUL, so it is safe to join and re-split on
+        let header = names
+            .iter()
+            .map(|name| name.as_ref())
+            .collect::<Vec<_>>()
+            .join("\0");
+        let delim = RegexOrString::String(String::from("\0"));
+        let (_, fields) = self.resolve_fields(&delim, || Ok(header.as_bytes().to_vec()))?;
+        Ok(fields)
+    }
+
+    /// Parse the raw user input fields and header fields for an input that is already in memory,
+    /// taking any header from its first record.
+    pub fn parse_fields_from_bytes(&self, bytes: &[u8]) -> Result<Vec<FieldRange>> {
+        let bytes = self.mapped_bytes(bytes).unwrap_or(bytes);
+        let (_, fields) = match &self.header_names {
+            Some(names) => self.resolve_named_fields(names, || self.read_first_record(bytes))?,
+            None => self.resolve_fields(&self.parsed_delim, || self.read_first_record(bytes))?,
+        };
+        Ok(fields)
+    }
+
+    /// The column names of headerless inputs, see [`CoreConfigBuilder::header_names`].
+    ///
+    /// `first_line` is only looked at to count the columns for generated names.
+    pub fn column_names(&self, first_line: &[u8]) -> Option<Vec<String>> {
+        let columns = match self.header_names.as_ref()? {
+            HeaderNames::Names(names) => return Some(names.clone()),
+            HeaderNames::Generated => self.line_parser(&[]).count_fields(first_line),
+        };
+        Some((1..=columns).map(|i| format!("c{}", i)).collect())
+    }
+
+    /// The header line to write for headerless inputs: the column names selected by `fields`,
+    /// joined by the output delimiter and without a line terminator.
+    ///
+    /// `first_line` is the first line of the input, see [`CoreConfig::column_names`]. Returns
+    /// `None` if there are no header names.
+    pub fn header_line(&self, first_line: &[u8], fields: &[FieldRange]) -> Option<Vec<u8>> {
+        // Column names can't contain a NUL, as in `parse_fields_from_names`
+        let names = self.column_names(first_line)?.join("\0");
+        let parser = SubStrLineParser::new(fields, b"\0");
+        let mut shuffler = Shuffler::new(fields);
+        Some(shuffler.with_row(&parser, names.as_bytes(), |row| {
+            row.join(self.output_delimiter.as_ref())
+        }))
+    }
+
+    /// The names in the header line `first_line`, split the way header selections see them, or the
+    /// column names of headerless inputs if there are any.
+    pub fn header_fields(&self, first_line: &[u8]) -> Vec<Vec<u8>> {
+        if let Some(names) = self.column_names(first_line) {
+            return names.into_iter().map(String::into_bytes).collect();
+        }
+        let line = self.normalize_header(&self.parsed_delim, first_line.to_vec());
+        self.parsed_delim.split(&line).map(<[u8]>::to_vec).collect()
+    }
+
+    /// Drop the leading and trailing blanks of a header line for awk style fields, or squeeze its
+    /// delimiters, so it splits into the same fields as the lines after it.
+    fn normalize_header(&self, delim: &RegexOrString, line: Vec<u8>) -> Vec<u8> {
+        match (self.awk_fields, delim) {
+            (true, _) => trim_blanks(&line).to_vec(),
+            (false, RegexOrString::String(delim)) if self.squeeze => {
+                squeeze_delimiters(&line, delim.as_bytes())
+            }
+            _ => line,
+        }
+    }
+
+    /// Resolve the fields to select against the column names of headerless inputs, calling `peek`
+    /// for the first line if the names are generated. Returns the first line if it was read.
+    fn resolve_named_fields<F>(
+        &self,
+        names: &HeaderNames,
+        mut peek: F,
+    ) -> Result<(Option<Vec<u8>>, Vec<FieldRange>)>
+    where
+        F: FnMut() -> Result<Vec<u8>, io::Error>,
+    {
+        let first_line = match names {
+            HeaderNames::Names(_) => None,
+            HeaderNames::Generated => Some(peek()?),
+        };
+        let names = self
+            .column_names(first_line.as_deref().unwrap_or_default())
+            .unwrap_or_default();
+        Ok((first_line, self.parse_fields_from_names(&names)?))
+    }
+
+    /// Resolve the fields to select, calling `peek` if the header is needed to do so.
+    fn resolve_fields<F>(
+        &self,
+        delim: &RegexOrString,
+        mut peek: F,
+    ) -> Result<(Option<Vec<u8>>, Vec<FieldRange>)>
+    where
+        F: FnMut() -> Result<Vec<u8>, io::Error>,
+    {
+        let mut peek = || Ok::<_, io::Error>(self.normalize_header(delim, peek()?));
         // Parser the fields in the context of the files being looked at
-        let (mut extra, fields) = match (self.raw_fields, self.raw_header_fields) {
+        let (mut extra, fields) = match (
+            self.raw_fields.as_deref(),
+            self.raw_header_fields.as_deref(),
+        ) {
             (Some(field_list), Some(header_fields)) => {
-                let first_line = self.peek_first_line(input)?;
+                let first_line = peek()?;
                 let mut fields = FieldRange::from_list(field_list)?;
                 let header_fields = FieldRange::from_header_list(
                     header_fields,
                     first_line.as_bytes(),
-                    &self.parsed_delim,
+                    delim,
                     self.header_is_regex,
                     false,
                 )?;
@@ -145,11 +737,11 @@ impl<'a> CoreConfig<'a> {
             }
             (Some(field_list), None) => (None, FieldRange::from_list(field_list)?),
             (None, Some(header_fields)) => {
-                let first_line = self.peek_first_line(input)?;
+                let first_line = peek()?;
                 let fields = FieldRange::from_header_list(
                     header_fields,
                     first_line.as_bytes(),
-                    &self.parsed_delim,
+                    delim,
                     self.header_is_regex,
                     false,
                 )?;
@@ -158,19 +750,22 @@ impl<'a> CoreConfig<'a> {
             (None, None) => (None, FieldRange::from_list("1-")?),
         };
 
-        let fields = match (&self.raw_exclude, &self.raw_exclude_headers) {
+        let fields = match (
+            self.raw_exclude.as_deref(),
+            self.raw_exclude_headers.as_deref(),
+        ) {
             (Some(exclude), Some(exclude_header)) => {
                 let exclude = FieldRange::from_list(exclude)?;
                 let fields = FieldRange::exclude(fields, exclude);
                 let first_line = if let Some(first_line) = extra {
                     first_line
                 } else {
-                    self.peek_first_line(input)?
+                    peek()?
                 };
                 let exclude_headers = FieldRange::from_header_list(
                     exclude_header,
                     first_line.as_bytes(),
-                    &self.parsed_delim,
+                    delim,
                     self.header_is_regex,
                     true,
                 )?;
@@ -185,12 +780,12 @@ impl<'a> CoreConfig<'a> {
                 let first_line = if let Some(first_line) = extra {
                     first_line
                 } else {
-                    self.peek_first_line(input)?
+                    peek()?
                 };
                 let exclude_headers = FieldRange::from_header_list(
                     exclude_header,
                     first_line.as_bytes(),
-                    &self.parsed_delim,
+                    delim,
                     self.header_is_regex,
                     true,
                 )?;
@@ -217,27 +812,106 @@ impl<'a> CoreConfigBuilder<'a> {
     }
 
     pub fn build(mut self) -> Result<CoreConfig<'a>> {
-        let delim = if self.config.is_parser_regex {
-            RegexOrString::Regex(Regex::new(self.config.delimiter.to_str()?)?)
+        let delim = if let Some(widths) = &self.config.widths {
+            RegexOrString::Widths(widths.to_vec())
+        } else if self.config.awk_fields {
+            RegexOrString::Regex(Regex::new(AWK_DELIM)?)
+        } else if self.config.is_parser_regex {
+            let pattern = self.config.delimiter.to_str()?;
+            match escaped_byte(pattern) {
+                // Split on it as a literal so that fast mode can be used
+                Some(byte) => {
+                    self.config.is_parser_regex = false;
+                    if self.config.reuse_input_delim {
+                        self.config.output_delimiter = Cow::Owned(vec![byte]);
+                    }
+                    RegexOrString::String(char::from(byte).to_string())
+                }
+                None => RegexOrString::Regex(Regex::new(pattern)?),
+            }
         } else {
-            let unescaped =
-                std::str::from_utf8(&grep_cli::unescape(self.config.delimiter.to_str()?))?
-                    .to_string();
-            RegexOrString::String(unescaped)
+            let unescaped = Vec::unescape_bytes(self.config.delimiter.to_str()?);
+            match String::from_utf8(unescaped) {
+                Ok(unescaped) => RegexOrString::String(unescaped),
+                // Bytes that aren't UTF-8, ex: `\xFF`, can only be matched by a bytes regex
+                Err(err) => RegexOrString::Regex(literal_regex(err.as_bytes())?),
+            }
         };
+        if self.config.squeeze && !matches!(delim, RegexOrString::String(_)) {
+            bail!("Squeezing delimiters needs a literal delimiter");
+        }
         self.config.parsed_delim = delim;
+        if let Some(sep) = self.config.record_separator.take() {
+            self.parse_record_separator(&sep)?;
+            self.config.record_separator = Some(sep);
+        }
+        if let Some(sep) = self.config.output_record_separator.take() {
+            self.parse_output_record_separator(&sep)?;
+            self.config.output_record_separator = Some(sep);
+        }
+        if let Cow::Borrowed(output_delimiter) = &self.config.output_delimiter {
+            if let Ok(escaped) = output_delimiter.to_str() {
+                self.config.output_delimiter = Cow::Owned(Vec::unescape_bytes(escaped));
+            }
+        }
         Ok(self.config)
     }
 
+    /// Resolve the record separator into a [`LineTerminator`] if it is a single byte or CRLF, and
+    /// a [`RecordSeparator`] otherwise.
+    fn parse_record_separator(&mut self, sep: &[u8]) -> Result<()> {
+        let sep = sep.to_str()?;
+        let (regex, output) = if self.config.record_separator_is_regex {
+            (Regex::new(sep)?, b"\n".to_vec())
+        } else {
+            let unescaped = Vec::unescape_bytes(sep);
+            match unescaped.as_slice() {
+                [] => bail!("The record separator can't be empty"),
+                [byte] => {
+                    self.config.line_terminator = LineTerminator::byte(*byte);
+                    return Ok(());
+                }
+                b"\r\n" => {
+                    self.config.line_terminator = LineTerminator::crlf();
+                    return Ok(());
+                }
+                bytes => (literal_regex(bytes)?, unescaped),
+            }
+        };
+        if regex.is_match(b"") {
+            bail!("The record separator can't match an empty string: {}", sep);
+        }
+        self.config.parsed_record_separator = Some(RecordSeparator { regex, output });
+        Ok(())
+    }
+
+    /// Resolve the output record separator into a [`LineTerminator`].
+    fn parse_output_record_separator(&mut self, sep: &[u8]) -> Result<()> {
+        let sep = sep.to_str()?;
+        let term = match Vec::unescape_bytes(sep).as_slice() {
+            [byte] => LineTerminator::byte(*byte),
+            b"\r\n" => LineTerminator::crlf(),
+            _ => bail!(
+                "The output record separator must be a single byte or \\r\\n: {}",
+                sep
+            ),
+        };
+        self.config.parsed_output_terminator = Some(term);
+        Ok(())
+    }
+
     /// The substr to split lines on.
-    pub fn delimiter(mut self, delim: &'a [u8]) -> Self {
-        self.config.delimiter = delim;
+    pub fn delimiter(mut self, delim: impl Into<Cow<'a, [u8]>>) -> Self {
+        self.config.delimiter = delim.into();
         self
     }
 
-    /// The substr to use as the output delimiter
-    pub fn output_delimiter(mut self, delim: &'a [u8]) -> Self {
-        self.config.output_delimiter = delim;
+    /// The substr to use as the output delimiter.
+    ///
+    /// Escapes like `\t` and `\x1f` are resolved when the config is built, the same as for a literal
+    /// input delimiter.
+    pub fn output_delimiter(mut self, delim: impl Into<Cow<'a, [u8]>>) -> Self {
+        self.config.output_delimiter = delim.into();
         self
     }
 
@@ -247,6 +921,29 @@ impl<'a> CoreConfigBuilder<'a> {
         self
     }
 
+    /// Split records on `sep` instead of the line terminator, escapes like `\x1e` are resolved.
+    ///
+    /// A single byte or `\r\n` replaces the line terminator. Anything longer, or any regex, is only
+    /// handled in slow mode and is written back out after each record, or as a newline for regexes.
+    pub fn record_separator<S: Into<Cow<'a, [u8]>>>(mut self, sep: Option<S>) -> Self {
+        self.config.record_separator = sep.map(Into::into);
+        self
+    }
+
+    /// Whether the record separator is a regex.
+    pub fn record_separator_is_regex(mut self, is_regex: bool) -> Self {
+        self.config.record_separator_is_regex = is_regex;
+        self
+    }
+
+    /// Write `sep` after each record instead of the input's line terminator or record separator,
+    /// ex: to read CRLF lines and write `\n` or `\0` terminated ones. Escapes are resolved, and it
+    /// must be a single byte or `\r\n`.
+    pub fn output_record_separator<S: Into<Cow<'a, [u8]>>>(mut self, sep: Option<S>) -> Self {
+        self.config.output_record_separator = sep.map(Into::into);
+        self
+    }
+
     /// Whether or not to try to use mmap mode
     pub fn mmap(mut self, mmap_choice: MmapChoice) -> Self {
         self.config.mmap_choice = mmap_choice;
@@ -260,33 +957,58 @@ impl<'a> CoreConfigBuilder<'a> {
         self
     }
 
+    /// Transcode inputs from `encoding` to UTF-8 before splitting them, ex: UTF-16LE exports from
+    /// Excel. A BOM at the start of an input is dropped.
+    ///
+    /// Transcoded inputs are never memory mapped, but can still use fast mode.
+    pub fn encoding(mut self, encoding: Option<&'static Encoding>) -> Self {
+        self.config.encoding = encoding;
+        self
+    }
+
+    /// Leave byte order marks alone instead of stripping a UTF-8 BOM from the start of each input
+    /// and transcoding inputs that start with a UTF-16 BOM.
+    pub fn keep_bom(mut self, keep_bom: bool) -> Self {
+        self.config.keep_bom = keep_bom;
+        self
+    }
+
     /// Try to decompress an input file
     pub fn try_decompress(mut self, try_decompress: bool) -> Self {
         self.config.try_decompress = try_decompress;
         self
     }
 
+    /// The [`Decompressors`] used to open inputs when `try_decompress` is set
+    pub fn decompressors(mut self, decompressors: Decompressors) -> Self {
+        self.config.decompressors = decompressors;
+        self
+    }
+
     /// The raw user input fields to output
-    pub fn fields(mut self, fields: Option<&'a str>) -> Self {
-        self.config.raw_fields = fields;
+    pub fn fields<S: Into<Cow<'a, str>>>(mut self, fields: Option<S>) -> Self {
+        self.config.raw_fields = fields.map(Into::into);
         self
     }
 
     /// The raw user input header to output
-    pub fn headers(mut self, headers: Option<&'a [Regex]>) -> Self {
-        self.config.raw_header_fields = headers;
+    pub fn headers<H: Into<Cow<'a, [Regex]>>>(mut self, headers: Option<H>) -> Self {
+        self.config.raw_header_fields = headers.map(Into::into);
         self
     }
 
     /// The raw user input fields to exclude
-    pub fn exclude(mut self, exclude: Option<&'a str>) -> Self {
-        self.config.raw_exclude = exclude;
+    pub fn exclude<S: Into<Cow<'a, str>>>(mut self, exclude: Option<S>) -> Self {
+        self.config.raw_exclude = exclude.map(Into::into);
         self
     }
 
     /// The raw user input headers to exclude
-    pub fn exclude_headers(mut self, exclude_headers: Option<&'a [Regex]>) -> Self {
-        self.config.raw_exclude_headers = exclude_headers;
+    pub fn exclude_headers<H: Into<Cow<'a, [Regex]>>>(
+        mut self,
+        exclude_headers: Option<H>,
+    ) -> Self {
+        self.config.raw_exclude_headers = exclude_headers.map(Into::into);
         self
     }
 
@@ -295,243 +1017,1513 @@ impl<'a> CoreConfigBuilder<'a> {
         self.config.header_is_regex = header_is_regex;
         self
     }
-}
 
-impl<'a> Default for CoreConfigBuilder<'a> {
-    fn default() -> Self {
-        Self::new()
+    /// Treat inputs as having no header line and resolve header selections against `names`
+    /// instead, so every line of an input is data.
+    ///
+    /// [`CoreConfig::header_line`] gives the header to write for the selected fields.
+    pub fn header_names(mut self, names: Option<HeaderNames>) -> Self {
+        self.config.header_names = names;
+        self
     }
-}
 
-/// The main processing loop
-pub struct Core<'a, L> {
-    /// The [`CoreConfig`] object that determines how [`Core`] is run
-    config: &'a CoreConfig<'a>,
-    /// The [`FieldRange`]'s to keep, in the order to output them
-    fields: &'a [FieldRange],
-    /// The reusable line parse that defines how to parse a line (regex or substr).
-    line_parser: L,
-    /// The reusable line buffer that holds bytes from reads
-    line_buffer: &'a mut LineBuffer,
-}
+    /// Write out the first delimiter matched on each line instead of the output delimiter.
+    ///
+    /// This only applies to regex delimiters, the output delimiter is used for lines with no match.
+    pub fn reuse_input_delim(mut self, reuse_input_delim: bool) -> Self {
+        self.config.reuse_input_delim = reuse_input_delim;
+        self
+    }
 
-impl<'a, L> Core<'a, L>
-where
-    L: LineParser<'a>,
-{
-    /// Create a new "core" the can be used to parse multiple inputs
-    pub fn new(
-        config: &'a CoreConfig,
-        fields: &'a [FieldRange],
-        line_parser: L,
-        line_buffer: &'a mut LineBuffer,
-    ) -> Self {
-        Self {
-            config,
-            fields,
-            line_parser,
-            line_buffer,
-        }
+    /// Only count records and selected fields into the [`Metrics`], writing nothing.
+    pub fn count_only(mut self, count_only: bool) -> Self {
+        self.config.count_only = count_only;
+        self
     }
 
-    /// Check if no reordering of fields is happening
-    #[inline]
-    fn are_fields_pos_sorted(&self) -> bool {
-        let mut test = 0;
-        for field in self.fields {
-            if field.pos < test {
-                return false;
-            }
-            test = field.pos
-        }
-        true
+    /// Write `fill` in place of any selected fields a line is too short to have, instead of leaving
+    /// them out.
+    ///
+    /// The line parsers handed to [`Core`] must be given the same value.
+    pub fn fill<F: Into<Cow<'a, [u8]>>>(mut self, fill: Option<F>) -> Self {
+        self.config.fill = fill.map(Into::into);
+        self
     }
 
-    /// Check if we can run in `fast mode`.
+    /// Check that every row has as many fields as the first row of its input.
     ///
-    /// delimiter is 1 byte, newline is 1 bytes, and we are not using a regex
-    fn allow_fastmode(&self) -> bool {
-        self.config.delimiter.len() == 1
-            && self.config.line_terminator.as_bytes().len() == 1
-            && !self.config.is_parser_regex
-            && self.are_fields_pos_sorted()
+    /// Counting fields means scanning every line in full, so this disables fast mode.
+    pub fn ragged_rows(mut self, ragged_rows: RaggedRows) -> Self {
+        self.config.ragged_rows = ragged_rows;
+        self
     }
 
-    pub fn hck_input<P, W>(
-        &mut self,
+    /// Write the number of fields on each line, counted the same way as for
+    /// [`ragged_rows`](Self::ragged_rows), instead of or after the selected fields.
+    ///
+    /// The count is part of the record a [`Pipeline`] sees, so filters can pick out ragged rows. With
+    /// a single byte delimiter, [`FieldCount::Only`] keeps fast mode and counts delimiters without
+    /// splitting lines into fields.
+    pub fn field_count(mut self, field_count: Option<FieldCount>) -> Self {
+        self.config.field_count = field_count;
+        self
+    }
+
+    /// Only keep lines where every pattern matches its column, where the [`ColumnPattern::index`]
+    /// is a 0-based column of the input rather than an output position, ex: awk's `$3 ~ /^chr/`.
+    /// Lines too short to have a column never match it. A header kept by a [`Pipeline`] is
+    /// always kept.
+    ///
+    /// Columns are found on the whole line, so this disables fast mode.
+    pub fn column_matches(mut self, patterns: Vec<ColumnPattern>) -> Self {
+        self.config.column_matches = patterns;
+        self
+    }
+
+    /// Keep the lines that [`column_matches`](Self::column_matches) would drop, and drop the rest.
+    pub fn invert_match(mut self, invert: bool) -> Self {
+        self.config.invert_match = invert;
+        self
+    }
+
+    /// Write the record for a final input line with no terminator without one too.
+    ///
+    /// By default every record is terminated, so the output always ends with a line terminator.
+    pub fn mirror_missing_terminator(mut self, mirror_missing_terminator: bool) -> Self {
+        self.config.mirror_missing_terminator = mirror_missing_terminator;
+        self
+    }
+
+    /// Fixed column widths to split lines on instead of a delimiter
+    pub fn widths<W: Into<Cow<'a, [usize]>>>(mut self, widths: Option<W>) -> Self {
+        self.config.widths = widths.map(Into::into);
+        self
+    }
+
+    /// The largest read to make from `Stdin`, or [`DEFAULT_STDIN_BUFFER_SIZE`] if `None`.
+    ///
+    /// Reads grow towards this size while `Stdin` keeps filling them and shrink when it doesn't.
+    /// They are also capped by the capacity of the line buffer.
+    pub fn stdin_buffer_size(mut self, stdin_buffer_size: Option<usize>) -> Self {
+        self.config.stdin_buffer_size = stdin_buffer_size;
+        self
+    }
+
+    /// The size of each read from inputs split on a record separator, or read with
+    /// [`Core::hck_async_reader`], which default to 64K and 128K.
+    ///
+    /// Inputs split into lines are read into the [`LineBuffer`] given to [`Core::new`], which is
+    /// sized with [`LineBufferBuilder::capacity`].
+    pub fn read_buffer_size(mut self, read_buffer_size: Option<usize>) -> Self {
+        self.config.read_buffer_size = read_buffer_size;
+        self
+    }
+
+    /// Hand each buffer's selected fields to the output with [`Write::write_vectored`] instead of
+    /// copying them out one at a time.
+    ///
+    /// This only applies in fast mode, and only pays off when the output has real vectored
+    /// support and the fields are wide; small fields are cheaper to copy into a buffer.
+    pub fn vectored_writes(mut self, vectored_writes: bool) -> Self {
+        self.config.vectored_writes = vectored_writes;
+        self
+    }
+
+    /// Read input files with `O_DIRECT`, bypassing the page cache.
+    ///
+    /// This is meant for benchmarking throughput on uncached inputs. It never uses memory maps,
+    /// doesn't apply to compressed inputs, and fails to open inputs off of Linux.
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.config.direct_io = direct_io;
+        self
+    }
+
+    /// Hint to the OS that input files are read once from start to end, with `madvise` for memory
+    /// maps and `posix_fadvise` for files that are read, so it reads further ahead. This is on by
+    /// default, and the hints are skipped where they aren't supported.
+    pub fn read_hints(mut self, read_hints: bool) -> Self {
+        self.config.read_hints = read_hints;
+        self
+    }
+
+    /// Drop the part of each line after the last field that can be selected while reading it, so a
+    /// very long line never has to fit in the line buffer when only its first fields are wanted.
+    ///
+    /// This only applies to inputs that are read rather than memory mapped, with a single byte
+    /// literal delimiter, no record separator, no [`squeeze`](Self::squeeze) or ragged row checks,
+    /// and no open ended field ranges. Other inputs are read as usual.
+    pub fn truncate_lines(mut self, truncate_lines: bool) -> Self {
+        self.config.truncate_lines = truncate_lines;
+        self
+    }
+
+    /// Fail with the offending line number on a line longer than this many bytes, instead of
+    /// growing the line buffer until the line fits. The line terminator isn't counted, and lines
+    /// are measured after any [`truncate_lines`](Self::truncate_lines).
+    ///
+    /// Inputs that are memory mapped or already in memory are never copied into a buffer, so their
+    /// lines aren't limited unless they are split on a [`record_separator`](Self::record_separator).
+    /// Neither are the lines of [`Core::hck_async_reader`].
+    pub fn max_line_length(mut self, max_line_length: Option<usize>) -> Self {
+        self.config.max_line_length = max_line_length;
+        self
+    }
+
+    /// Split fields like awk's default field separator does, on runs of spaces and tabs with leading
+    /// and trailing ones ignored, so field numbers match awk's `$1`, `$2`, ...
+    ///
+    /// This replaces the delimiter.
+    pub fn awk_fields(mut self, awk_fields: bool) -> Self {
+        self.config.awk_fields = awk_fields;
+        self
+    }
+
+    /// Treat runs of a literal delimiter as one, so empty fields are skipped, without switching to a
+    /// regex and losing fast mode.
+    ///
+    /// Building the config fails if the delimiter isn't a literal. The line parsers handed to
+    /// [`Core`] must be given the same value.
+    pub fn squeeze(mut self, squeeze: bool) -> Self {
+        self.config.squeeze = squeeze;
+        self
+    }
+
+    /// The format to write the selected fields in
+    pub fn output_format(mut self, output_format: OutputFormat) -> Self {
+        self.config.output_format = output_format;
+        self
+    }
+
+    /// Read input files with io_uring, keeping several reads in flight to keep fast storage busy.
+    ///
+    /// This never uses memory maps and doesn't apply to compressed inputs. Files are read normally
+    /// off of Linux, or where io_uring is unavailable.
+    #[cfg(feature = "uring")]
+    pub fn uring(mut self, uring: bool) -> Self {
+        self.config.uring = uring;
+        self
+    }
+
+    /// Read all inputs through a [`ChaosReader`](crate::chaos::ChaosReader) seeded with `seed`
+    #[cfg(feature = "test-util")]
+    pub fn chaos_read(mut self, seed: Option<u64>) -> Self {
+        self.config.chaos_seed = seed;
+        self
+    }
+}
+
+impl<'a> Default for CoreConfigBuilder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The main processing loop
+pub struct Core<'a, L> {
+    /// The [`CoreConfig`] object that determines how [`Core`] is run
+    config: &'a CoreConfig<'a>,
+    /// The [`FieldRange`]'s to keep, in the order to output them
+    fields: &'a [FieldRange],
+    /// The reusable line parse that defines how to parse a line (regex or substr).
+    line_parser: L,
+    /// The reusable line buffer that holds bytes from reads
+    line_buffer: &'a mut LineBuffer,
+    /// Counters for the inputs processed so far
+    metrics: Metrics,
+    /// The stages to run each record through before writing it
+    pipeline: Option<&'a mut Pipeline>,
+    /// The field count check, if [`RaggedRows`] isn't `Ignore`
+    ragged: Option<RaggedCheck<'a>>,
+    /// How the current input is arriving, if it is `Stdin`
+    pace: Option<Pace>,
+    /// The compressed bytes read from the current input, moved into `metrics` when it is done
+    compressed: Arc<AtomicU64>,
+    /// Whether the first record is a header the pipeline keeps, which isn't counted as written
+    keeps_header: bool,
+}
+
+impl<'a, L> Core<'a, L>
+where
+    L: LineParser<'a>,
+{
+    /// Create a new "core" the can be used to parse multiple inputs
+    pub fn new(
+        config: &'a CoreConfig,
+        fields: &'a [FieldRange],
+        line_parser: L,
+        line_buffer: &'a mut LineBuffer,
+    ) -> Self {
+        Self {
+            config,
+            fields,
+            line_parser,
+            line_buffer,
+            metrics: Metrics::default(),
+            pipeline: None,
+            ragged: (config.ragged_rows != RaggedRows::Ignore).then(|| RaggedCheck {
+                mode: config.ragged_rows,
+                name: String::new(),
+                expected: None,
+                diagnostics: Box::new(io::stderr()),
+            }),
+            pace: None,
+            compressed: Arc::new(AtomicU64::new(0)),
+            keeps_header: false,
+        }
+    }
+
+    /// Where ragged rows are reported, defaults to `Stderr`.
+    pub fn diagnostics<D: Write + 'a>(mut self, diagnostics: D) -> Self {
+        if let Some(ragged) = self.ragged.as_mut() {
+            ragged.diagnostics = Box::new(diagnostics);
+        }
+        self
+    }
+
+    /// Run every record through `pipeline` before writing it out, starting a new input for it, see
+    /// [`Pipeline::start_input`].
+    ///
+    /// A pipeline that would leave every record as is is ignored so that it doesn't disable the fast path.
+    pub fn pipeline(mut self, mut pipeline: Option<&'a mut Pipeline>) -> Self {
+        if let Some(pipeline) = pipeline.as_deref_mut() {
+            pipeline.start_input();
+        }
+        self.keeps_header = pipeline
+            .as_ref()
+            .is_some_and(|pipeline| pipeline.keeps_header());
+        self.pipeline = pipeline.filter(|pipeline| !pipeline.is_noop());
+        self
+    }
+
+    /// Count the `read` records that fast mode just wrote, which is all of them but the header.
+    #[inline]
+    fn count_written_fast(&mut self, read: u64) {
+        if !self.config.count_only {
+            let header = self.keeps_header && self.metrics.records == 0 && read > 0;
+            self.metrics.written += read - u64::from(header);
+        }
+    }
+
+    /// The [`CoreConfig`] this [`Core`] was created with.
+    pub fn config(&self) -> &'a CoreConfig<'a> {
+        self.config
+    }
+
+    /// Work out how `input` would be processed by [`Core::hck_input`] without processing it.
+    ///
+    /// Deciding whether to memory map a path opens it, but no records are read.
+    pub fn explain<P: AsRef<Path>>(&self, input: &HckInput<P>) -> Result<Explanation, io::Error> {
+        let config = self.config;
+        let parser = match &config.parsed_delim {
+            _ if self.allow_passthrough() => ParserKind::Passthrough,
+            _ if self.allow_fastmode() => ParserKind::Fast,
+            RegexOrString::Regex(regex) if is_whitespace_regex(regex) => ParserKind::Whitespace,
+            RegexOrString::Regex(_) => ParserKind::Regex,
+            RegexOrString::String(_) => ParserKind::SubStr,
+            RegexOrString::Widths(_) => ParserKind::FixedWidth,
+        };
+        let decompressor = match input {
+            _ if !config.try_decompress => None,
+            HckInput::Stdin => config.decompressors.find_stdin(),
+            HckInput::Path(path) => config.decompressors.find(path.as_ref()),
+        };
+        if let Some(reason) = config.mmap_required_but_unavailable(input) {
+            return Err(mmap_required(reason));
+        }
+        let mmap = match input {
+            HckInput::Path(path)
+                if !(config.try_decompress
+                    || config.encoding.is_some()
+                    || config.direct_io
+                    || config.uses_uring()) =>
+            {
+                let file = File::open(path)?;
+                config
+                    .mmap_choice
+                    .open(&file, Some(path))?
+                    .is_some_and(|mmap| config.mapped_bytes(mmap.as_bytes()).is_some())
+            }
+            _ => false,
+        };
+        Ok(Explanation {
+            parser,
+            mmap,
+            decompressor: decompressor.map(|backend| backend.name().to_owned()),
+        })
+    }
+
+    /// The [`Metrics`] collected over all inputs processed by this [`Core`].
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Check if no reordering of fields is happening
+    #[inline]
+    fn are_fields_pos_sorted(&self) -> bool {
+        let mut test = 0;
+        for field in self.fields {
+            if field.pos < test {
+                return false;
+            }
+            test = field.pos
+        }
+        true
+    }
+
+    /// Check if no two fields overlap, and so each field of a line is written at most once.
+    ///
+    /// This only holds if the fields are also sorted by `low`.
+    fn are_fields_disjoint(&self) -> bool {
+        self.fields
+            .windows(2)
+            .all(|pair| pair[0].high < pair[1].low)
+    }
+
+    /// Check if we can run in `fast mode`.
+    ///
+    /// delimiter is 1 byte, newline is 1 byte or CRLF, we are not using a regex or fixed widths, the output is plain delimited text,
+    /// there is no pipeline to run records through, fields aren't being counted, records are split on lines,
+    /// any field counts are written on their own without squeezing delimiters, no columns are matched, and fields are either
+    /// written in input order or don't overlap so they can be reordered
+    fn allow_fastmode(&self) -> bool {
+        matches!(self.config.literal_delimiter(), Some(delim) if delim.len() == 1)
+            && !self.config.is_parser_regex
+            && self.config.widths.is_none()
+            && self.config.output_format.is_delimited()
+            && self.pipeline.is_none()
+            && self.ragged.is_none()
+            && self.config.parsed_record_separator.is_none()
+            && self.config.column_matches.is_empty()
+            && match self.config.field_count {
+                None => true,
+                Some(FieldCount::Only) => !self.config.squeeze,
+                Some(FieldCount::Column) => false,
+            }
+            && (self.are_fields_pos_sorted() || self.are_fields_disjoint())
+    }
+
+    /// Check if the output would be identical to the input, so it can be copied through without
+    /// splitting lines into fields.
+    ///
+    /// Every field is selected with `1-`, the literal delimiter is written back out as is, and nothing
+    /// rewrites a line on the way: no squeezing, pipeline, or counting, plain delimited output, and
+    /// records split on a single byte line terminator that is also written out. CRLF mode also ends
+    /// bare `\n` lines with `\r\n`.
+    fn allow_passthrough(&self) -> bool {
+        let config = self.config;
+        matches!(self.fields, [field] if field.low == 0 && field.is_open_ended())
+            && !config.is_parser_regex
+            && config.literal_delimiter() == Some(config.output_delimiter())
+            && config.output_format.is_delimited()
+            && !config.squeeze
+            && !config.awk_fields
+            && !config.count_only
+            && self.pipeline.is_none()
+            && self.ragged.is_none()
+            && config.parsed_record_separator.is_none()
+            && config.field_count.is_none()
+            && config.column_matches.is_empty()
+            && !config.line_terminator.is_crlf()
+            && config.output_line_terminator() == config.line_terminator
+    }
+
+    /// The single byte delimiter and the index of the last field to keep on each line, if lines can
+    /// be cut short while reading, see [`CoreConfigBuilder::truncate_lines`].
+    fn truncate_after(&self) -> Option<(u8, usize)> {
+        if !self.config.truncate_lines
+            || self.config.parsed_record_separator.is_some()
+            || self.config.squeeze
+            || self.ragged.is_some()
+            || self.config.field_count.is_some()
+            || !self.config.column_matches.is_empty()
+        {
+            return None;
+        }
+        let delim = match self.config.literal_delimiter() {
+            Some(&[delim]) => delim,
+            _ => return None,
+        };
+        let last = self.fields.iter().map(|field| field.high).max()?;
+        // Open ended ranges run to the end of the line
+        (last < usize::MAX - 1).then_some((delim, last))
+    }
+
+    /// Process an input, writing the selected fields to `output`.
+    ///
+    /// `header` holds any bytes already consumed from `Stdin` while parsing the fields.
+    pub fn hck_input<P, W>(
+        &mut self,
         input: HckInput<P>,
-        mut output: W,
+        output: W,
         header: Option<Vec<u8>>,
     ) -> Result<(), io::Error>
     where
         P: AsRef<Path>,
         W: Write,
     {
-        // Dispatch to a given `hck_*` runner depending on configuration
+        if self.pipeline.as_ref().is_some_and(|p| p.is_stopped()) {
+            return Ok(());
+        }
+        if let Some(ragged) = self.ragged.as_mut() {
+            ragged.reset(&input);
+        }
+        let span = Span::start();
+        let result = self.dispatch_input(input, output, header);
+        span.finish(&mut self.metrics);
+        self.metrics.compressed_bytes += self.compressed.swap(0, Ordering::Relaxed);
+        result
+    }
+
+    /// Call `f` with the selected fields of each line of `input`, in output order, instead of
+    /// writing them out.
+    ///
+    /// The fields borrow from a buffer that is reused for the next line. The pipeline and output
+    /// format are not applied, and processing stops at the first error from `f`. As with
+    /// [`Core::hck_input`], any header consumed from `Stdin` while parsing the fields is not seen
+    /// again. Record separators are not supported.
+    pub fn for_each_row<P, F>(&mut self, input: HckInput<P>, mut f: F) -> Result<(), io::Error>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&[&[u8]]) -> Result<(), io::Error>,
+    {
+        if let Some(ragged) = self.ragged.as_mut() {
+            ragged.reset(&input);
+        }
+        let span = Span::start();
+        let result = self
+            .open_input(&input)
+            .and_then(|reader| self.visit_reader(self.config.decode(reader), &mut f));
+        span.finish(&mut self.metrics);
+        self.metrics.compressed_bytes += self.compressed.swap(0, Ordering::Relaxed);
+        result
+    }
+
+    /// Like [`Core::for_each_row`], but over the lines of a slice of bytes.
+    pub fn for_each_row_bytes<F>(&mut self, bytes: &[u8], mut f: F) -> Result<(), io::Error>
+    where
+        F: FnMut(&[&[u8]]) -> Result<(), io::Error>,
+    {
+        if let Some(ragged) = self.ragged.as_mut() {
+            ragged.reset_named(String::from("bytes"));
+        }
+        let span = Span::start();
+        let result = self.visit_reader(bytes, &mut f);
+        span.finish(&mut self.metrics);
+        result
+    }
+
+    /// Call `f` with the selected fields of each line from a reader.
+    fn visit_reader<R, F>(&mut self, reader: R, f: &mut F) -> Result<(), io::Error>
+    where
+        R: Read,
+        F: FnMut(&[&[u8]]) -> Result<(), io::Error>,
+    {
+        if self.config.parsed_record_separator.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "record separators are not supported when visiting rows",
+            ));
+        }
+        match self.config.max_line_length {
+            Some(max) => {
+                let newline = self.config.line_terminator.as_byte();
+                let reader = LineLimitReader::new(reader, newline, max, self.metrics.records);
+                self.visit_lines(reader, f)
+            }
+            None => self.visit_lines(reader, f),
+        }
+    }
+
+    /// [`Core::visit_reader`] once the reader is set up.
+    fn visit_lines<R, F>(&mut self, reader: R, f: &mut F) -> Result<(), io::Error>
+    where
+        R: Read,
+        F: FnMut(&[&[u8]]) -> Result<(), io::Error>,
+    {
+        let mut reader = LineBufferReader::new(RetryReader(reader), self.line_buffer);
+        let mut shuffler = Shuffler::new(self.fields);
+        while reader.fill()? {
+            self.metrics.bytes += reader.buffer().len() as u64;
+            for line in LineIter::new(self.config.line_terminator.as_byte(), reader.buffer()) {
+                self.metrics.records += 1;
+                let line = lines::without_terminator(line, self.config.line_terminator);
+                if let Some(ragged) = self.ragged.as_mut() {
+                    ragged.check(self.metrics.records, self.line_parser.count_fields(line))?;
+                }
+                shuffler.with_row(&self.line_parser, line, |row| {
+                    self.metrics.fields += row.len() as u64;
+                    f(row)
+                })?;
+            }
+            reader.consume(reader.buffer().len());
+        }
+        Ok(())
+    }
+
+    /// Open a reader over the raw bytes of `input`, reading `Stdin` through an [`AdaptiveReader`]
+    /// whose [`Pace`] decides when output is flushed, see [`flush_if_interactive`].
+    fn open_input<P: AsRef<Path>>(
+        &mut self,
+        input: &HckInput<P>,
+    ) -> Result<Box<dyn Read>, io::Error> {
+        let reader = self.config.open_input(input, Some(&self.compressed))?;
         match input {
+            HckInput::Stdin => {
+                let reader = AdaptiveReader::new(reader, self.config.stdin_buffer_size);
+                self.pace = Some(reader.pace());
+                Ok(Box::new(reader))
+            }
+            HckInput::Path(_) => {
+                self.pace = None;
+                Ok(reader)
+            }
+        }
+    }
+
+    /// Dispatch to a given `hck_*` runner depending on configuration
+    fn dispatch_input<P, W>(
+        &mut self,
+        input: HckInput<P>,
+        mut output: W,
+        header: Option<Vec<u8>>,
+    ) -> Result<(), io::Error>
+    where
+        P: AsRef<Path>,
+        W: Write,
+    {
+        #[cfg(feature = "test-util")]
+        if let Some(seed) = self.config.chaos_seed {
+            return self.hck_input_chaos(input, output, header, seed);
+        }
+
+        let config = self.config;
+        if let Some(reason) = config.mmap_required_but_unavailable(&input) {
+            return Err(mmap_required(reason));
+        }
+        let reader = match &input {
             HckInput::Stdin => {
                 if let Some(header) = header {
-                    self.hck_bytes(header.as_bytes(), &mut output)?;
-                }
-                let reader: Box<dyn Read> = if self.config.try_decompress {
-                    Box::new(MultiGzDecoder::new(io::stdin()))
-                } else {
-                    Box::new(io::stdin())
-                };
-                if self.allow_fastmode() {
-                    self.hck_reader_fast(reader, &mut output)
-                } else {
-                    self.hck_reader(reader, &mut output)
+                    self.hck_header(header, &mut output)?;
+                    // Any byte order mark was skipped along with the header
+                    let reader = self.open_input(&input)?;
+                    return self.hck_reader_any(reader, output);
                 }
+                config.decode(self.open_input(&input)?)
+            }
+            HckInput::Path(_)
+                if config.try_decompress
+                    || config.encoding.is_some()
+                    || config.direct_io
+                    || config.uses_uring() =>
+            {
+                config.decode(self.open_input(&input)?)
             }
             HckInput::Path(path) => {
-                if self.config.try_decompress {
-                    let reader: Box<dyn Read> = if path
-                        .as_ref()
-                        .to_str()
-                        .map(|p| p.ends_with(".gz"))
-                        .unwrap_or(false)
-                    {
-                        Box::new(MultiGzDecoder::new(File::open(&path)?))
-                    } else {
-                        Box::new(
-                            DecompressionReaderBuilder::new()
-                                // .matcher(matcher)
-                                .build(&path)?,
-                        )
-                    };
-                    if self.allow_fastmode() {
-                        self.hck_reader_fast(reader, &mut output)
-                    } else {
-                        self.hck_reader(reader, &mut output)
+                self.pace = None;
+                let file = File::open(path)?;
+                if let Some(mmap) = config.mmap_choice.open(&file, Some(path))? {
+                    if config.read_hints {
+                        advise_sequential_map(&mmap);
                     }
-                } else {
-                    let file = File::open(&path)?;
-                    if let Some(mmap) = self.config.mmap_choice.open(&file, Some(&path)) {
-                        if self.allow_fastmode() {
-                            self.hck_bytes_fast(mmap.as_bytes(), &mut output)
-                        } else {
-                            self.hck_bytes(mmap.as_bytes(), &mut output)
+                    match config.mapped_bytes(mmap.as_bytes()) {
+                        Some(bytes) => return self.hck_bytes_any(bytes, output),
+                        None if config.mmap_choice.is_required() => {
+                            return Err(mmap_required("input with a non UTF-8 byte order mark"))
                         }
-                    } else if self.allow_fastmode() {
-                        self.hck_reader_fast(file, &mut output)
-                    } else {
-                        self.hck_reader(file, &mut output)
+                        None => (),
                     }
                 }
+                if config.read_hints {
+                    advise_sequential_file(&file);
+                }
+                config.decode(file)
             }
+        };
+        self.hck_reader_any(reader, output)
+    }
+
+    /// Process an input through a [`ChaosReader`](crate::chaos::ChaosReader), never using mmap.
+    #[cfg(feature = "test-util")]
+    fn hck_input_chaos<P, W>(
+        &mut self,
+        input: HckInput<P>,
+        mut output: W,
+        header: Option<Vec<u8>>,
+        seed: u64,
+    ) -> Result<(), io::Error>
+    where
+        P: AsRef<Path>,
+        W: Write,
+    {
+        let reader = match (&input, header) {
+            (HckInput::Stdin, Some(header)) => {
+                self.hck_header(header, &mut output)?;
+                self.open_input(&input)?
+            }
+            _ => self.config.decode(self.open_input(&input)?),
+        };
+        let reader = crate::chaos::ChaosReader::new(reader, seed);
+        self.hck_reader_any(reader, output)
+    }
+
+    /// Process an input that is already in memory, handling any byte order mark or encoding the
+    /// way a file would be.
+    pub fn hck_slice<W: Write>(&mut self, bytes: &[u8], output: W) -> Result<(), io::Error> {
+        if let Some(ragged) = self.ragged.as_mut() {
+            ragged.reset_named(String::from("bytes"));
+        }
+        let span = Span::start();
+        let result = match self.config.mapped_bytes(bytes) {
+            Some(bytes) => self.hck_bytes_any(bytes, output),
+            None => self.hck_reader_any(self.config.decode(bytes), output),
+        };
+        span.finish(&mut self.metrics);
+        result
+    }
+
+    /// Process an async reader, writing the selected fields to an async writer.
+    ///
+    /// Input is read a chunk at a time and the whole lines in each chunk are processed in memory,
+    /// so a runtime thread is only busy while a chunk is processed. The writer is flushed at the
+    /// end. Header selections aren't resolved here, parse the fields from the first line with
+    /// [`CoreConfig::parse_fields_from_bytes`]. Record separators and transcoding aren't supported.
+    #[cfg(feature = "async")]
+    pub async fn hck_async_reader<R, W>(
+        &mut self,
+        mut reader: R,
+        mut writer: W,
+    ) -> Result<(), io::Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if self.config.parsed_record_separator.is_some() || self.config.encoding.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "record separators and transcoding are not supported for async readers",
+            ));
+        }
+        let term = self.config.line_terminator.as_byte();
+        let chunk_size = self.config.read_buffer_size.unwrap_or(ASYNC_CHUNK_SIZE);
+        let mut buffer = Vec::with_capacity(chunk_size);
+        let mut output = vec![];
+        let mut first = true;
+        while !self.pipeline.as_ref().is_some_and(|p| p.is_stopped()) {
+            buffer.reserve(chunk_size);
+            let read = reader.read_buf(&mut buffer).await?;
+            // Hold on to a partial last line until the rest of it is read
+            let end = match memchr::memrchr(term, &buffer) {
+                _ if read == 0 => buffer.len(),
+                Some(i) => i + 1,
+                None => continue,
+            };
+            let mut bytes = &buffer[..end];
+            if first && !bytes.is_empty() {
+                first = false;
+                bytes = self.config.mapped_bytes(bytes).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "only UTF-8 byte order marks are supported for async readers",
+                    )
+                })?;
+            }
+            let span = Span::start();
+            self.hck_bytes_any(bytes, &mut output)?;
+            span.finish(&mut self.metrics);
+            writer.write_all(&output).await?;
+            output.clear();
+            buffer.drain(..end);
+            if read == 0 {
+                break;
+            }
+        }
+        writer.flush().await
+    }
+
+    /// Process a whole input in memory with whichever of the `hck_bytes*` runners the configuration
+    /// allows.
+    fn hck_bytes_any<W: Write>(&mut self, bytes: &[u8], output: W) -> Result<(), io::Error> {
+        if let Some(sep) = &self.config.parsed_record_separator {
+            self.hck_records(bytes, sep, true, false, output)
+                .map(|_| ())
+        } else if self.allow_passthrough() {
+            self.hck_bytes_passthrough(bytes, output)
+        } else if self.allow_fastmode() {
+            self.hck_bytes_fast(bytes, output)
+        } else {
+            self.hck_bytes(bytes, output)
+        }
+    }
+
+    /// Process a reader, cutting its lines short as they are read if possible.
+    fn hck_reader_any<R: Read, W: Write>(&mut self, reader: R, output: W) -> Result<(), io::Error> {
+        match self.truncate_after() {
+            Some((delim, last)) => {
+                let terminator = self.config.line_terminator;
+                let reader = TruncatingReader::new(reader, delim, terminator, last);
+                self.hck_reader_limited(reader, output)
+            }
+            None => self.hck_reader_limited(reader, output),
+        }
+    }
+
+    /// Process a reader, failing on any line longer than [`CoreConfigBuilder::max_line_length`].
+    ///
+    /// Records split on a [`RecordSeparator`] are checked as they are buffered instead.
+    fn hck_reader_limited<R: Read, W: Write>(
+        &mut self,
+        reader: R,
+        output: W,
+    ) -> Result<(), io::Error> {
+        match self.config.max_line_length {
+            Some(max) if self.config.parsed_record_separator.is_none() => {
+                let newline = self.config.line_terminator.as_byte();
+                let reader = LineLimitReader::new(reader, newline, max, self.metrics.records);
+                self.hck_reader_select(reader, output)
+            }
+            _ => self.hck_reader_select(reader, output),
         }
     }
 
+    /// Process a reader with whichever of the `hck_reader*` runners the configuration allows.
+    fn hck_reader_select<R: Read, W: Write>(
+        &mut self,
+        reader: R,
+        output: W,
+    ) -> Result<(), io::Error> {
+        if let Some(sep) = &self.config.parsed_record_separator {
+            self.hck_reader_sep(reader, sep, output)
+        } else if self.allow_passthrough() {
+            self.hck_reader_passthrough(reader, output)
+        } else if self.allow_fastmode() {
+            self.hck_reader_fast(reader, output)
+        } else {
+            self.hck_reader(reader, output)
+        }
+    }
+
+    /// Process the first line of `Stdin`, which was consumed without its terminator while parsing the fields.
+    fn hck_header<W: Write>(&mut self, mut header: Vec<u8>, output: W) -> Result<(), io::Error> {
+        if let Some(sep) = &self.config.parsed_record_separator {
+            return self
+                .hck_records(&header, sep, true, true, output)
+                .map(|_| ());
+        }
+        header.extend_from_slice(self.config.line_terminator.as_bytes());
+        self.hck_bytes(&header, output)
+    }
+
     /// Iterate over the lines in a slice of bytes.
     ///
-    /// The input slice of bytes is assumed to end in a newline.
-    #[allow(clippy::missing_transmute_annotations)]
+    /// A final line without a terminator is still processed, and its record is terminated unless
+    /// [`CoreConfigBuilder::mirror_missing_terminator`] is set.
     pub fn hck_bytes<W>(&mut self, bytes: &[u8], mut output: W) -> Result<(), io::Error>
     where
         W: Write,
     {
+        self.metrics.bytes += bytes.len() as u64;
         let iter = LineIter::new(self.config.line_terminator.as_byte(), bytes.as_bytes());
-        let mut shuffler: Vec<Vec<&'static [u8]>> =
-            vec![vec![]; self.fields.iter().map(|f| f.pos).max().unwrap() + 1];
+        let mut shuffler = Shuffler::new(self.fields);
+        let mut count_buffer = vec![];
         for line in iter {
-            let mut s: Vec<Vec<&[u8]>> = shuffler;
-            self.line_parser.parse_line(
-                lines::without_terminator(line, self.config.line_terminator),
-                &mut s,
-            );
-            let items = s.iter_mut().flat_map(|s| s.drain(..));
-            output.join_append(
-                self.config.output_delimiter,
-                items,
-                &self.config.line_terminator,
+            self.metrics.records += 1;
+            let terminated = self.config.line_terminator.is_suffix(line);
+            let line = lines::without_terminator(line, self.config.line_terminator);
+            if let Some(ragged) = self.ragged.as_mut() {
+                ragged.check(self.metrics.records, self.line_parser.count_fields(line))?;
+            }
+            let header = self.keeps_header && self.metrics.records == 1;
+            if !matches_columns(self.config, &self.line_parser, line, header) {
+                continue;
+            }
+            let count = format_field_count(self.config, &self.line_parser, line, &mut count_buffer);
+            let action = shuffler.with_fields(
+                &self.line_parser,
+                line,
+                |items, record| -> io::Result<_> {
+                    if self.config.count_only {
+                        self.metrics.fields += items.count() as u64;
+                        return Ok(StageAction::Keep);
+                    }
+                    let items = with_field_count(items, count, self.config.field_count);
+                    Self::write_record(
+                        self.config,
+                        self.config.output_delimiter_for(line),
+                        self.pipeline.as_deref_mut(),
+                        &RecordContext {
+                            record_number: self.metrics.records,
+                            line,
+                        },
+                        items,
+                        record,
+                        terminated,
+                        &mut output,
+                    )
+                },
+            )?;
+            count_written(&mut self.metrics, self.config, self.keeps_header, action);
+            if action.stops() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fast mode iteration over lines in a slice of bytes.
+    ///
+    /// This expects the seperator to be a single byte and the newline to be a singel byte.
+    ///
+    /// Instead of  seaching for linebreaks, then splitting up the line on the `sep`,
+    /// fast mode looks for either `sep` or `newline` at the same time, so instead of two passes
+    /// over the bytes we only make one pass.
+    pub fn hck_bytes_fast<W: Write>(&mut self, bytes: &[u8], output: W) -> Result<(), io::Error> {
+        self.metrics.bytes += bytes.len() as u64;
+        let config = self.config;
+        let mut buffer_parser = config.single_byte_parser(self.fields).unwrap();
+        buffer_parser.process_buffer(bytes, output)?;
+        self.count_written_fast(buffer_parser.records());
+        self.metrics.records += buffer_parser.records();
+        self.metrics.fields += buffer_parser.fields();
+        Ok(())
+    }
+
+    /// Fast mode iteration over lines in a reader.
+    ///
+    /// This expects the separator to be a single byte and the newline to be a single byte.
+    ///
+    /// Instead of  seaching for linebreaks, then splitting up the line on the `sep`,
+    /// fast mode looks for either `sep` or `newline` at the same time, so instead of two passes
+    /// over the bytes we only make one pass.
+    pub fn hck_reader_fast<R: Read, W: Write>(
+        &mut self,
+        reader: R,
+        mut output: W,
+    ) -> Result<(), io::Error> {
+        let mut reader = LineBufferReader::new(RetryReader(reader), self.line_buffer);
+        let config = self.config;
+        let pace = self.pace.clone();
+        let mut buffer_parser = config.single_byte_parser(self.fields).unwrap();
+
+        while reader.fill()? {
+            self.metrics.bytes += reader.buffer().len() as u64;
+            buffer_parser.process_buffer(reader.buffer(), &mut output)?;
+            buffer_parser.reset();
+            reader.consume(reader.buffer().len());
+            flush_if_interactive(pace.as_ref(), &mut output)?;
+        }
+        self.count_written_fast(buffer_parser.records());
+        self.metrics.records += buffer_parser.records();
+        self.metrics.fields += buffer_parser.fields();
+        Ok(())
+    }
+
+    /// Copy a slice of bytes to `output` in one write, see [`Core::allow_passthrough`].
+    fn hck_bytes_passthrough<W: Write>(
+        &mut self,
+        bytes: &[u8],
+        mut output: W,
+    ) -> Result<(), io::Error> {
+        self.metrics.bytes += bytes.len() as u64;
+        output.write_all(bytes)?;
+        let terminator = self.config.line_terminator.as_byte();
+        let records = memchr::memchr_iter(terminator, bytes).count() as u64;
+        self.finish_passthrough(records, bytes.last().copied(), output)
+    }
+
+    /// Copy a reader to `output` a buffer at a time, see [`Core::allow_passthrough`].
+    fn hck_reader_passthrough<R: Read, W: Write>(
+        &mut self,
+        reader: R,
+        mut output: W,
+    ) -> Result<(), io::Error> {
+        let mut reader = LineBufferReader::new(RetryReader(reader), self.line_buffer);
+        let terminator = self.config.line_terminator.as_byte();
+        let pace = self.pace.clone();
+        let mut records = 0;
+        let mut last = None;
+        while reader.fill()? {
+            let buffer = reader.buffer();
+            self.metrics.bytes += buffer.len() as u64;
+            output.write_all(buffer)?;
+            records += memchr::memchr_iter(terminator, buffer).count() as u64;
+            last = buffer.last().copied().or(last);
+            reader.consume(reader.buffer().len());
+            flush_if_interactive(pace.as_ref(), &mut output)?;
+        }
+        self.finish_passthrough(records, last, output)
+    }
+
+    /// Count the `records` terminated lines that were copied through, plus a final line without a
+    /// terminator if `last` wasn't one, which gets a terminator unless missing ones are mirrored.
+    fn finish_passthrough<W: Write>(
+        &mut self,
+        mut records: u64,
+        last: Option<u8>,
+        mut output: W,
+    ) -> Result<(), io::Error> {
+        let terminator = self.config.line_terminator.as_byte();
+        if last.is_some_and(|last| last != terminator) {
+            records += 1;
+            if !self.config.mirrors_missing_terminator() {
+                output.write_all(&[terminator])?;
+            }
+        }
+        self.count_written_fast(records);
+        self.metrics.records += records;
+        Ok(())
+    }
+
+    /// Process records split on a [`RecordSeparator`] from a reader.
+    fn hck_reader_sep<R: Read, W: Write>(
+        &mut self,
+        reader: R,
+        sep: &RecordSeparator,
+        mut output: W,
+    ) -> Result<(), io::Error> {
+        let mut reader = RetryReader(reader);
+        let pace = self.pace.clone();
+        let mut chunk = vec![0; self.config.read_buffer_size.unwrap_or(RECORD_CHUNK_SIZE)];
+        let mut buffer = vec![];
+        loop {
+            let n = reader.read(&mut chunk)?;
+            buffer.extend_from_slice(&chunk[..n]);
+            let consumed = self.hck_records(&buffer, sep, n == 0, false, &mut output)?;
+            buffer.drain(..consumed);
+            if let Some(max) = self.config.max_line_length {
+                // What is left is the start of a record, and possibly of the separator after it
+                if buffer.len() > max + sep.output.len() {
+                    return Err(line_too_long(self.metrics.records + 1, max));
+                }
+            }
+            flush_if_interactive(pace.as_ref(), &mut output)?;
+            if n == 0 || self.pipeline.as_ref().is_some_and(|p| p.is_stopped()) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Iterate over the records in a slice of bytes that are split on a [`RecordSeparator`].
+    ///
+    /// Unless `eof` is set, the bytes after the last separator are left for the next call, along
+    /// with any separator that touches the end of `bytes` since more input could extend it. Returns
+    /// the number of bytes consumed. `final_terminated` writes a separator after a final record that
+    /// had none.
+    fn hck_records<W: Write>(
+        &mut self,
+        bytes: &[u8],
+        sep: &RecordSeparator,
+        eof: bool,
+        final_terminated: bool,
+        mut output: W,
+    ) -> Result<usize, io::Error> {
+        let config = self.config;
+        let mut shuffler = Shuffler::new(self.fields);
+        let mut count_buffer = vec![];
+        let mut matches = sep.regex.find_iter(bytes);
+        let mut start = 0;
+        loop {
+            let (end, next, terminated) = match matches.next() {
+                Some(m) if eof || m.end() < bytes.len() => (m.start(), m.end(), true),
+                _ if eof && start < bytes.len() => (bytes.len(), bytes.len(), final_terminated),
+                _ => break,
+            };
+            let line = &bytes[start..end];
+            start = next;
+            self.metrics.records += 1;
+            if let Some(max) = config.max_line_length.filter(|max| line.len() > *max) {
+                return Err(line_too_long(self.metrics.records, max));
+            }
+            if let Some(ragged) = self.ragged.as_mut() {
+                ragged.check(self.metrics.records, self.line_parser.count_fields(line))?;
+            }
+            let header = self.keeps_header && self.metrics.records == 1;
+            if !matches_columns(self.config, &self.line_parser, line, header) {
+                continue;
+            }
+            let count = format_field_count(self.config, &self.line_parser, line, &mut count_buffer);
+            let action = shuffler.with_fields(
+                &self.line_parser,
+                line,
+                |items, record| -> io::Result<_> {
+                    if config.count_only {
+                        self.metrics.fields += items.count() as u64;
+                        return Ok(StageAction::Keep);
+                    }
+                    let items = with_field_count(items, count, config.field_count);
+                    let ctx = RecordContext {
+                        record_number: self.metrics.records,
+                        line,
+                    };
+                    let pipeline = self.pipeline.as_deref_mut();
+                    let term = config.output_line_terminator();
+                    let action = write_unterminated(&mut output, &term, |buffer| {
+                        let sep = config.output_delimiter_for(line);
+                        Self::write_record(config, sep, pipeline, &ctx, items, record, true, buffer)
+                    })?;
+                    if action.keeps() && (terminated || !config.mirrors_missing_terminator()) {
+                        match config.parsed_output_terminator {
+                            Some(term) => output.write_all(term.as_bytes())?,
+                            None => output.write_all(&sep.output)?,
+                        }
+                    }
+                    Ok(action)
+                },
+            )?;
+            count_written(&mut self.metrics, self.config, self.keeps_header, action);
+            if action.stops() {
+                start = bytes.len();
+                break;
+            }
+        }
+        self.metrics.bytes += start as u64;
+        Ok(start)
+    }
+
+    /// Process lines from a reader.
+    pub fn hck_reader<R: Read, W: Write>(
+        &mut self,
+        reader: R,
+        mut output: W,
+    ) -> Result<(), io::Error> {
+        let mut reader = LineBufferReader::new(RetryReader(reader), self.line_buffer);
+        let mut shuffler = Shuffler::new(self.fields);
+        let mut count_buffer = vec![];
+        let pace = self.pace.clone();
+        while reader.fill()? {
+            self.metrics.bytes += reader.buffer().len() as u64;
+            let iter = LineIter::new(self.config.line_terminator.as_byte(), reader.buffer());
+
+            for line in iter {
+                self.metrics.records += 1;
+                let terminated = self.config.line_terminator.is_suffix(line);
+                let line = lines::without_terminator(line, self.config.line_terminator);
+                if let Some(ragged) = self.ragged.as_mut() {
+                    ragged.check(self.metrics.records, self.line_parser.count_fields(line))?;
+                }
+                let header = self.keeps_header && self.metrics.records == 1;
+                if !matches_columns(self.config, &self.line_parser, line, header) {
+                    continue;
+                }
+                let count =
+                    format_field_count(self.config, &self.line_parser, line, &mut count_buffer);
+                let action = shuffler.with_fields(
+                    &self.line_parser,
+                    line,
+                    |items, record| -> io::Result<_> {
+                        if self.config.count_only {
+                            self.metrics.fields += items.count() as u64;
+                            return Ok(StageAction::Keep);
+                        }
+                        let items = with_field_count(items, count, self.config.field_count);
+                        Self::write_record(
+                            self.config,
+                            self.config.output_delimiter_for(line),
+                            self.pipeline.as_deref_mut(),
+                            &RecordContext {
+                                record_number: self.metrics.records,
+                                line,
+                            },
+                            items,
+                            record,
+                            terminated,
+                            &mut output,
+                        )
+                    },
+                )?;
+                count_written(&mut self.metrics, self.config, self.keeps_header, action);
+                if action.stops() {
+                    return Ok(());
+                }
+            }
+            reader.consume(reader.buffer().len());
+            flush_if_interactive(pace.as_ref(), &mut output)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, L> Core<'a, L> {
+    /// Run the selected `items` through the pipeline, if there is one, and write them out if they are kept.
+    ///
+    /// `record` is scratch space for the pipeline and is left empty. `terminated` is whether the input
+    /// line ended with a line terminator, which is only ever false for the final line of an input.
+    #[inline]
+    #[allow(clippy::too_many_arguments)]
+    fn write_record<'b, W: Write>(
+        config: &CoreConfig,
+        sep: &[u8],
+        pipeline: Option<&mut Pipeline>,
+        ctx: &RecordContext<'b>,
+        items: impl Iterator<Item = &'b [u8]>,
+        record: &mut Record<'b>,
+        terminated: bool,
+        output: W,
+    ) -> Result<StageAction, io::Error> {
+        let term = config.output_line_terminator();
+        if !terminated && config.mirrors_missing_terminator() {
+            return write_unterminated(output, &term, |buffer| {
+                Self::write_record(config, sep, pipeline, ctx, items, record, true, buffer)
+            });
+        }
+
+        let pipeline = match pipeline {
+            Some(pipeline) => pipeline,
+            None => {
+                config
+                    .output_format
+                    .write_record(output, sep, items, &term)?;
+                return Ok(StageAction::Keep);
+            }
+        };
+
+        record.extend(items.map(Cow::Borrowed));
+        let action = pipeline.process(ctx, record);
+        if action.keeps() {
+            config.output_format.write_record(
+                output,
+                sep,
+                record.iter().map(|field| field.as_ref()),
+                &term,
             )?;
-            shuffler = unsafe { core::mem::transmute(s) };
         }
+        record.clear();
+        Ok(action)
+    }
+}
+
+/// The number of fields `parser` finds on `line` written out in `buffer`, if fields are counted, see
+/// [`CoreConfigBuilder::field_count`].
+#[inline]
+fn format_field_count<'a, 'c, L: LineParser<'a>>(
+    config: &CoreConfig,
+    parser: &L,
+    line: &[u8],
+    buffer: &'c mut Vec<u8>,
+) -> Option<&'c [u8]> {
+    config.field_count?;
+    buffer.clear();
+    write!(buffer, "{}", parser.count_fields(line)).ok()?;
+    Some(buffer)
+}
+
+/// Whether `line` is kept by [`CoreConfigBuilder::column_matches`], which a kept `header` always is.
+#[inline]
+fn matches_columns<'a, L: LineParser<'a>>(
+    config: &CoreConfig,
+    parser: &L,
+    line: &[u8],
+    header: bool,
+) -> bool {
+    if config.column_matches.is_empty() || header {
+        return true;
+    }
+    let matched = config.column_matches.iter().all(|pattern| {
+        parser
+            .nth_field(line, pattern.index)
+            .is_some_and(|field| pattern.regex.is_match(field))
+    });
+    matched != config.invert_match
+}
+
+/// The selected `items` followed by the field `count`, or only the `count` for [`FieldCount::Only`].
+#[inline]
+fn with_field_count<'b>(
+    items: impl Iterator<Item = &'b [u8]>,
+    count: Option<&'b [u8]>,
+    field_count: Option<FieldCount>,
+) -> impl Iterator<Item = &'b [u8]> {
+    let keep = match field_count {
+        Some(FieldCount::Only) => 0,
+        _ => usize::MAX,
+    };
+    items.take(keep).chain(count)
+}
+
+/// Count the record just read as written if `action` kept it, unless it is a header that is kept.
+#[inline]
+fn count_written(
+    metrics: &mut Metrics,
+    config: &CoreConfig,
+    keeps_header: bool,
+    action: StageAction,
+) {
+    let header = keeps_header && metrics.records == 1;
+    if action.keeps() && !config.count_only && !header {
+        metrics.written += 1;
+    }
+}
+
+/// The error for an input that can't be memory mapped when memory maps are required.
+fn mmap_required(input: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "memory maps are required, but {} can't be memory mapped",
+            input
+        ),
+    )
+}
+
+/// Read the first line of `reader`, without its terminator.
+fn read_line<R: BufRead>(mut reader: R, term: LineTerminator) -> Result<Vec<u8>, io::Error> {
+    let mut line = vec![];
+    reader.read_until(term.as_byte(), &mut line)?;
+    Ok(lines::without_terminator(&line, term).to_owned())
+}
+
+/// Read up to the first match of `sep`, consuming the separator but leaving it off the record.
+fn read_record<R: BufRead>(mut reader: R, sep: &Regex) -> Result<Vec<u8>, io::Error> {
+    let mut record = vec![];
+    loop {
+        let chunk = reader.fill_buf()?;
+        if chunk.is_empty() {
+            return Ok(record);
+        }
+        let (read, len) = (record.len(), chunk.len());
+        record.extend_from_slice(chunk);
+        if let Some(m) = sep.find(&record) {
+            let (start, end) = (m.start(), m.end());
+            reader.consume(end - read);
+            record.truncate(start);
+            return Ok(record);
+        }
+        reader.consume(len);
+    }
+}
+
+/// Compares the field count of each row of an input to its first row.
+struct RaggedCheck<'a> {
+    mode: RaggedRows,
+    /// The name of the current input, for messages
+    name: String,
+    /// The field count of the first row
+    expected: Option<usize>,
+    diagnostics: Box<dyn Write + 'a>,
+}
+
+impl<'a> RaggedCheck<'a> {
+    /// Start checking a new input.
+    fn reset<P: AsRef<Path>>(&mut self, input: &HckInput<P>) {
+        self.reset_named(match input {
+            HckInput::Stdin => String::from("stdin"),
+            HckInput::Path(path) => path.as_ref().display().to_string(),
+        });
+    }
+
+    /// Start checking a new input called `name`.
+    fn reset_named(&mut self, name: String) {
+        self.name = name;
+        self.expected = None;
+    }
+
+    /// Check the `found` field count of line number `line`.
+    #[inline]
+    fn check(&mut self, line: u64, found: usize) -> Result<(), io::Error> {
+        let expected = *self.expected.get_or_insert(found);
+        if found == expected {
+            return Ok(());
+        }
+        let message = format!(
+            "{}:{}: expected {} fields, found {}",
+            self.name, line, expected, found
+        );
+        match self.mode {
+            RaggedRows::Error => Err(io::Error::new(io::ErrorKind::InvalidData, message)),
+            _ => writeln!(self.diagnostics, "{}", message),
+        }
+    }
+}
+
+/// Write a record with `write`, leaving off its line terminator.
+///
+/// This is for the final line of an input that had no terminator when
+/// [`CoreConfigBuilder::mirror_missing_terminator`] is set, so the record is buffered
+/// rather than complicating the normal write path.
+#[inline]
+pub(crate) fn write_unterminated<T, W, F>(
+    mut output: W,
+    term: &LineTerminator,
+    write: F,
+) -> Result<T, io::Error>
+where
+    W: Write,
+    F: FnOnce(&mut Vec<u8>) -> Result<T, io::Error>,
+{
+    let mut buffer = vec![];
+    let result = write(&mut buffer)?;
+    let record = buffer.strip_suffix(term.as_bytes()).unwrap_or(&buffer);
+    output.write_all(record)?;
+    Ok(result)
+}
+
+/// The most slices handed to one [`Write::write_vectored`] call, which is `IOV_MAX` on Linux.
+pub(crate) const IO_BATCH_SIZE: usize = 1024;
+
+/// The most slices [`JoinAppend`] collects for one record, longer records take more than one write.
+const RECORD_BATCH_SIZE: usize = 32;
+
+/// Slices collected to be written together with [`Write::write_vectored`], `N` at a time.
+pub(crate) struct IoBatch<'s, const N: usize = IO_BATCH_SIZE> {
+    slices: [IoSlice<'s>; N],
+    len: usize,
+}
+
+impl<'s, const N: usize> IoBatch<'s, N> {
+    pub(crate) fn new() -> Self {
+        Self {
+            slices: [IoSlice::new(&[]); N],
+            len: 0,
+        }
+    }
+
+    /// Add `slice` to the batch, writing the batch out first if it is full.
+    #[inline]
+    pub(crate) fn push<W: Write + ?Sized>(
+        &mut self,
+        writer: &mut W,
+        slice: &'s [u8],
+    ) -> Result<(), io::Error> {
+        if slice.is_empty() {
+            return Ok(());
+        }
+        if self.len == N {
+            self.flush(writer)?;
+        }
+        self.slices[self.len] = IoSlice::new(slice);
+        self.len += 1;
         Ok(())
     }
 
-    /// Fast mode iteration over lines in a slice of bytes.
-    ///
-    /// This expects the seperator to be a single byte and the newline to be a singel byte.
-    ///
-    /// Instead of  seaching for linebreaks, then splitting up the line on the `sep`,
-    /// fast mode looks for either `sep` or `newline` at the same time, so instead of two passes
-    /// over the bytes we only make one pass.
-    pub fn hck_bytes_fast<W: Write>(&mut self, bytes: &[u8], output: W) -> Result<(), io::Error> {
-        let mut buffer_parser = SingleByteDelimParser::new(
-            self.config.line_terminator,
-            self.config.output_delimiter,
-            self.fields,
-            self.config.delimiter[0],
-        );
-        buffer_parser.process_buffer(bytes, output)?;
+    /// Write out everything in the batch.
+    #[inline]
+    pub(crate) fn flush<W: Write + ?Sized>(&mut self, writer: &mut W) -> Result<(), io::Error> {
+        write_all_vectored(writer, &mut self.slices[..self.len])?;
+        self.len = 0;
         Ok(())
     }
+}
 
-    /// Fast mode iteration over lines in a reader.
-    ///
-    /// This expects the separator to be a single byte and the newline to be a single byte.
-    ///
-    /// Instead of  seaching for linebreaks, then splitting up the line on the `sep`,
-    /// fast mode looks for either `sep` or `newline` at the same time, so instead of two passes
-    /// over the bytes we only make one pass.
-    pub fn hck_reader_fast<R: Read, W: Write>(
-        &mut self,
-        reader: R,
-        mut output: W,
-    ) -> Result<(), io::Error> {
-        let mut reader = LineBufferReader::new(reader, self.line_buffer);
-        let mut buffer_parser = SingleByteDelimParser::new(
-            self.config.line_terminator,
-            self.config.output_delimiter,
-            self.fields,
-            self.config.delimiter[0],
-        );
-
-        while reader.fill()? {
-            buffer_parser.process_buffer(reader.buffer(), &mut output)?;
-            buffer_parser.reset();
-            reader.consume(reader.buffer().len());
+/// Write all of `slices`, like the unstable `Write::write_all_vectored`.
+///
+/// Writers without real vectored support only write the first slice per call, so this degrades to
+/// a `write` per slice for them.
+fn write_all_vectored<W: Write + ?Sized>(
+    writer: &mut W,
+    mut slices: &mut [IoSlice<'_>],
+) -> Result<(), io::Error> {
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
         }
-        Ok(())
     }
+    Ok(())
+}
 
-    /// Process lines from a reader.
-    #[allow(clippy::missing_transmute_annotations)]
-    pub fn hck_reader<R: Read, W: Write>(
-        &mut self,
-        reader: R,
-        mut output: W,
-    ) -> Result<(), io::Error> {
-        let mut reader = LineBufferReader::new(reader, self.line_buffer);
-        let mut shuffler: Vec<Vec<&'static [u8]>> =
-            vec![vec![]; self.fields.iter().map(|f| f.pos).max().unwrap() + 1];
-        while reader.fill()? {
-            let iter = LineIter::new(self.config.line_terminator.as_byte(), reader.buffer());
+/// Flush `output` once a buffer is processed if `pace` shows the input arriving interactively, so
+/// its results show up without waiting on more input.
+#[inline]
+fn flush_if_interactive<W: Write>(pace: Option<&Pace>, output: &mut W) -> Result<(), io::Error> {
+    match pace {
+        Some(pace) if pace.is_interactive() => output.flush(),
+        _ => Ok(()),
+    }
+}
 
-            for line in iter {
-                let mut s: Vec<Vec<&[u8]>> = shuffler;
-                self.line_parser.parse_line(
-                    lines::without_terminator(line, self.config.line_terminator),
-                    &mut s,
-                );
+/// A [`Read`] wrapper that retries reads failing with [`io::ErrorKind::Interrupted`].
+///
+/// [`LineBufferReader::fill`] passes interrupts straight through, which would end processing early.
+struct RetryReader<R>(R);
 
-                let items = s.iter_mut().flat_map(|s| s.drain(..));
-                output.join_append(
-                    self.config.output_delimiter,
-                    items,
-                    &self.config.line_terminator,
-                )?;
-                shuffler = unsafe { core::mem::transmute(s) };
+impl<R: Read> Read for RetryReader<R> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => return result,
             }
-            reader.consume(reader.buffer().len());
         }
-        Ok(())
+    }
+}
+
+/// The selected fields of a line, in output order, as lent out by [`Shuffler::with_fields`].
+type Fields<'s, 'b> = iter::Copied<iter::Flatten<slice::Iter<'s, Vec<&'b [u8]>>>>;
+
+/// Reusable storage for the fields a [`LineParser`] selects from each line.
+///
+/// The storage outlives the lines it holds, so between lines it is kept empty with a `'static`
+/// lifetime and it is only lent out for the lifetime of the line being parsed.
+struct Shuffler {
+    /// One slot per field position, see [`LineParser::parse_line`]
+    slots: Vec<Vec<&'static [u8]>>,
+    /// Scratch space for a [`Pipeline`]
+    record: Record<'static>,
+    /// The selected fields collected into one slice, for [`Shuffler::with_row`]
+    row: Vec<&'static [u8]>,
+}
+
+impl Shuffler {
+    fn new(fields: &[FieldRange]) -> Self {
+        Self {
+            slots: vec![vec![]; fields.iter().map(|f| f.pos + 1).max().unwrap_or(0)],
+            record: vec![],
+            row: vec![],
+        }
+    }
+
+    /// Parse `line` with `parser` and call `f` with the selected fields and an empty record.
+    #[inline]
+    fn with_fields<'p, 'b, L, T, F>(&mut self, parser: &L, line: &'b [u8], f: F) -> T
+    where
+        L: LineParser<'p>,
+        'p: 'b,
+        F: FnOnce(Fields<'_, 'b>, &mut Record<'b>) -> T,
+    {
+        let mut slots: Vec<Vec<&'b [u8]>> = mem::take(&mut self.slots);
+        let mut record: Record<'b> = mem::take(&mut self.record);
+        parser.parse_line(line, &mut slots);
+        let result = f(slots.iter().flatten().copied(), &mut record);
+        slots.iter_mut().for_each(Vec::clear);
+        record.clear();
+        // Nothing borrowed from `line` is left, only the allocations are kept
+        self.slots =
+            unsafe { mem::transmute::<Vec<Vec<&'b [u8]>>, Vec<Vec<&'static [u8]>>>(slots) };
+        self.record = unsafe { mem::transmute::<Record<'b>, Record<'static>>(record) };
+        result
+    }
+
+    /// Like [`Shuffler::with_fields`], but `f` gets the selected fields as a slice.
+    #[inline]
+    fn with_row<'p, 'b, L, T, F>(&mut self, parser: &L, line: &'b [u8], f: F) -> T
+    where
+        L: LineParser<'p>,
+        'p: 'b,
+        F: FnOnce(&[&'b [u8]]) -> T,
+    {
+        let mut row: Vec<&'b [u8]> = mem::take(&mut self.row);
+        let result = self.with_fields(parser, line, |items, _| {
+            row.extend(items);
+            f(&row)
+        });
+        row.clear();
+        // As in `with_fields`, the row is empty
+        self.row = unsafe { mem::transmute::<Vec<&'b [u8]>, Vec<&'static [u8]>>(row) };
+        result
     }
 }
 
 /// A trait for adding `join_append` to a writer.
+///
+/// The pieces of each record are gathered and handed to [`Write::write_vectored`] together, rather
+/// than written one at a time.
 pub trait JoinAppend {
     /// Given an input iterator of items, write them with a serparator and a newline.
     fn join_append<'b>(
@@ -540,6 +2532,24 @@ pub trait JoinAppend {
         items: impl Iterator<Item = &'b [u8]>,
         term: &LineTerminator,
     ) -> Result<(), io::Error>;
+
+    /// Like [`JoinAppend::join_append`], but any item containing the separator, a double quote, or a
+    /// line break is wrapped in double quotes with internal quotes doubled, per RFC 4180.
+    fn join_append_quoted<'b>(
+        &mut self,
+        sep: &[u8],
+        items: impl Iterator<Item = &'b [u8]>,
+        term: &LineTerminator,
+    ) -> Result<(), io::Error>;
+
+    /// Like [`JoinAppend::join_append`], but backslashes and separators in items are escaped with a
+    /// backslash, and line breaks are written as `\n` and `\r`.
+    fn join_append_escaped<'b>(
+        &mut self,
+        sep: &[u8],
+        items: impl Iterator<Item = &'b [u8]>,
+        term: &LineTerminator,
+    ) -> Result<(), io::Error>;
 }
 
 /// [`JoinAppend`] for [`Write`].
@@ -552,15 +2562,1096 @@ impl<W: Write> JoinAppend for W {
         mut items: impl Iterator<Item = &'b [u8]>,
         term: &LineTerminator,
     ) -> Result<(), io::Error> {
+        let mut batch = IoBatch::<RECORD_BATCH_SIZE>::new();
         if let Some(item) = items.next() {
-            self.write_all(item)?;
+            batch.push(self, item)?;
         }
 
         for item in items {
-            self.write_all(sep)?;
-            self.write_all(item)?;
+            batch.push(self, sep)?;
+            batch.push(self, item)?;
         }
-        self.write_all(term.as_bytes())?;
-        Ok(())
+        batch.push(self, term.as_bytes())?;
+        batch.flush(self)
+    }
+
+    /// Given an input iterator of items, write them quoted as needed with a serparator and a newline.
+    #[inline(always)]
+    fn join_append_quoted<'b>(
+        &mut self,
+        sep: &[u8],
+        mut items: impl Iterator<Item = &'b [u8]>,
+        term: &LineTerminator,
+    ) -> Result<(), io::Error> {
+        let mut batch = IoBatch::<RECORD_BATCH_SIZE>::new();
+        if let Some(item) = items.next() {
+            push_quoted(&mut batch, self, sep, item)?;
+        }
+
+        for item in items {
+            batch.push(self, sep)?;
+            push_quoted(&mut batch, self, sep, item)?;
+        }
+        batch.push(self, term.as_bytes())?;
+        batch.flush(self)
+    }
+
+    /// Given an input iterator of items, write them escaped as needed with a separator and a newline.
+    #[inline(always)]
+    fn join_append_escaped<'b>(
+        &mut self,
+        sep: &[u8],
+        mut items: impl Iterator<Item = &'b [u8]>,
+        term: &LineTerminator,
+    ) -> Result<(), io::Error> {
+        let mut batch = IoBatch::<RECORD_BATCH_SIZE>::new();
+        if let Some(item) = items.next() {
+            push_escaped(&mut batch, self, sep, item)?;
+        }
+
+        for item in items {
+            batch.push(self, sep)?;
+            push_escaped(&mut batch, self, sep, item)?;
+        }
+        batch.push(self, term.as_bytes())?;
+        batch.flush(self)
+    }
+}
+
+/// Add an item to `batch`, quoting it if it contains `sep`, a double quote, or a line break.
+#[inline]
+fn push_quoted<'s, W: Write + ?Sized, const N: usize>(
+    batch: &mut IoBatch<'s, N>,
+    output: &mut W,
+    sep: &[u8],
+    item: &'s [u8],
+) -> Result<(), io::Error> {
+    let needs_quotes = memchr::memchr3(b'"', b'\n', b'\r', item).is_some()
+        || (!sep.is_empty() && item.find(sep).is_some());
+    if !needs_quotes {
+        return batch.push(output, item);
+    }
+
+    batch.push(output, b"\"")?;
+    for (i, chunk) in item.split(|b| *b == b'"').enumerate() {
+        if i > 0 {
+            batch.push(output, b"\"\"")?;
+        }
+        batch.push(output, chunk)?;
+    }
+    batch.push(output, b"\"")
+}
+
+/// Add an item to `batch` with a backslash before each backslash or `sep`, and line breaks written
+/// as `\n` and `\r`.
+#[inline]
+fn push_escaped<'s, W: Write + ?Sized, const N: usize>(
+    batch: &mut IoBatch<'s, N>,
+    output: &mut W,
+    sep: &'s [u8],
+    mut item: &'s [u8],
+) -> Result<(), io::Error> {
+    loop {
+        let special = memchr::memchr3(b'\\', b'\n', b'\r', item);
+        let delim = if sep.is_empty() { None } else { item.find(sep) };
+        let (at, len, escaped): (_, _, &[u8]) = match (special, delim) {
+            (Some(i), Some(d)) if d < i => (d, sep.len(), sep),
+            (None, Some(d)) => (d, sep.len(), sep),
+            (Some(i), _) => match item[i] {
+                b'\n' => (i, 1, b"n"),
+                b'\r' => (i, 1, b"r"),
+                _ => (i, 1, b"\\"),
+            },
+            (None, None) => return batch.push(output, item),
+        };
+        batch.push(output, &item[..at])?;
+        batch.push(output, b"\\")?;
+        batch.push(output, escaped)?;
+        item = &item[at + len..];
+    }
+}
+
+/// Select the fields of `input` and return what would be written, ex: for `wasm32-unknown-unknown`
+/// where there are no files to read or write.
+///
+/// This only needs an in memory [`CoreConfig`], so it works without the `native` feature.
+pub fn select_bytes(config: &CoreConfig, input: &[u8]) -> Result<Vec<u8>> {
+    let fields = config.parse_fields_from_bytes(input)?;
+    let mut line_buffer = LineBufferBuilder::new().build();
+    let mut output = vec![];
+    let parser = config.line_parser(&fields);
+    Core::new(config, &fields, parser, &mut line_buffer).hck_slice(input, &mut output)?;
+    Ok(output)
+}
+
+/// Like [`select_bytes`], but return where the selected fields are in `input` instead of copying
+/// them out: one list of byte ranges per line, in output order.
+///
+/// A missing field given a [`fill`](CoreConfigBuilder::fill) value is an empty range at the end of
+/// its line. Transcoded inputs and record separators aren't supported, since their fields aren't
+/// slices of `input`.
+pub fn select_offsets(config: &CoreConfig, input: &[u8]) -> Result<Vec<Vec<Range<usize>>>> {
+    if config.parsed_record_separator.is_some() {
+        bail!("Field offsets are not supported with record separators");
+    }
+    let bytes = match config.mapped_bytes(input) {
+        Some(bytes) => bytes,
+        None => bail!("Field offsets are not supported for transcoded input"),
+    };
+    let fields = config.parse_fields_from_bytes(input)?;
+    let parser = config.line_parser(&fields);
+    Ok(line_offsets(config, &fields, &parser, input, bytes))
+}
+
+/// The ranges of the fields `parser` selects from each line of `bytes`, relative to the start of
+/// `input`, which `bytes` is a slice of.
+fn line_offsets<'p, L: LineParser<'p>>(
+    config: &CoreConfig,
+    fields: &[FieldRange],
+    parser: &L,
+    input: &[u8],
+    bytes: &[u8],
+) -> Vec<Vec<Range<usize>>> {
+    let mut shuffler = Shuffler::new(fields);
+    let base = input.as_ptr() as usize;
+    LineIter::new(config.line_terminator.as_byte(), bytes)
+        .map(|line| {
+            let line = lines::without_terminator(line, config.line_terminator);
+            let start = line.as_ptr() as usize - base;
+            let end = start + line.len();
+            shuffler.with_row(parser, line, |row| {
+                row.iter()
+                    .map(|field| {
+                        let offset = (field.as_ptr() as usize).wrapping_sub(base);
+                        // Fill values are the only fields that aren't a slice of the line
+                        if (start..=end).contains(&offset) && offset + field.len() <= end {
+                            offset..offset + field.len()
+                        } else {
+                            end..end
+                        }
+                    })
+                    .collect()
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::line_parser::SubStrLineParser;
+    use ripline::line_buffer::{BufferAllocation, LineBufferBuilder};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_ragged_rows() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("input.txt");
+        fs::write(&path, "a,b\n1\n2,3\n4,5,6").unwrap();
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .fields(Some("2"))
+            .ragged_rows(RaggedRows::Report)
+            .build()
+            .unwrap();
+        let fields = FieldRange::from_list("2").unwrap();
+        let mut line_buffer = LineBufferBuilder::new().build();
+        let mut diagnostics = vec![];
+        let mut output = vec![];
+        let mut core = Core::new(
+            &conf,
+            &fields,
+            SubStrLineParser::new(&fields, b","),
+            &mut line_buffer,
+        )
+        .diagnostics(&mut diagnostics);
+        core.hck_input(HckInput::Path(&path), &mut output, None)
+            .unwrap();
+        drop(core);
+        assert_eq!(output, b"b\n\n3\n5\n");
+        let name = path.display();
+        assert_eq!(
+            String::from_utf8(diagnostics).unwrap(),
+            format!(
+                "{}:2: expected 2 fields, found 1\n{}:4: expected 2 fields, found 3\n",
+                name, name
+            )
+        );
+    }
+
+    #[test]
+    fn test_crlf_fast_matches_slow() {
+        let inputs: [&[u8]; 3] = [
+            b"a,b,c\r\n1,2\r\n\r\nx,y,z,w\r\n",
+            b"a,b,c\r\n,\r\nlf,only\nx,y\r",
+            b"a,b,c\r\n1,2,3",
+        ];
+        for data in inputs {
+            for list in ["1-", "2", "3,1", "1,3-"] {
+                for fill in [None, Some(&b"NA"[..])] {
+                    let conf = CoreConfigBuilder::new()
+                        .delimiter(b",")
+                        .line_terminator(LineTerminator::crlf())
+                        .fields(Some(list))
+                        .fill(fill)
+                        .build()
+                        .unwrap();
+                    let fields = FieldRange::from_list(list).unwrap();
+                    let mut line_buffer = LineBufferBuilder::new().build();
+                    let mut core = Core::new(
+                        &conf,
+                        &fields,
+                        SubStrLineParser::new(&fields, b",").fill(fill),
+                        &mut line_buffer,
+                    );
+                    assert!(core.allow_fastmode());
+                    let mut slow = vec![];
+                    core.hck_bytes(data, &mut slow).unwrap();
+                    let mut fast = vec![];
+                    core.hck_bytes_fast(data, &mut fast).unwrap();
+                    assert_eq!(fast.as_bstr(), slow.as_bstr(), "fields {}", list);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_passthrough_matches_slow() {
+        let inputs: [&[u8]; 4] = [b"a,b,c\n1,2\n\nx,y,z,w\n", b"a,b\r\n,\n1,2", b"", b"\n\n"];
+        for data in inputs {
+            for mirror in [false, true] {
+                let conf = CoreConfigBuilder::new()
+                    .delimiter(b",")
+                    .output_delimiter(b",")
+                    .fields(Some("1-"))
+                    .mirror_missing_terminator(mirror)
+                    .build()
+                    .unwrap();
+                let fields = FieldRange::from_list("1-").unwrap();
+                let mut line_buffer = LineBufferBuilder::new().capacity(4).build();
+                let mut core = Core::new(
+                    &conf,
+                    &fields,
+                    SubStrLineParser::new(&fields, b","),
+                    &mut line_buffer,
+                );
+                assert!(core.allow_passthrough());
+                let explanation = core.explain(&HckInput::<&str>::Stdin).unwrap();
+                assert_eq!(explanation.parser, ParserKind::Passthrough);
+                let mut slow = vec![];
+                core.hck_bytes(data, &mut slow).unwrap();
+                let slow_metrics = *core.metrics();
+                let mut copied = vec![];
+                core.hck_bytes_passthrough(data, &mut copied).unwrap();
+                assert_eq!(copied.as_bstr(), slow.as_bstr());
+                let mut read = vec![];
+                core.hck_reader_passthrough(data, &mut read).unwrap();
+                assert_eq!(read.as_bstr(), slow.as_bstr());
+                assert_eq!(core.metrics().records, 3 * slow_metrics.records);
+                assert_eq!(core.metrics().written, 3 * slow_metrics.written);
+                assert_eq!(core.metrics().bytes, 3 * slow_metrics.bytes);
+            }
+        }
+
+        // Fields are still split when the output differs from the input
+        for (list, output_delimiter) in [("1-", "\t"), ("2-", ","), ("2,1-", ",")] {
+            let conf = CoreConfigBuilder::new()
+                .delimiter(b",")
+                .output_delimiter(output_delimiter.as_bytes())
+                .fields(Some(list))
+                .build()
+                .unwrap();
+            let fields = FieldRange::from_list(list).unwrap();
+            let mut line_buffer = LineBufferBuilder::new().build();
+            let core = Core::new(
+                &conf,
+                &fields,
+                SubStrLineParser::new(&fields, b","),
+                &mut line_buffer,
+            );
+            assert!(!core.allow_passthrough(), "fields {}", list);
+            let explanation = core.explain(&HckInput::<&str>::Stdin).unwrap();
+            assert_ne!(explanation.parser, ParserKind::Passthrough);
+        }
+    }
+
+    #[test]
+    fn test_join_append() {
+        /// A writer without `write_vectored`, so each slice is its own write.
+        struct Plain(Vec<u8>);
+
+        impl Write for Plain {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Write::write(&mut self.0, buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let term = LineTerminator::default();
+        // More pieces than fit in one batch, with quoted items split across batches
+        let items = (0..RECORD_BATCH_SIZE)
+            .map(|i| match i % 3 {
+                0 => format!("{}", i),
+                1 => format!("a \"{}\" b", i),
+                _ => String::new(),
+            })
+            .collect::<Vec<_>>();
+        let expected = items.join(",") + "\n";
+        let quoted = items
+            .iter()
+            .map(|item| match item.contains('"') {
+                true => format!("\"{}\"", item.replace('"', "\"\"")),
+                false => item.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+            + "\n";
+
+        let mut output = vec![];
+        output
+            .join_append(b",", items.iter().map(|item| item.as_bytes()), &term)
+            .unwrap();
+        assert_eq!(output.as_bstr(), expected.as_bytes().as_bstr());
+        let mut output = Plain(vec![]);
+        output
+            .join_append(b",", items.iter().map(|item| item.as_bytes()), &term)
+            .unwrap();
+        assert_eq!(output.0.as_bstr(), expected.as_bytes().as_bstr());
+
+        let mut output = vec![];
+        output
+            .join_append_quoted(b",", items.iter().map(|item| item.as_bytes()), &term)
+            .unwrap();
+        assert_eq!(output.as_bstr(), quoted.as_bytes().as_bstr());
+        let mut output = Plain(vec![]);
+        output
+            .join_append_quoted(b",", items.iter().map(|item| item.as_bytes()), &term)
+            .unwrap();
+        assert_eq!(output.0.as_bstr(), quoted.as_bytes().as_bstr());
+    }
+
+    #[test]
+    fn test_truncate_lines() {
+        let inputs: [&[u8]; 3] = [
+            b"a,b,c,d\n1,2\n\nx,y,z,w,v\n",
+            b"a,b,c\r\n,\r\nlf,only\nx,y,z\r\n",
+            b"a,b,c\n1,2,3",
+        ];
+        for data in inputs {
+            for list in ["1", "2", "3,1", "2-3", "1-", "1,3-"] {
+                let fields = FieldRange::from_list(list).unwrap();
+                let run = |truncate_lines: bool, crlf: bool| {
+                    let conf = CoreConfigBuilder::new()
+                        .delimiter(b",")
+                        .line_terminator(if crlf {
+                            LineTerminator::crlf()
+                        } else {
+                            LineTerminator::default()
+                        })
+                        .fields(Some(list))
+                        .truncate_lines(truncate_lines)
+                        .build()
+                        .unwrap();
+                    let mut line_buffer = LineBufferBuilder::new().build();
+                    let mut core = Core::new(
+                        &conf,
+                        &fields,
+                        SubStrLineParser::new(&fields, b","),
+                        &mut line_buffer,
+                    );
+                    assert_eq!(
+                        core.truncate_after().is_some(),
+                        truncate_lines && !list.ends_with('-')
+                    );
+                    let mut out = vec![];
+                    core.hck_reader_any(data, &mut out).unwrap();
+                    out
+                };
+                for crlf in [false, true] {
+                    assert_eq!(
+                        run(true, crlf).as_bstr(),
+                        run(false, crlf).as_bstr(),
+                        "fields {}",
+                        list
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncate_lines_bounds_line_buffer() {
+        let line = format!("a,b,{}\n", "c".repeat(1 << 20));
+        let data = line.repeat(3);
+        let fields = FieldRange::from_list("2,1").unwrap();
+        for truncate_lines in [false, true] {
+            let conf = CoreConfigBuilder::new()
+                .delimiter(b",")
+                .fields(Some("2,1"))
+                .truncate_lines(truncate_lines)
+                .build()
+                .unwrap();
+            let mut line_buffer = LineBufferBuilder::new()
+                .capacity(1024)
+                .buffer_alloc(BufferAllocation::Error(4096))
+                .build();
+            let mut core = Core::new(
+                &conf,
+                &fields,
+                SubStrLineParser::new(&fields, b","),
+                &mut line_buffer,
+            );
+            let mut out = vec![];
+            let result = core.hck_reader_any(data.as_bytes(), &mut out);
+            if truncate_lines {
+                result.unwrap();
+                assert_eq!(out.as_bstr(), "b\ta\n".repeat(3).as_bytes().as_bstr());
+            } else {
+                assert!(result.is_err());
+            }
+        }
+    }
+
+    /// A config built from values that don't outlive the function.
+    fn owned_config(delim: &str, header: &str) -> CoreConfig<'static> {
+        CoreConfigBuilder::new()
+            .delimiter(delim.as_bytes().to_vec())
+            .output_delimiter(b"|".to_vec())
+            .fields(None::<String>)
+            .headers(Some(vec![Regex::new(header).unwrap()]))
+            .header_is_regex(true)
+            .fill(Some(b"NA".to_vec()))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_owned_config() {
+        let conf = owned_config(String::from(",").as_str(), "^b$");
+        let (header, fields) = conf
+            .resolve_fields(conf.parsed_delim(), || Ok(b"a,b".to_vec()))
+            .unwrap();
+        assert_eq!(header.as_deref(), Some(&b"a,b"[..]));
+        assert_eq!(fields, FieldRange::from_list("2").unwrap());
+        assert_eq!(conf.output_delimiter(), b"|");
+        assert_eq!(conf.fill(), Some(&b"NA"[..]));
+    }
+
+    #[test]
+    fn test_for_each_row() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("input.txt");
+        fs::write(&path, "a,b,c\n1,2,3\n4\n").unwrap();
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .fields(Some("3,1"))
+            .build()
+            .unwrap();
+        let fields = FieldRange::from_list("3,1").unwrap();
+        let mut line_buffer = LineBufferBuilder::new().build();
+        let mut core = Core::new(
+            &conf,
+            &fields,
+            SubStrLineParser::new(&fields, b","),
+            &mut line_buffer,
+        );
+        let mut rows = vec![];
+        core.for_each_row(HckInput::Path(&path), |row| {
+            rows.push(row.iter().map(|field| field.to_vec()).collect::<Vec<_>>());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                vec![b"c".to_vec(), b"a".to_vec()],
+                vec![b"3".to_vec(), b"1".to_vec()],
+                vec![b"4".to_vec()],
+            ]
+        );
+        assert_eq!(core.metrics().records, 3);
+        assert_eq!(core.metrics().fields, 5);
+
+        // An error from the callback stops processing
+        let mut seen = 0;
+        let err = core
+            .for_each_row(HckInput::Path(&path), |_| {
+                seen += 1;
+                Err(io::Error::other("stop"))
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "stop");
+        assert_eq!(seen, 1);
+    }
+
+    #[test]
+    fn test_escaped_delimiters() {
+        // A regex that is just an escaped byte is split on as a literal
+        for (escaped, byte) in [(r"\x1f", "\x1f"), (r"\0", "\0"), (r"\t", "\t")] {
+            let conf = CoreConfigBuilder::new()
+                .delimiter(escaped.as_bytes())
+                .is_regex_parser(true)
+                .reuse_input_delim(true)
+                .fields(Some("2,1"))
+                .build()
+                .unwrap();
+            assert!(matches!(conf.parsed_delim(), RegexOrString::String(d) if d == byte));
+            assert!(!conf.is_parser_regex);
+            let input = format!("a{byte}b\n");
+            let out = select_bytes(&conf, input.as_bytes()).unwrap();
+            assert_eq!(out, format!("b{byte}a\n").as_bytes());
+        }
+        // Anything more is still a regex
+        for pattern in [r"\x1f+", r"\xff", r"\s", r"\."] {
+            let conf = CoreConfigBuilder::new()
+                .delimiter(pattern.as_bytes())
+                .is_regex_parser(true)
+                .build()
+                .unwrap();
+            assert!(matches!(conf.parsed_delim(), RegexOrString::Regex(_)));
+        }
+
+        // Literal bytes that aren't UTF-8 are matched as bytes
+        let conf = CoreConfigBuilder::new()
+            .delimiter(&br"\xFF"[..])
+            .output_delimiter(&br"\0"[..])
+            .fields(Some("2,1"))
+            .build()
+            .unwrap();
+        let out = select_bytes(&conf, b"a\xffb\xffc\n").unwrap();
+        assert_eq!(out, b"b\0a\n");
+    }
+
+    #[test]
+    fn test_single_byte_parser() {
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .output_delimiter(b"|")
+            .fields(Some("3,1"))
+            .build()
+            .unwrap();
+        let fields = FieldRange::from_list("3,1").unwrap();
+        let mut parser = conf.single_byte_parser(&fields).unwrap();
+        let mut out = vec![];
+        parser.process_buffer(b"a,b,c\n1,2,3\n", &mut out).unwrap();
+        assert_eq!(out, b"c|a\n3|1\n");
+        assert_eq!(parser.records(), 2);
+
+        // Only single byte literal delimiters split on lines have a fast parser
+        for builder in [
+            CoreConfigBuilder::new().delimiter(b"::"),
+            CoreConfigBuilder::new()
+                .delimiter(b",+")
+                .is_regex_parser(true),
+            CoreConfigBuilder::new()
+                .delimiter(b",")
+                .record_separator(Some(&b";;"[..])),
+        ] {
+            let conf = builder.build().unwrap();
+            assert!(conf.single_byte_parser(&fields).is_none());
+        }
+    }
+
+    #[test]
+    fn test_select_bytes() {
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .output_delimiter(b"|")
+            .fields(Some("3,1"))
+            .build()
+            .unwrap();
+        let out = select_bytes(&conf, b"a,b,c\n1,2,3\n4").unwrap();
+        assert_eq!(out, b"c|a\n3|1\n4\n");
+
+        // Headers are found past a byte order mark, which isn't written
+        let headers = [Regex::new("^c$").unwrap()];
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .fields(None::<&str>)
+            .headers(Some(&headers[..]))
+            .header_is_regex(true)
+            .build()
+            .unwrap();
+        let out = select_bytes(&conf, b"\xEF\xBB\xBFc,a\n1,2\n").unwrap();
+        assert_eq!(out, b"c\n1\n");
+    }
+
+    #[test]
+    fn test_select_offsets() {
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .fields(Some("3,1"))
+            .fill(Some(b"NA"))
+            .build()
+            .unwrap();
+        let input = b"\xEF\xBB\xBFa,b,c\n1,22,333\n4\n";
+        let offsets = select_offsets(&conf, input).unwrap();
+        assert_eq!(
+            offsets,
+            vec![vec![7..8, 3..4], vec![14..17, 9..10], vec![19..19, 18..19]]
+        );
+        assert_eq!(&input[14..17], b"333");
+
+        let headers = [Regex::new("^c$").unwrap()];
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b" ")
+            .squeeze(true)
+            .fields(None::<&str>)
+            .headers(Some(&headers[..]))
+            .header_is_regex(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            select_offsets(&conf, b"a  c\n1   2").unwrap(),
+            vec![vec![3..4], vec![9..10]]
+        );
+
+        let conf = CoreConfigBuilder::new()
+            .record_separator(Some(b";;"))
+            .build()
+            .unwrap();
+        assert!(select_offsets(&conf, b"a;;b").is_err());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_hck_async_reader() {
+        use crate::pipeline::HeadStage;
+        use tokio::io::AsyncReadExt;
+
+        let fields = FieldRange::from_list("3,1").unwrap();
+        for is_regex in [true, false] {
+            let conf = CoreConfigBuilder::new()
+                .delimiter(b",")
+                .is_regex_parser(is_regex)
+                .build()
+                .unwrap();
+            let mut line_buffer = LineBufferBuilder::new().build();
+            let mut core = Core::new(
+                &conf,
+                &fields,
+                SubStrLineParser::new(&fields, b","),
+                &mut line_buffer,
+            );
+            // Reads end partway through a line
+            let reader = AsyncReadExt::chain(&b"\xEF\xBB\xBFa,b,c\n1,2"[..], &b",3\n4,5,6"[..]);
+            let mut output = vec![];
+            core.hck_async_reader(reader, &mut output).await.unwrap();
+            assert_eq!(output.as_bstr(), "c\ta\n3\t1\n6\t4\n".as_bytes().as_bstr());
+            assert_eq!(core.metrics().records, 3);
+        }
+
+        // Reading stops once the pipeline is done
+        let conf = CoreConfigBuilder::new().delimiter(b",").build().unwrap();
+        let mut pipeline = Pipeline::new();
+        pipeline.push(HeadStage::new(1));
+        let mut line_buffer = LineBufferBuilder::new().build();
+        let mut core = Core::new(
+            &conf,
+            &fields,
+            SubStrLineParser::new(&fields, b","),
+            &mut line_buffer,
+        )
+        .pipeline(Some(&mut pipeline));
+        let reader = AsyncReadExt::chain(&b"a,b,c\n"[..], &b"1,2,3\n"[..]);
+        let mut output = vec![];
+        core.hck_async_reader(reader, &mut output).await.unwrap();
+        assert_eq!(output, b"c\ta\n");
+    }
+
+    #[test]
+    fn test_header_names() {
+        let headers = [Regex::new("name").unwrap()];
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .fields(Some("3"))
+            .headers(Some(&headers[..]))
+            .header_names(Some(HeaderNames::Names(vec![
+                String::from("id"),
+                String::from("name"),
+            ])))
+            .build()
+            .unwrap();
+        // Every line is data
+        let out = select_bytes(&conf, b"1,a,x\n2,b,y\n").unwrap();
+        assert_eq!(out, b"a\tx\nb\ty\n");
+        let fields = conf.parse_fields_from_bytes(b"1,a,x\n").unwrap();
+        // There is no name for the third column
+        assert_eq!(conf.header_line(b"", &fields).unwrap(), b"name");
+
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b" ")
+            .squeeze(true)
+            .fields(Some("3,1"))
+            .header_names(Some(HeaderNames::Generated))
+            .build()
+            .unwrap();
+        assert_eq!(
+            conf.column_names(b"a  b c").unwrap(),
+            vec!["c1", "c2", "c3"]
+        );
+        let fields = conf.parse_fields_from_bytes(b"a  b c\n").unwrap();
+        assert_eq!(conf.header_line(b"a  b c", &fields).unwrap(), b"c3\tc1");
+
+        let conf = CoreConfigBuilder::new().build().unwrap();
+        assert_eq!(conf.column_names(b"a\tb"), None);
+    }
+
+    #[test]
+    fn test_header_selection_on_transcoded_stdin() {
+        let headers = [Regex::new("a").unwrap()];
+        let conf = CoreConfigBuilder::new()
+            .headers(Some(&headers[..]))
+            .encoding(Some(encoding_rs::WINDOWS_1252))
+            .build()
+            .unwrap();
+        // Stdin can't be reopened to transcode it after the header is read, so this is an error
+        let err = conf.peek_first_line(&HckInput::<&str>::Stdin).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(conf.parse_fields(&HckInput::<&str>::Stdin).is_err());
+    }
+
+    #[test]
+    fn test_explain() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("input.csv.gz");
+        fs::write(&path, "a,b\n1,2\n").unwrap();
+        let fields = FieldRange::from_list("2,1").unwrap();
+        let explain = |conf: &CoreConfig, pipeline: Option<&mut Pipeline>| {
+            let mut line_buffer = LineBufferBuilder::new().build();
+            let core = Core::new(
+                conf,
+                &fields,
+                SubStrLineParser::new(&fields, b","),
+                &mut line_buffer,
+            )
+            .pipeline(pipeline);
+            core.explain(&HckInput::Path(&path)).unwrap()
+        };
+
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .is_regex_parser(false)
+            .build()
+            .unwrap();
+        assert_eq!(
+            explain(&conf, None),
+            Explanation {
+                parser: ParserKind::Fast,
+                mmap: true,
+                decompressor: None,
+            }
+        );
+        // A pipeline that changes records needs the slow path
+        let mut pipeline = Pipeline::new();
+        pipeline.push(crate::pipeline::HeadStage::new(1));
+        assert_eq!(
+            explain(&conf, Some(&mut pipeline)).parser,
+            ParserKind::SubStr
+        );
+
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .is_regex_parser(true)
+            .mmap(MmapChoice::never())
+            .build()
+            .unwrap();
+        let explanation = explain(&conf, None);
+        assert_eq!(explanation.parser, ParserKind::Regex);
+        assert!(!explanation.mmap);
+
+        // The `\s+` delimiter hck defaults to doesn't need the regex engine
+        let conf = CoreConfigBuilder::new()
+            .delimiter(br"\s+")
+            .is_regex_parser(true)
+            .build()
+            .unwrap();
+        assert_eq!(explain(&conf, None).parser, ParserKind::Whitespace);
+        assert!(matches!(
+            conf.line_parser(&fields),
+            DelimLineParser::Whitespace(_)
+        ));
+
+        #[cfg(feature = "native")]
+        {
+            let conf = CoreConfigBuilder::new()
+                .try_decompress(true)
+                .build()
+                .unwrap();
+            let explanation = explain(&conf, None);
+            assert_eq!(explanation.decompressor.as_deref(), Some("gzip"));
+            assert!(!explanation.mmap);
+        }
+    }
+
+    #[test]
+    fn test_mmap_always() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("input.csv");
+        fs::write(&path, "a,b\n1,2\n").unwrap();
+        let fields = FieldRange::from_list("2").unwrap();
+        let run = |conf: &CoreConfig| {
+            let mut line_buffer = LineBufferBuilder::new().build();
+            let mut core = Core::new(
+                conf,
+                &fields,
+                SubStrLineParser::new(&fields, b","),
+                &mut line_buffer,
+            );
+            let mut output = vec![];
+            core.hck_input(HckInput::Path(&path), &mut output, None)
+                .map(|_| output)
+        };
+
+        let builder = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .mmap(unsafe { MmapChoice::always() });
+        let conf = builder.clone().build().unwrap();
+        assert_eq!(run(&conf).unwrap(), b"b\n2\n");
+        // Inputs that can't be mapped are an error instead of being read
+        let conf = builder.direct_io(true).build().unwrap();
+        let err = run(&conf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(
+            err.to_string(),
+            "memory maps are required, but input read with direct I/O can't be memory mapped"
+        );
+    }
+
+    #[test]
+    fn test_final_line_without_terminator() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("input.csv");
+        for sep in ["\n", ";;"] {
+            fs::write(&path, format!("a,b,c{}1,2,3", sep)).unwrap();
+            for (list, expected) in [
+                ("2", ["b", "2"]),
+                ("1-", ["a,b,c", "1,2,3"]),
+                ("3,1", ["c,a", "3,1"]),
+            ] {
+                let fields = FieldRange::from_list(list).unwrap();
+                for mirror in [false, true] {
+                    for vectored in [false, true] {
+                        for mmap in [unsafe { MmapChoice::auto() }, MmapChoice::never()] {
+                            // A tiny line buffer splits the final line across reads
+                            for capacity in [1, 1 << 16] {
+                                let conf = CoreConfigBuilder::new()
+                                    .delimiter(b",")
+                                    .output_delimiter(b",")
+                                    .record_separator((sep != "\n").then_some(sep.as_bytes()))
+                                    .mirror_missing_terminator(mirror)
+                                    .vectored_writes(vectored)
+                                    .mmap(mmap)
+                                    .build()
+                                    .unwrap();
+                                let mut line_buffer =
+                                    LineBufferBuilder::new().capacity(capacity).build();
+                                let mut core = Core::new(
+                                    &conf,
+                                    &fields,
+                                    SubStrLineParser::new(&fields, b","),
+                                    &mut line_buffer,
+                                );
+                                let mut output = vec![];
+                                core.hck_input(HckInput::Path(&path), &mut output, None)
+                                    .unwrap();
+                                let mut expected = expected.join(sep);
+                                if !mirror {
+                                    expected.push_str(sep);
+                                }
+                                assert_eq!(output.as_bstr(), expected.as_bytes().as_bstr());
+                                assert_eq!(core.metrics().records, 2);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_output_record_separator() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("input.csv");
+        for sep in ["\n", "\r\n", ";;"] {
+            fs::write(&path, format!("a,b,c{}1,2,3{}", sep, sep)).unwrap();
+            for (list, expected) in [("2", "b|2|"), ("1-", "a,b,c|1,2,3|"), ("3,1", "c,a|3,1|")] {
+                let fields = FieldRange::from_list(list).unwrap();
+                for (out, term) in [("\\0", "\0"), ("\n", "\n"), ("\\r\\n", "\r\n")] {
+                    for mmap in [unsafe { MmapChoice::auto() }, MmapChoice::never()] {
+                        let conf = CoreConfigBuilder::new()
+                            .delimiter(b",")
+                            .output_delimiter(b",")
+                            .line_terminator(if sep == "\r\n" {
+                                LineTerminator::crlf()
+                            } else {
+                                LineTerminator::default()
+                            })
+                            .record_separator((sep == ";;").then_some(sep.as_bytes()))
+                            .output_record_separator(Some(out.as_bytes()))
+                            .mmap(mmap)
+                            .build()
+                            .unwrap();
+                        let mut line_buffer = LineBufferBuilder::new().build();
+                        let mut core = Core::new(
+                            &conf,
+                            &fields,
+                            SubStrLineParser::new(&fields, b","),
+                            &mut line_buffer,
+                        );
+                        let mut output = vec![];
+                        core.hck_input(HckInput::Path(&path), &mut output, None)
+                            .unwrap();
+                        let expected = expected.replace('|', term);
+                        assert_eq!(output.as_bstr(), expected.as_bytes().as_bstr());
+                    }
+                }
+            }
+        }
+
+        for out in ["", "ab", "\\n\\n"] {
+            let err = CoreConfigBuilder::new()
+                .output_record_separator(Some(out.as_bytes()))
+                .build()
+                .unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with("The output record separator must be"));
+        }
+    }
+
+    #[test]
+    fn test_read_hints() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("input.csv");
+        fs::write(&path, "a,b\n1,2\n").unwrap();
+        let fields = FieldRange::from_list("2").unwrap();
+        for read_hints in [true, false] {
+            for mmap in [unsafe { MmapChoice::auto() }, MmapChoice::never()] {
+                let conf = CoreConfigBuilder::new()
+                    .delimiter(b",")
+                    .mmap(mmap)
+                    .read_hints(read_hints)
+                    .build()
+                    .unwrap();
+                let mut line_buffer = LineBufferBuilder::new().build();
+                let mut core = Core::new(
+                    &conf,
+                    &fields,
+                    SubStrLineParser::new(&fields, b","),
+                    &mut line_buffer,
+                );
+                let mut output = vec![];
+                core.hck_input(HckInput::Path(&path), &mut output, None)
+                    .unwrap();
+                // The hints never change what is read
+                assert_eq!(output, b"b\n2\n");
+            }
+        }
+    }
+
+    #[test]
+    fn test_metrics_count_written() {
+        use crate::pipeline::{HeadStage, HeaderPolicy};
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("input.csv");
+        fs::write(&path, "h,x\n1,a\n2,b\n3,c\n").unwrap();
+        let fields = FieldRange::from_list("1").unwrap();
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .fields(Some("1"))
+            .build()
+            .unwrap();
+        let mut line_buffer = LineBufferBuilder::new().build();
+        let mut core = Core::new(
+            &conf,
+            &fields,
+            SubStrLineParser::new(&fields, b","),
+            &mut line_buffer,
+        );
+        core.hck_input(HckInput::Path(&path), io::sink(), None)
+            .unwrap();
+        assert_eq!(core.metrics().written, 4);
+
+        // A header the pipeline keeps and records a stage drops aren't written records
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(HeadStage::new(2));
+        let mut line_buffer = LineBufferBuilder::new().build();
+        let mut core = Core::new(
+            &conf,
+            &fields,
+            SubStrLineParser::new(&fields, b","),
+            &mut line_buffer,
+        )
+        .pipeline(Some(&mut pipeline));
+        core.hck_input(HckInput::Path(&path), io::sink(), None)
+            .unwrap();
+        assert_eq!(core.metrics().written, 2);
+
+        // Nothing is written when only counting
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .fields(Some("1"))
+            .count_only(true)
+            .build()
+            .unwrap();
+        let mut line_buffer = LineBufferBuilder::new().build();
+        let mut core = Core::new(
+            &conf,
+            &fields,
+            SubStrLineParser::new(&fields, b","),
+            &mut line_buffer,
+        );
+        core.hck_input(HckInput::Path(&path), io::sink(), None)
+            .unwrap();
+        assert_eq!(core.metrics().records, 4);
+        assert_eq!(core.metrics().written, 0);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_metrics_count_compressed_bytes() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let dir = TempDir::new().unwrap();
+        let gz = dir.path().join("input.csv.gz");
+        let data = b"a,b\n1,2\n".repeat(1000);
+        let mut encoder = GzEncoder::new(fs::File::create(&gz).unwrap(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        encoder.finish().unwrap();
+        let plain = dir.path().join("input.csv");
+        fs::write(&plain, &data).unwrap();
+
+        let fields = FieldRange::from_list("1").unwrap();
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .fields(Some("1"))
+            .try_decompress(true)
+            .build()
+            .unwrap();
+        let mut line_buffer = LineBufferBuilder::new().build();
+        let mut core = Core::new(
+            &conf,
+            &fields,
+            SubStrLineParser::new(&fields, b","),
+            &mut line_buffer,
+        );
+        core.hck_input(HckInput::Path(&gz), io::sink(), None)
+            .unwrap();
+        let compressed = fs::metadata(&gz).unwrap().len();
+        assert_eq!(core.metrics().bytes, data.len() as u64);
+        assert_eq!(core.metrics().compressed_bytes, compressed);
+        // Plain inputs add to the bytes processed only
+        core.hck_input(HckInput::Path(&plain), io::sink(), None)
+            .unwrap();
+        assert_eq!(core.metrics().bytes, 2 * data.len() as u64);
+        assert_eq!(core.metrics().compressed_bytes, compressed);
     }
 }
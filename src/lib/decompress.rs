@@ -0,0 +1,410 @@
+//! Pluggable decompression backends.
+//!
+//! When `try_decompress` is set, [`Core`](crate::core::Core) asks its [`Decompressors`] registry
+//! for a reader over each input. The registry walks its backends, most recently registered first,
+//! and uses the first one that [`Decompressor::matches`] the input path. Inputs no backend claims
+//! are read as is.
+//!
+//! The default registry holds native gzip and zstd backends followed by [`CommandDecompressor`],
+//! which shells out to tools like `xz` and `bzip2`. Library users can add their own formats with
+//! [`Decompressors::register`]. The built in backends need the `native` feature, without it the
+//! default registry is empty.
+//!
+//! [`Decompressors::open_counted`] reads through a [`CountingReader`] so that how far into the
+//! compressed input a run is can be tracked, which the decompressed byte count can't tell.
+#[cfg(feature = "native")]
+use std::ffi::OsStr;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+#[cfg(feature = "native")]
+use flate2::read::MultiGzDecoder;
+#[cfg(feature = "native")]
+use grep_cli::{DecompressionMatcher, DecompressionMatcherBuilder, DecompressionReaderBuilder};
+
+/// A decompression backend.
+pub trait Decompressor: fmt::Debug + Send + Sync {
+    /// A short name for the backend, used in error messages.
+    fn name(&self) -> &str;
+
+    /// Whether this backend should be used to open `path`.
+    fn matches(&self, path: &Path) -> bool;
+
+    /// Open `path`, returning a reader over the decompressed bytes.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>>;
+
+    /// Decompress an already open stream, such as stdin.
+    ///
+    /// Backends that can only work on files return an [`io::ErrorKind::Unsupported`] error.
+    fn decompress(&self, _reader: Box<dyn Read>) -> io::Result<Box<dyn Read>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} decompression does not support streams", self.name()),
+        ))
+    }
+}
+
+/// Whether `path` ends with any of `extensions`.
+#[cfg(feature = "native")]
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| extensions.contains(&ext))
+        .unwrap_or(false)
+}
+
+#[cfg(feature = "native")]
+/// Native, multi-member gzip decompression for `*.gz` files.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GzDecompressor;
+
+#[cfg(feature = "native")]
+impl Decompressor for GzDecompressor {
+    fn name(&self) -> &str {
+        "gzip"
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        has_extension(path, &["gz"])
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        self.decompress(Box::new(File::open(path)?))
+    }
+
+    fn decompress(&self, reader: Box<dyn Read>) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    }
+}
+
+#[cfg(feature = "native")]
+/// Native zstd decompression for `*.zst` and `*.zstd` files.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZstdDecompressor;
+
+#[cfg(feature = "native")]
+impl Decompressor for ZstdDecompressor {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        has_extension(path, &["zst", "zstd"])
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        self.decompress(Box::new(File::open(path)?))
+    }
+
+    fn decompress(&self, reader: Box<dyn Read>) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(zstd::stream::read::Decoder::new(reader)?))
+    }
+}
+
+#[cfg(feature = "native")]
+/// Decompression by an external command, ex: `xz -d -c`, chosen by glob.
+///
+/// The default rules are the ones from [`grep_cli`], see the README for the full list.
+#[derive(Debug, Clone)]
+pub struct CommandDecompressor {
+    matcher: DecompressionMatcher,
+}
+
+#[cfg(feature = "native")]
+impl CommandDecompressor {
+    /// Create a [`CommandDecompressor`] with the default rules.
+    pub fn new() -> Self {
+        Self {
+            matcher: DecompressionMatcher::new(),
+        }
+    }
+
+    /// Create a [`CommandDecompressor`] that runs `program` with `args` on files matching `glob`.
+    ///
+    /// The file path is appended to `args`, and the command must write the decompressed bytes to stdout.
+    pub fn with_command<P, I, A>(glob: &str, program: P, args: I) -> io::Result<Self>
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = A>,
+        A: AsRef<OsStr>,
+    {
+        let matcher = DecompressionMatcherBuilder::new()
+            .defaults(false)
+            .try_associate(glob, program, args)
+            .and_then(|builder| builder.build())
+            .map_err(io::Error::other)?;
+        Ok(Self { matcher })
+    }
+}
+
+#[cfg(feature = "native")]
+impl Default for CommandDecompressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "native")]
+impl Decompressor for CommandDecompressor {
+    fn name(&self) -> &str {
+        "command"
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        self.matcher.has_command(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(
+            DecompressionReaderBuilder::new()
+                .matcher(self.matcher.clone())
+                .build(path)?,
+        ))
+    }
+}
+
+/// An ordered registry of [`Decompressor`] backends.
+#[derive(Debug, Clone)]
+pub struct Decompressors {
+    /// Backends in the order they are tried
+    backends: Vec<Arc<dyn Decompressor>>,
+    /// The backend used for stdin, which has no path to match on, stdin is read as is without one
+    stdin: Option<Arc<dyn Decompressor>>,
+}
+
+impl Decompressors {
+    /// Create a registry with no backends, where stdin is treated as gzip.
+    ///
+    /// Without the `native` feature stdin is read as is.
+    pub fn empty() -> Self {
+        #[cfg(feature = "native")]
+        let stdin: Option<Arc<dyn Decompressor>> = Some(Arc::new(GzDecompressor));
+        #[cfg(not(feature = "native"))]
+        let stdin = None;
+        Self {
+            backends: vec![],
+            stdin,
+        }
+    }
+
+    /// Register a backend, it will be tried before any already registered.
+    pub fn register<D: Decompressor + 'static>(&mut self, backend: D) -> &mut Self {
+        self.backends.insert(0, Arc::new(backend));
+        self
+    }
+
+    /// Set the backend used to decompress stdin.
+    pub fn stdin<D: Decompressor + 'static>(&mut self, backend: D) -> &mut Self {
+        self.stdin = Some(Arc::new(backend));
+        self
+    }
+
+    /// The first backend that matches `path`, if any.
+    pub fn find(&self, path: &Path) -> Option<&dyn Decompressor> {
+        self.backends
+            .iter()
+            .find(|backend| backend.matches(path))
+            .map(|backend| backend.as_ref())
+    }
+
+    /// The backend used for stdin, if any.
+    pub fn find_stdin(&self) -> Option<&dyn Decompressor> {
+        self.stdin.as_deref()
+    }
+
+    /// Open `path` with the first matching backend, or as a plain file if none match.
+    pub fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        match self.find(path) {
+            Some(backend) => backend.open(path),
+            None => Ok(Box::new(File::open(path)?)),
+        }
+    }
+
+    /// Decompress stdin with the stdin backend.
+    pub fn open_stdin(&self) -> io::Result<Box<dyn Read>> {
+        match &self.stdin {
+            Some(backend) => backend.decompress(Box::new(io::stdin())),
+            None => Ok(Box::new(io::stdin())),
+        }
+    }
+
+    /// Like [`Decompressors::open`], but adds the number of compressed bytes read to `count`.
+    ///
+    /// Nothing is counted for files no backend matches. Backends that can't decompress a stream,
+    /// like external commands, read the file themselves, so the whole file size is counted when it
+    /// is opened.
+    pub fn open_counted(&self, path: &Path, count: &Arc<AtomicU64>) -> io::Result<Box<dyn Read>> {
+        let backend = match self.find(path) {
+            Some(backend) => backend,
+            None => return Ok(Box::new(File::open(path)?)),
+        };
+        let file = CountingReader::new(File::open(path)?, Arc::clone(count));
+        match backend.decompress(Box::new(file)) {
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                count.fetch_add(std::fs::metadata(path)?.len(), Ordering::Relaxed);
+                backend.open(path)
+            }
+            result => result,
+        }
+    }
+
+    /// Like [`Decompressors::open_stdin`], but adds the number of compressed bytes read to `count`.
+    ///
+    /// Nothing is counted when there is no stdin backend.
+    pub fn open_stdin_counted(&self, count: &Arc<AtomicU64>) -> io::Result<Box<dyn Read>> {
+        match &self.stdin {
+            Some(backend) => backend.decompress(Box::new(CountingReader::new(
+                io::stdin(),
+                Arc::clone(count),
+            ))),
+            None => Ok(Box::new(io::stdin())),
+        }
+    }
+}
+
+/// A [`Read`] wrapper that adds the number of bytes read through it to a shared counter.
+///
+/// The counter can be read from another thread while the reader is in use, ex: to report progress.
+#[derive(Debug)]
+pub struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> CountingReader<R> {
+    /// Wrap `inner`, adding to `count`.
+    pub fn new(inner: R, count: Arc<AtomicU64>) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl Default for Decompressors {
+    /// Native gzip and zstd, falling back to external commands for everything else.
+    fn default() -> Self {
+        #[allow(unused_mut)]
+        let mut decompressors = Self::empty();
+        #[cfg(feature = "native")]
+        decompressors
+            .register(CommandDecompressor::new())
+            .register(ZstdDecompressor)
+            .register(GzDecompressor);
+        decompressors
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod test {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    const DATA: &[u8] = b"a\tb\tc\n1\t2\t3\n";
+
+    fn read_all(mut reader: Box<dyn Read>) -> Vec<u8> {
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_native_backends() {
+        let dir = TempDir::new().unwrap();
+        let gz = dir.path().join("data.tsv.gz");
+        let mut encoder = GzEncoder::new(File::create(&gz).unwrap(), Compression::default());
+        encoder.write_all(DATA).unwrap();
+        encoder.finish().unwrap();
+        let zst = dir.path().join("data.tsv.zst");
+        zstd::stream::copy_encode(DATA, File::create(&zst).unwrap(), 0).unwrap();
+        let plain = dir.path().join("data.tsv");
+        std::fs::write(&plain, DATA).unwrap();
+
+        let decompressors = Decompressors::default();
+        assert_eq!(decompressors.find(&gz).unwrap().name(), "gzip");
+        assert_eq!(decompressors.find(&zst).unwrap().name(), "zstd");
+        assert!(decompressors.find(&plain).is_none());
+        for path in [gz, zst, plain] {
+            assert_eq!(read_all(decompressors.open(&path).unwrap()), DATA);
+        }
+    }
+
+    #[derive(Debug)]
+    struct Upper;
+
+    impl Decompressor for Upper {
+        fn name(&self) -> &str {
+            "upper"
+        }
+
+        fn matches(&self, path: &Path) -> bool {
+            has_extension(path, &["up", "gz"])
+        }
+
+        fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+            let bytes = std::fs::read(path)?;
+            Ok(Box::new(io::Cursor::new(bytes.to_ascii_uppercase())))
+        }
+    }
+
+    #[test]
+    fn test_registered_backends_take_precedence() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("data.gz");
+        std::fs::write(&path, DATA).unwrap();
+
+        let mut decompressors = Decompressors::default();
+        decompressors.register(Upper);
+        assert_eq!(decompressors.find(&path).unwrap().name(), "upper");
+        assert_eq!(
+            read_all(decompressors.open(&path).unwrap()),
+            DATA.to_ascii_uppercase()
+        );
+        assert!(Upper.decompress(Box::new(io::empty())).is_err());
+    }
+
+    #[test]
+    fn test_open_counted() {
+        let dir = TempDir::new().unwrap();
+        let gz = dir.path().join("data.tsv.gz");
+        let mut encoder = GzEncoder::new(File::create(&gz).unwrap(), Compression::default());
+        encoder.write_all(&DATA.repeat(100)).unwrap();
+        encoder.finish().unwrap();
+        let plain = dir.path().join("data.tsv");
+        std::fs::write(&plain, DATA).unwrap();
+        let up = dir.path().join("data.up");
+        std::fs::write(&up, DATA).unwrap();
+
+        let mut decompressors = Decompressors::default();
+        let count = Arc::new(AtomicU64::new(0));
+        let read = read_all(decompressors.open_counted(&gz, &count).unwrap());
+        assert_eq!(read, DATA.repeat(100));
+        let compressed = std::fs::metadata(&gz).unwrap().len();
+        assert_eq!(count.load(Ordering::Relaxed), compressed);
+        // Plain files aren't counted
+        read_all(decompressors.open_counted(&plain, &count).unwrap());
+        assert_eq!(count.load(Ordering::Relaxed), compressed);
+        // Backends that only open files count the whole file
+        decompressors.register(Upper);
+        read_all(decompressors.open_counted(&up, &count).unwrap());
+        assert_eq!(
+            count.load(Ordering::Relaxed),
+            compressed + DATA.len() as u64
+        );
+    }
+}
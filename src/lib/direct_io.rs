@@ -0,0 +1,162 @@
+//! Reading files with `O_DIRECT`, bypassing the page cache.
+//!
+//! This is for benchmarking how fast inputs can be processed when they aren't already cached,
+//! not for general use. Direct reads must land in aligned buffers and be a multiple of the block
+//! size, so a [`DirectReader`] reads into its own aligned buffer and copies out of it, which
+//! costs a copy that a normal read doesn't have.
+use std::{
+    alloc::{self, Layout},
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    slice,
+};
+
+/// The alignment of the buffer and of the size of each read, which covers the logical block size
+/// of common filesystems.
+pub const DIRECT_IO_ALIGN: usize = 4096;
+
+/// The size of each read from the file.
+pub const DIRECT_IO_READ_SIZE: usize = 1 << 20;
+
+/// A buffer aligned to [`DIRECT_IO_ALIGN`].
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size, DIRECT_IO_ALIGN).unwrap();
+        // SAFETY: The layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Self { ptr, layout }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: `ptr` is a live, initialized allocation of `layout.size()` bytes.
+        unsafe { slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: As above, and `&mut self` makes this the only reference to it.
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+// SAFETY: The buffer uniquely owns its allocation, like a `Vec<u8>`.
+unsafe impl Send for AlignedBuffer {}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` was allocated with `layout`.
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// A [`Read`] over a file opened with `O_DIRECT`.
+pub struct DirectReader {
+    file: File,
+    buffer: AlignedBuffer,
+    /// The start of the unread data in `buffer`
+    pos: usize,
+    /// The end of the data in `buffer`
+    len: usize,
+    /// Whether a read came back short of a whole block, which only happens at the end of the file
+    eof: bool,
+}
+
+impl DirectReader {
+    /// Open `path` for direct reads.
+    ///
+    /// This fails on platforms other than Linux, and on filesystems that don't support `O_DIRECT`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            file: open_direct(path.as_ref())?,
+            buffer: AlignedBuffer::new(DIRECT_IO_READ_SIZE),
+            pos: 0,
+            len: 0,
+            eof: false,
+        })
+    }
+}
+
+impl Read for DirectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.len {
+            if self.eof {
+                return Ok(0);
+            }
+            let n = loop {
+                match self.file.read(self.buffer.as_mut_slice()) {
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    result => break result?,
+                }
+            };
+            // The file offset has to stay aligned, so anything short of whole blocks is the end
+            self.eof = n == 0 || n % DIRECT_IO_ALIGN != 0;
+            self.pos = 0;
+            self.len = n;
+        }
+        let n = buf.len().min(self.len - self.pos);
+        buf[..n].copy_from_slice(&self.buffer.as_slice()[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> io::Result<File> {
+    use std::{fs::OpenOptions, os::unix::fs::OpenOptionsExt};
+
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_path: &Path) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct IO is only supported on Linux",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_direct_reader() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("input.txt");
+        // Not a multiple of the block size, so the last read comes back short
+        let data: Vec<u8> = (0..DIRECT_IO_READ_SIZE + 3 * DIRECT_IO_ALIGN + 17)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs::write(&path, &data).unwrap();
+
+        let mut reader = match DirectReader::open(&path) {
+            Ok(reader) => reader,
+            // Some filesystems, like tmpfs, don't support O_DIRECT
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(err) => panic!("{}", err),
+        };
+        let mut read = vec![];
+        let mut chunk = [0; 1000];
+        loop {
+            let n = reader.read(&mut chunk).unwrap();
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!(read, data);
+    }
+}
@@ -0,0 +1,151 @@
+//! Counting distinct keys.
+//!
+//! [`DistinctCounter::Exact`] remembers every key it has seen, which is simple but grows with the
+//! number of distinct keys. For very high cardinality inputs [`DistinctCounter::Approx`] uses a
+//! [`HyperLogLog`] instead, which estimates the count in a fixed amount of memory.
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::Hasher,
+};
+
+/// The default [`HyperLogLog`] precision, 16 KiB of registers for about 0.8% standard error.
+pub const DEFAULT_PRECISION: u8 = 14;
+
+/// A HyperLogLog cardinality estimator.
+///
+/// Each key is hashed to 64 bits, the first `precision` bits pick a register and the register keeps
+/// the longest run of leading zeros seen in the rest.
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// Create an estimator with `2^precision` one byte registers, `precision` is clamped to `4..=18`.
+    pub fn new(precision: u8) -> Self {
+        let precision = precision.clamp(4, 18);
+        Self {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    /// Add a key.
+    #[inline]
+    pub fn insert(&mut self, key: &[u8]) {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - self.precision)) as usize;
+        // Set a sentinel bit so the rank is bounded when the remaining bits are all zero
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        let register = &mut self.registers[index];
+        *register = (*register).max(rank);
+    }
+
+    /// The estimated number of distinct keys added.
+    pub fn count(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|r| 2f64.powi(-i32::from(*r)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        // Linear counting is more accurate while many registers are still empty
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    /// The relative standard error of [`HyperLogLog::count`].
+    pub fn standard_error(&self) -> f64 {
+        1.04 / (self.registers.len() as f64).sqrt()
+    }
+
+    /// The bytes used by the registers, which never grows.
+    pub fn memory(&self) -> usize {
+        self.registers.len()
+    }
+}
+
+/// Counts distinct keys, exactly or approximately.
+#[derive(Debug, Clone)]
+pub enum DistinctCounter {
+    Exact(HashSet<Vec<u8>>),
+    Approx(HyperLogLog),
+}
+
+impl DistinctCounter {
+    /// An exact counter.
+    pub fn exact() -> Self {
+        DistinctCounter::Exact(HashSet::new())
+    }
+
+    /// An approximate counter with the given [`HyperLogLog`] precision.
+    pub fn approx(precision: u8) -> Self {
+        DistinctCounter::Approx(HyperLogLog::new(precision))
+    }
+
+    /// Add a key.
+    #[inline]
+    pub fn insert(&mut self, key: &[u8]) {
+        match self {
+            DistinctCounter::Exact(seen) => {
+                if !seen.contains(key) {
+                    seen.insert(key.to_vec());
+                }
+            }
+            DistinctCounter::Approx(hll) => hll.insert(key),
+        }
+    }
+
+    /// The number of distinct keys added, or an estimate of it.
+    pub fn count(&self) -> u64 {
+        match self {
+            DistinctCounter::Exact(seen) => seen.len() as u64,
+            DistinctCounter::Approx(hll) => hll.count(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hyperloglog() {
+        for n in [0u64, 1, 100, 10_000, 200_000] {
+            let mut hll = HyperLogLog::new(DEFAULT_PRECISION);
+            for i in 0..n {
+                // Duplicates don't change the estimate
+                hll.insert(i.to_string().as_bytes());
+                hll.insert(i.to_string().as_bytes());
+            }
+            let error = (hll.count() as f64 - n as f64).abs() / (n.max(1) as f64);
+            assert!(error < 4.0 * hll.standard_error(), "{} {}", n, hll.count());
+        }
+        assert_eq!(HyperLogLog::new(DEFAULT_PRECISION).memory(), 16 * 1024);
+        assert_eq!(HyperLogLog::new(0).memory(), 16);
+    }
+
+    #[test]
+    fn test_exact() {
+        let mut counter = DistinctCounter::exact();
+        for key in ["a", "b", "a", ""] {
+            counter.insert(key.as_bytes());
+        }
+        assert_eq!(counter.count(), 3);
+    }
+}
@@ -0,0 +1,478 @@
+//! A tiny expression language for filtering records.
+//!
+//! ```text
+//! col(3) == "PASS" && (col(5) > 30 || !col(6))
+//! ```
+//!
+//! `col(N)` is the Nth (1-based) selected field, in output order. A bare value is true if it is
+//! non-empty, missing columns are treated as empty.
+//!
+//! Comparisons with a number, like `col(5) > 30`, and `<`, `<=`, `>`, and `>=` between columns are
+//! numeric. A numeric comparison is false when either side isn't a number, or an error with
+//! [`Expr::try_matches`]. Other comparisons, like `col(3) == "PASS"` or `col(1) < 'b'`, are bytewise.
+//!
+//! Every record is evaluated, so a header row will usually fail numeric comparisons. Callers that know
+//! which row is the header should keep it out, as a [`Pipeline`](crate::pipeline::Pipeline) with
+//! [`HeaderPolicy::Keep`](crate::pipeline::HeaderPolicy::Keep) does.
+use std::{borrow::Cow, cmp::Ordering};
+
+use bstr::ByteSlice;
+use thiserror::Error;
+
+/// Errors for parsing an [`Expr`], or evaluating one with [`Expr::try_matches`].
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ExprError {
+    #[error("Unexpected character `{0}` at position {1}")]
+    UnexpectedChar(char, usize),
+    #[error("Unterminated string starting at position {0}")]
+    UnterminatedString(usize),
+    #[error("Expected {0} at position {1}")]
+    Expected(&'static str, usize),
+    #[error("Columns are numbered from 1, found col({0})")]
+    InvalidColumn(usize),
+    #[error("Expected a number for {0}, found `{1}`")]
+    NotANumber(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Col,
+    Str(Vec<u8>),
+    Num(String),
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Cmp(CmpOp),
+}
+
+/// A comparison operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    fn matches(self, ordering: Ordering) -> bool {
+        match self {
+            CmpOp::Eq => ordering == Ordering::Equal,
+            CmpOp::Ne => ordering != Ordering::Equal,
+            CmpOp::Lt => ordering == Ordering::Less,
+            CmpOp::Le => ordering != Ordering::Greater,
+            CmpOp::Gt => ordering == Ordering::Greater,
+            CmpOp::Ge => ordering != Ordering::Less,
+        }
+    }
+
+    /// Whether the operator only makes sense for numbers, unless a side is a string.
+    fn orders(self) -> bool {
+        matches!(self, CmpOp::Lt | CmpOp::Le | CmpOp::Gt | CmpOp::Ge)
+    }
+}
+
+/// A value in an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// A 0-based index into the selected fields
+    Column(usize),
+    /// A string literal
+    Literal(Vec<u8>),
+    /// A number literal
+    Number(f64),
+}
+
+impl Operand {
+    #[inline]
+    fn resolve<'b>(&'b self, fields: &'b [Cow<[u8]>]) -> Cow<'b, [u8]> {
+        match self {
+            Operand::Column(i) => Cow::Borrowed(fields.get(*i).map(|f| f.as_ref()).unwrap_or(b"")),
+            Operand::Literal(value) => Cow::Borrowed(value),
+            Operand::Number(n) => Cow::Owned(n.to_string().into_bytes()),
+        }
+    }
+
+    /// The value as a number, or an error naming the operand if it isn't one.
+    #[inline]
+    fn number(&self, fields: &[Cow<[u8]>]) -> Result<f64, ExprError> {
+        let value = match self {
+            Operand::Number(n) => return Ok(*n),
+            Operand::Column(i) => fields.get(*i).map(|f| f.as_ref()).unwrap_or(b""),
+            Operand::Literal(value) => value,
+        };
+        parse_number(value).ok_or_else(|| {
+            let operand = match self {
+                Operand::Column(i) => format!("col({})", i + 1),
+                _ => String::from("a string"),
+            };
+            ExprError::NotANumber(operand, value.to_str_lossy().into_owned())
+        })
+    }
+}
+
+/// Parse a value as a number, ignoring surrounding whitespace. `NaN` isn't a number.
+#[inline]
+fn parse_number(value: &[u8]) -> Option<f64> {
+    value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .filter(|n| !n.is_nan())
+}
+
+/// A parsed filter expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cmp(Operand, CmpOp, Operand),
+    Truthy(Operand),
+}
+
+impl Expr {
+    /// Parse an expression.
+    pub fn parse(input: &str) -> Result<Expr, ExprError> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(ExprError::Expected("end of expression", parser.offset()));
+        }
+        Ok(expr)
+    }
+
+    /// Evaluate the expression against a record's selected fields. A numeric comparison where
+    /// either side isn't a number is false.
+    pub fn matches(&self, fields: &[Cow<[u8]>]) -> bool {
+        self.eval(fields, false).unwrap_or(false)
+    }
+
+    /// Like [`matches`](Self::matches), but fail on a numeric comparison where either side isn't a
+    /// number.
+    pub fn try_matches(&self, fields: &[Cow<[u8]>]) -> Result<bool, ExprError> {
+        self.eval(fields, true)
+    }
+
+    fn eval(&self, fields: &[Cow<[u8]>], strict: bool) -> Result<bool, ExprError> {
+        Ok(match self {
+            Expr::And(a, b) => a.eval(fields, strict)? && b.eval(fields, strict)?,
+            Expr::Or(a, b) => a.eval(fields, strict)? || b.eval(fields, strict)?,
+            Expr::Not(a) => !a.eval(fields, strict)?,
+            Expr::Truthy(a) => !a.resolve(fields).is_empty(),
+            Expr::Cmp(a, op, b) if is_numeric(a, *op, b) => {
+                match (a.number(fields), b.number(fields)) {
+                    (Ok(a), Ok(b)) => op.matches(a.total_cmp(&b)),
+                    (Err(err), _) | (_, Err(err)) if strict => return Err(err),
+                    _ => false,
+                }
+            }
+            Expr::Cmp(a, op, b) => op.matches(a.resolve(fields).cmp(&b.resolve(fields))),
+        })
+    }
+}
+
+/// Whether a comparison is numeric: either side is a number, or it's an ordering with no strings.
+#[inline]
+fn is_numeric(a: &Operand, op: CmpOp, b: &Operand) -> bool {
+    let is = |pred: fn(&Operand) -> bool| pred(a) || pred(b);
+    is(|o| matches!(o, Operand::Number(_)))
+        || (op.orders() && !is(|o| matches!(o, Operand::Literal(_))))
+}
+
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>, ExprError> {
+    let bytes = input.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let two = bytes.get(i..i + 2);
+        let token = match bytes[i] {
+            b if b.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b'(' => Token::LParen,
+            b')' => Token::RParen,
+            _ if two == Some(b"&&") => Token::And,
+            _ if two == Some(b"||") => Token::Or,
+            _ if two == Some(b"==") => Token::Cmp(CmpOp::Eq),
+            _ if two == Some(b"!=") => Token::Cmp(CmpOp::Ne),
+            _ if two == Some(b"<=") => Token::Cmp(CmpOp::Le),
+            _ if two == Some(b">=") => Token::Cmp(CmpOp::Ge),
+            b'<' => Token::Cmp(CmpOp::Lt),
+            b'>' => Token::Cmp(CmpOp::Gt),
+            b'!' => Token::Not,
+            quote @ (b'"' | b'\'') => {
+                let mut value = vec![];
+                i += 1;
+                loop {
+                    match bytes.get(i) {
+                        None => return Err(ExprError::UnterminatedString(start)),
+                        Some(b'\\') if bytes.get(i + 1).is_some() => {
+                            value.push(bytes[i + 1]);
+                            i += 2;
+                        }
+                        Some(b) if *b == quote => break,
+                        Some(b) => {
+                            value.push(*b);
+                            i += 1;
+                        }
+                    }
+                }
+                Token::Str(value)
+            }
+            b if b.is_ascii_digit() || b == b'-' || b == b'.' => {
+                let end = bytes[i + 1..]
+                    .iter()
+                    .position(|b| !(b.is_ascii_alphanumeric() || *b == b'.' || *b == b'+'))
+                    .map(|p| i + 1 + p)
+                    .unwrap_or(bytes.len());
+                let num = input[i..end].to_owned();
+                i = end - 1;
+                Token::Num(num)
+            }
+            _ if input[i..].starts_with("col") => {
+                i += 2;
+                Token::Col
+            }
+            _ => {
+                let c = input[i..].chars().next().unwrap();
+                return Err(ExprError::UnexpectedChar(c, i));
+            }
+        };
+        i += match token {
+            Token::And | Token::Or => 2,
+            Token::Cmp(CmpOp::Eq | CmpOp::Ne | CmpOp::Le | CmpOp::Ge) => 2,
+            _ => 1,
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    /// The input offset of the current token, for error messages.
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map(|(_, offset)| *offset)
+            .unwrap_or_else(|| self.tokens.last().map(|(_, o)| o + 1).unwrap_or(0))
+    }
+
+    fn expect(&mut self, token: Token, what: &'static str) -> Result<(), ExprError> {
+        if self.peek() == Some(&token) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(ExprError::Expected(what, self.offset()))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ExprError> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, ExprError> {
+        let mut expr = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, ExprError> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.not()?)))
+            }
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let expr = self.or()?;
+                self.expect(Token::RParen, "`)`")?;
+                Ok(expr)
+            }
+            _ => self.cmp(),
+        }
+    }
+
+    fn cmp(&mut self) -> Result<Expr, ExprError> {
+        let left = self.operand()?;
+        if let Some(Token::Cmp(op)) = self.peek() {
+            let op = *op;
+            self.pos += 1;
+            let right = self.operand()?;
+            Ok(Expr::Cmp(left, op, right))
+        } else {
+            Ok(Expr::Truthy(left))
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, ExprError> {
+        let offset = self.offset();
+        let operand = match self.tokens.get(self.pos).map(|(t, _)| t.clone()) {
+            Some(Token::Str(value)) => Operand::Literal(value),
+            Some(Token::Num(num)) => match parse_number(num.as_bytes()) {
+                Some(n) => Operand::Number(n),
+                None => return Err(ExprError::Expected("a number", offset)),
+            },
+            Some(Token::Col) => {
+                self.pos += 1;
+                self.expect(Token::LParen, "`(` after `col`")?;
+                let column = match self.peek() {
+                    Some(Token::Num(num)) => num
+                        .parse::<usize>()
+                        .map_err(|_| ExprError::Expected("a column number", self.offset()))?,
+                    _ => return Err(ExprError::Expected("a column number", self.offset())),
+                };
+                if column == 0 {
+                    return Err(ExprError::InvalidColumn(column));
+                }
+                self.pos += 1;
+                self.expect(Token::RParen, "`)`")?;
+                return Ok(Operand::Column(column - 1));
+            }
+            _ => {
+                return Err(ExprError::Expected(
+                    "`col(N)`, a string, or a number",
+                    offset,
+                ))
+            }
+        };
+        self.pos += 1;
+        Ok(operand)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record<'a>(fields: &[&'a str]) -> Vec<Cow<'a, [u8]>> {
+        fields.iter().map(|f| Cow::Borrowed(f.as_bytes())).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Expr::parse("col(3) == \"PASS\"").unwrap(),
+            Expr::Cmp(
+                Operand::Column(2),
+                CmpOp::Eq,
+                Operand::Literal(b"PASS".to_vec())
+            )
+        );
+        assert_eq!(
+            Expr::parse("col(5) > 30").unwrap(),
+            Expr::Cmp(Operand::Column(4), CmpOp::Gt, Operand::Number(30.0))
+        );
+        assert!(matches!(
+            Expr::parse("col(1) > 1 && col(2) < 2 || !col(3)").unwrap(),
+            Expr::Or(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Expr::parse("col(0)"), Err(ExprError::InvalidColumn(0)));
+        assert_eq!(
+            Expr::parse("col(1) == 'x"),
+            Err(ExprError::UnterminatedString(10))
+        );
+        assert_eq!(
+            Expr::parse("col(1) = 2"),
+            Err(ExprError::UnexpectedChar('=', 7))
+        );
+        assert!(Expr::parse("(col(1)").is_err());
+        assert!(Expr::parse("col(1) == 2 3").is_err());
+        assert!(Expr::parse("").is_err());
+        assert_eq!(
+            Expr::parse("col(1) > 3x"),
+            Err(ExprError::Expected("a number", 9))
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let expr = Expr::parse("col(3) == \"PASS\" && col(5) > 30").unwrap();
+        assert!(expr.matches(&record(&["chr1", "10", "PASS", "x", "31"])));
+        assert!(!expr.matches(&record(&["chr1", "10", "PASS", "x", "30"])));
+        assert!(!expr.matches(&record(&["chr1", "10", "FAIL", "x", "99"])));
+        // Numeric, not lexical, comparison when both sides are numbers
+        assert!(expr.matches(&record(&["chr1", "10", "PASS", "x", "100"])));
+        assert!(!expr.matches(&record(&["chr1", "10", "PASS"])));
+
+        let expr = Expr::parse("!(col(1) <= 'b') || col(2)").unwrap();
+        assert!(expr.matches(&record(&["c", ""])));
+        assert!(!expr.matches(&record(&["a", ""])));
+        assert!(expr.matches(&record(&["a", "y"])));
+
+        let expr = Expr::parse("col(1) != -1.5e0").unwrap();
+        assert!(!expr.matches(&record(&["-1.50"])));
+        assert!(expr.matches(&record(&["2"])));
+        assert!(!expr.matches(&record(&["abc"])));
+
+        // Numbers compare equal however they are written
+        assert!(Expr::parse("col(1) == 3")
+            .unwrap()
+            .matches(&record(&["3.0"])));
+        assert!(!Expr::parse("col(1) == '3'")
+            .unwrap()
+            .matches(&record(&["3.0"])));
+        // Orderings between columns are numeric
+        let expr = Expr::parse("col(1) < col(2)").unwrap();
+        assert!(expr.matches(&record(&["9", "10"])));
+        assert!(!expr.matches(&record(&["a", "b"])));
+    }
+
+    #[test]
+    fn test_numeric_needs_numbers() {
+        let expr = Expr::parse("col(2) > 30").unwrap();
+        for value in ["NA", "", "nan", "31x"] {
+            assert!(!expr.matches(&record(&["x", value])));
+            // The comparison is false, so its negation is true
+            assert!(Expr::parse("!(col(2) <= 30)")
+                .unwrap()
+                .matches(&record(&["x", value])));
+        }
+        assert_eq!(expr.try_matches(&record(&["x", "31"])), Ok(true));
+        assert_eq!(
+            expr.try_matches(&record(&["x", "NA"])),
+            Err(ExprError::NotANumber(
+                String::from("col(2)"),
+                String::from("NA")
+            ))
+        );
+        assert_eq!(
+            expr.try_matches(&record(&["x"])),
+            Err(ExprError::NotANumber(String::from("col(2)"), String::new()))
+        );
+        // A header is evaluated like any other row
+        assert!(expr.try_matches(&record(&["name", "score"])).is_err());
+        // Bytewise comparisons never fail
+        let expr = Expr::parse("col(1) == 'PASS' || col(1) < 'b'").unwrap();
+        assert_eq!(expr.try_matches(&record(&["NA"])), Ok(true));
+    }
+}
@@ -0,0 +1,288 @@
+//! A C API for field extraction, enabled with the `ffi` feature.
+//!
+//! Build it as a shared library with
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib`. The matching header is
+//! `include/hcklib.h`, regenerate it with `cbindgen --config cbindgen.toml --output include/hcklib.h src/lib/ffi.rs`
+//! after changing this module.
+//!
+//! An [`HckExtractor`] is created once from a field list and a delimiter, then fed buffers of
+//! whole lines. For each line a callback gets the offsets of the selected fields within the
+//! buffer, in output order, so nothing is copied.
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    fmt::Display,
+    ptr, slice,
+};
+
+use ripline::{
+    lines::{self, LineIter},
+    LineTerminator,
+};
+
+use crate::{
+    core::{CoreConfig, CoreConfigBuilder},
+    field_range::FieldRange,
+    line_parser::LineParser,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Keep `err` around for [`hck_last_error`].
+fn set_last_error<E: Display>(err: E) {
+    // Messages don't contain a NUL, but fall back to nothing rather than failing
+    let message = CString::new(err.to_string()).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// A compiled field selection, see [`hck_extractor_new`].
+pub struct HckExtractor {
+    config: CoreConfig<'static>,
+    fields: Vec<FieldRange>,
+}
+
+/// The `[start, end)` byte offsets of a field within the buffer given to [`hck_extractor_feed`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HckSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Called with the spans of the selected fields of a line, returning anything but 0 stops.
+pub type HckRowCallback = Option<
+    unsafe extern "C" fn(user_data: *mut c_void, spans: *const HckSpan, len: usize) -> c_int,
+>;
+
+impl HckExtractor {
+    fn new(fields: &CStr, delimiter: &CStr, literal: bool) -> anyhow::Result<Self> {
+        let config = CoreConfigBuilder::new()
+            .delimiter(delimiter.to_bytes().to_vec())
+            .is_regex_parser(!literal)
+            .fields(Some(fields.to_str()?.to_owned()))
+            .build()?;
+        let fields = config.parse_fields_from_names::<&str>(&[])?;
+        Ok(Self { config, fields })
+    }
+
+    /// Call `f` with the spans of the selected fields of each line in `buffer`.
+    fn feed<F>(&self, buffer: &[u8], f: F) -> c_int
+    where
+        F: FnMut(&[HckSpan]) -> c_int,
+    {
+        let fields = &self.fields;
+        let term = self.config.line_terminator();
+        feed_lines(&self.config.line_parser(fields), fields, term, buffer, f)
+    }
+}
+
+/// Split `buffer` into lines on `term` and call `f` with the spans of the `fields` that `parser`
+/// selects from each.
+fn feed_lines<'a, L, F>(
+    parser: &L,
+    fields: &[FieldRange],
+    term: LineTerminator,
+    buffer: &[u8],
+    mut f: F,
+) -> c_int
+where
+    L: LineParser<'a>,
+    F: FnMut(&[HckSpan]) -> c_int,
+{
+    let mut shuffler = vec![vec![]; fields.iter().map(|f| f.pos + 1).max().unwrap_or(0)];
+    let mut spans = vec![];
+    let base = buffer.as_ptr() as usize;
+    for line in LineIter::new(term.as_byte(), buffer) {
+        let line = lines::without_terminator(line, term);
+        parser.parse_line(line, &mut shuffler);
+        // Without a fill value every field is a slice of `buffer`
+        spans.extend(shuffler.iter_mut().flat_map(|s| s.drain(..)).map(|field| {
+            let start = field.as_ptr() as usize - base;
+            HckSpan {
+                start,
+                end: start + field.len(),
+            }
+        }));
+        let status = f(&spans);
+        spans.clear();
+        if status != 0 {
+            return status;
+        }
+    }
+    0
+}
+
+/// Create an extractor selecting `fields`, ex: `"1,3-5"`, from lines split on `delimiter`.
+///
+/// The delimiter is a regex unless `literal` is set, and escapes like `\t` are resolved. Returns
+/// null on failure, see [`hck_last_error`]. Free it with [`hck_extractor_free`].
+///
+/// # Safety
+///
+/// `fields` and `delimiter` must be null or valid NUL terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn hck_extractor_new(
+    fields: *const c_char,
+    delimiter: *const c_char,
+    literal: bool,
+) -> *mut HckExtractor {
+    if fields.is_null() || delimiter.is_null() {
+        set_last_error("fields and delimiter must not be null");
+        return ptr::null_mut();
+    }
+    match HckExtractor::new(CStr::from_ptr(fields), CStr::from_ptr(delimiter), literal) {
+        Ok(extractor) => Box::into_raw(Box::new(extractor)),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Split the `len` bytes at `buffer` into lines and call `callback` with the spans of the selected
+/// fields of each, passing `user_data` through.
+///
+/// A final line without a terminator is processed like any other, so feed whole lines. Returns 0
+/// once every line is processed, -1 if an argument is null, or the non-zero value `callback`
+/// stopped with. The spans are only valid for the call to `callback`.
+///
+/// # Safety
+///
+/// `extractor` must come from [`hck_extractor_new`] and `buffer` must point to `len` readable
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn hck_extractor_feed(
+    extractor: *const HckExtractor,
+    buffer: *const u8,
+    len: usize,
+    callback: HckRowCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let (Some(extractor), Some(callback)) = (extractor.as_ref(), callback) else {
+        set_last_error("extractor and callback must not be null");
+        return -1;
+    };
+    let buffer = match (buffer.is_null(), len) {
+        (_, 0) => &[][..],
+        (false, _) => slice::from_raw_parts(buffer, len),
+        (true, _) => {
+            set_last_error("buffer must not be null");
+            return -1;
+        }
+    };
+    extractor.feed(buffer, |spans| {
+        callback(user_data, spans.as_ptr(), spans.len())
+    })
+}
+
+/// Free an extractor, null is ignored.
+///
+/// # Safety
+///
+/// `extractor` must come from [`hck_extractor_new`] and not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn hck_extractor_free(extractor: *mut HckExtractor) {
+    if !extractor.is_null() {
+        drop(Box::from_raw(extractor));
+    }
+}
+
+/// The message for the last error on this thread, or null if there hasn't been one.
+///
+/// The string is owned by hcklib and is valid until the next error on the same thread.
+#[no_mangle]
+pub extern "C" fn hck_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |err| err.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Collects the spans of each line into the `Vec<Vec<HckSpan>>` behind `user_data`.
+    unsafe extern "C" fn collect(
+        user_data: *mut c_void,
+        spans: *const HckSpan,
+        len: usize,
+    ) -> c_int {
+        let rows = &mut *(user_data as *mut Vec<Vec<HckSpan>>);
+        rows.push(slice::from_raw_parts(spans, len).to_vec());
+        0
+    }
+
+    /// Stops after the first line.
+    unsafe extern "C" fn stop(_: *mut c_void, _: *const HckSpan, _: usize) -> c_int {
+        7
+    }
+
+    fn feed(extractor: *const HckExtractor, buffer: &[u8]) -> Vec<Vec<&[u8]>> {
+        let mut rows: Vec<Vec<HckSpan>> = vec![];
+        let status = unsafe {
+            hck_extractor_feed(
+                extractor,
+                buffer.as_ptr(),
+                buffer.len(),
+                Some(collect),
+                &mut rows as *mut _ as *mut c_void,
+            )
+        };
+        assert_eq!(status, 0);
+        rows.into_iter()
+            .map(|spans| {
+                spans
+                    .into_iter()
+                    .map(|span| &buffer[span.start..span.end])
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let extractor = unsafe { hck_extractor_new(c"3,1".as_ptr(), c",".as_ptr(), true) };
+        assert!(!extractor.is_null());
+        assert_eq!(
+            feed(extractor, b"a,b,c\n1,2,3\n4"),
+            vec![
+                vec![&b"c"[..], b"a"],
+                vec![&b"3"[..], b"1"],
+                vec![&b"4"[..]]
+            ]
+        );
+        let buffer = b"a,b,c\n1,2,3\n";
+        let status = unsafe {
+            hck_extractor_feed(
+                extractor,
+                buffer.as_ptr(),
+                buffer.len(),
+                Some(stop),
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(status, 7);
+        unsafe { hck_extractor_free(extractor) };
+
+        let extractor = unsafe { hck_extractor_new(c"2-".as_ptr(), c"\\s+".as_ptr(), false) };
+        assert_eq!(feed(extractor, b"a  b\tc\n"), vec![vec![&b"b"[..], b"c"]]);
+        unsafe { hck_extractor_free(extractor) };
+    }
+
+    #[test]
+    fn test_errors() {
+        let extractor = unsafe { hck_extractor_new(c"0".as_ptr(), c",".as_ptr(), true) };
+        assert!(extractor.is_null());
+        let message = unsafe { CStr::from_ptr(hck_last_error()) };
+        assert!(!message.to_bytes().is_empty());
+
+        let status =
+            unsafe { hck_extractor_feed(ptr::null(), ptr::null(), 0, Some(stop), ptr::null_mut()) };
+        assert_eq!(status, -1);
+        unsafe { hck_extractor_free(ptr::null_mut()) };
+    }
+}
@@ -6,7 +6,7 @@
 
 use bstr::ByteSlice;
 use regex::bytes::Regex;
-use std::{cmp::max, collections::VecDeque, str::FromStr};
+use std::{cmp::max, collections::VecDeque, fmt, str::FromStr};
 use thiserror::Error;
 
 /// The fartest right possible field
@@ -14,6 +14,7 @@ const MAX: usize = usize::MAX;
 
 /// Errors for parsing / validating [`FieldRange`] strings.
 #[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum FieldError {
     #[error("Header not found: {0}")]
     HeaderNotFound(String),
@@ -31,17 +32,44 @@ pub enum FieldError {
 pub enum RegexOrString {
     Regex(Regex),
     String(String),
+    /// Fixed column widths, in bytes, for input without a delimiter.
+    ///
+    /// Header names split with this are trimmed of whitespace padding.
+    Widths(Vec<usize>),
 }
 
 impl RegexOrString {
-    fn split<'a>(&'a self, line: &'a [u8]) -> Box<dyn Iterator<Item = &'a [u8]> + 'a> {
+    pub(crate) fn split<'a>(&'a self, line: &'a [u8]) -> Box<dyn Iterator<Item = &'a [u8]> + 'a> {
         match self {
             RegexOrString::Regex(r) => Box::new(r.split(line)),
             RegexOrString::String(s) => Box::new(line.split_str(s)),
+            RegexOrString::Widths(widths) => Box::new(
+                widths
+                    .iter()
+                    .scan(0, |start, width| {
+                        let span = (*start, *start + width);
+                        *start += width;
+                        Some(span)
+                    })
+                    .take_while(move |(start, _)| *start < line.len())
+                    // Fixed width headers are padded, so trim them for matching
+                    .map(move |(start, end)| line[start..end.min(line.len())].trim()),
+            ),
         }
     }
 }
 
+/// Parse a comma separated list of column widths, ex: `10,8,20`.
+pub fn parse_widths(list: &str) -> Result<Vec<usize>, FieldError> {
+    list.split(',')
+        .map(|width| match width.trim().parse::<usize>() {
+            Ok(0) => Err(FieldError::InvalidField(0)),
+            Ok(width) => Ok(width),
+            Err(_) => Err(FieldError::FailedParse(width.to_owned())),
+        })
+        .collect()
+}
+
 /// Represent a range of columns to keep.
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone)]
 pub struct FieldRange {
@@ -134,17 +162,27 @@ impl FieldRange {
         }
     }
 
-    /// Parse a comma separated list of fields and merge any overlaps
-    pub fn from_list(list: &str) -> Result<Vec<FieldRange>, FieldError> {
-        let mut ranges: Vec<FieldRange> = vec![];
-        for (i, item) in list.split(',').enumerate() {
-            let mut rnge: FieldRange = FromStr::from_str(item)?;
-            rnge.pos = i;
-            ranges.push(rnge);
+    /// Whether the range has no upper bound, ex: `3-`.
+    #[inline]
+    pub fn is_open_ended(&self) -> bool {
+        self.high == MAX - 1
+    }
+
+    /// The number of fields in this range at or after index `from`.
+    ///
+    /// An open ended range counts as a single field, and only if it starts at or after `from`.
+    #[inline]
+    pub fn count_from(&self, from: usize) -> usize {
+        if self.is_open_ended() {
+            usize::from(self.low >= from)
+        } else {
+            (self.high + 1).saturating_sub(self.low.max(from))
         }
-        FieldRange::post_process_ranges(&mut ranges);
+    }
 
-        Ok(ranges)
+    /// Parse a comma separated list of fields and merge any overlaps
+    pub fn from_list(list: &str) -> Result<Vec<FieldRange>, FieldError> {
+        Ok(FieldSpec::parse(list)?.to_ranges())
     }
 
     /// Get the indices of the headers that match any of the provided regex's.
@@ -323,6 +361,106 @@ impl FieldRange {
     }
 }
 
+impl fmt::Display for FieldRange {
+    /// Write the range as it would be parsed, 1-based, ex: `3`, `3-5`, or `3-`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_open_ended() {
+            write!(f, "{}-", self.low + 1)
+        } else if self.low == self.high {
+            write!(f, "{}", self.low + 1)
+        } else {
+            write!(f, "{}-{}", self.low + 1, self.high + 1)
+        }
+    }
+}
+
+/// A comma separated field list like `1,3-5,7-`, as the user wrote it.
+///
+/// Unlike [`FieldRange::from_list`] the ranges aren't merged, so a spec displays back to an
+/// equivalent list in the same order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSpec(Vec<FieldRange>);
+
+impl FieldSpec {
+    /// Parse a comma separated list of fields.
+    pub fn parse(list: &str) -> Result<Self, FieldError> {
+        let mut ranges: Vec<FieldRange> = vec![];
+        for (i, item) in list.split(',').enumerate() {
+            let mut rnge: FieldRange = FromStr::from_str(item)?;
+            rnge.pos = i;
+            ranges.push(rnge);
+        }
+        Ok(Self(ranges))
+    }
+
+    /// The ranges in the order they were written.
+    pub fn ranges(&self) -> &[FieldRange] {
+        &self.0
+    }
+
+    /// The ranges sorted and with any overlaps merged, ready to select fields with.
+    pub fn to_ranges(&self) -> Vec<FieldRange> {
+        let mut ranges = self.0.clone();
+        FieldRange::post_process_ranges(&mut ranges);
+        ranges
+    }
+}
+
+impl FromStr for FieldSpec {
+    type Err = FieldError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for FieldSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", range)?;
+        }
+        Ok(())
+    }
+}
+
+/// Field ranges and specs are (de)serialized as the strings they are parsed from.
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use super::{FieldRange, FieldSpec};
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    impl Serialize for FieldRange {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for FieldRange {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            String::deserialize(deserializer)?
+                .parse()
+                .map_err(de::Error::custom)
+        }
+    }
+
+    impl Serialize for FieldSpec {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for FieldSpec {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            String::deserialize(deserializer)?
+                .parse()
+                .map_err(de::Error::custom)
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -382,6 +520,37 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_widths() {
+        assert_eq!(parse_widths("10,8, 20").unwrap(), vec![10, 8, 20]);
+        assert!(parse_widths("10,0").is_err());
+        assert!(parse_widths("10,,2").is_err());
+    }
+
+    #[test]
+    fn test_parse_header_fields_widths() {
+        let header = b"id  name    score";
+        let delim = RegexOrString::Widths(vec![4, 8, 5]);
+        let header_fields = vec![Regex::new(r"^score$").unwrap(), Regex::new(r"^id").unwrap()];
+        let fields =
+            FieldRange::from_header_list(&header_fields, header, &delim, true, false).unwrap();
+        assert_eq!(
+            vec![
+                FieldRange {
+                    low: 0,
+                    high: 0,
+                    pos: 1
+                },
+                FieldRange {
+                    low: 2,
+                    high: 2,
+                    pos: 0
+                }
+            ],
+            fields
+        );
+    }
+
     #[test]
     fn test_parse_header_fields_literal() {
         let header = b"is_cat-is-isdog-wascow-was_is_apple-12345-!$%*(_)";
@@ -615,4 +784,27 @@ mod test {
             "-f5-16,20-30 : -e10-25"
         );
     }
+
+    #[test]
+    fn test_field_spec_round_trip() {
+        for list in ["1", "1,2,3,4", "4-,1,5-8", "3,1,3", "2-7,10-"] {
+            let spec = FieldSpec::parse(list).unwrap();
+            assert_eq!(spec.to_string(), list);
+            assert_eq!(spec.to_ranges(), FieldRange::from_list(list).unwrap());
+        }
+        assert_eq!(FieldSpec::parse("-4,05").unwrap().to_string(), "1-4,5");
+        assert!(FieldSpec::parse("4-1").is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_field_spec_serde() {
+        let spec = FieldSpec::parse("1,3-5,7-").unwrap();
+        let json = serde_json::to_string(&spec).unwrap();
+        assert_eq!(json, r#""1,3-5,7-""#);
+        assert_eq!(serde_json::from_str::<FieldSpec>(&json).unwrap(), spec);
+        assert!(serde_json::from_str::<FieldSpec>(r#""0""#).is_err());
+        let range: FieldRange = serde_json::from_str(r#""2-""#).unwrap();
+        assert_eq!(serde_json::to_string(&range).unwrap(), r#""2-""#);
+    }
 }
@@ -0,0 +1,557 @@
+//! Hash joins of two inputs on key fields.
+//!
+//! A [`HashJoin`] loads every record of the left, or build, side into a hash table on its key, and
+//! then streams the right, or probe, side past it, handing each pair of records with equal keys to
+//! a [`JoinSink`]. Neither input needs to be sorted.
+//!
+//! When the left side doesn't fit in its [`MemoryBudget`], both sides are spilled to disk with a
+//! [`Partitioner`] and joined a partition at a time, a grace hash join. Matches then come out in
+//! partition order instead of the order of the right input.
+use std::{collections::HashMap, fmt, io, path::PathBuf};
+
+use crate::{
+    field_range::FieldRange,
+    key::KeyNormalize,
+    spill::{MemoryBudget, Partition, Partitioner},
+};
+
+/// The number of partitions to spill each side into, see [`HashJoin::partitions`].
+pub const DEFAULT_PARTITIONS: usize = 16;
+
+/// How many times a partition that still doesn't fit is partitioned again before it is joined in
+/// memory anyway, which happens when a single key has more records than the budget allows.
+const MAX_LEVEL: u32 = 3;
+
+/// A rough count of the bytes a record in the hash table costs beyond its key and record bytes.
+const RECORD_OVERHEAD: usize = 64;
+
+/// The fields of `row` selected by `ranges`, taking the ranges in the order they are in.
+///
+/// [`FieldRange::from_list`] sorts ranges by where they start, sort them by
+/// [`pos`](FieldRange::pos) first to select fields in the order they were listed. Fields past the
+/// end of `row` are skipped.
+pub fn select_fields<'r, F: AsRef<[u8]>>(
+    ranges: &'r [FieldRange],
+    row: &'r [F],
+) -> impl Iterator<Item = &'r [u8]> + 'r {
+    ranges.iter().flat_map(move |range| {
+        let end = range.high.saturating_add(1).min(row.len());
+        row.get(range.low..end)
+            .unwrap_or_default()
+            .iter()
+            .map(AsRef::as_ref)
+    })
+}
+
+/// The fields of a record that are joined on.
+///
+/// Unlike [`Key`](crate::key::Key), the fields are taken in the order they are listed, so that
+/// `-k 2,1` on one side lines up with `-k 1,2` on the other.
+#[derive(Debug, Clone)]
+pub struct JoinKey {
+    fields: Vec<FieldRange>,
+    normalize: KeyNormalize,
+}
+
+impl JoinKey {
+    /// A key of the fields covered by `fields`, in the order they were listed.
+    pub fn new(mut fields: Vec<FieldRange>) -> Self {
+        fields.sort_by_key(|range| range.pos);
+        Self {
+            fields,
+            normalize: KeyNormalize::default(),
+        }
+    }
+
+    /// Normalize each key field before it is written.
+    pub fn normalize(mut self, normalize: KeyNormalize) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Write the key of `row`, all the fields of a line, to `buffer`, replacing its contents.
+    ///
+    /// Returns false if `row` is missing any key field, an open ended range only needs its first.
+    #[inline]
+    pub fn extract_into<F: AsRef<[u8]>>(&self, row: &[F], buffer: &mut Vec<u8>) -> bool {
+        buffer.clear();
+        for field in select_fields(&self.fields, row) {
+            // Length prefix each field so that `a|bc` and `ab|c` are different keys
+            let start = buffer.len();
+            buffer.extend_from_slice(&0u64.to_le_bytes());
+            self.normalize.write_into(field, buffer);
+            let len = (buffer.len() - start - 8) as u64;
+            buffer[start..start + 8].copy_from_slice(&len.to_le_bytes());
+        }
+        self.fields.iter().all(|range| {
+            let last = if range.is_open_ended() {
+                range.low
+            } else {
+                range.high
+            };
+            last < row.len()
+        })
+    }
+}
+
+/// Where the results of a [`HashJoin`] go.
+pub trait JoinSink {
+    /// A left and a right record with equal keys.
+    fn matched(&mut self, left: &[u8], right: &[u8]) -> io::Result<()>;
+
+    /// A left record that no right record matched.
+    fn unmatched_left(&mut self, _left: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// A right record that no left record matched.
+    fn unmatched_right(&mut self, _right: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Counts of the records seen and matched by a [`HashJoin`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct JoinSummary {
+    /// The number of left records.
+    pub left: u64,
+    /// The number of left records matched by at least one right record.
+    pub left_matched: u64,
+    /// The number of right records.
+    pub right: u64,
+    /// The number of right records matched by at least one left record.
+    pub right_matched: u64,
+    /// The number of matched pairs.
+    pub pairs: u64,
+    /// The number of partitions the inputs were spilled to, zero if they were joined in memory.
+    pub partitions: usize,
+}
+
+impl fmt::Display for JoinSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "left: {} records, {} matched ({:.2}%), right: {} records, {} matched ({:.2}%), pairs: {}",
+            self.left,
+            self.left_matched,
+            percent(self.left_matched, self.left),
+            self.right,
+            self.right_matched,
+            percent(self.right_matched, self.right),
+            self.pairs
+        )?;
+        if self.partitions > 0 {
+            write!(f, ", spilled to {} partitions", self.partitions)?;
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+fn percent(count: u64, total: u64) -> f64 {
+    if total > 0 {
+        100.0 * count as f64 / total as f64
+    } else {
+        0.0
+    }
+}
+
+/// The in memory hash table of left records.
+#[derive(Default)]
+struct Table {
+    /// The left records in input order, and whether any right record matched them
+    records: Vec<(Vec<u8>, bool)>,
+    /// The indices into `records` for each key
+    index: HashMap<Vec<u8>, Vec<usize>>,
+}
+
+impl Table {
+    fn insert(&mut self, key: &[u8], record: &[u8]) {
+        let i = self.records.len();
+        self.records.push((record.to_vec(), false));
+        match self.index.get_mut(key) {
+            Some(indices) => indices.push(i),
+            None => {
+                self.index.insert(key.to_vec(), vec![i]);
+            }
+        }
+    }
+
+    /// Hand every left record matching `key` to `sink` with `record`.
+    fn probe<S: JoinSink>(
+        &mut self,
+        key: &[u8],
+        record: &[u8],
+        sink: &mut S,
+        summary: &mut JoinSummary,
+    ) -> io::Result<()> {
+        let indices = match self.index.get(key) {
+            Some(indices) => indices,
+            None => return sink.unmatched_right(record),
+        };
+        summary.right_matched += 1;
+        for &i in indices {
+            let (left, matched) = &mut self.records[i];
+            *matched = true;
+            sink.matched(left, record)?;
+            summary.pairs += 1;
+        }
+        Ok(())
+    }
+
+    /// Hand the left records that were never matched to `sink`.
+    fn finish<S: JoinSink>(self, sink: &mut S, summary: &mut JoinSummary) -> io::Result<()> {
+        for (record, matched) in self.records {
+            if matched {
+                summary.left_matched += 1;
+            } else {
+                sink.unmatched_left(&record)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the left records are being kept.
+enum State {
+    Memory(Table),
+    Spilled {
+        left: Partitioner,
+        right: Partitioner,
+    },
+}
+
+/// A hash join of a left input against a right one.
+///
+/// Every left record has to be [built](HashJoin::build) before the first right record is
+/// [probed](HashJoin::probe), and [`HashJoin::finish`] hands out whatever is left once the right
+/// input is done. Records are opaque bytes, usually the fields to write out for that side, and are
+/// matched on separately extracted keys, see [`JoinKey`].
+pub struct HashJoin {
+    limit: usize,
+    budget: MemoryBudget,
+    partitions: usize,
+    spill_dir: Option<PathBuf>,
+    state: State,
+    summary: JoinSummary,
+}
+
+impl HashJoin {
+    /// Create a join that holds at most about `limit` bytes of left records in memory.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            budget: MemoryBudget::new(limit),
+            partitions: DEFAULT_PARTITIONS,
+            spill_dir: None,
+            state: State::Memory(Table::default()),
+            summary: JoinSummary::default(),
+        }
+    }
+
+    /// The number of partitions to spill each side into when the left side doesn't fit, defaults to
+    /// [`DEFAULT_PARTITIONS`].
+    pub fn partitions(mut self, partitions: usize) -> Self {
+        self.partitions = partitions.max(1);
+        self
+    }
+
+    /// Where to create the temporary spill directory, defaults to the system temporary directory.
+    pub fn spill_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.spill_dir = dir;
+        self
+    }
+
+    /// Add a left record, spilling to disk if it doesn't fit in the budget.
+    pub fn build(&mut self, key: &[u8], record: &[u8]) -> io::Result<()> {
+        self.summary.left += 1;
+        if let State::Memory(table) = &mut self.state {
+            if self
+                .budget
+                .try_reserve(key.len() + record.len() + RECORD_OVERHEAD)
+            {
+                table.insert(key, record);
+                return Ok(());
+            }
+            self.spill()?;
+        }
+        if let State::Spilled { left, .. } = &mut self.state {
+            left.push(key, record)?;
+        }
+        Ok(())
+    }
+
+    /// Move the left records held in memory to disk, in the order they were built.
+    fn spill(&mut self) -> io::Result<()> {
+        let mut left = self.partitioner(0)?;
+        let right = self.partitioner(0)?;
+        if let State::Memory(table) = &self.state {
+            let mut keys: Vec<&[u8]> = vec![&[]; table.records.len()];
+            for (key, indices) in &table.index {
+                for &i in indices {
+                    keys[i] = key;
+                }
+            }
+            for (key, (record, _)) in keys.into_iter().zip(&table.records) {
+                left.push(key, record)?;
+            }
+        }
+        self.state = State::Spilled { left, right };
+        Ok(())
+    }
+
+    /// Match a right record against the left ones.
+    ///
+    /// Matches are handed to `sink` right away when the left side fit in memory, and by
+    /// [`HashJoin::finish`] otherwise.
+    pub fn probe<S: JoinSink>(
+        &mut self,
+        key: &[u8],
+        record: &[u8],
+        sink: &mut S,
+    ) -> io::Result<()> {
+        self.summary.right += 1;
+        match &mut self.state {
+            State::Memory(table) => table.probe(key, record, sink, &mut self.summary),
+            State::Spilled { right, .. } => right.push(key, record),
+        }
+    }
+
+    /// Hand any remaining matches and the unmatched left records to `sink`.
+    pub fn finish<S: JoinSink>(mut self, sink: &mut S) -> io::Result<JoinSummary> {
+        let state = std::mem::replace(&mut self.state, State::Memory(Table::default()));
+        match state {
+            State::Memory(table) => table.finish(sink, &mut self.summary)?,
+            State::Spilled { left, right } => {
+                let (left, right) = (left.finish()?, right.finish()?);
+                self.summary.partitions = left.len();
+                for i in 0..left.len() {
+                    self.join_partition(&left.open(i)?, &right.open(i)?, 0, sink)?;
+                }
+            }
+        }
+        Ok(self.summary)
+    }
+
+    fn partitioner(&self, level: u32) -> io::Result<Partitioner> {
+        match &self.spill_dir {
+            Some(dir) => Partitioner::new_in(dir, self.partitions, level),
+            None => Partitioner::new(self.partitions, level),
+        }
+    }
+
+    /// Join a pair of spilled partitions, partitioning them again if the left one doesn't fit.
+    fn join_partition<S: JoinSink>(
+        &mut self,
+        left: &Partition,
+        right: &Partition,
+        level: u32,
+        sink: &mut S,
+    ) -> io::Result<()> {
+        let mut budget = MemoryBudget::new(self.limit);
+        let fits = level >= MAX_LEVEL
+            || left.records().all(|(key, record)| {
+                budget.try_reserve(key.len() + record.len() + RECORD_OVERHEAD)
+            });
+        if !fits {
+            let (mut sub_left, mut sub_right) =
+                (self.partitioner(level + 1)?, self.partitioner(level + 1)?);
+            for (key, record) in left.records() {
+                sub_left.push(key, record)?;
+            }
+            for (key, record) in right.records() {
+                sub_right.push(key, record)?;
+            }
+            let (sub_left, sub_right) = (sub_left.finish()?, sub_right.finish()?);
+            for i in 0..sub_left.len() {
+                self.join_partition(&sub_left.open(i)?, &sub_right.open(i)?, level + 1, sink)?;
+            }
+            return Ok(());
+        }
+
+        let mut table = Table::default();
+        for (key, record) in left.records() {
+            table.insert(key, record);
+        }
+        for (key, record) in right.records() {
+            table.probe(key, record, sink, &mut self.summary)?;
+        }
+        table.finish(sink, &mut self.summary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Collects everything a join hands out.
+    #[derive(Default)]
+    struct Collect {
+        matched: Vec<(String, String)>,
+        unmatched_left: Vec<String>,
+        unmatched_right: Vec<String>,
+    }
+
+    impl JoinSink for Collect {
+        fn matched(&mut self, left: &[u8], right: &[u8]) -> io::Result<()> {
+            let to_string = |b: &[u8]| String::from_utf8(b.to_vec()).unwrap();
+            self.matched.push((to_string(left), to_string(right)));
+            Ok(())
+        }
+
+        fn unmatched_left(&mut self, left: &[u8]) -> io::Result<()> {
+            self.unmatched_left
+                .push(String::from_utf8(left.to_vec()).unwrap());
+            Ok(())
+        }
+
+        fn unmatched_right(&mut self, right: &[u8]) -> io::Result<()> {
+            self.unmatched_right
+                .push(String::from_utf8(right.to_vec()).unwrap());
+            Ok(())
+        }
+    }
+
+    /// Join `left` and `right` records of the form `key:value` on their keys.
+    fn join(join: HashJoin, left: &[&str], right: &[&str]) -> (Collect, JoinSummary) {
+        let mut join = join;
+        let mut sink = Collect::default();
+        for record in left {
+            join.build(
+                record.split(':').next().unwrap().as_bytes(),
+                record.as_bytes(),
+            )
+            .unwrap();
+        }
+        for record in right {
+            join.probe(
+                record.split(':').next().unwrap().as_bytes(),
+                record.as_bytes(),
+                &mut sink,
+            )
+            .unwrap();
+        }
+        let summary = join.finish(&mut sink).unwrap();
+        (sink, summary)
+    }
+
+    #[test]
+    fn test_select_fields() {
+        let row = ["a", "b", "c", "d"];
+        let mut fields = FieldRange::from_list("3,1").unwrap();
+        assert_eq!(
+            select_fields(&fields, &row).collect::<Vec<_>>(),
+            vec![&b"a"[..], b"c"]
+        );
+        fields.sort_by_key(|range| range.pos);
+        assert_eq!(
+            select_fields(&fields, &row).collect::<Vec<_>>(),
+            vec![&b"c"[..], b"a"]
+        );
+        let fields = FieldRange::from_list("2-").unwrap();
+        assert_eq!(
+            select_fields(&fields, &row).collect::<Vec<_>>(),
+            vec![&b"b"[..], b"c", b"d"]
+        );
+        // Missing fields are skipped
+        let fields = FieldRange::from_list("9,1").unwrap();
+        assert_eq!(select_fields(&fields, &row).count(), 1);
+    }
+
+    #[test]
+    fn test_join_key() {
+        let left = JoinKey::new(FieldRange::from_list("2,1").unwrap());
+        let right = JoinKey::new(FieldRange::from_list("1,3").unwrap());
+        let (mut l, mut r) = (vec![], vec![]);
+        assert!(left.extract_into(&["x", "y"], &mut l));
+        assert!(right.extract_into(&["y", "z", "x"], &mut r));
+        assert_eq!(l, r);
+        assert!(right.extract_into(&["yx", "z", ""], &mut r));
+        assert_ne!(l, r);
+        // Missing fields
+        assert!(!right.extract_into(&["y", "z"], &mut r));
+        let open = JoinKey::new(FieldRange::from_list("2-").unwrap());
+        assert!(open.extract_into(&["a", "b"], &mut r));
+        assert!(!open.extract_into(&["a"], &mut r));
+
+        let key = JoinKey::new(FieldRange::from_list("1").unwrap())
+            .normalize("lower,trim".parse().unwrap());
+        key.extract_into(&[" Foo "], &mut l);
+        key.extract_into(&["foo"], &mut r);
+        assert_eq!(l, r);
+    }
+
+    #[test]
+    fn test_hash_join() {
+        let left = ["a:1", "b:2", "a:3", "c:4"];
+        let right = ["a:x", "d:y", "b:z", "a:w"];
+        let (sink, summary) = join(HashJoin::new(usize::MAX), &left, &right);
+        let pairs: Vec<(&str, &str)> = sink
+            .matched
+            .iter()
+            .map(|(l, r)| (l.as_str(), r.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("a:1", "a:x"),
+                ("a:3", "a:x"),
+                ("b:2", "b:z"),
+                ("a:1", "a:w"),
+                ("a:3", "a:w")
+            ]
+        );
+        assert_eq!(sink.unmatched_left, vec!["c:4"]);
+        assert_eq!(sink.unmatched_right, vec!["d:y"]);
+        assert_eq!(
+            summary,
+            JoinSummary {
+                left: 4,
+                left_matched: 3,
+                right: 4,
+                right_matched: 3,
+                pairs: 5,
+                partitions: 0,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "left: 4 records, 3 matched (75.00%), right: 4 records, 3 matched (75.00%), pairs: 5"
+        );
+    }
+
+    #[test]
+    fn test_hash_join_spills() {
+        let left: Vec<String> = (0..500).map(|i| format!("k{}:l{}", i % 200, i)).collect();
+        let right: Vec<String> = (0..300).map(|i| format!("k{}:r{}", i % 250, i)).collect();
+        let left: Vec<&str> = left.iter().map(String::as_str).collect();
+        let right: Vec<&str> = right.iter().map(String::as_str).collect();
+
+        let (expected, expected_summary) = join(HashJoin::new(usize::MAX), &left, &right);
+        // Small enough that partitions have to be split again, and one key alone is over budget
+        for limit in [0, 4 * RECORD_OVERHEAD, 1 << 12] {
+            let (mut sink, summary) = join(HashJoin::new(limit).partitions(4), &left, &right);
+            assert_eq!(summary.partitions, 4);
+            assert_eq!(
+                JoinSummary {
+                    partitions: 0,
+                    ..summary
+                },
+                expected_summary
+            );
+            let mut expected_matched = expected.matched.clone();
+            expected_matched.sort();
+            sink.matched.sort();
+            assert_eq!(sink.matched, expected_matched);
+            sink.unmatched_left.sort();
+            sink.unmatched_right.sort();
+            let mut unmatched_left = expected.unmatched_left.clone();
+            let mut unmatched_right = expected.unmatched_right.clone();
+            unmatched_left.sort();
+            unmatched_right.sort();
+            assert_eq!(sink.unmatched_left, unmatched_left);
+            assert_eq!(sink.unmatched_right, unmatched_right);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Key extraction for the stages that compare records by some of their fields.
+//!
+//! A [`Key`] picks output positions out of a record and writes them into a single buffer that can
+//! be hashed or compared, optionally normalizing each field first so that minor formatting
+//! differences like `Foo ` and `foo` don't produce different keys.
+//!
+//! A [`SurrogateKind`] turns an extracted key into a fixed width surrogate key, for loading into
+//! systems that require a primary key.
+use std::{borrow::Cow, str::FromStr};
+
+use bstr::ByteSlice;
+use thiserror::Error;
+
+use crate::field_range::{FieldError, FieldRange};
+
+/// The RFC 4122 namespace for ISO OIDs, `6ba7b812-9dad-11d1-80b4-00c04fd430c8`.
+const NAMESPACE_OID: [u8; 16] = [
+    0x6b, 0xa7, 0xb8, 0x12, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8,
+];
+
+/// Errors for parsing a [`KeyNormalize`].
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum KeyNormalizeError {
+    #[error("Unknown key normalization `{0}`, expected `lower` or `trim`")]
+    Unknown(String),
+}
+
+/// Errors for parsing a [`GenKeySpec`].
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum GenKeyError {
+    #[error("Invalid key spec `{0}`, expected FIELDS:NAME:KIND like `1+2+3:id:uuid5`")]
+    InvalidSpec(String),
+    #[error("Unknown surrogate key kind `{0}`, expected `uuid5` or `xxh3`")]
+    UnknownKind(String),
+    #[error(transparent)]
+    InvalidField(#[from] FieldError),
+}
+
+/// How to normalize each key field before comparing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KeyNormalize {
+    /// Lowercase the field, Unicode aware for valid UTF-8
+    pub lower: bool,
+    /// Strip leading and trailing whitespace
+    pub trim: bool,
+}
+
+impl KeyNormalize {
+    /// Write the normalized `field` to `buffer`.
+    #[inline]
+    pub fn write_into(&self, field: &[u8], buffer: &mut Vec<u8>) {
+        let field = if self.trim { field.trim() } else { field };
+        if self.lower {
+            field.to_lowercase_into(buffer);
+        } else {
+            buffer.extend_from_slice(field);
+        }
+    }
+}
+
+impl FromStr for KeyNormalize {
+    type Err = KeyNormalizeError;
+
+    /// Parse a comma separated list like `lower,trim`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut normalize = KeyNormalize::default();
+        for name in s.split(',').map(str::trim) {
+            match name {
+                "lower" => normalize.lower = true,
+                "trim" => normalize.trim = true,
+                _ => return Err(KeyNormalizeError::Unknown(name.to_owned())),
+            }
+        }
+        Ok(normalize)
+    }
+}
+
+/// The fields of a record that make up its key.
+#[derive(Debug, Clone, Default)]
+pub struct Key {
+    fields: Option<Vec<FieldRange>>,
+    normalize: KeyNormalize,
+}
+
+impl Key {
+    /// A key of the output positions covered by `fields`, or the whole record if `None`.
+    pub fn new(fields: Option<Vec<FieldRange>>) -> Self {
+        Self {
+            fields,
+            normalize: KeyNormalize::default(),
+        }
+    }
+
+    /// Normalize each key field before it is written.
+    pub fn normalize(mut self, normalize: KeyNormalize) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    #[inline]
+    fn contains(&self, index: usize) -> bool {
+        match &self.fields {
+            Some(ranges) => ranges.iter().any(|r| r.low <= index && index <= r.high),
+            None => true,
+        }
+    }
+
+    /// Write the key of `record` to `buffer`, replacing its contents.
+    #[inline]
+    pub fn extract_into(&self, record: &[Cow<[u8]>], buffer: &mut Vec<u8>) {
+        buffer.clear();
+        for (i, field) in record.iter().enumerate() {
+            if self.contains(i) {
+                // Length prefix each field so that `a|bc` and `ab|c` are different keys
+                let start = buffer.len();
+                buffer.extend_from_slice(&0u64.to_le_bytes());
+                self.normalize.write_into(field, buffer);
+                let len = (buffer.len() - start - 8) as u64;
+                buffer[start..start + 8].copy_from_slice(&len.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// How a surrogate key is derived from the bytes of a [`Key`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SurrogateKind {
+    /// A UUIDv5 in the OID namespace, as a lowercase hyphenated string
+    Uuid5,
+    /// A 64 bit XXH3 hash as 16 lowercase hex digits
+    Xxh3,
+}
+
+impl SurrogateKind {
+    /// Write the surrogate key for `key` to `buffer`, replacing its contents.
+    #[inline]
+    pub fn write_into(&self, key: &[u8], buffer: &mut Vec<u8>) {
+        buffer.clear();
+        match self {
+            SurrogateKind::Uuid5 => {
+                for (i, byte) in uuid5(&NAMESPACE_OID, key).iter().enumerate() {
+                    if matches!(i, 4 | 6 | 8 | 10) {
+                        buffer.push(b'-');
+                    }
+                    buffer.extend_from_slice(format!("{:02x}", byte).as_bytes());
+                }
+            }
+            SurrogateKind::Xxh3 => {
+                let hash = xxhash_rust::xxh3::xxh3_64(key);
+                buffer.extend_from_slice(format!("{:016x}", hash).as_bytes());
+            }
+        }
+    }
+}
+
+impl FromStr for SurrogateKind {
+    type Err = GenKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uuid5" => Ok(SurrogateKind::Uuid5),
+            "xxh3" => Ok(SurrogateKind::Xxh3),
+            _ => Err(GenKeyError::UnknownKind(s.to_owned())),
+        }
+    }
+}
+
+/// The RFC 4122 version 5 UUID of `name` in `namespace`.
+fn uuid5(namespace: &[u8; 16], name: &[u8]) -> [u8; 16] {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(namespace);
+    sha1.update(name);
+    let mut uuid = [0; 16];
+    uuid.copy_from_slice(&sha1.digest().bytes()[..16]);
+    uuid[6] = (uuid[6] & 0x0f) | 0x50;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
+
+/// A surrogate key column to generate, parsed from `FIELDS:NAME:KIND` like `1+2+3:id:uuid5`.
+///
+/// `FIELDS` are `+` separated output positions or ranges, and `NAME` is the column's header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenKeySpec {
+    pub fields: Vec<FieldRange>,
+    pub name: String,
+    pub kind: SurrogateKind,
+}
+
+impl FromStr for GenKeySpec {
+    type Err = GenKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let [fields, name, kind] = parts[..] else {
+            return Err(GenKeyError::InvalidSpec(s.to_owned()));
+        };
+        if fields.is_empty() {
+            return Err(GenKeyError::InvalidSpec(s.to_owned()));
+        }
+        Ok(Self {
+            fields: FieldRange::from_list(&fields.replace('+', ","))?,
+            name: name.to_owned(),
+            kind: kind.parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(key: &Key, fields: &[&str]) -> Vec<u8> {
+        let record: Vec<Cow<[u8]>> = fields.iter().map(|f| Cow::Borrowed(f.as_bytes())).collect();
+        let mut buffer = b"stale".to_vec();
+        key.extract_into(&record, &mut buffer);
+        buffer
+    }
+
+    #[test]
+    fn test_parse_normalize() {
+        assert_eq!(
+            "lower, trim".parse::<KeyNormalize>().unwrap(),
+            KeyNormalize {
+                lower: true,
+                trim: true
+            }
+        );
+        assert_eq!(
+            "trim".parse::<KeyNormalize>().unwrap(),
+            KeyNormalize {
+                lower: false,
+                trim: true
+            }
+        );
+        assert_eq!(
+            "upper".parse::<KeyNormalize>(),
+            Err(KeyNormalizeError::Unknown(String::from("upper")))
+        );
+    }
+
+    #[test]
+    fn test_extract() {
+        let whole = Key::new(None);
+        assert_ne!(key(&whole, &["a", "bc"]), key(&whole, &["ab", "c"]));
+        assert_ne!(key(&whole, &["a", "b"]), key(&whole, &["A", "b"]));
+
+        let first = Key::new(Some(FieldRange::from_list("1").unwrap()));
+        assert_eq!(key(&first, &["a", "1"]), key(&first, &["a", "2"]));
+
+        let normalized = Key::new(None).normalize("lower,trim".parse().unwrap());
+        assert_eq!(
+            key(&normalized, &[" Straße ", "X"]),
+            key(&normalized, &["straße", " x"])
+        );
+        assert_ne!(key(&normalized, &["a b"]), key(&normalized, &["ab"]));
+    }
+
+    #[test]
+    fn test_parse_gen_key() {
+        let spec: GenKeySpec = "1+3-4:id:uuid5".parse().unwrap();
+        assert_eq!(spec.fields, FieldRange::from_list("1,3-4").unwrap());
+        assert_eq!(spec.name, "id");
+        assert_eq!(spec.kind, SurrogateKind::Uuid5);
+        assert_eq!(
+            "1:id:md5".parse::<GenKeySpec>(),
+            Err(GenKeyError::UnknownKind(String::from("md5")))
+        );
+        for bad in ["1:uuid5", ":id:uuid5", "1:id:uuid5:x"] {
+            assert_eq!(
+                bad.parse::<GenKeySpec>(),
+                Err(GenKeyError::InvalidSpec(String::from(bad)))
+            );
+        }
+        assert!(matches!(
+            "0:id:xxh3".parse::<GenKeySpec>(),
+            Err(GenKeyError::InvalidField(_))
+        ));
+    }
+
+    #[test]
+    fn test_surrogate() {
+        let mut buffer = vec![];
+        // The uuid5 of python.org in the DNS namespace, from the Python docs
+        let dns = [
+            0x6b, 0xa7, 0xb8, 0x10, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4,
+            0x30, 0xc8,
+        ];
+        assert_eq!(
+            uuid5(&dns, b"python.org"),
+            [
+                0x88, 0x63, 0x13, 0xe1, 0x3b, 0x8a, 0x53, 0x72, 0x9b, 0x90, 0x0c, 0x9a, 0xee, 0x19,
+                0x9e, 0x5d
+            ]
+        );
+        SurrogateKind::Uuid5.write_into(b"a", &mut buffer);
+        assert_eq!(buffer.len(), 36);
+        assert_eq!(&buffer[14..15], b"5");
+        assert_eq!(buffer.iter().filter(|b| **b == b'-').count(), 4);
+
+        SurrogateKind::Xxh3.write_into(b"a", &mut buffer);
+        assert_eq!(buffer.len(), 16);
+        let first = buffer.clone();
+        SurrogateKind::Xxh3.write_into(b"b", &mut buffer);
+        assert_ne!(buffer, first);
+        SurrogateKind::Xxh3.write_into(b"a", &mut buffer);
+        assert_eq!(buffer, first);
+    }
+}
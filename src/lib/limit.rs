@@ -0,0 +1,109 @@
+//! Capping the length of lines while reading.
+//!
+//! The line buffer grows until the longest line of an input fits, so a single runaway line, ex: in
+//! a file with the wrong line terminator, can take all of the memory there is. A [`LineLimitReader`]
+//! sits between the input and the line buffer and fails the read as soon as a line runs past the
+//! limit, before the line buffer has grown to hold it.
+use std::io::{self, Read};
+
+/// A [`Read`] wrapper that fails once a line is longer than a given number of bytes.
+#[derive(Debug)]
+pub struct LineLimitReader<R> {
+    inner: R,
+    newline: u8,
+    /// The most bytes a line can have, not counting the newline that ends it
+    max: usize,
+    /// The number of lines read so far
+    lines: u64,
+    /// The length of the current line so far
+    current: usize,
+}
+
+impl<R: Read> LineLimitReader<R> {
+    /// Fail on lines longer than `max` bytes, where `lines` have already been read.
+    pub fn new(inner: R, newline: u8, max: usize, lines: u64) -> Self {
+        Self {
+            inner,
+            newline,
+            max,
+            lines,
+            current: 0,
+        }
+    }
+
+    /// Check the length of the current line.
+    #[inline]
+    fn check(&self, len: usize) -> io::Result<()> {
+        if len > self.max {
+            return Err(line_too_long(self.lines + 1, self.max));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for LineLimitReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut start = 0;
+        for end in memchr::memchr_iter(self.newline, &buf[..n]) {
+            self.check(self.current + end - start)?;
+            self.lines += 1;
+            self.current = 0;
+            start = end + 1;
+        }
+        self.current += n - start;
+        self.check(self.current)?;
+        Ok(n)
+    }
+}
+
+/// The error for line number `line` running past `max` bytes.
+pub(crate) fn line_too_long(line: u64, max: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "line {} is longer than the maximum line length of {} bytes",
+            line, max
+        ),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Read everything through a [`LineLimitReader`], `step` bytes at a time.
+    fn read_limited(input: &[u8], max: usize, step: usize) -> io::Result<Vec<u8>> {
+        let mut reader = LineLimitReader::new(input, b'\n', max, 0);
+        let mut out = vec![];
+        let mut chunk = vec![0; step];
+        loop {
+            let n = reader.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(out);
+            }
+            out.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    #[test]
+    fn test_line_limit_reader() {
+        let input = b"abc\n\nabcd\nab";
+        for step in [1, 2, 3, 7, 1000] {
+            assert_eq!(read_limited(input, 4, step).unwrap(), input.to_vec());
+            let err = read_limited(input, 3, step).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(
+                err.to_string(),
+                "line 3 is longer than the maximum line length of 3 bytes"
+            );
+            // An unterminated last line counts too
+            let err = read_limited(b"a\nb\nabc", 2, step).unwrap_err();
+            assert!(err.to_string().starts_with("line 3 "), "step {}", step);
+        }
+        // Lines already read are counted in the line numbers
+        let mut reader = LineLimitReader::new(&b"abc"[..], b'\n', 2, 10);
+        let err = reader.read(&mut [0; 8]).unwrap_err();
+        assert!(err.to_string().starts_with("line 11 "));
+    }
+}
@@ -1,7 +1,7 @@
 use crate::field_range::FieldRange;
 use bstr::ByteSlice;
 use regex::bytes::Regex;
-use std::cmp::max;
+use std::cmp::{max, min};
 
 /// Methods for parsing a line into a reordered `shuffler`
 pub trait LineParser<'a> {
@@ -9,12 +9,44 @@ pub trait LineParser<'a> {
     fn parse_line<'b>(&self, line: &'b [u8], shuffler: &mut Vec<Vec<&'b [u8]>>)
     where
         'a: 'b;
+
+    /// The number of fields on the line, selected or not.
+    fn count_fields(&self, line: &[u8]) -> usize;
+
+    /// The field at 0-based `index` of the line, selected or not, if the line has it.
+    fn nth_field<'b>(&self, line: &'b [u8], index: usize) -> Option<&'b [u8]>;
+}
+
+/// Push `fill` for every field selected by `ranges` at or after index `from`, for a line that has
+/// no fields from `from` on.
+///
+/// Fields before `from` were already written by an earlier range, and open ended ranges only get
+/// a `fill` if they start past the end of the line.
+#[inline]
+fn fill_missing<'b>(
+    ranges: &[FieldRange],
+    mut from: usize,
+    fill: &'b [u8],
+    shuffler: &mut [Vec<&'b [u8]>],
+) {
+    for range in ranges {
+        if let Some(reshuffled_range) = shuffler.get_mut(range.pos) {
+            reshuffled_range.extend(std::iter::repeat(fill).take(range.count_from(from)));
+        }
+        if !range.is_open_ended() {
+            from = max(from, range.high + 1);
+        }
+    }
 }
 
 /// A line parser that works on fixed substrings
 pub struct SubStrLineParser<'a> {
     field_ranges: &'a [FieldRange],
     delimiter: &'a [u8],
+    /// The value to write for selected fields that are missing from a line
+    fill: Option<&'a [u8]>,
+    /// Whether empty fields are skipped, see [`SubStrLineParser::squeeze`]
+    squeeze: bool,
 }
 
 impl<'a> SubStrLineParser<'a> {
@@ -22,32 +54,53 @@ impl<'a> SubStrLineParser<'a> {
         Self {
             field_ranges,
             delimiter,
+            fill: None,
+            squeeze: false,
         }
     }
-}
-impl<'a> LineParser<'a> for SubStrLineParser<'a> {
+
+    /// Write `fill` in place of any selected fields a line is too short to have.
+    pub fn fill(mut self, fill: Option<&'a [u8]>) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Treat runs of the delimiter as one, skipping the empty fields between them along with any
+    /// at the start or end of the line.
+    pub fn squeeze(mut self, squeeze: bool) -> Self {
+        self.squeeze = squeeze;
+        self
+    }
+
+    /// Write the selected fields of `parts` to the shuffler.
     #[inline]
-    fn parse_line<'b>(&self, line: &'b [u8], shuffler: &mut Vec<Vec<&'b [u8]>>)
+    fn shuffle_parts<'b, I>(&self, parts: I, shuffler: &mut [Vec<&'b [u8]>])
     where
         'a: 'b,
+        I: Iterator<Item = &'b [u8]>,
     {
-        let mut parts = line.split_str(self.delimiter).peekable();
+        let mut parts = parts.peekable();
         let mut iterator_index = 0;
 
         // Iterate over our ranges and write any fields that are contained by them.
-        for &FieldRange { low, high, pos } in self.field_ranges {
+        for (i, &FieldRange { low, high, pos }) in self.field_ranges.iter().enumerate() {
             // Advance up to low end of range
             if low > iterator_index {
                 match parts.nth(low - iterator_index - 1) {
                     Some(_part) => {
                         iterator_index = low;
                     }
-                    None => break,
+                    None => {
+                        if let Some(fill) = self.fill {
+                            fill_missing(&self.field_ranges[i..], low, fill, shuffler);
+                        }
+                        break;
+                    }
                 }
             }
 
             // Advance through the range
-            for _ in max(low, iterator_index)..=high {
+            for index in max(low, iterator_index)..=high {
                 match parts.next() {
                     Some(part) => {
                         // Guaranteed to be in range since shuffler is created based on field pos anyways
@@ -55,7 +108,12 @@ impl<'a> LineParser<'a> for SubStrLineParser<'a> {
                             reshuffled_range.push(part)
                         }
                     }
-                    None => break,
+                    None => {
+                        if let Some(fill) = self.fill {
+                            fill_missing(&self.field_ranges[i..], index, fill, shuffler);
+                        }
+                        return;
+                    }
                 }
                 iterator_index += 1;
             }
@@ -63,10 +121,128 @@ impl<'a> LineParser<'a> for SubStrLineParser<'a> {
     }
 }
 
+impl<'a> LineParser<'a> for SubStrLineParser<'a> {
+    #[inline]
+    fn parse_line<'b>(&self, line: &'b [u8], shuffler: &mut Vec<Vec<&'b [u8]>>)
+    where
+        'a: 'b,
+    {
+        let parts = line.split_str(self.delimiter);
+        if self.squeeze {
+            self.shuffle_parts(parts.filter(|part| !part.is_empty()), shuffler);
+        } else {
+            self.shuffle_parts(parts, shuffler);
+        }
+    }
+
+    #[inline]
+    fn count_fields(&self, line: &[u8]) -> usize {
+        let parts = line.split_str(self.delimiter);
+        if self.squeeze {
+            parts.filter(|part| !part.is_empty()).count()
+        } else {
+            parts.count()
+        }
+    }
+
+    #[inline]
+    fn nth_field<'b>(&self, line: &'b [u8], index: usize) -> Option<&'b [u8]> {
+        let mut parts = line.split_str(self.delimiter);
+        if self.squeeze {
+            parts.filter(|part| !part.is_empty()).nth(index)
+        } else {
+            parts.nth(index)
+        }
+    }
+}
+
+/// `line` with runs of `delimiter` collapsed into one and any at the start or end removed, so it
+/// splits into the same fields a [`SubStrLineParser::squeeze`] parser finds.
+pub fn squeeze_delimiters(line: &[u8], delimiter: &[u8]) -> Vec<u8> {
+    let mut squeezed = Vec::with_capacity(line.len());
+    for part in line.split_str(delimiter).filter(|part| !part.is_empty()) {
+        if !squeezed.is_empty() {
+            squeezed.extend_from_slice(delimiter);
+        }
+        squeezed.extend_from_slice(part);
+    }
+    squeezed
+}
+
+/// `line` without any leading or trailing spaces and tabs, the blanks awk's default field splitting
+/// ignores.
+#[inline]
+pub fn trim_blanks(line: &[u8]) -> &[u8] {
+    let is_blank = |b: &u8| *b == b' ' || *b == b'\t';
+    let start = line.iter().position(|b| !is_blank(b)).unwrap_or(line.len());
+    let end = line
+        .iter()
+        .rposition(|b| !is_blank(b))
+        .map_or(start, |i| i + 1);
+    &line[start..end]
+}
+
+/// Push the `parts` of a line selected by `field_ranges` to the shuffler, writing `fill` in place of
+/// any selected fields the line is too short to have.
+#[inline]
+fn select_parts<'b, I>(
+    field_ranges: &[FieldRange],
+    fill: Option<&'b [u8]>,
+    mut parts: I,
+    shuffler: &mut [Vec<&'b [u8]>],
+) where
+    I: Iterator<Item = &'b [u8]>,
+{
+    let mut iterator_index = 0;
+
+    // Iterate over our ranges and write any fields that are contained by them.
+    for (i, &FieldRange { low, high, pos }) in field_ranges.iter().enumerate() {
+        // Advance up to low end of range
+        if low > iterator_index {
+            match parts.nth(low - iterator_index - 1) {
+                Some(_part) => {
+                    iterator_index = low;
+                }
+                None => {
+                    if let Some(fill) = fill {
+                        fill_missing(&field_ranges[i..], low, fill, shuffler);
+                    }
+                    break;
+                }
+            }
+        }
+
+        // Advance through the range
+        for index in max(low, iterator_index)..=high {
+            match parts.next() {
+                Some(part) => {
+                    // Guaranteed to be in range since shuffler is created based on field pos anyways
+                    if let Some(reshuffled_range) = shuffler.get_mut(pos) {
+                        reshuffled_range.push(part)
+                    } else {
+                        unreachable!()
+                    }
+                }
+                None => {
+                    if let Some(fill) = fill {
+                        fill_missing(&field_ranges[i..], index, fill, shuffler);
+                    }
+                    return;
+                }
+            }
+            iterator_index += 1;
+        }
+    }
+}
+
 /// A line parser that works on fixed substrings
 pub struct RegexLineParser<'a> {
     field_ranges: &'a [FieldRange],
     delimiter: &'a Regex,
+    /// The value to write for selected fields that are missing from a line
+    fill: Option<&'a [u8]>,
+    /// Whether leading and trailing blanks are ignored, see [`trim_blanks`]
+    trim_blanks: bool,
 }
 
 impl<'a> RegexLineParser<'a> {
@@ -74,8 +250,23 @@ impl<'a> RegexLineParser<'a> {
         Self {
             field_ranges,
             delimiter,
+            fill: None,
+            trim_blanks: false,
         }
     }
+
+    /// Write `fill` in place of any selected fields a line is too short to have.
+    pub fn fill(mut self, fill: Option<&'a [u8]>) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Ignore leading and trailing spaces and tabs, so that with a `[ \t]+` delimiter fields are
+    /// numbered the way awk numbers them. A blank line then has no fields at all.
+    pub fn trim_blanks(mut self, trim_blanks: bool) -> Self {
+        self.trim_blanks = trim_blanks;
+        self
+    }
 }
 impl<'a> LineParser<'a> for RegexLineParser<'a> {
     #[inline]
@@ -83,36 +274,520 @@ impl<'a> LineParser<'a> for RegexLineParser<'a> {
     where
         'a: 'b,
     {
-        let mut parts = self.delimiter.split(line).peekable();
-        let mut iterator_index = 0;
+        let line = if self.trim_blanks {
+            let line = trim_blanks(line);
+            if line.is_empty() {
+                if let Some(fill) = self.fill {
+                    fill_missing(self.field_ranges, 0, fill, shuffler);
+                }
+                return;
+            }
+            line
+        } else {
+            line
+        };
+        select_parts(
+            self.field_ranges,
+            self.fill,
+            self.delimiter.split(line),
+            shuffler,
+        );
+    }
 
-        // Iterate over our ranges and write any fields that are contained by them.
-        for &FieldRange { low, high, pos } in self.field_ranges {
-            // Advance up to low end of range
-            if low > iterator_index {
-                match parts.nth(low - iterator_index - 1) {
-                    Some(_part) => {
-                        iterator_index = low;
-                    }
-                    None => break,
+    #[inline]
+    fn count_fields(&self, line: &[u8]) -> usize {
+        match self.trim_blanks {
+            true if trim_blanks(line).is_empty() => 0,
+            true => self.delimiter.split(trim_blanks(line)).count(),
+            false => self.delimiter.split(line).count(),
+        }
+    }
+
+    #[inline]
+    fn nth_field<'b>(&self, line: &'b [u8], index: usize) -> Option<&'b [u8]> {
+        match self.trim_blanks {
+            true if trim_blanks(line).is_empty() => None,
+            true => self.delimiter.split(trim_blanks(line)).nth(index),
+            false => self.delimiter.split(line).nth(index),
+        }
+    }
+}
+
+/// Whether `regex` is the default `\s+` delimiter, which a [`WhitespaceLineParser`] splits on
+/// without going through the regex engine.
+pub fn is_whitespace_regex(regex: &Regex) -> bool {
+    regex.as_str() == r"\s+"
+}
+
+/// Bytes that can start a whitespace character: ASCII whitespace and the lead bytes of the UTF-8
+/// encodings of the rest of Unicode's `White_Space`.
+static WHITESPACE_START: [bool; 256] = {
+    let mut table = [false; 256];
+    let starts = [
+        b'\t', b'\n', 0x0B, 0x0C, b'\r', b' ', 0xC2, 0xE1, 0xE2, 0xE3,
+    ];
+    let mut i = 0;
+    while i < starts.len() {
+        table[starts[i] as usize] = true;
+        i += 1;
+    }
+    table
+};
+
+/// The length of the whitespace character at the start of `bytes`, or 0 if it doesn't start with one.
+///
+/// This matches what `\s` does in a Unicode regex, including only matching valid UTF-8.
+#[inline]
+fn whitespace_len(bytes: &[u8]) -> usize {
+    match bytes {
+        [b'\t' | b'\n' | 0x0B | 0x0C | b'\r' | b' ', ..] => 1,
+        // U+0085 and U+00A0
+        [0xC2, 0x85 | 0xA0, ..] => 2,
+        // U+1680
+        [0xE1, 0x9A, 0x80, ..] => 3,
+        // U+2000 to U+200A, U+2028, U+2029, and U+202F
+        [0xE2, 0x80, 0x80..=0x8A | 0xA8 | 0xA9 | 0xAF, ..] => 3,
+        // U+205F
+        [0xE2, 0x81, 0x9F, ..] => 3,
+        // U+3000
+        [0xE3, 0x80, 0x80, ..] => 3,
+        _ => 0,
+    }
+}
+
+/// An iterator over the fields of a line split on runs of whitespace, the same as splitting on a
+/// `\s+` regex. Leading or trailing whitespace makes an empty first or last field.
+#[derive(Debug, Clone)]
+pub struct WhitespaceSplit<'b> {
+    line: &'b [u8],
+    /// Where the next field starts, `None` once the last field has been returned
+    start: Option<usize>,
+}
+
+impl<'b> WhitespaceSplit<'b> {
+    pub fn new(line: &'b [u8]) -> Self {
+        Self {
+            line,
+            start: Some(0),
+        }
+    }
+}
+
+impl<'b> Iterator for WhitespaceSplit<'b> {
+    type Item = &'b [u8];
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.start?;
+        let line = self.line;
+        let mut i = start;
+        while i < line.len() {
+            // Most bytes can't start whitespace, so skip the full check for them
+            if !WHITESPACE_START[line[i] as usize] {
+                i += 1;
+                continue;
+            }
+            let len = whitespace_len(&line[i..]);
+            if len == 0 {
+                i += 1;
+                continue;
+            }
+            let mut end = i + len;
+            loop {
+                let len = whitespace_len(&line[end..]);
+                if len == 0 {
+                    break;
                 }
+                end += len;
             }
+            self.start = Some(end);
+            return Some(&line[start..i]);
+        }
+        self.start = None;
+        Some(&line[start..])
+    }
+}
 
-            // Advance through the range
-            for _ in max(low, iterator_index)..=high {
-                match parts.next() {
-                    Some(part) => {
-                        // Guaranteed to be in range since shuffler is created based on field pos anyways
-                        if let Some(reshuffled_range) = shuffler.get_mut(pos) {
-                            reshuffled_range.push(part)
-                        } else {
-                            unreachable!()
-                        }
+/// A line parser that splits on runs of whitespace, giving the same fields as a [`RegexLineParser`]
+/// with the default `\s+` delimiter without the overhead of the regex engine.
+pub struct WhitespaceLineParser<'a> {
+    field_ranges: &'a [FieldRange],
+    /// The value to write for selected fields that are missing from a line
+    fill: Option<&'a [u8]>,
+    /// Whether leading and trailing blanks are ignored, see [`trim_blanks`]
+    trim_blanks: bool,
+}
+
+impl<'a> WhitespaceLineParser<'a> {
+    pub fn new(field_ranges: &'a [FieldRange]) -> Self {
+        Self {
+            field_ranges,
+            fill: None,
+            trim_blanks: false,
+        }
+    }
+
+    /// Write `fill` in place of any selected fields a line is too short to have.
+    pub fn fill(mut self, fill: Option<&'a [u8]>) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Ignore leading and trailing spaces and tabs, so that fields are numbered the way awk numbers
+    /// them. A blank line then has no fields at all.
+    pub fn trim_blanks(mut self, trim_blanks: bool) -> Self {
+        self.trim_blanks = trim_blanks;
+        self
+    }
+
+    /// The fields of `line`, or `None` for a blank line when trimming blanks.
+    #[inline]
+    fn split<'b>(&self, line: &'b [u8]) -> Option<WhitespaceSplit<'b>> {
+        if self.trim_blanks {
+            let line = trim_blanks(line);
+            (!line.is_empty()).then(|| WhitespaceSplit::new(line))
+        } else {
+            Some(WhitespaceSplit::new(line))
+        }
+    }
+}
+
+impl<'a> LineParser<'a> for WhitespaceLineParser<'a> {
+    #[inline]
+    fn parse_line<'b>(&self, line: &'b [u8], shuffler: &mut Vec<Vec<&'b [u8]>>)
+    where
+        'a: 'b,
+    {
+        match self.split(line) {
+            Some(parts) => select_parts(self.field_ranges, self.fill, parts, shuffler),
+            None => {
+                if let Some(fill) = self.fill {
+                    fill_missing(self.field_ranges, 0, fill, shuffler);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn count_fields(&self, line: &[u8]) -> usize {
+        self.split(line).map_or(0, Iterator::count)
+    }
+
+    #[inline]
+    fn nth_field<'b>(&self, line: &'b [u8], index: usize) -> Option<&'b [u8]> {
+        self.split(line)?.nth(index)
+    }
+}
+
+/// A line parser that works on fixed column widths instead of a delimiter.
+///
+/// Since every column is at a known byte offset there is no need to scan the line, each selected
+/// field is sliced out directly.
+pub struct FixedWidthLineParser<'a> {
+    field_ranges: &'a [FieldRange],
+    /// The `[start, end)` byte offsets of each column
+    offsets: Vec<(usize, usize)>,
+    /// The value to write for selected columns that are missing from a line
+    fill: Option<&'a [u8]>,
+}
+
+impl<'a> FixedWidthLineParser<'a> {
+    pub fn new(field_ranges: &'a [FieldRange], widths: &[usize]) -> Self {
+        let mut start = 0;
+        let offsets = widths
+            .iter()
+            .map(|width| {
+                let span = (start, start + width);
+                start += width;
+                span
+            })
+            .collect();
+        Self {
+            field_ranges,
+            offsets,
+            fill: None,
+        }
+    }
+
+    /// Write `fill` in place of any selected columns a line is too short to have.
+    ///
+    /// Columns past the end of the widths are considered missing from every line.
+    pub fn fill(mut self, fill: Option<&'a [u8]>) -> Self {
+        self.fill = fill;
+        self
+    }
+}
+
+impl<'a> LineParser<'a> for FixedWidthLineParser<'a> {
+    #[inline]
+    fn parse_line<'b>(&self, line: &'b [u8], shuffler: &mut Vec<Vec<&'b [u8]>>)
+    where
+        'a: 'b,
+    {
+        // Mirror the other parsers in only ever writing a column once
+        let mut iterator_index = 0;
+
+        for (i, &FieldRange { low, high, pos }) in self.field_ranges.iter().enumerate() {
+            let high = min(high, self.offsets.len().saturating_sub(1));
+            let first = max(low, iterator_index);
+            for (column, &(start, end)) in
+                (first..).zip(self.offsets.get(first..=high).unwrap_or(&[]))
+            {
+                if start >= line.len() {
+                    if let Some(fill) = self.fill {
+                        fill_missing(&self.field_ranges[i..], column, fill, shuffler);
                     }
-                    None => break,
+                    return;
+                }
+                if let Some(reshuffled_range) = shuffler.get_mut(pos) {
+                    reshuffled_range.push(&line[start..min(end, line.len())]);
                 }
-                iterator_index += 1;
             }
+            iterator_index = max(iterator_index, high + 1);
+        }
+        if let Some(fill) = self.fill {
+            fill_missing(self.field_ranges, self.offsets.len(), fill, shuffler);
+        }
+    }
+
+    /// The number of columns the line reaches into.
+    #[inline]
+    fn count_fields(&self, line: &[u8]) -> usize {
+        self.offsets
+            .iter()
+            .take_while(|(start, _)| *start < line.len())
+            .count()
+    }
+
+    #[inline]
+    fn nth_field<'b>(&self, line: &'b [u8], index: usize) -> Option<&'b [u8]> {
+        let &(start, end) = self.offsets.get(index)?;
+        (start < line.len()).then(|| &line[start..min(end, line.len())])
+    }
+}
+
+/// The line parser that suits a [`RegexOrString`](crate::field_range::RegexOrString) delimiter,
+/// see [`CoreConfig::line_parser`](crate::core::CoreConfig::line_parser).
+#[non_exhaustive]
+pub enum DelimLineParser<'a> {
+    /// The default `\s+` delimiter, see [`is_whitespace_regex`].
+    Whitespace(WhitespaceLineParser<'a>),
+    /// Any other regex delimiter.
+    Regex(RegexLineParser<'a>),
+    /// A literal delimiter.
+    SubStr(SubStrLineParser<'a>),
+    /// Fixed width columns.
+    FixedWidth(FixedWidthLineParser<'a>),
+}
+
+impl<'a> LineParser<'a> for DelimLineParser<'a> {
+    #[inline]
+    fn parse_line<'b>(&self, line: &'b [u8], shuffler: &mut Vec<Vec<&'b [u8]>>)
+    where
+        'a: 'b,
+    {
+        match self {
+            DelimLineParser::Whitespace(parser) => parser.parse_line(line, shuffler),
+            DelimLineParser::Regex(parser) => parser.parse_line(line, shuffler),
+            DelimLineParser::SubStr(parser) => parser.parse_line(line, shuffler),
+            DelimLineParser::FixedWidth(parser) => parser.parse_line(line, shuffler),
+        }
+    }
+
+    #[inline]
+    fn count_fields(&self, line: &[u8]) -> usize {
+        match self {
+            DelimLineParser::Whitespace(parser) => parser.count_fields(line),
+            DelimLineParser::Regex(parser) => parser.count_fields(line),
+            DelimLineParser::SubStr(parser) => parser.count_fields(line),
+            DelimLineParser::FixedWidth(parser) => parser.count_fields(line),
+        }
+    }
+
+    #[inline]
+    fn nth_field<'b>(&self, line: &'b [u8], index: usize) -> Option<&'b [u8]> {
+        match self {
+            DelimLineParser::Whitespace(parser) => parser.nth_field(line, index),
+            DelimLineParser::Regex(parser) => parser.nth_field(line, index),
+            DelimLineParser::SubStr(parser) => parser.nth_field(line, index),
+            DelimLineParser::FixedWidth(parser) => parser.nth_field(line, index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse<'b, L: LineParser<'b>>(parser: &L, line: &'b [u8], n: usize) -> Vec<&'b [u8]> {
+        let mut shuffler = vec![vec![]; n];
+        parser.parse_line(line, &mut shuffler);
+        shuffler.into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn test_fixed_width_parser() {
+        let fields = FieldRange::from_list("3,1").unwrap();
+        let parser = FixedWidthLineParser::new(&fields, &[4, 6, 3]);
+        assert_eq!(
+            parse(&parser, b"abcdEFGHIJxyz", 2),
+            vec![&b"xyz"[..], &b"abcd"[..]]
+        );
+        // Short lines only yield the columns that are present
+        assert_eq!(parse(&parser, b"abcdEF", 2), vec![&b"abcd"[..]]);
+        assert_eq!(
+            parse(&parser, b"abcdEFGHIJx", 2),
+            vec![&b"x"[..], &b"abcd"[..]]
+        );
+    }
+
+    #[test]
+    fn test_whitespace_split_matches_regex() {
+        let regex = Regex::new(r"\s+").unwrap();
+        assert!(is_whitespace_regex(&regex));
+        assert!(!is_whitespace_regex(&Regex::new(r"\s").unwrap()));
+        let lines: &[&[u8]] = &[
+            b"",
+            b" ",
+            b"a",
+            b"a b",
+            b"  a\t\tb \x0b\x0cc\r\n",
+            b"a\xc2\xa0b\xc2\x85c\xc2\xa1d",
+            "a\u{1680}b\u{2000}\u{200a}c\u{2028}\u{2029}d\u{202f}e\u{205f}f\u{3000}g".as_bytes(),
+            // Near misses and invalid UTF-8 aren't whitespace
+            "a\u{200b}b\u{180e}c\u{2030}d".as_bytes(),
+            b"a\xe2\x80 b\xe3\x80\xe2\xc2 c\xff",
+            b"\xe2\x80\x80",
+        ];
+        for line in lines {
+            let expected: Vec<&[u8]> = regex.split(line).collect();
+            let found: Vec<&[u8]> = WhitespaceSplit::new(line).collect();
+            assert_eq!(found, expected, "{:?}", line.as_bstr());
+        }
+
+        let fields = FieldRange::from_list("3,1").unwrap();
+        let fast = WhitespaceLineParser::new(&fields).fill(Some(b"NA"));
+        let slow = RegexLineParser::new(&fields, &regex).fill(Some(b"NA"));
+        for line in lines.iter().chain([&&b" a b c "[..]]) {
+            assert_eq!(parse(&fast, line, 2), parse(&slow, line, 2));
+            assert_eq!(fast.count_fields(line), slow.count_fields(line));
+            assert_eq!(fast.nth_field(line, 1), slow.nth_field(line, 1));
+            let fast = WhitespaceLineParser::new(&fields).trim_blanks(true);
+            let slow = RegexLineParser::new(&fields, &regex).trim_blanks(true);
+            assert_eq!(parse(&fast, line, 2), parse(&slow, line, 2));
+            assert_eq!(fast.count_fields(line), slow.count_fields(line));
+        }
+    }
+
+    #[test]
+    fn test_count_fields() {
+        let fields = FieldRange::from_list("1").unwrap();
+        let substr = SubStrLineParser::new(&fields, b",");
+        assert_eq!(substr.count_fields(b"a,b,,c"), 4);
+        assert_eq!(substr.count_fields(b""), 1);
+        let regex = Regex::new(r"\s+").unwrap();
+        assert_eq!(
+            RegexLineParser::new(&fields, &regex).count_fields(b"a  b c"),
+            3
+        );
+        let fixed = FixedWidthLineParser::new(&fields, &[2, 2, 2]);
+        assert_eq!(fixed.count_fields(b"abc"), 2);
+        assert_eq!(fixed.count_fields(b"abcdefgh"), 3);
+        assert_eq!(fixed.count_fields(b""), 0);
+    }
+
+    #[test]
+    fn test_nth_field() {
+        let fields = FieldRange::from_list("1").unwrap();
+        let substr = SubStrLineParser::new(&fields, b",");
+        assert_eq!(substr.nth_field(b"a,b,,c", 3), Some(&b"c"[..]));
+        assert_eq!(substr.nth_field(b"a,b,,c", 2), Some(&b""[..]));
+        assert_eq!(substr.nth_field(b"a,b,,c", 4), None);
+        let squeezed = SubStrLineParser::new(&fields, b",").squeeze(true);
+        assert_eq!(squeezed.nth_field(b",a,,c", 1), Some(&b"c"[..]));
+        let regex = Regex::new(r"\s+").unwrap();
+        let awk = RegexLineParser::new(&fields, &regex).trim_blanks(true);
+        assert_eq!(awk.nth_field(b"  a  b c", 0), Some(&b"a"[..]));
+        assert_eq!(awk.nth_field(b"  ", 0), None);
+        let fixed = FixedWidthLineParser::new(&fields, &[2, 2, 2]);
+        assert_eq!(fixed.nth_field(b"abc", 1), Some(&b"c"[..]));
+        assert_eq!(fixed.nth_field(b"abc", 2), None);
+        assert_eq!(fixed.nth_field(b"abcdefgh", 3), None);
+    }
+
+    #[test]
+    fn test_squeeze() {
+        let fields = FieldRange::from_list("1,3").unwrap();
+        let parser = SubStrLineParser::new(&fields, b"::").squeeze(true);
+        assert_eq!(
+            parse(&parser, b"::a::::b::c::", 2),
+            vec![&b"a"[..], &b"c"[..]]
+        );
+        assert_eq!(parser.count_fields(b"::a::::b::c::"), 3);
+        assert_eq!(parser.count_fields(b"::::"), 0);
+        let parser = parser.fill(Some(b"-"));
+        assert_eq!(parse(&parser, b"", 2), vec![&b"-"[..], &b"-"[..]]);
+        assert_eq!(squeeze_delimiters(b"::a::::b::c::", b"::"), b"a::b::c");
+    }
+
+    #[test]
+    fn test_fill() {
+        let fields = FieldRange::from_list("4,1-2,6-").unwrap();
+        let n = fields.iter().map(|f| f.pos).max().unwrap() + 1;
+        let substr = SubStrLineParser::new(&fields, b" ").fill(Some(b"-"));
+        assert_eq!(parse(&substr, b"a", n), vec![&b"-"[..], b"a", b"-", b"-"]);
+        assert_eq!(
+            parse(&substr, b"a b c d e f g", n),
+            vec![&b"d"[..], b"a", b"b", b"f", b"g"]
+        );
+        let regex = Regex::new(" ").unwrap();
+        let parser = RegexLineParser::new(&fields, &regex).fill(Some(b"-"));
+        assert_eq!(
+            parse(&parser, b"a b c", n),
+            vec![&b"-"[..], b"a", b"b", b"-"]
+        );
+        // Widths only cover 5 columns, so `6-` is always missing
+        let fixed = FixedWidthLineParser::new(&fields, &[1, 1, 1, 1, 1]).fill(Some(b"-"));
+        assert_eq!(parse(&fixed, b"abc", n), vec![&b"-"[..], b"a", b"b", b"-"]);
+        assert_eq!(
+            parse(&fixed, b"abcde", n),
+            vec![&b"d"[..], b"a", b"b", b"-"]
+        );
+    }
+
+    #[test]
+    fn test_trim_blanks() {
+        assert_eq!(trim_blanks(b" \t a b \t"), b"a b");
+        assert_eq!(trim_blanks(b"a"), b"a");
+        assert_eq!(trim_blanks(b" \t "), b"");
+
+        let fields = FieldRange::from_list("1,3").unwrap();
+        let n = fields.iter().map(|f| f.pos).max().unwrap() + 1;
+        let regex = Regex::new(r"[ \t]+").unwrap();
+        let parser = RegexLineParser::new(&fields, &regex).trim_blanks(true);
+        assert_eq!(parse(&parser, b"  a \tb  c  ", n), vec![&b"a"[..], b"c"]);
+        assert_eq!(parser.count_fields(b"  a \tb  c  "), 3);
+        assert_eq!(parser.count_fields(b"   "), 0);
+        let parser = parser.fill(Some(b"-"));
+        assert_eq!(parse(&parser, b" \t ", n), vec![&b"-"[..], b"-"]);
+        // Without trimming the leading blanks make an empty first field
+        let untrimmed = RegexLineParser::new(&fields, &regex);
+        assert_eq!(parse(&untrimmed, b"  a b c", n), vec![&b""[..], b"b"]);
+    }
+
+    #[test]
+    fn test_fixed_width_parser_matches_substr() {
+        for list in ["2-,1,2-3", "3-,1,4-5", "1,2,4,3", "-2,4-"] {
+            let fields = FieldRange::from_list(list).unwrap();
+            let n = fields.iter().map(|f| f.pos).max().unwrap() + 1;
+            let fixed = FixedWidthLineParser::new(&fields, &[1, 1, 1, 1, 1]);
+            let substr = SubStrLineParser::new(&fields, b" ");
+            assert_eq!(
+                parse(&fixed, b"abcde", n),
+                parse(&substr, b"a b c d e", n),
+                "{}",
+                list
+            );
         }
     }
 }
@@ -0,0 +1,292 @@
+//! Performance counters collected while processing input.
+//!
+//! [`Core`](crate::core::Core) keeps a [`Metrics`] for each run that can be retrieved with
+//! [`Core::metrics`](crate::core::Core::metrics) once processing is done.
+//!
+//! Heap allocations are only counted with the `alloc-stats` feature, which adds a
+//! [`CountingAllocator`] for the binary to register as its global allocator.
+use std::{
+    fmt,
+    ops::AddAssign,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "alloc-stats")]
+pub use alloc_stats::CountingAllocator;
+
+/// Counters collected by [`Core`](crate::core::Core) while processing input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Metrics {
+    /// The number of records read.
+    pub records: u64,
+    /// The number of records written, leaving out a header kept by the pipeline and any records a
+    /// pipeline only emits when it is finished. Not counted when
+    /// [`count_only`](crate::core::CoreConfigBuilder::count_only) is set.
+    pub written: u64,
+    /// The number of input bytes processed, after any decompression.
+    pub bytes: u64,
+    /// The number of compressed bytes read from inputs that were decompressed, only counted when
+    /// [`try_decompress`](crate::core::CoreConfigBuilder::try_decompress) is set.
+    pub compressed_bytes: u64,
+    /// The number of fields selected, only counted when
+    /// [`count_only`](crate::core::CoreConfigBuilder::count_only) is set.
+    pub fields: u64,
+    /// The wall time spent processing.
+    pub elapsed: Duration,
+    /// The number of heap allocations made while processing, by any thread. Only counted with the
+    /// `alloc-stats` feature when a [`CountingAllocator`] is the global allocator.
+    pub allocations: u64,
+    /// The number of bytes asked for by those allocations.
+    pub allocated_bytes: u64,
+}
+
+impl Metrics {
+    /// Records processed per second.
+    pub fn records_per_sec(&self) -> f64 {
+        per_sec(self.records, self.elapsed)
+    }
+
+    /// Bytes processed per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        per_sec(self.bytes, self.elapsed)
+    }
+}
+
+#[inline]
+fn per_sec(count: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        count as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+impl AddAssign for Metrics {
+    fn add_assign(&mut self, other: Self) {
+        self.records += other.records;
+        self.written += other.written;
+        self.bytes += other.bytes;
+        self.compressed_bytes += other.compressed_bytes;
+        self.fields += other.fields;
+        self.elapsed += other.elapsed;
+        self.allocations += other.allocations;
+        self.allocated_bytes += other.allocated_bytes;
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "records: {}, bytes: {}", self.records, self.bytes)?;
+        if self.compressed_bytes > 0 {
+            write!(f, ", compressed bytes: {}", self.compressed_bytes)?;
+        }
+        write!(
+            f,
+            ", elapsed: {:.3}s, records/s: {:.0}, MiB/s: {:.2}",
+            self.elapsed.as_secs_f64(),
+            self.records_per_sec(),
+            self.bytes_per_sec() / (1024.0 * 1024.0)
+        )?;
+        if self.allocations > 0 {
+            write!(
+                f,
+                ", allocations: {}, allocated MiB: {:.2}",
+                self.allocations,
+                self.allocated_bytes as f64 / (1024.0 * 1024.0)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Measures a stretch of processing, adding the time taken and allocations made to a [`Metrics`].
+pub(crate) struct Span {
+    start: Instant,
+    allocations: (u64, u64),
+}
+
+impl Span {
+    pub(crate) fn start() -> Self {
+        Self {
+            start: Instant::now(),
+            allocations: allocation_counts(),
+        }
+    }
+
+    pub(crate) fn finish(self, metrics: &mut Metrics) {
+        metrics.elapsed += self.start.elapsed();
+        let (allocations, bytes) = allocation_counts();
+        metrics.allocations += allocations - self.allocations.0;
+        metrics.allocated_bytes += bytes - self.allocations.1;
+    }
+}
+
+/// The allocations and allocated bytes counted so far.
+#[cfg(feature = "alloc-stats")]
+fn allocation_counts() -> (u64, u64) {
+    alloc_stats::counts()
+}
+
+/// Allocations aren't counted without the `alloc-stats` feature.
+#[cfg(not(feature = "alloc-stats"))]
+fn allocation_counts() -> (u64, u64) {
+    (0, 0)
+}
+
+#[cfg(feature = "alloc-stats")]
+mod alloc_stats {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+    static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+    /// A global allocator that counts allocations before handing them to the [`System`] allocator,
+    /// so [`Metrics`](super::Metrics) can report them.
+    ///
+    /// Nothing is counted unless it is registered with `#[global_allocator]`, as `hck` does when
+    /// built with the `alloc-stats` feature:
+    ///
+    /// ```
+    /// #[global_allocator]
+    /// static ALLOCATOR: hcklib::metrics::CountingAllocator = hcklib::metrics::CountingAllocator;
+    /// ```
+    pub struct CountingAllocator;
+
+    #[inline]
+    fn count(size: usize) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn counts() -> (u64, u64) {
+        (
+            ALLOCATIONS.load(Ordering::Relaxed),
+            ALLOCATED_BYTES.load(Ordering::Relaxed),
+        )
+    }
+
+    // SAFETY: Every call is passed straight through to the system allocator.
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            count(layout.size());
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        /// A reallocation is counted as an allocation of the new size.
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "alloc-stats")]
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn test_rates() {
+        let metrics = Metrics {
+            records: 10,
+            bytes: 2048,
+            elapsed: Duration::from_secs(2),
+            ..Metrics::default()
+        };
+        assert_eq!(metrics.records_per_sec(), 5.0);
+        assert_eq!(metrics.bytes_per_sec(), 1024.0);
+        assert_eq!(Metrics::default().records_per_sec(), 0.0);
+    }
+
+    #[test]
+    fn test_add_assign() {
+        let mut total = Metrics::default();
+        total += Metrics {
+            records: 1,
+            written: 1,
+            bytes: 2,
+            compressed_bytes: 1,
+            fields: 3,
+            elapsed: Duration::from_millis(3),
+            allocations: 2,
+            allocated_bytes: 64,
+        };
+        total += Metrics {
+            records: 4,
+            written: 2,
+            bytes: 5,
+            compressed_bytes: 0,
+            fields: 6,
+            elapsed: Duration::from_millis(6),
+            allocations: 1,
+            allocated_bytes: 8,
+        };
+        assert_eq!(
+            total,
+            Metrics {
+                records: 5,
+                written: 3,
+                bytes: 7,
+                compressed_bytes: 1,
+                fields: 9,
+                elapsed: Duration::from_millis(9),
+                allocations: 3,
+                allocated_bytes: 72,
+            }
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let mut metrics = Metrics {
+            records: 2,
+            bytes: 10,
+            ..Metrics::default()
+        };
+        assert!(metrics
+            .to_string()
+            .starts_with("records: 2, bytes: 10, elapsed:"));
+        metrics.compressed_bytes = 4;
+        assert!(metrics
+            .to_string()
+            .starts_with("records: 2, bytes: 10, compressed bytes: 4, elapsed:"));
+        assert!(!metrics.to_string().contains("allocations"));
+        metrics.allocations = 3;
+        metrics.allocated_bytes = 3 << 20;
+        assert!(metrics
+            .to_string()
+            .ends_with(", allocations: 3, allocated MiB: 3.00"));
+    }
+
+    #[test]
+    fn test_span() {
+        let mut metrics = Metrics::default();
+        let span = Span::start();
+        let buffer = std::hint::black_box(vec![0u8; 1024]);
+        drop(buffer);
+        span.finish(&mut metrics);
+        if cfg!(feature = "alloc-stats") {
+            assert!(metrics.allocations >= 1);
+            assert!(metrics.allocated_bytes >= 1024);
+        } else {
+            assert_eq!((metrics.allocations, metrics.allocated_bytes), (0, 0));
+        }
+    }
+}
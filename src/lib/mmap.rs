@@ -1,8 +1,24 @@
 use std::fs::File;
+use std::io;
 use std::path::Path;
 
+#[cfg(feature = "native")]
 use memmap::Mmap;
 
+/// Stands in for a memory map without the `native` feature, where one can never be opened.
+#[cfg(not(feature = "native"))]
+#[derive(Debug)]
+pub(crate) enum Mmap {}
+
+#[cfg(not(feature = "native"))]
+impl std::ops::Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {}
+    }
+}
+
 /// Controls the strategy used for determining when to use memory maps.
 ///
 /// If a searcher is called in circumstances where it is possible to use memory
@@ -16,6 +32,7 @@ pub struct MmapChoice(MmapChoiceImpl);
 #[derive(Clone, Debug, Copy)]
 enum MmapChoiceImpl {
     Auto,
+    Always,
     Never,
 }
 
@@ -51,6 +68,18 @@ impl MmapChoice {
         MmapChoice(MmapChoiceImpl::Auto)
     }
 
+    /// Always use memory maps, even where [`MmapChoice::auto`] wouldn't, like on macOS.
+    ///
+    /// Inputs that can't be memory mapped are an error instead of being read with normal OS read
+    /// calls, which makes this useful for benchmarking memory maps.
+    ///
+    /// # Safety
+    ///
+    /// See [`MmapChoice::auto`].
+    pub unsafe fn always() -> MmapChoice {
+        MmapChoice(MmapChoiceImpl::Always)
+    }
+
     /// Never use memory maps, no matter what. This is the default.
     pub fn never() -> MmapChoice {
         MmapChoice(MmapChoiceImpl::Never)
@@ -62,11 +91,32 @@ impl MmapChoice {
     ///
     /// If this does attempt to open a memory map and it fails, then `None`
     /// is returned and the corresponding error (along with the file path, if
-    /// present) is logged at the debug level.
-    pub(crate) fn open<P: AsRef<Path>>(&self, file: &File, path: Option<&P>) -> Option<Mmap> {
-        if !self.is_enabled() {
-            return None;
+    /// present) is logged at the debug level. When memory maps are required,
+    /// see [`MmapChoice::always`], the error is returned instead.
+    pub(crate) fn open<P: AsRef<Path>>(
+        &self,
+        file: &File,
+        path: Option<&P>,
+    ) -> io::Result<Option<Mmap>> {
+        match self.0 {
+            MmapChoiceImpl::Never => Ok(None),
+            MmapChoiceImpl::Auto => Ok(self.open_auto(file, path)),
+            MmapChoiceImpl::Always => try_map(file).map(Some).map_err(|err| match path {
+                Some(path) => io::Error::new(
+                    err.kind(),
+                    format!(
+                        "{}: failed to open memory map: {}",
+                        path.as_ref().display(),
+                        err
+                    ),
+                ),
+                None => err,
+            }),
         }
+    }
+
+    /// Open a memory map if it is believed to be advantageous for performance.
+    fn open_auto<P: AsRef<Path>>(&self, file: &File, path: Option<&P>) -> Option<Mmap> {
         if !cfg!(target_pointer_width = "64") {
             // For 32-bit systems, it looks like mmap will succeed even if it
             // can't address the entire file. This seems to happen at least on
@@ -81,32 +131,86 @@ impl MmapChoice {
             // I guess memory maps on macOS aren't great. Should re-evaluate.
             return None;
         }
-        // SAFETY: This is acceptable because the only way `MmapChoiceImpl` can
-        // be `Auto` is if the caller invoked the `auto` constructor, which
-        // is itself not safe. Thus, this is a propagation of the caller's
-        // assertion that using memory maps is safe.
-        match unsafe { Mmap::map(file) } {
-            Ok(mmap) => Some(mmap),
-            Err(err) => {
-                if let Some(path) = path {
-                    log::debug!(
-                        "{}: failed to open memory map: {}",
-                        path.as_ref().display(),
-                        err
-                    );
-                } else {
-                    log::debug!("failed to open memory map: {}", err);
-                }
-                None
-            }
+        map(file, path)
+    }
+
+    /// Whether inputs that can't be memory mapped are an error, see [`MmapChoice::always`].
+    pub(crate) fn is_required(&self) -> bool {
+        matches!(self.0, MmapChoiceImpl::Always)
+    }
+}
+
+/// Memory map `file`.
+#[cfg(feature = "native")]
+fn try_map(file: &File) -> io::Result<Mmap> {
+    // SAFETY: This is acceptable because the only way `MmapChoiceImpl` can
+    // be `Auto` or `Always` is if the caller invoked the `auto` or `always`
+    // constructor, which are themselves not safe. Thus, this is a propagation
+    // of the caller's assertion that using memory maps is safe.
+    unsafe { Mmap::map(file) }
+}
+
+/// Memory maps need the `native` feature.
+#[cfg(not(feature = "native"))]
+fn try_map(_file: &File) -> io::Result<Mmap> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "memory maps need the `native` feature",
+    ))
+}
+
+/// Hint that `mmap` will be read once from start to end, so the OS reads ahead of it and drops
+/// pages once they are read. Failures are only logged, since the hint is just an optimization.
+#[cfg(all(feature = "native", unix))]
+pub(crate) fn advise_sequential_map(mmap: &Mmap) {
+    use memmap::Advice;
+
+    for advice in [Advice::Sequential, Advice::WillNeed] {
+        if let Err(err) = mmap.advise(advice) {
+            log::debug!("failed to advise memory map: {}", err);
         }
     }
+}
 
-    /// Whether this strategy may employ memory maps or not.
-    pub(crate) fn is_enabled(&self) -> bool {
-        match self.0 {
-            MmapChoiceImpl::Auto => true,
-            MmapChoiceImpl::Never => false,
+/// `madvise` is only available on unix.
+#[cfg(not(all(feature = "native", unix)))]
+pub(crate) fn advise_sequential_map(_mmap: &Mmap) {}
+
+/// Hint that `file` will be read once from start to end, so the OS reads further ahead of each
+/// read. Failures are only logged, since the hint is just an optimization.
+#[cfg(target_os = "linux")]
+pub(crate) fn advise_sequential_file(file: &File) {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: The descriptor is open for as long as `file` is borrowed
+    let err = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) };
+    if err != 0 {
+        log::debug!(
+            "failed to advise file: {}",
+            io::Error::from_raw_os_error(err)
+        );
+    }
+}
+
+/// `posix_fadvise` is only used on Linux, where the `libc` dependency is.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn advise_sequential_file(_file: &File) {}
+
+/// Memory map `file`, logging any failure.
+fn map<P: AsRef<Path>>(file: &File, path: Option<&P>) -> Option<Mmap> {
+    match try_map(file) {
+        Ok(mmap) => Some(mmap),
+        Err(err) => {
+            if let Some(path) = path {
+                log::debug!(
+                    "{}: failed to open memory map: {}",
+                    path.as_ref().display(),
+                    err
+                );
+            } else {
+                log::debug!("failed to open memory map: {}", err);
+            }
+            None
         }
     }
 }
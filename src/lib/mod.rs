@@ -1,6 +1,57 @@
+//! hck's field selection as a library.
+//!
+//! The common entry points are re-exported from [`prelude`]: build a [`CoreConfig`] with a
+//! [`CoreConfigBuilder`], pick a [`LineParser`], and hand both to [`Core`] along with an
+//! [`HckInput`] and a writer.
+//!
+//! Everything reachable from the public modules follows semver. Modules that only support the
+//! internals of [`Core`], like the adaptive, direct IO, and io_uring readers, are private, and
+//! enums and structs that are expected to grow are `#[non_exhaustive]`.
+//!
+//! [`CoreConfig`]: core::CoreConfig
+//! [`CoreConfigBuilder`]: core::CoreConfigBuilder
+//! [`LineParser`]: line_parser::LineParser
+//! [`Core`]: core::Core
+//! [`HckInput`]: core::HckInput
 #![allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
+mod adaptive;
+#[cfg(any(test, feature = "test-util"))]
+pub mod chaos;
+pub mod codec;
+mod compare;
 pub mod core;
+pub mod decompress;
+mod direct_io;
+pub mod distinct;
+pub mod expr;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod field_range;
+pub mod gen;
+#[cfg(feature = "native")]
+pub mod join;
+pub mod key;
+mod limit;
 pub mod line_parser;
+pub mod metrics;
 pub mod mmap;
+pub mod output;
+#[cfg(feature = "parquet")]
+pub mod parquet_input;
+pub mod pattern;
+pub mod pipeline;
+pub mod prelude;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod recode;
 pub mod single_byte_delim_parser;
+pub mod sink;
+pub mod spec;
+#[cfg(feature = "native")]
+pub mod spill;
+pub mod split;
+pub mod subfield;
+mod truncate;
+#[cfg(feature = "uring")]
+mod uring;
+pub mod verify;
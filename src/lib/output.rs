@@ -0,0 +1,299 @@
+//! Output formats for the records selected by [`Core`](crate::core::Core).
+//!
+//! The default [`OutputFormat::Delimited`] joins the selected fields with the output delimiter, and
+//! [`OutputFormat::Quoted`] and [`OutputFormat::Escaped`] do the same while quoting fields per RFC
+//! 4180 or escaping them with backslashes, so fields that hold the delimiter can still be split.
+//! Other formats reinterpret the selected fields, i.e. [`OutputFormat::Dot`] treats them as the
+//! source, target, and optional weight of a Graphviz edge.
+use std::{
+    io::{self, Write},
+    str::FromStr,
+};
+
+use ripline::LineTerminator;
+use thiserror::Error;
+
+use crate::core::JoinAppend;
+
+/// Errors for parsing an [`EdgeSpec`].
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum EdgeSpecError {
+    #[error("Edge spec must be `SOURCE,TARGET[:weight=WEIGHT]`: {0}")]
+    InvalidSpec(String),
+    #[error("Edge columns are numbered from 1: {0}")]
+    InvalidColumn(String),
+    #[error("Edge columns must all be different, column {0} is used twice")]
+    DuplicateColumn(usize),
+}
+
+/// The columns that make up an edge, ex: `1,3:weight=5`.
+///
+/// Columns are 1-based, the same as `-f`, and must all be different since they are selected as one
+/// field list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdgeSpec {
+    pub source: usize,
+    pub target: usize,
+    pub weight: Option<usize>,
+}
+
+impl EdgeSpec {
+    /// The field list to select the edge columns with, in output order.
+    pub fn field_list(&self) -> String {
+        match self.weight {
+            Some(weight) => format!("{},{},{}", self.source, self.target, weight),
+            None => format!("{},{}", self.source, self.target),
+        }
+    }
+}
+
+impl FromStr for EdgeSpec {
+    type Err = EdgeSpecError;
+
+    /// Convert a [`str`] like `1,3` or `1,3:weight=5` into an [`EdgeSpec`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_column = |column: &str| match column.trim().parse::<usize>() {
+            Ok(column) if column > 0 => Ok(column),
+            _ => Err(EdgeSpecError::InvalidColumn(column.to_owned())),
+        };
+
+        let (columns, weight) = match s.split_once(':') {
+            Some((columns, weight)) => match weight.strip_prefix("weight=") {
+                Some(weight) => (columns, Some(parse_column(weight)?)),
+                None => return Err(EdgeSpecError::InvalidSpec(s.to_owned())),
+            },
+            None => (s, None),
+        };
+
+        let (source, target) = match columns.split_once(',') {
+            Some((source, target)) => (parse_column(source)?, parse_column(target)?),
+            None => return Err(EdgeSpecError::InvalidSpec(s.to_owned())),
+        };
+        if source == target || weight == Some(source) {
+            return Err(EdgeSpecError::DuplicateColumn(source));
+        }
+        if weight == Some(target) {
+            return Err(EdgeSpecError::DuplicateColumn(target));
+        }
+        Ok(EdgeSpec {
+            source,
+            target,
+            weight,
+        })
+    }
+}
+
+/// How selected fields are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum OutputFormat {
+    /// Join the selected fields with the output delimiter.
+    #[default]
+    Delimited,
+    /// Join the selected fields with the output delimiter, quoting any field that contains the
+    /// delimiter, a double quote, or a line break (RFC 4180 / CSV style).
+    Quoted,
+    /// Join the selected fields with the output delimiter, escaping any backslash or output delimiter
+    /// in a field with a backslash, and line breaks as `\n` and `\r`.
+    Escaped,
+    /// Write a Graphviz digraph, one edge per record, from the first two selected fields
+    /// with the third (if `weighted`) used as the edge weight.
+    Dot { weighted: bool },
+}
+
+impl OutputFormat {
+    /// Whether this format writes the fields exactly as `join_append` would.
+    pub fn is_delimited(&self) -> bool {
+        matches!(self, OutputFormat::Delimited)
+    }
+
+    /// Write anything that needs to come before the first record.
+    pub fn write_prologue<W: Write>(&self, mut output: W) -> Result<(), io::Error> {
+        match self {
+            OutputFormat::Delimited | OutputFormat::Quoted | OutputFormat::Escaped => Ok(()),
+            OutputFormat::Dot { .. } => output.write_all(b"digraph {\n"),
+        }
+    }
+
+    /// Write anything that needs to come after the last record.
+    pub fn write_epilogue<W: Write>(&self, mut output: W) -> Result<(), io::Error> {
+        match self {
+            OutputFormat::Delimited | OutputFormat::Quoted | OutputFormat::Escaped => Ok(()),
+            OutputFormat::Dot { .. } => output.write_all(b"}\n"),
+        }
+    }
+
+    /// Write a single record made up of the selected `items`.
+    #[inline]
+    pub fn write_record<'b, W: Write>(
+        &self,
+        mut output: W,
+        sep: &[u8],
+        mut items: impl Iterator<Item = &'b [u8]>,
+        term: &LineTerminator,
+    ) -> Result<(), io::Error> {
+        match self {
+            OutputFormat::Delimited => output.join_append(sep, items, term),
+            OutputFormat::Quoted => output.join_append_quoted(sep, items, term),
+            OutputFormat::Escaped => output.join_append_escaped(sep, items, term),
+            OutputFormat::Dot { weighted } => {
+                // Rows that are too short to form an edge are skipped
+                let (source, target) = match (items.next(), items.next()) {
+                    (Some(source), Some(target)) => (source, target),
+                    _ => return Ok(()),
+                };
+                output.write_all(b"    ")?;
+                write_dot_id(&mut output, source)?;
+                output.write_all(b" -> ")?;
+                write_dot_id(&mut output, target)?;
+                if let (true, Some(weight)) = (weighted, items.next()) {
+                    output.write_all(b" [weight=")?;
+                    write_dot_id(&mut output, weight)?;
+                    output.write_all(b"]")?;
+                }
+                output.write_all(b";\n")
+            }
+        }
+    }
+}
+
+/// Write a value as a quoted DOT ID, escaping any internal backslashes and quotes.
+fn write_dot_id<W: Write>(mut output: W, mut value: &[u8]) -> Result<(), io::Error> {
+    output.write_all(b"\"")?;
+    while let Some(i) = memchr::memchr2(b'\\', b'"', value) {
+        output.write_all(&value[..i])?;
+        output.write_all(b"\\")?;
+        output.write_all(&value[i..=i])?;
+        value = &value[i + 1..];
+    }
+    output.write_all(value)?;
+    output.write_all(b"\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_edge_spec() {
+        assert_eq!(
+            EdgeSpec {
+                source: 1,
+                target: 3,
+                weight: None
+            },
+            "1,3".parse().unwrap()
+        );
+        assert_eq!(
+            EdgeSpec {
+                source: 4,
+                target: 2,
+                weight: Some(5)
+            },
+            "4,2:weight=5".parse().unwrap()
+        );
+        assert_eq!(
+            "1,3:weight=5".parse::<EdgeSpec>().unwrap().field_list(),
+            "1,3,5"
+        );
+    }
+
+    #[test]
+    fn test_parse_edge_spec_bad() {
+        assert!("1".parse::<EdgeSpec>().is_err());
+        assert!("0,1".parse::<EdgeSpec>().is_err());
+        assert!("1,cat".parse::<EdgeSpec>().is_err());
+        assert!("1,2:color=3".parse::<EdgeSpec>().is_err());
+        assert!("1,2:weight=".parse::<EdgeSpec>().is_err());
+        // The columns are selected as one field list, so a repeated column would be lost
+        assert_eq!(
+            "1,1".parse::<EdgeSpec>(),
+            Err(EdgeSpecError::DuplicateColumn(1))
+        );
+        assert_eq!(
+            "1,3:weight=3".parse::<EdgeSpec>(),
+            Err(EdgeSpecError::DuplicateColumn(3))
+        );
+        assert_eq!(
+            "2,3:weight=2".parse::<EdgeSpec>(),
+            Err(EdgeSpecError::DuplicateColumn(2))
+        );
+    }
+
+    #[test]
+    fn test_write_quoted_records() {
+        let format = OutputFormat::Quoted;
+        let term = LineTerminator::default();
+        let mut output = vec![];
+        let records: Vec<Vec<&[u8]>> = vec![
+            vec![b"plain", b"has,comma", b"say \"hi\""],
+            vec![b"two\nlines", b"", b"ok"],
+        ];
+        for record in records {
+            format
+                .write_record(&mut output, b",", record.into_iter(), &term)
+                .unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "plain,\"has,comma\",\"say \"\"hi\"\"\"\n\"two\nlines\",,ok\n"
+        );
+    }
+
+    #[test]
+    fn test_write_escaped_records() {
+        let format = OutputFormat::Escaped;
+        let term = LineTerminator::default();
+        let mut output = vec![];
+        let records: Vec<Vec<&[u8]>> = vec![
+            vec![b"plain", b"has\ttab", b"back\\slash"],
+            vec![b"two\nlines\r", b"", b"\t\t"],
+        ];
+        for record in records {
+            format
+                .write_record(&mut output, b"\t", record.into_iter(), &term)
+                .unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "plain\thas\\\ttab\tback\\\\slash\ntwo\\nlines\\r\t\t\\\t\\\t\n"
+        );
+
+        // Multi byte delimiters are escaped as a whole
+        let mut output = vec![];
+        format
+            .write_record(
+                &mut output,
+                b"::",
+                [&b"a::b:c"[..], b"d"].into_iter(),
+                &term,
+            )
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "a\\::b:c::d\n");
+    }
+
+    #[test]
+    fn test_write_dot_records() {
+        let format = OutputFormat::Dot { weighted: true };
+        let term = LineTerminator::default();
+        let mut output = vec![];
+        format.write_prologue(&mut output).unwrap();
+        let records: Vec<Vec<&[u8]>> = vec![
+            vec![b"a", b"b", b"5"],
+            vec![b"say \"hi\"", b"c", b"1.5"],
+            vec![b"C:\\", b"a \\\"b", b"2"],
+            vec![b"lonely"],
+        ];
+        for record in records {
+            format
+                .write_record(&mut output, b"\t", record.into_iter(), &term)
+                .unwrap();
+        }
+        format.write_epilogue(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "digraph {\n    \"a\" -> \"b\" [weight=\"5\"];\n    \"say \\\"hi\\\"\" -> \"c\" [weight=\"1.5\"];\n    \"C:\\\\\" -> \"a \\\\\\\"b\" [weight=\"2\"];\n}\n"
+        );
+    }
+}
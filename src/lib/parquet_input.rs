@@ -0,0 +1,251 @@
+//! Parquet input, available with the `parquet` feature.
+//!
+//! Parquet files don't fit the byte stream model of [`HckInput`](crate::core::HckInput), so they
+//! skip [`Core`](crate::core::Core) entirely. The `-f` / `-F` selections are resolved against the
+//! column names in the file schema and turned into a projection, so only the selected columns are
+//! ever decoded. The selected columns are written as delimited text, with the column names as the
+//! first record.
+use std::{cmp::min, fs::File, io::Write, path::Path, sync::Arc};
+
+use anyhow::Result;
+use parquet::{
+    file::reader::{FileReader, SerializedFileReader},
+    record::Field,
+    schema::types::Type,
+};
+
+use crate::{
+    core::CoreConfig,
+    field_range::FieldRange,
+    metrics::{Metrics, Span},
+};
+
+/// Whether `path` looks like a Parquet file.
+pub fn is_parquet<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("parquet"))
+        .unwrap_or(false)
+}
+
+/// Select the fields described by `config` from the Parquet file at `path`, writing them to `output`.
+pub fn hck_parquet<P, W>(path: P, config: &CoreConfig, mut output: W) -> Result<Metrics>
+where
+    P: AsRef<Path>,
+    W: Write,
+{
+    let span = Span::start();
+    let file = File::open(path)?;
+    let bytes = file.metadata()?.len();
+    let reader = SerializedFileReader::new(file)?;
+    let schema = reader.metadata().file_metadata().schema();
+    let columns = schema.get_fields();
+    let names: Vec<&str> = columns.iter().map(|column| column.name()).collect();
+
+    let fields = config.parse_fields_from_names(&names)?;
+    let shuffled = shuffle_columns(&fields, columns.len());
+
+    // Project only the selected columns, in file order, so nothing else is decoded
+    let mut projected: Vec<usize> = shuffled.iter().flatten().copied().collect();
+    projected.sort_unstable();
+    projected.dedup();
+    if projected.is_empty() {
+        return Ok(Metrics::default());
+    }
+    let projection = Type::group_type_builder(schema.name())
+        .with_fields(projected.iter().map(|&i| Arc::clone(&columns[i])).collect())
+        .build()?;
+    // Where each selected column lands in a projected row, in output order
+    let order: Vec<usize> = shuffled
+        .iter()
+        .flatten()
+        .map(|i| projected.binary_search(i).unwrap())
+        .collect();
+
+    let mut metrics = Metrics {
+        bytes,
+        ..Metrics::default()
+    };
+    let format = config.output_format();
+    let sep = config.output_delimiter();
+    let term = config.output_line_terminator();
+
+    if config.count_only() {
+        for row in reader.get_row_iter(Some(projection))? {
+            row?;
+            metrics.records += 1;
+            metrics.fields += order.len() as u64;
+        }
+        span.finish(&mut metrics);
+        return Ok(metrics);
+    }
+
+    let header: Vec<&[u8]> = order
+        .iter()
+        .map(|&i| names[projected[i]].as_bytes())
+        .collect();
+    format.write_record(&mut output, sep, header.into_iter(), &term)?;
+
+    let mut values: Vec<Vec<u8>> = vec![vec![]; projected.len()];
+    for row in reader.get_row_iter(Some(projection))? {
+        let row = row?;
+        metrics.records += 1;
+        metrics.written += 1;
+        for (value, (_, field)) in values.iter_mut().zip(row.get_column_iter()) {
+            value.clear();
+            write_field(value, field)?;
+        }
+        format.write_record(
+            &mut output,
+            sep,
+            order.iter().map(|&i| values[i].as_slice()),
+            &term,
+        )?;
+    }
+
+    span.finish(&mut metrics);
+    Ok(metrics)
+}
+
+/// Work out which columns each [`FieldRange`] selects, following the same rules as the line parsers.
+///
+/// The result is indexed by [`FieldRange::pos`].
+fn shuffle_columns(fields: &[FieldRange], num_columns: usize) -> Vec<Vec<usize>> {
+    let mut shuffled = vec![vec![]; fields.iter().map(|f| f.pos + 1).max().unwrap_or(0)];
+    let mut index = 0;
+    for &FieldRange { low, high, pos } in fields {
+        if low > index {
+            if low >= num_columns {
+                break;
+            }
+            index = low;
+        }
+        let end = min(high, num_columns - 1);
+        while index <= end {
+            shuffled[pos].push(index);
+            index += 1;
+        }
+    }
+    shuffled
+}
+
+/// Write a Parquet value as plain text, nulls are written as empty fields.
+fn write_field(output: &mut Vec<u8>, field: &Field) -> Result<()> {
+    match field {
+        Field::Null => (),
+        Field::Str(s) => output.extend_from_slice(s.as_bytes()),
+        Field::Bytes(b) => output.extend_from_slice(b.data()),
+        other => write!(output, "{}", other)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::CoreConfigBuilder;
+    use parquet::{
+        data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type},
+        file::{properties::WriterProperties, writer::SerializedFileWriter},
+        schema::parser::parse_message_type,
+    };
+    use regex::bytes::Regex;
+    use tempfile::TempDir;
+
+    fn write_parquet(path: &Path) {
+        let schema = Arc::new(
+            parse_message_type(
+                "message schema {
+                    REQUIRED INT64 id;
+                    REQUIRED BYTE_ARRAY name (UTF8);
+                    OPTIONAL DOUBLE score;
+                }",
+            )
+            .unwrap(),
+        );
+        let props = Arc::new(WriterProperties::builder().build());
+        let mut writer =
+            SerializedFileWriter::new(File::create(path).unwrap(), schema, props).unwrap();
+        let mut row_group = writer.next_row_group().unwrap();
+
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column
+            .typed::<Int64Type>()
+            .write_batch(&[1, 2, 3], None, None)
+            .unwrap();
+        column.close().unwrap();
+
+        let mut column = row_group.next_column().unwrap().unwrap();
+        let names: Vec<ByteArray> = vec!["alice".into(), "bob".into(), "carol".into()];
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(&names, None, None)
+            .unwrap();
+        column.close().unwrap();
+
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column
+            .typed::<DoubleType>()
+            .write_batch(&[0.5, 2.25], Some(&[1, 0, 1]), None)
+            .unwrap();
+        column.close().unwrap();
+
+        row_group.close().unwrap();
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn test_is_parquet() {
+        assert!(is_parquet("data.parquet"));
+        assert!(is_parquet("data.PARQUET"));
+        assert!(!is_parquet("data.csv"));
+    }
+
+    #[test]
+    fn test_select_by_index() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("data.parquet");
+        write_parquet(&path);
+
+        let conf = CoreConfigBuilder::new()
+            .fields(Some("3,1"))
+            .output_delimiter(b",")
+            .build()
+            .unwrap();
+        let mut output = vec![];
+        let metrics = hck_parquet(&path, &conf, &mut output).unwrap();
+        assert_eq!(metrics.records, 3);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "score,id\n0.5,1\n,2\n2.25,3\n"
+        );
+    }
+
+    #[test]
+    fn test_select_by_header() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("data.parquet");
+        write_parquet(&path);
+
+        let headers = vec![Regex::new("name").unwrap(), Regex::new("id").unwrap()];
+        let conf = CoreConfigBuilder::new()
+            .fields(None::<&str>)
+            .headers(Some(&headers))
+            .build()
+            .unwrap();
+        let mut output = vec![];
+        hck_parquet(&path, &conf, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name\tid\nalice\t1\nbob\t2\ncarol\t3\n"
+        );
+    }
+
+    #[test]
+    fn test_shuffle_columns() {
+        let fields = FieldRange::from_list("2-").unwrap();
+        assert_eq!(shuffle_columns(&fields, 4), vec![vec![1, 2, 3]]);
+        let fields = FieldRange::from_list("5,1").unwrap();
+        assert_eq!(shuffle_columns(&fields, 3), vec![vec![], vec![0]]);
+    }
+}
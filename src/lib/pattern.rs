@@ -0,0 +1,137 @@
+//! Regexes applied to a single output field.
+//!
+//! A [`ColumnPattern`] can be used to require that a field looks a certain way, ex: `^\d+$`, or to
+//! cut a field down to part of itself, ex: the prefix matched by `^(\w+)-`.
+use std::{borrow::Cow, str::FromStr};
+
+use regex::bytes::Regex;
+use thiserror::Error;
+
+/// Errors for parsing a [`ColumnPattern`].
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum PatternError {
+    #[error("Invalid column pattern `{0}`, expected FIELD:REGEX like `3:^\\d+$`")]
+    InvalidSpec(String),
+    #[error("Invalid pattern field `{0}`, expected a 1-based position")]
+    InvalidField(String),
+    #[error(transparent)]
+    InvalidRegex(#[from] regex::Error),
+}
+
+/// A regex for one output field, parsed from `FIELD:REGEX`.
+#[derive(Debug, Clone)]
+pub struct ColumnPattern {
+    /// The 0-based output position the regex applies to
+    pub index: usize,
+    pub regex: Regex,
+}
+
+impl ColumnPattern {
+    /// Whether the field of `record` matches, records too short to have it never do.
+    #[inline]
+    pub fn is_match(&self, record: &[Cow<[u8]>]) -> bool {
+        record
+            .get(self.index)
+            .is_some_and(|field| self.regex.is_match(field))
+    }
+
+    /// The first capture group matched in `value`, or the whole match if the regex has no groups.
+    ///
+    /// A group that didn't take part in the match is empty. Returns `None` if there is no match.
+    #[inline]
+    pub fn capture<'v>(&self, value: &'v [u8]) -> Option<&'v [u8]> {
+        let captures = self.regex.captures(value)?;
+        let group = if captures.len() > 1 {
+            captures.get(1)
+        } else {
+            captures.get(0)
+        };
+        Some(group.map_or(&[][..], |m| m.as_bytes()))
+    }
+
+    /// Parse `FIELD:/REGEX/` like `2:/sample=(\w+)/`, the slashes around the regex are optional.
+    pub fn parse_slashed(s: &str) -> Result<Self, PatternError> {
+        match s.split_once(':') {
+            Some((field, regex))
+                if regex.len() >= 2 && regex.starts_with('/') && regex.ends_with('/') =>
+            {
+                format!("{}:{}", field, &regex[1..regex.len() - 1]).parse()
+            }
+            _ => s.parse(),
+        }
+    }
+}
+
+impl FromStr for ColumnPattern {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((field, regex)) = s.split_once(':') else {
+            return Err(PatternError::InvalidSpec(s.to_owned()));
+        };
+        let index = match field.trim().parse::<usize>() {
+            Ok(position) if position > 0 => position - 1,
+            _ => return Err(PatternError::InvalidField(field.to_owned())),
+        };
+        Ok(Self {
+            index,
+            regex: Regex::new(regex)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let pattern: ColumnPattern = r"3:^\d+:\d+$".parse().unwrap();
+        assert_eq!(pattern.index, 2);
+        assert_eq!(pattern.regex.as_str(), r"^\d+:\d+$");
+        assert!(matches!(
+            "^a".parse::<ColumnPattern>(),
+            Err(PatternError::InvalidSpec(_))
+        ));
+        assert!(matches!(
+            "0:a".parse::<ColumnPattern>(),
+            Err(PatternError::InvalidField(_))
+        ));
+        assert!(matches!(
+            "1:(".parse::<ColumnPattern>(),
+            Err(PatternError::InvalidRegex(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_slashed() {
+        let pattern = ColumnPattern::parse_slashed("2:/sample=([A-Z0-9]+)/").unwrap();
+        assert_eq!(pattern.index, 1);
+        assert_eq!(pattern.regex.as_str(), "sample=([A-Z0-9]+)");
+        let pattern = ColumnPattern::parse_slashed("1:a/b").unwrap();
+        assert_eq!(pattern.regex.as_str(), "a/b");
+        let pattern = ColumnPattern::parse_slashed("1:/").unwrap();
+        assert_eq!(pattern.regex.as_str(), "/");
+        assert!(ColumnPattern::parse_slashed("/a/").is_err());
+    }
+
+    #[test]
+    fn test_match_and_capture() {
+        let pattern: ColumnPattern = r"2:^\d+$".parse().unwrap();
+        let record = |fields: &[&'static str]| -> Vec<Cow<[u8]>> {
+            fields.iter().map(|f| Cow::Borrowed(f.as_bytes())).collect()
+        };
+        assert!(pattern.is_match(&record(&["a", "42"])));
+        assert!(!pattern.is_match(&record(&["a", "4x"])));
+        assert!(!pattern.is_match(&record(&["a"])));
+
+        let prefix: ColumnPattern = r"1:^(\w+)-".parse().unwrap();
+        assert_eq!(prefix.capture(b"abc-123"), Some(&b"abc"[..]));
+        assert_eq!(prefix.capture(b"abc"), None);
+        let whole: ColumnPattern = r"1:\d+".parse().unwrap();
+        assert_eq!(whole.capture(b"ab12cd"), Some(&b"12"[..]));
+        let optional: ColumnPattern = r"1:^a(b)?".parse().unwrap();
+        assert_eq!(optional.capture(b"ac"), Some(&b""[..]));
+    }
+}
@@ -0,0 +1,1584 @@
+//! Record processing stages.
+//!
+//! A [`Pipeline`] is an ordered list of [`RecordStage`]s that [`Core`](crate::core::Core) runs
+//! each record through after the [`LineParser`](crate::line_parser::LineParser) has selected the
+//! fields and before the [`OutputFormat`](crate::output::OutputFormat) writes them. Stages can
+//! filter records, transform their fields, inject new fields, or hold records back and emit
+//! aggregates once all input has been read.
+//!
+//! The pipeline lives outside of `Core` so that its state carries over from one input to the next.
+//! Whether the first record of each input is a header is decided once for the whole pipeline by
+//! its [`HeaderPolicy`], so a header is never counted, filtered, sampled, or limited as a data row.
+//! When a pipeline is in use the fast path is skipped since it never materializes a record.
+use std::{
+    borrow::Cow,
+    collections::{HashSet, VecDeque},
+    fmt, io,
+};
+
+use crate::{
+    distinct::DistinctCounter,
+    expr::Expr,
+    field_range::FieldRange,
+    gen::Rng,
+    key::{Key, SurrogateKind},
+    pattern::ColumnPattern,
+    recode::Recoder,
+    subfield::{MergeFieldsSpec, SplitFieldSpec},
+};
+
+/// What to do with a record after a [`RecordStage`] has seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageAction {
+    /// Pass the record on to the next stage.
+    Keep,
+    /// Drop the record.
+    Skip,
+    /// Drop the record and stop reading any more input.
+    Stop,
+    /// Pass the record on, then stop reading any more input.
+    Last,
+}
+
+impl StageAction {
+    /// Whether the record should be written out.
+    #[inline]
+    pub fn keeps(self) -> bool {
+        matches!(self, StageAction::Keep | StageAction::Last)
+    }
+
+    /// Whether to stop reading input.
+    #[inline]
+    pub fn stops(self) -> bool {
+        matches!(self, StageAction::Stop | StageAction::Last)
+    }
+}
+
+/// How a [`Pipeline`] treats the first record of each input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum HeaderPolicy {
+    /// Inputs have no header, every record is data.
+    #[default]
+    None,
+    /// The first record of each input is a header and is written out. It only goes through
+    /// [`RecordStage::header`], never [`RecordStage::process`].
+    Keep,
+    /// The first record of each input is a header and is dropped without going through any stage.
+    Drop,
+}
+
+/// Information about the record being processed.
+#[derive(Debug, Clone, Copy)]
+pub struct RecordContext<'a> {
+    /// The 1-based record number within the current input, 0 for records emitted by [`RecordStage::finish`].
+    pub record_number: u64,
+    /// The full input line the record was selected from, without its line terminator.
+    pub line: &'a [u8],
+}
+
+impl<'a> RecordContext<'a> {
+    /// The context for records that don't come from an input line.
+    pub fn detached() -> Self {
+        Self {
+            record_number: 0,
+            line: &[],
+        }
+    }
+}
+
+/// A record is the list of selected fields in output order.
+pub type Record<'a> = Vec<Cow<'a, [u8]>>;
+
+/// A callback that receives records emitted by [`RecordStage::finish`].
+pub type Emit<'e> = dyn FnMut(Record<'static>) -> io::Result<()> + 'e;
+
+/// A single step in a [`Pipeline`].
+pub trait RecordStage {
+    /// Process a record, possibly modifying it in place.
+    fn process<'b>(&mut self, ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction;
+
+    /// Process the header of an input under [`HeaderPolicy::Keep`], possibly modifying it in place.
+    ///
+    /// Headers are left as is by default. Anything but keeping the header drops it, and a header
+    /// never stops input from being read.
+    #[inline]
+    fn header<'b>(&mut self, _ctx: &RecordContext<'b>, _record: &mut Record<'b>) -> StageAction {
+        StageAction::Keep
+    }
+
+    /// Called before the first record of each input.
+    #[inline]
+    fn start_input(&mut self) {}
+
+    /// Called once all input has been processed, any records passed to `emit` go through the rest
+    /// of the pipeline and are then written out.
+    fn finish(&mut self, _emit: &mut Emit<'_>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A stage that keeps only the records for which `F` returns true.
+pub struct FilterStage<F>(pub F);
+
+impl<F> RecordStage for FilterStage<F>
+where
+    F: FnMut(&RecordContext, &[Cow<[u8]>]) -> bool,
+{
+    #[inline]
+    fn process<'b>(&mut self, ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        if (self.0)(ctx, record) {
+            StageAction::Keep
+        } else {
+            StageAction::Skip
+        }
+    }
+}
+
+/// A stage that keeps only the records matching an [`Expr`].
+///
+/// Headers under [`HeaderPolicy::Keep`] aren't evaluated. A numeric comparison where a side isn't a
+/// number is false, see [`strict`](Self::strict) to fail instead.
+pub struct ExprFilterStage {
+    expr: Expr,
+    strict: bool,
+    /// The first evaluation error, returned by `finish`
+    error: Option<String>,
+}
+
+impl ExprFilterStage {
+    pub fn new(expr: Expr) -> Self {
+        Self {
+            expr,
+            strict: false,
+            error: None,
+        }
+    }
+
+    /// Stop reading input at the first numeric comparison where a side isn't a number, and fail
+    /// [`Pipeline::finish`] with an [`io::ErrorKind::InvalidData`] error.
+    pub fn strict(mut self, yes: bool) -> Self {
+        self.strict = yes;
+        self
+    }
+}
+
+impl RecordStage for ExprFilterStage {
+    #[inline]
+    fn process<'b>(&mut self, ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        let matches = if self.strict {
+            match self.expr.try_matches(record) {
+                Ok(matches) => matches,
+                Err(err) => {
+                    self.error = Some(format!("record {}: {}", ctx.record_number, err));
+                    return StageAction::Stop;
+                }
+            }
+        } else {
+            self.expr.matches(record)
+        };
+        if matches {
+            StageAction::Keep
+        } else {
+            StageAction::Skip
+        }
+    }
+
+    fn finish(&mut self, _emit: &mut Emit<'_>) -> io::Result<()> {
+        match self.error.take() {
+            Some(message) => Err(io::Error::new(io::ErrorKind::InvalidData, message)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A stage that applies `F` to every field of every record.
+pub struct MapFieldsStage<F>(pub F);
+
+impl<F> RecordStage for MapFieldsStage<F>
+where
+    F: FnMut(usize, &[u8]) -> Option<Vec<u8>>,
+{
+    /// `F` is given the 0-based output position and value of each field, and returns the new value
+    /// or `None` to leave the field as is.
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        for (i, field) in record.iter_mut().enumerate() {
+            if let Some(value) = (self.0)(i, field) {
+                *field = Cow::Owned(value);
+            }
+        }
+        StageAction::Keep
+    }
+}
+
+/// A stage that inserts a fixed value at an output position.
+///
+/// Records too short to have `index` get the value appended instead. Headers get the value too,
+/// unless given a name of their own with [`InjectStage::header`].
+pub struct InjectStage {
+    index: usize,
+    value: Vec<u8>,
+    header: Option<Vec<u8>>,
+}
+
+impl InjectStage {
+    pub fn new(index: usize, value: Vec<u8>) -> Self {
+        Self {
+            index,
+            value,
+            header: None,
+        }
+    }
+
+    /// Insert `name` instead of the value into headers.
+    pub fn header(mut self, name: Vec<u8>) -> Self {
+        self.header = Some(name);
+        self
+    }
+}
+
+impl RecordStage for InjectStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        let index = self.index.min(record.len());
+        record.insert(index, Cow::Owned(self.value.clone()));
+        StageAction::Keep
+    }
+
+    #[inline]
+    fn header<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        let index = self.index.min(record.len());
+        let name = self.header.as_ref().unwrap_or(&self.value);
+        record.insert(index, Cow::Owned(name.clone()));
+        StageAction::Keep
+    }
+}
+
+/// A stage that replaces the value at an output position using a [`Recoder`].
+pub struct RecodeStage {
+    index: usize,
+    recoder: Recoder,
+}
+
+impl RecodeStage {
+    pub fn new(index: usize, recoder: Recoder) -> Self {
+        Self { index, recoder }
+    }
+}
+
+impl RecordStage for RecodeStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        if let Some(field) = record.get_mut(self.index) {
+            if let Some(value) = self.recoder.recode(field) {
+                *field = Cow::Owned(value.to_vec());
+            }
+        }
+        StageAction::Keep
+    }
+}
+
+/// A stage that drops records whose field doesn't match a [`ColumnPattern`].
+pub struct RequireStage {
+    pattern: ColumnPattern,
+}
+
+impl RequireStage {
+    pub fn new(pattern: ColumnPattern) -> Self {
+        Self { pattern }
+    }
+}
+
+impl RecordStage for RequireStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        if self.pattern.is_match(record) {
+            StageAction::Keep
+        } else {
+            StageAction::Skip
+        }
+    }
+}
+
+/// A stage that splits a field into pieces that each become a field of their own, see
+/// [`SplitFieldSpec`]. Records too short to have the field are left as is.
+pub struct SplitFieldStage {
+    spec: SplitFieldSpec,
+}
+
+impl SplitFieldStage {
+    pub fn new(spec: SplitFieldSpec) -> Self {
+        Self { spec }
+    }
+}
+
+impl RecordStage for SplitFieldStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        let index = self.spec.index;
+        if let Some(field) = record.get_mut(index) {
+            let pieces = self.spec.split(std::mem::take(field));
+            record.splice(index..=index, pieces);
+        }
+        StageAction::Keep
+    }
+}
+
+/// A stage that joins several fields into one, see [`MergeFieldsSpec`].
+///
+/// Headers are merged the same way, so the header names line up with the merged columns.
+pub struct MergeFieldsStage {
+    spec: MergeFieldsSpec,
+}
+
+impl MergeFieldsStage {
+    pub fn new(spec: MergeFieldsSpec) -> Self {
+        Self { spec }
+    }
+}
+
+impl RecordStage for MergeFieldsStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        self.spec.merge(record);
+        StageAction::Keep
+    }
+
+    #[inline]
+    fn header<'b>(&mut self, ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        self.process(ctx, record)
+    }
+}
+
+/// A stage that drops records with an empty field.
+///
+/// Every field is checked, or only the output positions in `fields` if given, where a record too
+/// short to have one of them counts as empty too.
+pub struct SkipEmptyStage {
+    fields: Option<Vec<FieldRange>>,
+}
+
+impl SkipEmptyStage {
+    pub fn new(fields: Option<Vec<FieldRange>>) -> Self {
+        Self { fields }
+    }
+}
+
+impl RecordStage for SkipEmptyStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        let empty = match &self.fields {
+            None => record.iter().any(|field| field.is_empty()),
+            Some(fields) => fields.iter().any(|range| {
+                let high = range.high.min(record.len().saturating_sub(1));
+                range.low >= record.len()
+                    || record[range.low..=high]
+                        .iter()
+                        .any(|field| field.is_empty())
+            }),
+        };
+        if empty {
+            StageAction::Skip
+        } else {
+            StageAction::Keep
+        }
+    }
+}
+
+/// A stage that replaces a field with what a [`ColumnPattern`] captures from it, see
+/// [`ColumnPattern::capture`]. Fields that don't match are left as is unless
+/// [`CaptureStage::clear_unmatched`] is set.
+pub struct CaptureStage {
+    pattern: ColumnPattern,
+    clear_unmatched: bool,
+}
+
+impl CaptureStage {
+    pub fn new(pattern: ColumnPattern) -> Self {
+        Self {
+            pattern,
+            clear_unmatched: false,
+        }
+    }
+
+    /// Empty fields that don't match instead of leaving them as is.
+    pub fn clear_unmatched(mut self, yes: bool) -> Self {
+        self.clear_unmatched = yes;
+        self
+    }
+}
+
+impl RecordStage for CaptureStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        if let Some(field) = record.get_mut(self.pattern.index) {
+            match self.pattern.capture(field) {
+                Some(value) => *field = Cow::Owned(value.to_vec()),
+                None if self.clear_unmatched => *field = Cow::Borrowed(&[]),
+                None => (),
+            }
+        }
+        StageAction::Keep
+    }
+}
+
+/// Which way a [`CaseStage`] changes the case of fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Case {
+    Upper,
+    Lower,
+}
+
+impl Case {
+    /// Change the case of a field.
+    ///
+    /// ASCII fields are changed in place, so a field is only copied if it is borrowed and has a
+    /// letter to change. Other UTF-8 goes through the full Unicode case mapping, which may change
+    /// its length, and invalid UTF-8 only has its ASCII letters changed.
+    pub fn apply(self, field: &mut Cow<[u8]>) {
+        if !field.is_ascii() {
+            if let Ok(value) = std::str::from_utf8(field) {
+                let mapped = match self {
+                    Case::Upper => value.to_uppercase(),
+                    Case::Lower => value.to_lowercase(),
+                };
+                if mapped.as_bytes() != field.as_ref() {
+                    *field = Cow::Owned(mapped.into_bytes());
+                }
+                return;
+            }
+        }
+        match self {
+            Case::Upper if field.iter().any(u8::is_ascii_lowercase) => {
+                field.to_mut().make_ascii_uppercase()
+            }
+            Case::Lower if field.iter().any(u8::is_ascii_uppercase) => {
+                field.to_mut().make_ascii_lowercase()
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A stage that changes the case of every field, or only the output positions in `fields` if given.
+pub struct CaseStage {
+    case: Case,
+    fields: Option<Vec<FieldRange>>,
+}
+
+impl CaseStage {
+    pub fn new(case: Case, fields: Option<Vec<FieldRange>>) -> Self {
+        Self { case, fields }
+    }
+}
+
+impl RecordStage for CaseStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        for (i, field) in record.iter_mut().enumerate() {
+            if self
+                .fields
+                .as_ref()
+                .map_or(true, |fields| fields.iter().any(|range| range.contains(i)))
+            {
+                self.case.apply(field);
+            }
+        }
+        StageAction::Keep
+    }
+}
+
+/// A stage that inserts a surrogate key derived from the record's [`Key`] as its first field.
+pub struct GenKeyStage {
+    key: Key,
+    kind: SurrogateKind,
+    header: Option<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl GenKeyStage {
+    pub fn new(key: Key, kind: SurrogateKind) -> Self {
+        Self {
+            key,
+            kind,
+            header: None,
+            buffer: vec![],
+        }
+    }
+
+    /// Insert `name` instead of a key into headers, an empty field is inserted otherwise.
+    pub fn header(mut self, name: Vec<u8>) -> Self {
+        self.header = Some(name);
+        self
+    }
+}
+
+impl RecordStage for GenKeyStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        self.key.extract_into(record, &mut self.buffer);
+        let mut value = Vec::with_capacity(36);
+        self.kind.write_into(&self.buffer, &mut value);
+        record.insert(0, Cow::Owned(value));
+        StageAction::Keep
+    }
+
+    fn header<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        let name = self.header.clone().unwrap_or_default();
+        record.insert(0, Cow::Owned(name));
+        StageAction::Keep
+    }
+}
+
+/// A stage that inserts a running row number as the first field.
+///
+/// Numbers count up from `start` by `step` across all inputs, or from `start` again for each input
+/// with [`EnumerateStage::restart_per_input`]. Headers get an empty field, or a name set with
+/// [`EnumerateStage::header`].
+pub struct EnumerateStage {
+    start: u64,
+    step: u64,
+    next: u64,
+    restart_per_input: bool,
+    header: Option<Vec<u8>>,
+}
+
+impl EnumerateStage {
+    pub fn new(start: u64, step: u64) -> Self {
+        Self {
+            start,
+            step,
+            next: start,
+            restart_per_input: false,
+            header: None,
+        }
+    }
+
+    /// Count from `start` again at the start of each input.
+    pub fn restart_per_input(mut self, yes: bool) -> Self {
+        self.restart_per_input = yes;
+        self
+    }
+
+    /// Insert `name` instead of an empty field into headers.
+    pub fn header(mut self, name: Vec<u8>) -> Self {
+        self.header = Some(name);
+        self
+    }
+}
+
+impl RecordStage for EnumerateStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        record.insert(0, Cow::Owned(self.next.to_string().into_bytes()));
+        self.next = self.next.saturating_add(self.step);
+        StageAction::Keep
+    }
+
+    fn header<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        let name = self.header.clone().unwrap_or_default();
+        record.insert(0, Cow::Owned(name));
+        StageAction::Keep
+    }
+
+    fn start_input(&mut self) {
+        if self.restart_per_input {
+            self.next = self.start;
+        }
+    }
+}
+
+/// A stage that appends `value` to records with fewer than `width` fields until they have `width`.
+///
+/// Longer records are left as is, and headers are padded too. With [`PadStage::to_header`] the width
+/// is that of the header of each input instead, which needs [`HeaderPolicy::Keep`].
+pub struct PadStage {
+    width: usize,
+    from_header: bool,
+    value: Vec<u8>,
+}
+
+impl PadStage {
+    pub fn new(width: usize, value: Vec<u8>) -> Self {
+        Self {
+            width,
+            from_header: false,
+            value,
+        }
+    }
+
+    /// Pad to as many fields as the header of the current input has.
+    pub fn to_header(value: Vec<u8>) -> Self {
+        Self {
+            width: 0,
+            from_header: true,
+            value,
+        }
+    }
+}
+
+impl RecordStage for PadStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        if record.len() < self.width {
+            record.resize(self.width, Cow::Owned(self.value.clone()));
+        }
+        StageAction::Keep
+    }
+
+    fn header<'b>(&mut self, ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        if self.from_header {
+            self.width = record.len();
+        }
+        self.process(ctx, record)
+    }
+}
+
+/// A stage that keeps the first `n` records and then stops reading input.
+pub struct HeadStage {
+    remaining: u64,
+}
+
+impl HeadStage {
+    pub fn new(n: u64) -> Self {
+        Self { remaining: n }
+    }
+}
+
+impl RecordStage for HeadStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, _record: &mut Record<'b>) -> StageAction {
+        match self.remaining {
+            0 => StageAction::Stop,
+            1 => {
+                self.remaining = 0;
+                StageAction::Last
+            }
+            _ => {
+                self.remaining -= 1;
+                StageAction::Keep
+            }
+        }
+    }
+}
+
+/// A stage that holds back the last `n` records and emits them once all input is read.
+pub struct TailStage {
+    n: usize,
+    records: VecDeque<Record<'static>>,
+}
+
+impl TailStage {
+    pub fn new(n: usize) -> Self {
+        Self {
+            n,
+            records: VecDeque::with_capacity(n),
+        }
+    }
+}
+
+impl RecordStage for TailStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        if self.n == 0 {
+            return StageAction::Skip;
+        }
+        // Reuse the oldest record's allocations where possible
+        let mut owned = if self.records.len() == self.n {
+            self.records.pop_front().unwrap()
+        } else {
+            Vec::with_capacity(record.len())
+        };
+        copy_record(record, &mut owned);
+        self.records.push_back(owned);
+        StageAction::Skip
+    }
+
+    fn finish(&mut self, emit: &mut Emit<'_>) -> io::Result<()> {
+        for record in self.records.drain(..) {
+            emit(record)?;
+        }
+        Ok(())
+    }
+}
+
+/// A stage that drops any record whose [`Key`] has already been seen.
+pub struct UniqueStage {
+    key: Key,
+    seen: HashSet<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl UniqueStage {
+    pub fn new(key: Key) -> Self {
+        Self {
+            key,
+            seen: HashSet::new(),
+            buffer: vec![],
+        }
+    }
+}
+
+impl RecordStage for UniqueStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        self.key.extract_into(record, &mut self.buffer);
+        if self.seen.contains(self.buffer.as_slice()) {
+            StageAction::Skip
+        } else {
+            self.seen.insert(self.buffer.clone());
+            StageAction::Keep
+        }
+    }
+}
+
+/// A stage that counts the distinct [`Key`]s of the records it sees, dropping them, and emits the
+/// count as a single record once all input is read.
+pub struct CountDistinctStage {
+    key: Key,
+    counter: DistinctCounter,
+    buffer: Vec<u8>,
+}
+
+impl CountDistinctStage {
+    pub fn new(key: Key, counter: DistinctCounter) -> Self {
+        Self {
+            key,
+            counter,
+            buffer: vec![],
+        }
+    }
+}
+
+impl RecordStage for CountDistinctStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        self.key.extract_into(record, &mut self.buffer);
+        self.counter.insert(&self.buffer);
+        StageAction::Skip
+    }
+
+    /// Only the count is written, so the header is dropped.
+    fn header<'b>(&mut self, _ctx: &RecordContext<'b>, _record: &mut Record<'b>) -> StageAction {
+        StageAction::Skip
+    }
+
+    fn finish(&mut self, emit: &mut Emit<'_>) -> io::Result<()> {
+        emit(vec![Cow::Owned(
+            self.counter.count().to_string().into_bytes(),
+        )])
+    }
+}
+
+/// A stage that keeps each record with probability `rate`.
+pub struct SampleStage {
+    rate: f64,
+    rng: Rng,
+}
+
+impl SampleStage {
+    pub fn new(rate: f64, seed: u64) -> Self {
+        Self {
+            rate,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl RecordStage for SampleStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, _record: &mut Record<'b>) -> StageAction {
+        if self.rng.next_f64() < self.rate {
+            StageAction::Keep
+        } else {
+            StageAction::Skip
+        }
+    }
+}
+
+/// A stage that holds back a uniform random sample of exactly `n` records, or all records if there
+/// are fewer, and emits them in input order once all input is read.
+pub struct ReservoirStage {
+    n: usize,
+    seen: u64,
+    rng: Rng,
+    /// The sampled records, tagged with their position in the input
+    records: Vec<(u64, Record<'static>)>,
+}
+
+impl ReservoirStage {
+    pub fn new(n: usize, seed: u64) -> Self {
+        Self {
+            n,
+            seen: 0,
+            rng: Rng::new(seed),
+            records: Vec::with_capacity(n),
+        }
+    }
+}
+
+impl RecordStage for ReservoirStage {
+    #[inline]
+    fn process<'b>(&mut self, _ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        self.seen += 1;
+        if self.records.len() < self.n {
+            let mut owned = Vec::with_capacity(record.len());
+            copy_record(record, &mut owned);
+            self.records.push((self.seen, owned));
+        } else {
+            let slot = self.rng.below(self.seen);
+            if let Some((position, owned)) = self.records.get_mut(slot as usize) {
+                *position = self.seen;
+                copy_record(record, owned);
+            }
+        }
+        StageAction::Skip
+    }
+
+    fn finish(&mut self, emit: &mut Emit<'_>) -> io::Result<()> {
+        self.records.sort_unstable_by_key(|(position, _)| *position);
+        for (_, record) in self.records.drain(..) {
+            emit(record)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy `record` into `owned`, reusing the allocations of any owned fields already there.
+fn copy_record(record: &[Cow<[u8]>], owned: &mut Record<'static>) {
+    owned.truncate(record.len());
+    for (i, field) in record.iter().enumerate() {
+        match owned.get_mut(i) {
+            Some(Cow::Owned(value)) => {
+                value.clear();
+                value.extend_from_slice(field);
+            }
+            _ => {
+                owned.truncate(i);
+                owned.push(Cow::Owned(field.to_vec()));
+            }
+        }
+    }
+}
+
+/// An ordered list of [`RecordStage`]s.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn RecordStage>>,
+    header: HeaderPolicy,
+    stopped: bool,
+}
+
+impl Pipeline {
+    /// Create an empty [`Pipeline`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a stage to the end of the pipeline.
+    pub fn push<S: RecordStage + 'static>(&mut self, stage: S) -> &mut Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Set how the first record of each input is treated.
+    pub fn header_policy(&mut self, header: HeaderPolicy) -> &mut Self {
+        self.header = header;
+        self
+    }
+
+    /// Whether there are any stages.
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Whether the first record is a header that is kept, see [`HeaderPolicy::Keep`].
+    pub fn keeps_header(&self) -> bool {
+        self.header == HeaderPolicy::Keep
+    }
+
+    /// Whether every record would be written out unchanged, so the pipeline can be skipped.
+    pub fn is_noop(&self) -> bool {
+        self.is_empty() && self.header != HeaderPolicy::Drop
+    }
+
+    /// Whether a stage has asked to stop processing input.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Let every stage know that the records of a new input are coming, see [`RecordStage::start_input`].
+    pub fn start_input(&mut self) {
+        for stage in self.stages.iter_mut() {
+            stage.start_input();
+        }
+    }
+
+    /// Run a record through every stage, stopping at the first stage that doesn't keep it.
+    #[inline]
+    pub fn process<'b>(&mut self, ctx: &RecordContext<'b>, record: &mut Record<'b>) -> StageAction {
+        if ctx.record_number == 1 {
+            match self.header {
+                HeaderPolicy::None => (),
+                HeaderPolicy::Keep => return run_header_stages(&mut self.stages, ctx, record),
+                HeaderPolicy::Drop => return StageAction::Skip,
+            }
+        }
+        let action = run_stages(&mut self.stages, ctx, record);
+        if action.stops() {
+            self.stopped = true;
+        }
+        action
+    }
+
+    /// Finish every stage in order, passing any records they emit through the stages after them and
+    /// then on to `output`.
+    pub fn finish<F>(&mut self, mut output: F) -> io::Result<()>
+    where
+        F: FnMut(&[Cow<[u8]>]) -> io::Result<()>,
+    {
+        let ctx = RecordContext::detached();
+        for i in 0..self.stages.len() {
+            let (head, tail) = self.stages.split_at_mut(i + 1);
+            head[i].finish(&mut |mut record| {
+                if run_stages(tail, &ctx, &mut record).keeps() {
+                    output(&record)
+                } else {
+                    Ok(())
+                }
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+fn run_stages<'b>(
+    stages: &mut [Box<dyn RecordStage>],
+    ctx: &RecordContext<'b>,
+    record: &mut Record<'b>,
+) -> StageAction {
+    let mut last = false;
+    for stage in stages.iter_mut() {
+        match stage.process(ctx, record) {
+            StageAction::Keep => (),
+            StageAction::Last => last = true,
+            StageAction::Skip if last => return StageAction::Stop,
+            action => return action,
+        }
+    }
+    if last {
+        StageAction::Last
+    } else {
+        StageAction::Keep
+    }
+}
+
+#[inline]
+fn run_header_stages<'b>(
+    stages: &mut [Box<dyn RecordStage>],
+    ctx: &RecordContext<'b>,
+    record: &mut Record<'b>,
+) -> StageAction {
+    for stage in stages.iter_mut() {
+        if !stage.header(ctx, record).keeps() {
+            return StageAction::Skip;
+        }
+    }
+    StageAction::Keep
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("stages", &self.stages.len())
+            .field("header", &self.header)
+            .field("stopped", &self.stopped)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Counts records and emits the count as a final record.
+    struct Count(u64);
+
+    impl RecordStage for Count {
+        fn process<'b>(
+            &mut self,
+            _ctx: &RecordContext<'b>,
+            _record: &mut Record<'b>,
+        ) -> StageAction {
+            self.0 += 1;
+            StageAction::Keep
+        }
+
+        fn finish(&mut self, emit: &mut Emit<'_>) -> io::Result<()> {
+            emit(vec![
+                Cow::Owned(b"count".to_vec()),
+                Cow::Owned(self.0.to_string().into_bytes()),
+            ])
+        }
+    }
+
+    struct StopAfter(u64);
+
+    impl RecordStage for StopAfter {
+        fn process<'b>(
+            &mut self,
+            ctx: &RecordContext<'b>,
+            _record: &mut Record<'b>,
+        ) -> StageAction {
+            if ctx.record_number > self.0 {
+                StageAction::Stop
+            } else {
+                StageAction::Keep
+            }
+        }
+    }
+
+    fn run(pipeline: &mut Pipeline, lines: &[&[u8]]) -> Vec<String> {
+        let mut out = vec![];
+        for (i, line) in lines.iter().enumerate() {
+            let ctx = RecordContext {
+                record_number: i as u64 + 1,
+                line,
+            };
+            let mut record: Record = line.split(|b| *b == b'\t').map(Cow::Borrowed).collect();
+            let action = pipeline.process(&ctx, &mut record);
+            if action.keeps() {
+                out.push(join(&record));
+            }
+            if action.stops() {
+                break;
+            }
+        }
+        pipeline
+            .finish(|record| {
+                out.push(join(record));
+                Ok(())
+            })
+            .unwrap();
+        out
+    }
+
+    fn join(record: &[Cow<[u8]>]) -> String {
+        let fields: Vec<_> = record.iter().map(|f| String::from_utf8_lossy(f)).collect();
+        fields.join(",")
+    }
+
+    #[test]
+    fn test_stages_run_in_order() {
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .push(FilterStage(|_: &RecordContext, record: &[Cow<[u8]>]| {
+                record[0].as_ref() != b"b"
+            }))
+            .push(MapFieldsStage(|i, value: &[u8]| {
+                (i == 1).then(|| value.to_ascii_uppercase())
+            }))
+            .push(InjectStage::new(0, b"x".to_vec()))
+            .push(Count(0));
+        assert_eq!(
+            run(&mut pipeline, &[b"a\tone", b"b\ttwo", b"c\tthree"]),
+            vec!["x,a,ONE", "x,c,THREE", "count,2"]
+        );
+    }
+
+    #[test]
+    fn test_emitted_records_go_through_later_stages() {
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .push(Count(0))
+            .push(InjectStage::new(99, b"end".to_vec()));
+        assert_eq!(
+            run(&mut pipeline, &[b"a", b"b"]),
+            vec!["a,end", "b,end", "count,2,end"]
+        );
+    }
+
+    #[test]
+    fn test_inject_header() {
+        let lines: &[&[u8]] = &[b"id\tn", b"1\ta"];
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(InjectStage::new(1, b"b1".to_vec()));
+        assert_eq!(run(&mut pipeline, lines), vec!["id,b1,n", "1,b1,a"]);
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(InjectStage::new(1, b"b1".to_vec()).header(b"batch".to_vec()));
+        assert_eq!(run(&mut pipeline, lines), vec!["id,batch,n", "1,b1,a"]);
+    }
+
+    #[test]
+    fn test_enumerate() {
+        let lines: &[&[u8]] = &[b"id", b"a", b"b"];
+        for restart in [false, true] {
+            let mut pipeline = Pipeline::new();
+            pipeline.header_policy(HeaderPolicy::Keep).push(
+                EnumerateStage::new(10, 5)
+                    .header(b"n".to_vec())
+                    .restart_per_input(restart),
+            );
+            let mut out = vec![];
+            for _ in 0..2 {
+                pipeline.start_input();
+                out.extend(run(&mut pipeline, lines));
+            }
+            let second = if restart {
+                ["n,id", "10,a", "15,b"]
+            } else {
+                ["n,id", "20,a", "25,b"]
+            };
+            assert_eq!(out[..3], ["n,id", "10,a", "15,b"]);
+            assert_eq!(out[3..], second);
+        }
+    }
+
+    #[test]
+    fn test_pad() {
+        let lines: &[&[u8]] = &[b"a\tb\tc", b"1", b"1\t2\t3\t4", b""];
+        let mut pipeline = Pipeline::new();
+        pipeline.push(PadStage::new(2, b"NA".to_vec()));
+        assert_eq!(
+            run(&mut pipeline, lines),
+            vec!["a,b,c", "1,NA", "1,2,3,4", ",NA"]
+        );
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(PadStage::to_header(vec![]));
+        assert_eq!(
+            run(&mut pipeline, lines),
+            vec!["a,b,c", "1,,", "1,2,3,4", ",,"]
+        );
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut pipeline = Pipeline::new();
+        pipeline.push(StopAfter(2));
+        assert_eq!(
+            run(&mut pipeline, &[b"a", b"b", b"c", b"d"]),
+            vec!["a", "b"]
+        );
+        assert!(pipeline.is_stopped());
+    }
+
+    #[test]
+    fn test_head_and_tail() {
+        let lines: &[&[u8]] = &[b"a", b"b", b"c", b"d", b"e"];
+        let mut pipeline = Pipeline::new();
+        pipeline.push(HeadStage::new(2));
+        assert_eq!(run(&mut pipeline, lines), vec!["a", "b"]);
+        assert!(pipeline.is_stopped());
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(TailStage::new(2));
+        assert_eq!(run(&mut pipeline, lines), vec!["d", "e"]);
+        assert!(!pipeline.is_stopped());
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(HeadStage::new(4)).push(TailStage::new(3));
+        assert_eq!(run(&mut pipeline, lines), vec!["b", "c", "d"]);
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(TailStage::new(0));
+        assert!(run(&mut pipeline, lines).is_empty());
+    }
+
+    #[test]
+    fn test_unique() {
+        let lines: &[&[u8]] = &[b"a\t1", b"b\t1", b"a\t1", b"a\t2", b"ab\t", b"a\tb"];
+        let mut pipeline = Pipeline::new();
+        pipeline.push(UniqueStage::new(Key::new(None)));
+        assert_eq!(
+            run(&mut pipeline, lines),
+            vec!["a,1", "b,1", "a,2", "ab,", "a,b"]
+        );
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(UniqueStage::new(Key::new(Some(
+            FieldRange::from_list("1").unwrap(),
+        ))));
+        assert_eq!(run(&mut pipeline, lines), vec!["a,1", "b,1", "ab,"]);
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(UniqueStage::new(Key::new(Some(
+            FieldRange::from_list("2-").unwrap(),
+        ))));
+        assert_eq!(run(&mut pipeline, lines), vec!["a,1", "a,2", "ab,", "a,b"]);
+    }
+
+    #[test]
+    fn test_count_distinct() {
+        let lines: &[&[u8]] = &[b"a\t1", b"b\t1", b"a\t1", b"a\t2"];
+        let mut pipeline = Pipeline::new();
+        pipeline.push(CountDistinctStage::new(
+            Key::new(None),
+            DistinctCounter::exact(),
+        ));
+        assert_eq!(run(&mut pipeline, lines), vec!["3"]);
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(CountDistinctStage::new(
+            Key::new(Some(FieldRange::from_list("2").unwrap())),
+            DistinctCounter::approx(crate::distinct::DEFAULT_PRECISION),
+        ));
+        assert_eq!(run(&mut pipeline, lines), vec!["2"]);
+    }
+
+    #[test]
+    fn test_gen_key() {
+        let lines: &[&[u8]] = &[b"name\tn", b"a\t1", b"b\t1", b"a\t2"];
+        let mut pipeline = Pipeline::new();
+        pipeline.header_policy(HeaderPolicy::Keep).push(
+            GenKeyStage::new(
+                Key::new(Some(FieldRange::from_list("1").unwrap())),
+                SurrogateKind::Xxh3,
+            )
+            .header(b"id".to_vec()),
+        );
+        let out = run(&mut pipeline, lines);
+        let keys: Vec<&str> = out.iter().map(|l| l.split(',').next().unwrap()).collect();
+        assert_eq!(keys[0], "id");
+        assert_eq!(out[1][16..], *",a,1");
+        assert_eq!(keys[1], keys[3]);
+        assert_ne!(keys[1], keys[2]);
+    }
+
+    #[test]
+    fn test_recode() {
+        let lines: &[&[u8]] = &[b"id\tcode", b"1\tA", b"2\tZ", b"3"];
+        let map = [
+            (b"A".to_vec(), b"Apple".to_vec()),
+            (b"code".to_vec(), b"x".to_vec()),
+        ];
+        let recoder = Recoder::new(map.into_iter().collect(), Some(b"?".to_vec()));
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(RecodeStage::new(1, recoder));
+        assert_eq!(
+            run(&mut pipeline, lines),
+            vec!["id,code", "1,Apple", "2,?", "3"]
+        );
+    }
+
+    #[test]
+    fn test_require_and_capture() {
+        let lines: &[&[u8]] = &[b"id\tname", b"1\tab-c", b"x\tcd-e", b"3\tfg"];
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(RequireStage::new(r"1:^\d+$".parse().unwrap()))
+            .push(CaptureStage::new(r"2:^(\w+)-".parse().unwrap()));
+        assert_eq!(run(&mut pipeline, lines), vec!["id,name", "1,ab", "3,fg"]);
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(CaptureStage::new(r"2:^(\w+)-".parse().unwrap()).clear_unmatched(true));
+        assert_eq!(run(&mut pipeline, lines), vec!["id,", "1,ab", "x,cd", "3,"]);
+    }
+
+    #[test]
+    fn test_expr_filter() {
+        let lines: &[&[u8]] = &[b"id\tscore", b"a\t31", b"b\tNA", b"c\t40"];
+        let expr = Expr::parse("col(2) > 30").unwrap();
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(ExprFilterStage::new(expr.clone()));
+        assert_eq!(run(&mut pipeline, lines), vec!["id,score", "a,31", "c,40"]);
+
+        // Without a header policy the header is data, and isn't a number either
+        let mut pipeline = Pipeline::new();
+        pipeline.push(ExprFilterStage::new(expr.clone()));
+        assert_eq!(run(&mut pipeline, lines), vec!["a,31", "c,40"]);
+
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(ExprFilterStage::new(expr).strict(true));
+        let mut out = vec![];
+        for (i, line) in lines.iter().enumerate() {
+            let ctx = RecordContext {
+                record_number: i as u64 + 1,
+                line,
+            };
+            let mut record: Record = line.split(|b| *b == b'\t').map(Cow::Borrowed).collect();
+            let action = pipeline.process(&ctx, &mut record);
+            if action.keeps() {
+                out.push(join(&record));
+            }
+            if action.stops() {
+                break;
+            }
+        }
+        assert_eq!(out, vec!["id,score", "a,31"]);
+        assert!(pipeline.is_stopped());
+        let err = pipeline.finish(|_| Ok(())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "record 3: Expected a number for col(2), found `NA`"
+        );
+    }
+
+    #[test]
+    fn test_split_field() {
+        let lines: &[&[u8]] = &[b"id\tann\tx", b"1\ta;b;c\ty", b"2\td\tz", b"3"];
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(SplitFieldStage::new("2:;:2".parse().unwrap()));
+        assert_eq!(
+            run(&mut pipeline, lines),
+            vec!["id,ann,x", "1,a,b,y", "2,d,z", "3"]
+        );
+    }
+
+    #[test]
+    fn test_merge_fields() {
+        let lines: &[&[u8]] = &[b"chrom\tpos\tx", b"chr1\t100\ty", b"chr2"];
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(MergeFieldsStage::new("1,2:_".parse().unwrap()));
+        assert_eq!(
+            run(&mut pipeline, lines),
+            vec!["chrom_pos,x", "chr1_100,y", "chr2"]
+        );
+    }
+
+    #[test]
+    fn test_skip_empty() {
+        let lines: &[&[u8]] = &[b"id\tname\tx", b"1\t\ty", b"\tb\tz", b"3\tc", b"4\td\t"];
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(SkipEmptyStage::new(None));
+        assert_eq!(run(&mut pipeline, lines), vec!["id,name,x", "3,c"]);
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(SkipEmptyStage::new(Some(
+            FieldRange::from_list("2").unwrap(),
+        )));
+        assert_eq!(
+            run(&mut pipeline, lines),
+            vec!["id,name,x", ",b,z", "3,c", "4,d,"]
+        );
+
+        // Missing fields are empty, open ended ranges only need the fields that are there
+        let mut pipeline = Pipeline::new();
+        pipeline.push(SkipEmptyStage::new(Some(
+            FieldRange::from_list("3").unwrap(),
+        )));
+        assert_eq!(run(&mut pipeline, lines), vec!["id,name,x", "1,,y", ",b,z"]);
+        let mut pipeline = Pipeline::new();
+        pipeline.push(SkipEmptyStage::new(Some(
+            FieldRange::from_list("2-").unwrap(),
+        )));
+        assert_eq!(run(&mut pipeline, lines), vec!["id,name,x", ",b,z", "3,c"]);
+    }
+
+    #[test]
+    fn test_case() {
+        let mut field: Cow<[u8]> = Cow::Borrowed(b"ABC-1");
+        Case::Upper.apply(&mut field);
+        assert!(matches!(field, Cow::Borrowed(_)));
+        Case::Lower.apply(&mut field);
+        assert_eq!(field.as_ref(), b"abc-1");
+        Case::Upper.apply(&mut field);
+        assert_eq!(field.as_ref(), b"ABC-1");
+
+        let mut field: Cow<[u8]> = Cow::Borrowed("straße é".as_bytes());
+        Case::Upper.apply(&mut field);
+        assert_eq!(field.as_ref(), "STRASSE É".as_bytes());
+        Case::Lower.apply(&mut field);
+        assert_eq!(field.as_ref(), "strasse é".as_bytes());
+
+        // Invalid UTF-8 only has its ASCII letters changed
+        let mut field: Cow<[u8]> = Cow::Borrowed(b"ab\xffC");
+        Case::Upper.apply(&mut field);
+        assert_eq!(field.as_ref(), b"AB\xffC");
+
+        let lines: &[&[u8]] = &[b"Id\tName\tX", b"1\tabc\tdEf"];
+        let mut pipeline = Pipeline::new();
+        pipeline.push(CaseStage::new(Case::Upper, None));
+        assert_eq!(run(&mut pipeline, lines), vec!["ID,NAME,X", "1,ABC,DEF"]);
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .header_policy(HeaderPolicy::Keep)
+            .push(CaseStage::new(
+                Case::Lower,
+                Some(FieldRange::from_list("3").unwrap()),
+            ));
+        assert_eq!(run(&mut pipeline, lines), vec!["Id,Name,X", "1,abc,def"]);
+    }
+
+    #[test]
+    fn test_header_policy() {
+        let lines: &[&[u8]] = &[b"id\tn", b"1\ta", b"2\tb", b"1\ta", b"3\tc"];
+        let run_with = |header: HeaderPolicy, stage: Box<dyn RecordStage>| {
+            let mut pipeline = Pipeline::new();
+            pipeline.header_policy(header);
+            pipeline.stages.push(stage);
+            run(&mut pipeline, lines)
+        };
+        let not_one =
+            || FilterStage(|_: &RecordContext, record: &[Cow<[u8]>]| record[0].as_ref() != b"1");
+
+        // Without a header the first record is data like any other
+        assert_eq!(
+            run_with(HeaderPolicy::None, Box::new(HeadStage::new(2))),
+            vec!["id,n", "1,a"]
+        );
+        assert_eq!(
+            run_with(
+                HeaderPolicy::None,
+                Box::new(CountDistinctStage::new(
+                    Key::new(None),
+                    DistinctCounter::exact()
+                ))
+            ),
+            vec!["4"]
+        );
+
+        // A kept header is written first and never counted as a row
+        assert_eq!(
+            run_with(HeaderPolicy::Keep, Box::new(HeadStage::new(2))),
+            vec!["id,n", "1,a", "2,b"]
+        );
+        assert_eq!(
+            run_with(HeaderPolicy::Keep, Box::new(TailStage::new(1))),
+            vec!["id,n", "3,c"]
+        );
+        assert_eq!(
+            run_with(HeaderPolicy::Keep, Box::new(not_one())),
+            vec!["id,n", "2,b", "3,c"]
+        );
+        assert_eq!(
+            run_with(
+                HeaderPolicy::Keep,
+                Box::new(UniqueStage::new(Key::new(None)))
+            ),
+            vec!["id,n", "1,a", "2,b", "3,c"]
+        );
+        assert_eq!(
+            run_with(HeaderPolicy::Keep, Box::new(ReservoirStage::new(10, 7))),
+            vec!["id,n", "1,a", "2,b", "1,a", "3,c"]
+        );
+        assert_eq!(
+            run_with(HeaderPolicy::Keep, Box::new(SampleStage::new(0.0, 7))),
+            vec!["id,n"]
+        );
+        assert_eq!(
+            run_with(
+                HeaderPolicy::Keep,
+                Box::new(CountDistinctStage::new(
+                    Key::new(None),
+                    DistinctCounter::exact()
+                ))
+            ),
+            vec!["3"]
+        );
+
+        // A dropped header doesn't reach any stage
+        assert_eq!(
+            run_with(HeaderPolicy::Drop, Box::new(HeadStage::new(2))),
+            vec!["1,a", "2,b"]
+        );
+        assert_eq!(
+            run_with(HeaderPolicy::Drop, Box::new(not_one())),
+            vec!["2,b", "3,c"]
+        );
+        let mut pipeline = Pipeline::new();
+        assert!(pipeline.is_noop());
+        assert!(pipeline.header_policy(HeaderPolicy::Keep).is_noop());
+        assert!(!pipeline.header_policy(HeaderPolicy::Drop).is_noop());
+    }
+
+    #[test]
+    fn test_sampling() {
+        let lines: Vec<Vec<u8>> = (0..1000).map(|i| i.to_string().into_bytes()).collect();
+        let lines: Vec<&[u8]> = lines.iter().map(|l| l.as_slice()).collect();
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(SampleStage::new(0.1, 42));
+        let sampled = run(&mut pipeline, &lines);
+        assert!((50..150).contains(&sampled.len()), "{}", sampled.len());
+        let mut pipeline = Pipeline::new();
+        pipeline.push(SampleStage::new(0.1, 42));
+        assert_eq!(run(&mut pipeline, &lines), sampled);
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(ReservoirStage::new(10, 7));
+        let sampled = run(&mut pipeline, &lines);
+        assert_eq!(sampled.len(), 10);
+        let positions: Vec<u64> = sampled.iter().map(|s| s.parse().unwrap()).collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+        assert!(positions.iter().any(|&p| p >= 10));
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(ReservoirStage::new(10, 7));
+        assert_eq!(run(&mut pipeline, &lines[..3]), vec!["0", "1", "2"]);
+    }
+
+    #[test]
+    fn test_last_then_skip_stops() {
+        let lines: &[&[u8]] = &[b"a", b"b", b"c"];
+        let mut pipeline = Pipeline::new();
+        pipeline.push(HeadStage::new(2)).push(FilterStage(
+            |_: &RecordContext, record: &[Cow<[u8]>]| record[0].as_ref() != b"b",
+        ));
+        assert_eq!(run(&mut pipeline, lines), vec!["a"]);
+        assert!(pipeline.is_stopped());
+    }
+
+    #[test]
+    fn test_core_runs_pipeline() {
+        use crate::{
+            core::{Core, CoreConfigBuilder, HckInput},
+            line_parser::SubStrLineParser,
+        };
+        use ripline::line_buffer::LineBufferBuilder;
+
+        let conf = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .output_delimiter(b",")
+            .fields(Some("2,1"))
+            .build()
+            .unwrap();
+        let (_, fields) = conf.parse_fields(&HckInput::<&str>::Stdin).unwrap();
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .push(FilterStage(|ctx: &RecordContext, _: &[Cow<[u8]>]| {
+                !ctx.line.starts_with(b"#")
+            }))
+            .push(StopAfter(4));
+
+        let data = b"1,a\n#2,b\n3,c\n4,d\n5,e\n";
+        for reader in [false, true] {
+            let mut line_buffer = LineBufferBuilder::new().build();
+            let mut output = vec![];
+            let mut core = Core::new(
+                &conf,
+                &fields,
+                SubStrLineParser::new(&fields, b","),
+                &mut line_buffer,
+            )
+            .pipeline(Some(&mut pipeline));
+            if reader {
+                core.hck_reader(&data[..], &mut output).unwrap();
+            } else {
+                core.hck_bytes(data, &mut output).unwrap();
+            }
+            assert_eq!(String::from_utf8(output).unwrap(), "a,1\nc,3\nd,4\n");
+        }
+    }
+}
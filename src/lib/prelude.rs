@@ -0,0 +1,23 @@
+//! The types most uses of hcklib need, for glob importing.
+//!
+//! ```
+//! use hcklib::prelude::*;
+//! ```
+//!
+//! Everything here follows semver: it is only removed or changed incompatibly in a new major
+//! version (or minor version while hcklib is below 1.0).
+pub use crate::{
+    core::{Core, CoreConfig, CoreConfigBuilder, FieldCount, HckInput, RaggedRows},
+    decompress::{Decompressor, Decompressors},
+    field_range::{FieldRange, RegexOrString},
+    line_parser::{
+        DelimLineParser, FixedWidthLineParser, LineParser, RegexLineParser, SubStrLineParser,
+        WhitespaceLineParser,
+    },
+    metrics::Metrics,
+    mmap::MmapChoice,
+    output::OutputFormat,
+    pipeline::{HeaderPolicy, Record, RecordContext, RecordStage, StageAction},
+    single_byte_delim_parser::SingleByteDelimParser,
+    sink::OutputSink,
+};
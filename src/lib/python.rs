@@ -0,0 +1,241 @@
+//! Python bindings, enabled with the `python` feature.
+//!
+//! This is the `hckpy` extension module, build it with `maturin build --release` using the
+//! `pyproject.toml` at the root of the repo. A `Selector` is created once with the same options as
+//! [`ConfigSpec`] and can then select fields from files or `bytes` objects in process:
+//!
+//! ```python
+//! import hckpy
+//!
+//! selector = hckpy.Selector(fields="1,3", delimiter=",")
+//! rows = selector.select_file("data.csv")  # [(b"a", b"c"), ...]
+//! rows = selector.select_bytes(b"a,b,c\n")
+//! selector.write_file("data.csv", "out.tsv")
+//! ```
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyBytes, PyTuple},
+};
+use ripline::line_buffer::LineBufferBuilder;
+
+use crate::{
+    core::{Core, CoreConfig, HckInput},
+    field_range::{FieldRange, FieldSpec},
+    line_parser::LineParser,
+    spec::ConfigSpec,
+};
+
+/// Where to select fields from.
+enum Source<'s> {
+    Path(&'s PathBuf),
+    Bytes(&'s [u8]),
+}
+
+/// Selects fields from files or `bytes`, see the module docs.
+#[pyclass(module = "hckpy", frozen)]
+pub struct Selector {
+    spec: ConfigSpec,
+}
+
+#[pymethods]
+impl Selector {
+    #[new]
+    #[pyo3(signature = (
+        fields = None,
+        header_fields = vec![],
+        exclude = None,
+        exclude_headers = vec![],
+        header_is_regex = false,
+        delimiter = String::from("\t"),
+        delimiter_is_regex = false,
+        output_delimiter = String::from("\t"),
+        fill = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        fields: Option<&str>,
+        header_fields: Vec<String>,
+        exclude: Option<&str>,
+        exclude_headers: Vec<String>,
+        header_is_regex: bool,
+        delimiter: String,
+        delimiter_is_regex: bool,
+        output_delimiter: String,
+        fill: Option<String>,
+    ) -> PyResult<Self> {
+        let parse = |list: &str| FieldSpec::parse(list).map_err(value_error);
+        let spec = ConfigSpec {
+            delimiter,
+            delimiter_is_regex,
+            output_delimiter,
+            fields: fields.map(parse).transpose()?,
+            header_fields,
+            exclude: exclude.map(parse).transpose()?,
+            exclude_headers,
+            header_is_regex,
+            fill,
+            ..ConfigSpec::default()
+        };
+        // Fail early on anything that doesn't depend on the input
+        spec.build().map_err(value_error)?;
+        Ok(Self { spec })
+    }
+
+    /// The selected fields of each line of the file at `path`, as tuples of `bytes`.
+    fn select_file<'py>(
+        &self,
+        py: Python<'py>,
+        path: PathBuf,
+    ) -> PyResult<Vec<Bound<'py, PyTuple>>> {
+        self.select(py, Source::Path(&path))
+    }
+
+    /// The selected fields of each line of `data`, as tuples of `bytes`.
+    fn select_bytes<'py>(
+        &self,
+        py: Python<'py>,
+        data: &[u8],
+    ) -> PyResult<Vec<Bound<'py, PyTuple>>> {
+        self.select(py, Source::Bytes(data))
+    }
+
+    /// Write the selected fields of the file at `path` to the file at `output`, like the `hck` binary.
+    fn write_file(&self, path: PathBuf, output: PathBuf) -> PyResult<()> {
+        let config = self.spec.build().map_err(value_error)?;
+        let (_, fields) = config
+            .parse_fields(&HckInput::Path(&path))
+            .map_err(value_error)?;
+        let mut writer = BufWriter::new(File::create(output)?);
+        run(&config, &fields, Job::Write(&path, &mut writer))?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Selector {
+    fn select<'py>(&self, py: Python<'py>, source: Source) -> PyResult<Vec<Bound<'py, PyTuple>>> {
+        let config = self.spec.build().map_err(value_error)?;
+        let fields = match &source {
+            Source::Path(path) => config.parse_fields(&HckInput::Path(path)).map(|(_, f)| f),
+            Source::Bytes(data) => config.parse_fields_from_bytes(data),
+        }
+        .map_err(value_error)?;
+        let mut rows = vec![];
+        let mut push = |row: &[&[u8]]| {
+            let fields = row.iter().map(|field| PyBytes::new(py, field));
+            let tuple =
+                PyTuple::new(py, fields).map_err(|err| io::Error::other(err.to_string()))?;
+            rows.push(tuple);
+            Ok(())
+        };
+        run(&config, &fields, Job::Visit(source, &mut push))?;
+        Ok(rows)
+    }
+}
+
+/// What to do with the selected fields.
+enum Job<'j> {
+    /// Call the function with the fields of each line of the source
+    Visit(Source<'j>, &'j mut dyn FnMut(&[&[u8]]) -> io::Result<()>),
+    /// Write the fields of each line of the file at the path to the writer
+    Write(&'j PathBuf, &'j mut dyn Write),
+}
+
+/// Run `job` with the line parser that `config` calls for.
+fn run(config: &CoreConfig, fields: &[FieldRange], job: Job) -> io::Result<()> {
+    let mut line_buffer = LineBufferBuilder::new().build();
+    let parser = config.line_parser(fields);
+    run_core(Core::new(config, fields, parser, &mut line_buffer), job)
+}
+
+fn run_core<'a, L: LineParser<'a>>(mut core: Core<'a, L>, job: Job) -> io::Result<()> {
+    match job {
+        Job::Visit(Source::Path(path), f) => core.for_each_row(HckInput::Path(path), f),
+        Job::Visit(Source::Bytes(data), f) => core.for_each_row_bytes(data, f),
+        Job::Write(path, output) => core.hck_input(HckInput::Path(path), output, None),
+    }
+}
+
+/// Build a [`PyValueError`] from any error.
+fn value_error<E: std::fmt::Display>(err: E) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+/// The `hckpy` module.
+#[pymodule]
+fn hckpy(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Selector>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn selector(fields: Option<&str>, header_fields: &[&str]) -> Selector {
+        let header_fields = header_fields.iter().map(|h| h.to_string()).collect();
+        let (delimiter, output_delimiter) = (String::from(","), String::from("|"));
+        Selector::new(
+            fields,
+            header_fields,
+            None,
+            vec![],
+            false,
+            delimiter,
+            false,
+            output_delimiter,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn extract(rows: Vec<Bound<PyTuple>>) -> Vec<Vec<Vec<u8>>> {
+        rows.iter().map(|row| row.extract().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_selector() {
+        Python::initialize();
+        Python::attach(|py| {
+            let rows = selector(Some("3,1"), &[])
+                .select_bytes(py, b"a,b,c\n1,2,3\n4\n")
+                .unwrap();
+            assert_eq!(
+                extract(rows),
+                vec![
+                    vec![b"c".to_vec(), b"a".to_vec()],
+                    vec![b"3".to_vec(), b"1".to_vec()],
+                    vec![b"4".to_vec()]
+                ]
+            );
+
+            let tmp = TempDir::new().unwrap();
+            let input = tmp.path().join("input.csv");
+            let output = tmp.path().join("output.csv");
+            std::fs::write(&input, "a,b,c\n1,2,3\n").unwrap();
+            let by_header = selector(None, &["c", "a"]);
+            let rows = by_header.select_file(py, input.clone()).unwrap();
+            assert_eq!(
+                extract(rows),
+                vec![
+                    vec![b"c".to_vec(), b"a".to_vec()],
+                    vec![b"3".to_vec(), b"1".to_vec()]
+                ]
+            );
+            let rows = by_header.select_bytes(py, b"c,a\n1,2\n").unwrap();
+            assert_eq!(extract(rows)[1], vec![b"1".to_vec(), b"2".to_vec()]);
+            by_header.write_file(input, output.clone()).unwrap();
+            assert_eq!(std::fs::read_to_string(output).unwrap(), "c|a\n3|1\n");
+
+            assert!(by_header.select_bytes(py, b"x,y\n").is_err());
+        });
+    }
+}
@@ -0,0 +1,195 @@
+//! Dictionary remapping of field values.
+//!
+//! A [`Recoder`] holds a mapping loaded from a two column, tab separated file, ex: codes in the
+//! first column and labels in the second, and replaces the values of a field that appear in it.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+/// Errors for parsing a [`RecodeSpec`] or loading its [`Recoder`].
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum RecodeError {
+    #[error("Invalid recode spec `{0}`, expected FIELD:PATH[:default=VALUE] like `2:map.tsv`")]
+    InvalidSpec(String),
+    #[error("Invalid recode field `{0}`, expected a 1-based position")]
+    InvalidField(String),
+    #[error("Failed to read mapping file {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{path}:{line}: expected two tab separated columns")]
+    MissingColumn { path: String, line: usize },
+}
+
+/// Which output field to recode and where its mapping lives, parsed from `FIELD:PATH[:default=VALUE]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecodeSpec {
+    /// The 0-based output position to recode
+    pub index: usize,
+    /// The mapping file
+    pub path: PathBuf,
+    /// The value for anything missing from the mapping, missing values are left as is without one
+    pub default: Option<String>,
+}
+
+impl FromStr for RecodeSpec {
+    type Err = RecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((field, rest)) = s.split_once(':') else {
+            return Err(RecodeError::InvalidSpec(s.to_owned()));
+        };
+        let (path, default) = match rest.rsplit_once(":default=") {
+            Some((path, default)) => (path, Some(default.to_owned())),
+            None => (rest, None),
+        };
+        if path.is_empty() {
+            return Err(RecodeError::InvalidSpec(s.to_owned()));
+        }
+        let index = match field.trim().parse::<usize>() {
+            Ok(position) if position > 0 => position - 1,
+            _ => return Err(RecodeError::InvalidField(field.to_owned())),
+        };
+        Ok(Self {
+            index,
+            path: PathBuf::from(path),
+            default,
+        })
+    }
+}
+
+/// Replaces values found in a mapping.
+#[derive(Debug, Clone, Default)]
+pub struct Recoder {
+    map: HashMap<Vec<u8>, Vec<u8>>,
+    default: Option<Vec<u8>>,
+}
+
+impl Recoder {
+    pub fn new(map: HashMap<Vec<u8>, Vec<u8>>, default: Option<Vec<u8>>) -> Self {
+        Self { map, default }
+    }
+
+    /// Load the mapping file for `spec`.
+    pub fn load(spec: &RecodeSpec) -> Result<Self, RecodeError> {
+        let path = spec.path.display().to_string();
+        let file = File::open(&spec.path).map_err(|source| RecodeError::Io {
+            path: path.clone(),
+            source,
+        })?;
+        let map = read_map(BufReader::new(file), &path)?;
+        Ok(Self::new(
+            map,
+            spec.default.as_ref().map(|d| d.as_bytes().to_vec()),
+        ))
+    }
+
+    /// The replacement for `value`, or `None` to leave it as is.
+    #[inline]
+    pub fn recode(&self, value: &[u8]) -> Option<&[u8]> {
+        self.map
+            .get(value)
+            .or(self.default.as_ref())
+            .map(Vec::as_slice)
+    }
+}
+
+/// Read `key<TAB>value` lines into a map, splitting on the first tab. Later lines win and blank
+/// lines are skipped.
+///
+/// `name` is only used for messages.
+pub fn read_map<R: BufRead>(
+    mut reader: R,
+    name: &str,
+) -> Result<HashMap<Vec<u8>, Vec<u8>>, RecodeError> {
+    let mut map = HashMap::new();
+    let mut line = vec![];
+    let mut number = 0;
+    loop {
+        line.clear();
+        let read = reader
+            .read_until(b'\n', &mut line)
+            .map_err(|source| RecodeError::Io {
+                path: name.to_owned(),
+                source,
+            })?;
+        if read == 0 {
+            return Ok(map);
+        }
+        number += 1;
+        let record = line.strip_suffix(b"\n").unwrap_or(&line);
+        let record = record.strip_suffix(b"\r").unwrap_or(record);
+        if record.is_empty() {
+            continue;
+        }
+        let Some(tab) = record.iter().position(|&b| b == b'\t') else {
+            return Err(RecodeError::MissingColumn {
+                path: name.to_owned(),
+                line: number,
+            });
+        };
+        map.insert(record[..tab].to_vec(), record[tab + 1..].to_vec());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(
+            "2:map.tsv".parse::<RecodeSpec>().unwrap(),
+            RecodeSpec {
+                index: 1,
+                path: PathBuf::from("map.tsv"),
+                default: None
+            }
+        );
+        assert_eq!(
+            "1:c:/maps/a.tsv:default=UNKNOWN"
+                .parse::<RecodeSpec>()
+                .unwrap(),
+            RecodeSpec {
+                index: 0,
+                path: PathBuf::from("c:/maps/a.tsv"),
+                default: Some(String::from("UNKNOWN"))
+            }
+        );
+        assert!(matches!(
+            "map.tsv".parse::<RecodeSpec>(),
+            Err(RecodeError::InvalidSpec(_))
+        ));
+        assert!(matches!(
+            "0:map.tsv".parse::<RecodeSpec>(),
+            Err(RecodeError::InvalidField(_))
+        ));
+        assert!(matches!(
+            "2:".parse::<RecodeSpec>(),
+            Err(RecodeError::InvalidSpec(_))
+        ));
+    }
+
+    #[test]
+    fn test_recode() {
+        let map = read_map(&b"A\tApple\r\n\nB\tBanana\tsplit\nA\tApricot\n"[..], "map").unwrap();
+        let recoder = Recoder::new(map.clone(), None);
+        assert_eq!(recoder.recode(b"A"), Some(&b"Apricot"[..]));
+        assert_eq!(recoder.recode(b"B"), Some(&b"Banana\tsplit"[..]));
+        assert_eq!(recoder.recode(b"C"), None);
+        let recoder = Recoder::new(map, Some(b"UNKNOWN".to_vec()));
+        assert_eq!(recoder.recode(b"C"), Some(&b"UNKNOWN"[..]));
+
+        let err = read_map(&b"A\tApple\nB\n"[..], "map").unwrap_err();
+        assert_eq!(err.to_string(), "map:2: expected two tab separated columns");
+    }
+}
@@ -3,28 +3,65 @@
 //! first look for both the line terminator and the separator in a single pass.
 //! Once the furthest right field has been parsed it switches to searching for
 //! just newlines.
+//!
+//! CRLF terminated lines are handled by searching for the `\n` and trimming the `\r`
+//! from the end of the final field on the line, so they run in fast mode like any other
+//! single byte terminator.
+//!
+//! The positions of the fields on each line are kept until the line is done, so fields can be
+//! written in a different order than they appear, ex: `-f 3,1`.
+//!
+//! With [`SingleByteDelimParser::vectored`] set, the selected fields of a whole buffer are
+//! gathered up and handed to the output with `write_vectored` instead of copied out one by one.
 use std::{
     cmp::min,
     io::{self, Write},
+    iter,
 };
 
 use ripline::LineTerminator;
 
-use crate::{core::JoinAppend, field_range::FieldRange};
+use crate::{
+    core::{write_unterminated, IoBatch, JoinAppend},
+    field_range::FieldRange,
+};
 
-/// A `SingleByteDelimParser` is a fast parser of fields from from a buffer.
+/// A `SingleByteDelimParser` is a fast parser of fields from a buffer.
+///
+/// [`CoreConfig::single_byte_parser`](crate::core::CoreConfig::single_byte_parser) builds one the
+/// way fast mode does, otherwise it can be set up with [`new`](Self::new) and the builder methods.
 pub struct SingleByteDelimParser<'a> {
-    /// newline aligned buffer, must end in newline
-    line_terminator: LineTerminator,
+    /// The terminator written after each record
+    output_terminator: LineTerminator,
     output_delimiter: &'a [u8],
-    fields: &'a [FieldRange],
+    /// The fields to select, in output order
+    fields: Vec<FieldRange>,
     sep: u8,
     /// The furthers right field
     max_field: usize,
     /// Current offset into the buffer
     offset: usize,
     newline: u8,
+    /// Whether to trim a `\r` preceding the newline
+    crlf: bool,
+    /// The `[start, end)` positions of the fields found on the current line
     line: Vec<(usize, usize)>,
+    /// The number of records processed
+    records: u64,
+    /// Whether to leave the terminator off the output for a final line that has none
+    mirror_missing_terminator: bool,
+    /// Whether to only count fields instead of writing them
+    count_only: bool,
+    /// The number of fields selected, only counted if `count_only` is set
+    selected: u64,
+    /// The value to write for selected fields that are missing from a line
+    fill: Option<&'a [u8]>,
+    /// Whether to write each buffer with vectored writes
+    vectored: bool,
+    /// Whether to skip empty fields, treating runs of `sep` as one
+    squeeze: bool,
+    /// Whether to write the number of fields on each line instead of the selected fields
+    count_fields: bool,
 }
 
 impl<'a> SingleByteDelimParser<'a> {
@@ -35,18 +72,90 @@ impl<'a> SingleByteDelimParser<'a> {
         fields: &'a [FieldRange],
         sep: u8,
     ) -> Self {
+        // A stable sort keeps ranges that share a position in the order the slow path writes them
+        let mut ordered = fields.to_vec();
+        ordered.sort_by_key(|f| f.pos);
         Self {
-            line_terminator,
+            output_terminator: line_terminator,
             output_delimiter,
-            fields,
+            fields: ordered,
             sep,
-            max_field: fields.last().map_or(usize::MAX, |f| f.high + 1),
+            max_field: fields
+                .iter()
+                .map(|f| f.high + 1)
+                .max()
+                .unwrap_or(usize::MAX),
             offset: 0,
             newline: line_terminator.as_byte(),
+            crlf: line_terminator.is_crlf(),
             line: vec![],
+            records: 0,
+            mirror_missing_terminator: false,
+            count_only: false,
+            selected: 0,
+            fill: None,
+            vectored: false,
+            squeeze: false,
+            count_fields: false,
         }
     }
 
+    /// Write `fill` in place of any selected fields a line is too short to have.
+    pub fn fill(mut self, fill: Option<&'a [u8]>) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Write `term` after each record instead of the input's line terminator.
+    pub fn output_terminator(mut self, term: LineTerminator) -> Self {
+        self.output_terminator = term;
+        self
+    }
+
+    /// Write a final line that has no line terminator without one, instead of adding it.
+    pub fn mirror_missing_terminator(mut self, mirror: bool) -> Self {
+        self.mirror_missing_terminator = mirror;
+        self
+    }
+
+    /// Only count the selected fields, writing nothing.
+    pub fn count_only(mut self, count_only: bool) -> Self {
+        self.count_only = count_only;
+        self
+    }
+
+    /// Treat runs of the separator as one, skipping empty fields like
+    /// [`SubStrLineParser::squeeze`](crate::line_parser::SubStrLineParser::squeeze).
+    pub fn squeeze(mut self, squeeze: bool) -> Self {
+        self.squeeze = squeeze;
+        self
+    }
+
+    /// Write the number of fields on each line instead of the selected fields, found by counting
+    /// separators without splitting lines up. Runs of the separator aren't squeezed.
+    pub fn count_fields(mut self, count_fields: bool) -> Self {
+        self.count_fields = count_fields;
+        self
+    }
+
+    /// Write the fields selected from each buffer with vectored writes.
+    pub fn vectored(mut self, vectored: bool) -> Self {
+        self.vectored = vectored;
+        self
+    }
+
+    /// The number of records processed across all buffers.
+    #[inline]
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// The number of fields selected across all buffers, if `count_only` is set.
+    #[inline]
+    pub fn fields(&self) -> u64 {
+        self.selected
+    }
+
     /// Clear all fields of the [`SingleByteDelimParser`].
     #[inline]
     pub fn reset(&mut self) {
@@ -55,44 +164,123 @@ impl<'a> SingleByteDelimParser<'a> {
 
     /// Parse fields from the lines found in buffer and write them to `output`.
     ///
-    /// **Note** The input buffer _must_ end with a newline.
+    /// The buffer must be newline aligned, except at the end of the input where the final line
+    /// may be missing its terminator.
     #[inline]
     pub fn process_buffer<W: Write>(
         &mut self,
         buffer: &[u8],
         mut output: W,
     ) -> Result<(), io::Error> {
-        // Advance pasts first newline
-        if let Some(byte) = buffer.first() {
-            if *byte == self.newline {
-                output.join_append(
-                    self.output_delimiter,
-                    std::iter::empty(),
-                    &self.line_terminator,
-                )?;
-                self.offset += 1;
-            }
+        if self.count_fields && !self.count_only {
+            return self.process_buffer_field_counts(buffer, output);
+        }
+        if self.vectored && !self.count_only {
+            return self.process_buffer_vectored(buffer, output);
         }
-
         while self.offset < buffer.len() {
-            self.fill_line(buffer)?;
+            let terminated = self.fill_line(buffer)?;
+            self.records += 1;
             let items = self.fields.iter().flat_map(|f| {
                 let slice = self
                     .line
                     .get(f.low..=min(f.high, self.line.len().saturating_sub(1)))
                     .unwrap_or(&[]);
-                slice.iter().map(|(start, stop)| &buffer[*start..=*stop])
+                let missing = self.fill.map_or(0, |_| f.count_from(self.line.len()));
+                slice
+                    .iter()
+                    .map(|(start, end)| &buffer[*start..*end])
+                    .chain(iter::repeat(self.fill.unwrap_or_default()).take(missing))
             });
-            output.join_append(self.output_delimiter, items, &self.line_terminator)?;
+            if self.count_only {
+                let count = items.count() as u64;
+                self.selected += count;
+            } else if terminated || !self.mirror_missing_terminator {
+                output.join_append(self.output_delimiter, items, &self.output_terminator)?;
+            } else {
+                write_unterminated(&mut output, &self.output_terminator, |buffer| {
+                    buffer.join_append(self.output_delimiter, items, &self.output_terminator)
+                })?;
+            }
             self.line.clear();
         }
         Ok(())
     }
 
+    /// [`process_buffer`](Self::process_buffer), gathering the fields into batches that are
+    /// written with `write_vectored`.
+    fn process_buffer_vectored<W: Write>(
+        &mut self,
+        buffer: &[u8],
+        mut output: W,
+    ) -> Result<(), io::Error> {
+        let term = self.output_terminator;
+        let fill = self.fill.unwrap_or_default();
+        let mut batch: IoBatch = IoBatch::new();
+        while self.offset < buffer.len() {
+            let terminated = self.fill_line(buffer)?;
+            self.records += 1;
+            let mut first = true;
+            for f in &self.fields {
+                let slice = self
+                    .line
+                    .get(f.low..=min(f.high, self.line.len().saturating_sub(1)))
+                    .unwrap_or(&[]);
+                let missing = self.fill.map_or(0, |_| f.count_from(self.line.len()));
+                let items = slice
+                    .iter()
+                    .map(|(start, end)| &buffer[*start..*end])
+                    .chain(iter::repeat(fill).take(missing));
+                for item in items {
+                    if !first {
+                        batch.push(&mut output, self.output_delimiter)?;
+                    }
+                    first = false;
+                    batch.push(&mut output, item)?;
+                }
+            }
+            if terminated || !self.mirror_missing_terminator {
+                batch.push(&mut output, term.as_bytes())?;
+            }
+            self.line.clear();
+        }
+        batch.flush(&mut output)
+    }
+
+    /// [`process_buffer`](Self::process_buffer) for [`count_fields`](Self::count_fields).
+    fn process_buffer_field_counts<W: Write>(
+        &mut self,
+        buffer: &[u8],
+        mut output: W,
+    ) -> Result<(), io::Error> {
+        let mut count = Vec::with_capacity(20);
+        while self.offset < buffer.len() {
+            let rest = &buffer[self.offset..];
+            let (mut line, terminated) = match memchr::memchr(self.newline, rest) {
+                Some(end) => (&rest[..end], true),
+                None => (rest, false),
+            };
+            self.offset += line.len() + usize::from(terminated);
+            if terminated && self.crlf {
+                line = line.strip_suffix(b"\r").unwrap_or(line);
+            }
+            self.records += 1;
+            count.clear();
+            write!(count, "{}", memchr::memchr_iter(self.sep, line).count() + 1)?;
+            output.write_all(&count)?;
+            if terminated || !self.mirror_missing_terminator {
+                output.write_all(self.output_terminator.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
     /// Fill `line` with the start/end positions of found columns
     /// The positions are relative to the held buffer
+    ///
+    /// Returns whether the line ended with a newline, rather than the end of the buffer.
     #[inline]
-    fn fill_line(&mut self, buffer: &[u8]) -> Result<(), io::Error> {
+    fn fill_line(&mut self, buffer: &[u8]) -> Result<bool, io::Error> {
         let mut field_count = 0;
         let iter = memchr::memchr2_iter(self.sep, self.newline, &buffer[self.offset..]);
 
@@ -100,28 +288,326 @@ impl<'a> SingleByteDelimParser<'a> {
         let mut found_newline = false;
 
         for index in iter {
-            if buffer[self.offset + index] == self.sep {
-                field_count += 1;
-            } else {
-                found_newline = true;
-            }
+            let is_sep = buffer[self.offset + index] == self.sep;
+            found_newline = !is_sep;
 
-            self.line
-                .push((self.offset + line_offset, self.offset + index - 1));
+            let start = self.offset + line_offset;
+            let mut end = self.offset + index;
+            if found_newline && self.crlf {
+                // Only a full `\r\n` ends a CRLF line, a bare `\n` is part of the last field
+                if index > line_offset && buffer[end - 1] == b'\r' {
+                    end -= 1;
+                } else {
+                    end += 1;
+                }
+            }
             line_offset = index + 1;
 
+            if self.squeeze && start == end {
+                // Runs of the separator count as one, so there are no empty fields
+                if found_newline {
+                    break;
+                }
+                continue;
+            }
+            if is_sep {
+                field_count += 1;
+            }
+            self.line.push((start, end));
+
             if found_newline || field_count == self.max_field {
                 break;
             }
         }
 
-        if !found_newline {
-            let end = memchr::memchr(self.newline, &buffer[self.offset + line_offset..])
-                .ok_or(io::ErrorKind::InvalidData)?;
-            self.offset += line_offset + end + 1;
-        } else {
+        if found_newline {
             self.offset += line_offset;
+            return Ok(true);
+        }
+        match memchr::memchr(self.newline, &buffer[self.offset + line_offset..]) {
+            Some(end) => {
+                self.offset += line_offset + end + 1;
+                Ok(true)
+            }
+            None => {
+                // The final line has no terminator, so the rest of the buffer is its last field
+                let empty = self.offset + line_offset == buffer.len();
+                if field_count < self.max_field && !(self.squeeze && empty) {
+                    self.line.push((self.offset + line_offset, buffer.len()));
+                }
+                self.offset = buffer.len();
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        core::{Core, CoreConfigBuilder, FieldCount, HckInput},
+        line_parser::SubStrLineParser,
+    };
+    use bstr::ByteSlice;
+    use ripline::line_buffer::LineBufferBuilder;
+
+    fn fields(list: &str) -> Vec<FieldRange> {
+        let conf = CoreConfigBuilder::new().fields(Some(list)).build().unwrap();
+        conf.parse_fields(&HckInput::<&str>::Stdin).unwrap().1
+    }
+
+    fn process(data: &[u8], list: &str, term: LineTerminator) -> String {
+        let fields = fields(list);
+        let mut parser = SingleByteDelimParser::new(term, b"|", &fields, b',');
+        let mut output = vec![];
+        parser.process_buffer(data, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_crlf_trims_last_field() {
+        let data = b"a,b\r\nc,d,e\r\n\r\nf\r\n,\r\n";
+        assert_eq!(
+            process(data, "2", LineTerminator::crlf()),
+            "b\r\nd\r\n\r\n\r\n\r\n"
+        );
+        assert_eq!(
+            process(data, "1-", LineTerminator::crlf()),
+            "a|b\r\nc|d|e\r\n\r\nf\r\n|\r\n"
+        );
+        // Without CRLF the `\r` is just data
+        assert_eq!(
+            process(data, "2", LineTerminator::default()),
+            "b\r\nd\n\n\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_leading_empty_fields() {
+        let data = b"\n,a\n,,\n";
+        assert_eq!(process(data, "1,2", LineTerminator::default()), "\n|a\n|\n");
+    }
+
+    #[test]
+    fn test_final_line_without_terminator() {
+        let data = b"a,b,c\n1,2,3";
+        assert_eq!(
+            process(data, "1-", LineTerminator::default()),
+            "a|b|c\n1|2|3\n"
+        );
+        // The field search stops before the end of the buffer
+        assert_eq!(process(data, "1", LineTerminator::default()), "a\n1\n");
+        assert_eq!(process(b"a,b\n,", "2", LineTerminator::default()), "b\n\n");
+
+        let fields = fields("2-");
+        let mut parser = SingleByteDelimParser::new(LineTerminator::crlf(), b"|", &fields, b',')
+            .mirror_missing_terminator(true);
+        let mut output = vec![];
+        parser
+            .process_buffer(b"a,b,c\r\n1,2,3", &mut output)
+            .unwrap();
+        assert_eq!(output, b"b|c\r\n2|3");
+        assert_eq!(parser.records(), 2);
+    }
+
+    #[test]
+    fn test_reordered_fields() {
+        let data = b"a,b,c,d\n1,2\n";
+        assert_eq!(process(data, "3,1", LineTerminator::default()), "c|a\n1\n");
+        assert_eq!(
+            process(data, "4-,1-2", LineTerminator::default()),
+            "d|a|b\n1|2\n"
+        );
+    }
+
+    #[test]
+    fn test_crlf_matches_slow_path() {
+        let data = b"id,name,score\r\n1,alice,0.5\r\n2,bob\r\n\r\n3,carol,1.25,extra\r\n";
+        for list in ["1", "2-3", "3", "1-", "4"] {
+            let fields = fields(list);
+            let conf = CoreConfigBuilder::new()
+                .delimiter(b",")
+                .output_delimiter(b"|")
+                .line_terminator(LineTerminator::crlf())
+                .build()
+                .unwrap();
+            let mut line_buffer = LineBufferBuilder::new().build();
+            let mut core = Core::new(
+                &conf,
+                &fields,
+                SubStrLineParser::new(&fields, b","),
+                &mut line_buffer,
+            );
+            let mut slow = vec![];
+            core.hck_bytes(data, &mut slow).unwrap();
+            let mut fast = vec![];
+            core.hck_bytes_fast(data, &mut fast).unwrap();
+            assert_eq!(
+                String::from_utf8(fast).unwrap(),
+                String::from_utf8(slow).unwrap(),
+                "fields {}",
+                list
+            );
+        }
+    }
+
+    #[test]
+    fn test_count_fields_matches_slow_path() {
+        let data = b"id,name,score\r\n1,alice,0.5\r\n2,bob\r\n\r\n,,3,carol,1.25,extra";
+        for term in [LineTerminator::default(), LineTerminator::crlf()] {
+            let fields = fields("2");
+            let conf = CoreConfigBuilder::new()
+                .delimiter(b",")
+                .line_terminator(term)
+                .field_count(Some(FieldCount::Only))
+                .build()
+                .unwrap();
+            let mut line_buffer = LineBufferBuilder::new().build();
+            let mut core = Core::new(
+                &conf,
+                &fields,
+                SubStrLineParser::new(&fields, b","),
+                &mut line_buffer,
+            );
+            let mut slow = vec![];
+            core.hck_bytes(data, &mut slow).unwrap();
+            let mut fast = vec![];
+            core.hck_bytes_fast(data, &mut fast).unwrap();
+            assert_eq!(fast.as_bstr(), slow.as_bstr());
+            assert_eq!(
+                fast.lines().collect::<Vec<_>>(),
+                [&b"3"[..], b"3", b"2", b"1", b"6"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_reordered_matches_slow_path() {
+        let data = b"a,b,c,d,e\n1,2\n\nx,y,z,w,v,u\n";
+        for list in ["3,1", "4-,1-2", "2,5,1", "5-,1", "1,3,2"] {
+            for fill in [None, Some(&b"NA"[..])] {
+                let fields = fields(list);
+                let conf = CoreConfigBuilder::new()
+                    .delimiter(b",")
+                    .output_delimiter(b"|")
+                    .fill(fill)
+                    .build()
+                    .unwrap();
+                let mut line_buffer = LineBufferBuilder::new().build();
+                let mut core = Core::new(
+                    &conf,
+                    &fields,
+                    SubStrLineParser::new(&fields, b",").fill(fill),
+                    &mut line_buffer,
+                );
+                let mut slow = vec![];
+                core.hck_bytes(data, &mut slow).unwrap();
+                let mut fast = vec![];
+                core.hck_bytes_fast(data, &mut fast).unwrap();
+                assert_eq!(
+                    String::from_utf8(fast).unwrap(),
+                    String::from_utf8(slow).unwrap(),
+                    "fields {}",
+                    list
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_squeeze_matches_slow_path() {
+        let inputs: [&[u8]; 3] = [
+            b",,a,,b,c,,\n,\n\nx,y\n,,,z",
+            b"a,b,,,\n,,,\n1,,2,,3,,4,,5,,6",
+            b"\n,,\n,",
+        ];
+        for data in inputs {
+            for list in ["1-", "2", "3,1", "1,5-"] {
+                for fill in [None, Some(&b"NA"[..])] {
+                    let fields = fields(list);
+                    let conf = CoreConfigBuilder::new()
+                        .delimiter(b",")
+                        .squeeze(true)
+                        .fill(fill)
+                        .build()
+                        .unwrap();
+                    let mut line_buffer = LineBufferBuilder::new().build();
+                    let mut core = Core::new(
+                        &conf,
+                        &fields,
+                        SubStrLineParser::new(&fields, b",")
+                            .fill(fill)
+                            .squeeze(true),
+                        &mut line_buffer,
+                    );
+                    let mut slow = vec![];
+                    core.hck_bytes(data, &mut slow).unwrap();
+                    let mut fast = vec![];
+                    core.hck_bytes_fast(data, &mut fast).unwrap();
+                    assert_eq!(
+                        String::from_utf8(fast).unwrap(),
+                        String::from_utf8(slow).unwrap(),
+                        "fields {}",
+                        list
+                    );
+                }
+            }
+        }
+        let fields = fields("1-");
+        let mut parser = SingleByteDelimParser::new(LineTerminator::default(), b"|", &fields, b',')
+            .squeeze(true);
+        let mut output = vec![];
+        parser.process_buffer(b",,a,,b,\n", &mut output).unwrap();
+        assert_eq!(output, b"a|b\n");
+    }
+
+    /// Writes at most 3 bytes per call, with no vectored support.
+    struct Trickle(Vec<u8>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_vectored_matches_copied() {
+        // Enough fields to take more than one batch, and a final line with no terminator
+        let mut data = b"a,b,,d,e\r\n1,2\r\n\r\n".repeat(300);
+        data.extend_from_slice(b"x,y,z");
+        for list in ["1-", "3,1", "2,5"] {
+            for fill in [None, Some(&b"NA"[..])] {
+                for mirror in [true, false] {
+                    let fields = fields(list);
+                    let parser = || {
+                        SingleByteDelimParser::new(LineTerminator::crlf(), b"|", &fields, b',')
+                            .mirror_missing_terminator(mirror)
+                            .fill(fill)
+                    };
+                    let mut copied = vec![];
+                    parser().process_buffer(&data, &mut copied).unwrap();
+                    let mut vectored = vec![];
+                    let mut vectored_parser = parser().vectored(true);
+                    vectored_parser
+                        .process_buffer(&data, &mut vectored)
+                        .unwrap();
+                    assert_eq!(vectored, copied, "fields {}", list);
+                    assert_eq!(vectored_parser.records(), 901);
+                    let mut trickle = Trickle(vec![]);
+                    parser()
+                        .vectored(true)
+                        .process_buffer(&data, &mut trickle)
+                        .unwrap();
+                    assert_eq!(trickle.0, copied, "fields {}", list);
+                }
+            }
         }
-        Ok(())
     }
 }
@@ -0,0 +1,601 @@
+//! Output sinks.
+//!
+//! An [`OutputSink`] is a [`Write`] that also knows how to finalize itself, flushing buffers,
+//! writing compression footers, and closing any files it opened. Sinks wrap each other, so a
+//! compressed, split, and tee'd output is assembled from the pieces here and handed to
+//! [`Core`](crate::core::Core) as a plain writer. [`OutputSink::finish`] must be called once all
+//! records are written.
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, IoSlice, LineWriter, Write},
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "native")]
+use flate2::Compression;
+#[cfg(feature = "native")]
+use gzp::{deflate::Bgzf, ZBuilder, ZWriter};
+
+/// A [`Write`] that must be finalized with [`OutputSink::finish`].
+pub trait OutputSink: Write {
+    /// Flush all buffered output and finalize the sink.
+    ///
+    /// Nothing may be written to the sink after this is called.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl<S: OutputSink + ?Sized> OutputSink for Box<S> {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+impl<S: OutputSink + ?Sized> OutputSink for &mut S {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+impl OutputSink for Vec<u8> {
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A buffered sink over any writer.
+pub struct BufferedSink<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl<W: Write> BufferedSink<W> {
+    /// Create a [`BufferedSink`] with the default buffer capacity.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+        }
+    }
+
+    /// Create a [`BufferedSink`] with a buffer of `capacity` bytes.
+    pub fn with_capacity(capacity: usize, writer: W) -> Self {
+        Self {
+            writer: BufWriter::with_capacity(capacity, writer),
+        }
+    }
+}
+
+impl<W: Write> Write for BufferedSink<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    #[inline]
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.writer.write_vectored(bufs)
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> OutputSink for BufferedSink<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A sink that flushes every line, for output someone is watching as it is written.
+///
+/// Lines are flushed on `\n`, so a custom record separator is flushed as the buffer fills.
+pub struct LineBufferedSink<W: Write> {
+    writer: LineWriter<W>,
+}
+
+impl<W: Write> LineBufferedSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: LineWriter::new(writer),
+        }
+    }
+}
+
+impl<W: Write> Write for LineBufferedSink<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    #[inline]
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.writer.write_vectored(bufs)
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> OutputSink for LineBufferedSink<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A buffered sink that writes out its buffer once it holds `flush_size` bytes, but lets it grow to
+/// `max_buffer` bytes so a write that doesn't fit in what's left of `flush_size` is still buffered
+/// whole.
+///
+/// A [`BufferedSink`] is the same with both sizes equal to its capacity. Writes larger than
+/// `max_buffer` go straight to the writer once the buffer is written out.
+pub struct BoundedSink<W: Write> {
+    writer: W,
+    buffer: Vec<u8>,
+    flush_size: usize,
+    max_buffer: usize,
+}
+
+impl<W: Write> BoundedSink<W> {
+    /// Create a [`BoundedSink`], `max_buffer` is raised to `flush_size` if it is smaller.
+    pub fn new(flush_size: usize, max_buffer: usize, writer: W) -> Self {
+        Self {
+            writer,
+            buffer: Vec::with_capacity(flush_size),
+            flush_size,
+            max_buffer: max_buffer.max(flush_size),
+        }
+    }
+
+    fn write_buffer(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.writer.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Make room for `len` more bytes, returning whether they fit in the buffer.
+    #[inline]
+    fn reserve(&mut self, len: usize) -> io::Result<bool> {
+        if self.buffer.len() + len > self.max_buffer {
+            self.write_buffer()?;
+        }
+        Ok(len <= self.max_buffer)
+    }
+
+    #[inline]
+    fn written(&mut self, len: usize) -> io::Result<usize> {
+        if self.buffer.len() >= self.flush_size {
+            self.write_buffer()?;
+        }
+        Ok(len)
+    }
+}
+
+impl<W: Write> Write for BoundedSink<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.reserve(buf.len())? {
+            return self.writer.write(buf);
+        }
+        self.buffer.extend_from_slice(buf);
+        self.written(buf.len())
+    }
+
+    #[inline]
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        if !self.reserve(len)? {
+            return self.writer.write_vectored(bufs);
+        }
+        for buf in bufs {
+            self.buffer.extend_from_slice(buf);
+        }
+        self.written(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffer()?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> OutputSink for BoundedSink<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+#[cfg(feature = "native")]
+/// A BGZF compressed sink, optionally compressing on multiple threads.
+///
+/// Writes are buffered before they reach the compressor, so records written in pieces, or with
+/// `write_vectored`, don't each pay for a call into it.
+pub struct CompressedSink {
+    writer: BufWriter<Box<dyn ZWriter>>,
+}
+
+#[cfg(feature = "native")]
+impl CompressedSink {
+    /// Compress to `writer` with the given compression `level`, using `threads` compression threads.
+    ///
+    /// Zero `threads` compresses on the calling thread.
+    pub fn bgzf<W: Write + Send + 'static>(writer: W, level: u32, threads: usize) -> Self {
+        Self {
+            writer: BufWriter::new(
+                ZBuilder::<Bgzf, _>::new()
+                    .compression_level(Compression::new(level))
+                    .num_threads(threads)
+                    .from_writer(writer),
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+impl Write for CompressedSink {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    #[inline]
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.writer.write_vectored(bufs)
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(feature = "native")]
+impl OutputSink for CompressedSink {
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_mut().finish().map_err(io::Error::other)
+    }
+}
+
+/// A sink that makes the file it writes to durable once it is finished.
+///
+/// After the inner sink finishes, the file and then the directory holding it are synced to disk,
+/// so the output survives a crash once [`OutputSink::finish`] returns.
+pub struct FsyncSink<S> {
+    inner: S,
+    /// A handle on the file `inner` writes to
+    file: File,
+    path: PathBuf,
+}
+
+impl<S: OutputSink> FsyncSink<S> {
+    /// Create an [`FsyncSink`] that syncs `file`, found at `path`, once `inner` is finished.
+    pub fn new(inner: S, file: File, path: PathBuf) -> Self {
+        Self { inner, file, path }
+    }
+}
+
+impl<S: OutputSink> Write for FsyncSink<S> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    #[inline]
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.inner.write_vectored(bufs)
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: OutputSink> OutputSink for FsyncSink<S> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.inner.finish()?;
+        self.file.sync_all()?;
+        sync_parent(&self.path)
+    }
+}
+
+/// Sync the directory holding `path`, so that a newly created file's directory entry is durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+/// Directories can't be opened to sync them off of unix.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// A sink that writes everything to two sinks.
+pub struct TeeSink<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: OutputSink, B: OutputSink> TeeSink<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+impl<A: OutputSink, B: OutputSink> Write for TeeSink<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Both sides must see the same bytes, so don't allow partial writes
+        self.first.write_all(buf)?;
+        self.second.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+impl<A: OutputSink, B: OutputSink> OutputSink for TeeSink<A, B> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.first.finish()?;
+        self.second.finish()
+    }
+}
+
+/// Creates the sink for the `n`th (0-based) chunk of a [`SplitSink`].
+pub type ChunkOpener = Box<dyn FnMut(usize) -> io::Result<Box<dyn OutputSink>>>;
+
+/// A sink that starts a new chunk every `records_per_chunk` records.
+///
+/// Records are counted by line terminator, so a record is never split across two chunks.
+pub struct SplitSink {
+    open: ChunkOpener,
+    terminator: u8,
+    records_per_chunk: u64,
+    records: u64,
+    chunks: usize,
+    current: Option<Box<dyn OutputSink>>,
+}
+
+impl SplitSink {
+    /// Create a [`SplitSink`], chunks are opened lazily with `open`.
+    pub fn new(records_per_chunk: u64, terminator: u8, open: ChunkOpener) -> Self {
+        Self {
+            open,
+            terminator,
+            records_per_chunk: records_per_chunk.max(1),
+            records: 0,
+            chunks: 0,
+            current: None,
+        }
+    }
+
+    /// The number of chunks opened so far.
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+
+    /// The current chunk, opening it if needed.
+    fn current(&mut self) -> io::Result<&mut Box<dyn OutputSink>> {
+        if self.current.is_none() {
+            self.current = Some((self.open)(self.chunks)?);
+            self.chunks += 1;
+        }
+        Ok(self.current.as_mut().unwrap())
+    }
+}
+
+impl fmt::Debug for SplitSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SplitSink")
+            .field("records_per_chunk", &self.records_per_chunk)
+            .field("records", &self.records)
+            .field("chunks", &self.chunks)
+            .finish()
+    }
+}
+
+impl Write for SplitSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let remaining = (self.records_per_chunk - self.records) as usize;
+            // Find the end of the last record that still fits in this chunk
+            match memchr::memchr_iter(self.terminator, rest).nth(remaining - 1) {
+                Some(end) => {
+                    self.current()?.write_all(&rest[..=end])?;
+                    if let Some(mut chunk) = self.current.take() {
+                        chunk.finish()?;
+                    }
+                    self.records = 0;
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    self.records += memchr::memchr_iter(self.terminator, rest).count() as u64;
+                    self.current()?.write_all(rest)?;
+                    rest = &[];
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.current.as_mut() {
+            Some(chunk) => chunk.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl OutputSink for SplitSink {
+    fn finish(&mut self) -> io::Result<()> {
+        match self.current.take() {
+            Some(mut chunk) => chunk.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{self, File};
+    use tempfile::TempDir;
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn test_compressed_sink() {
+        use flate2::read::MultiGzDecoder;
+        use std::io::Read;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("out.gz");
+        let mut sink = CompressedSink::bgzf(File::create(&path).unwrap(), 6, 0);
+        sink.write_all(b"a\tb\n1\t2\n").unwrap();
+        sink.finish().unwrap();
+
+        let mut out = String::new();
+        MultiGzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, "a\tb\n1\t2\n");
+    }
+
+    /// A writer that records how its input arrived.
+    struct Writes(Vec<Vec<u8>>);
+
+    impl Write for &mut Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_line_buffered_sink() {
+        let mut writes = Writes(vec![]);
+        let mut sink = LineBufferedSink::new(&mut writes);
+        sink.write_all(b"a\tb").unwrap();
+        sink.write_all(b"\n1").unwrap();
+        sink.write_all(b"\t2").unwrap();
+        sink.finish().unwrap();
+        drop(sink);
+        // Each line is written as soon as it ends
+        assert_eq!(writes.0, vec![b"a\tb\n".to_vec(), b"1\t2".to_vec()]);
+    }
+
+    #[test]
+    fn test_bounded_sink() {
+        let mut writes = Writes(vec![]);
+        let mut sink = BoundedSink::new(4, 8, &mut writes);
+        // Buffered until there are at least 4 bytes
+        sink.write_all(b"ab").unwrap();
+        sink.write_all(b"cdef").unwrap();
+        // Doesn't fit in what's left of 8 bytes, so the buffer goes out first
+        sink.write_all(b"g").unwrap();
+        sink.write_all(b"hijklmno").unwrap();
+        // Too big for the buffer, so written straight through
+        sink.write_all(b"pqrstuvwxyz").unwrap();
+        sink.write_all(b"!").unwrap();
+        sink.finish().unwrap();
+        drop(sink);
+        assert_eq!(
+            writes.0,
+            vec![
+                b"abcdef".to_vec(),
+                b"g".to_vec(),
+                b"hijklmno".to_vec(),
+                b"pqrstuvwxyz".to_vec(),
+                b"!".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tee_sink() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tee.txt");
+        let mut first = vec![];
+        let mut sink = TeeSink::new(&mut first, BufferedSink::new(File::create(&path).unwrap()));
+        sink.write_all(b"x\ny\n").unwrap();
+        sink.finish().unwrap();
+        drop(sink);
+        assert_eq!(first, b"x\ny\n");
+        assert_eq!(fs::read(&path).unwrap(), b"x\ny\n");
+    }
+
+    #[test]
+    fn test_fsync_sink() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("synced.txt");
+        let file = File::create(&path).unwrap();
+        let handle = file.try_clone().unwrap();
+        let mut sink = FsyncSink::new(BufferedSink::new(file), handle, path.clone());
+        sink.write_all(b"x\ny\n").unwrap();
+        sink.finish().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"x\ny\n");
+        // A bare file name is in the current directory
+        sync_parent(Path::new("synced.txt")).unwrap();
+    }
+
+    #[test]
+    fn test_split_sink() {
+        let dir = TempDir::new().unwrap();
+        let prefix = dir.path().to_owned();
+        let mut sink = SplitSink::new(
+            2,
+            b'\n',
+            Box::new(move |n| {
+                let file = File::create(prefix.join(format!("chunk{}.txt", n)))?;
+                Ok(Box::new(BufferedSink::new(file)) as Box<dyn OutputSink>)
+            }),
+        );
+        // Records arrive in pieces, the way `join_append` writes them
+        for piece in [&b"1\ta"[..], b"\n2\tb\n3", b"\tc\n4\td\n5\te\n"] {
+            sink.write_all(piece).unwrap();
+        }
+        sink.finish().unwrap();
+        assert_eq!(sink.chunks(), 3);
+        assert_eq!(
+            fs::read(dir.path().join("chunk0.txt")).unwrap(),
+            b"1\ta\n2\tb\n"
+        );
+        assert_eq!(
+            fs::read(dir.path().join("chunk1.txt")).unwrap(),
+            b"3\tc\n4\td\n"
+        );
+        assert_eq!(fs::read(dir.path().join("chunk2.txt")).unwrap(), b"5\te\n");
+    }
+}
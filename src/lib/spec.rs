@@ -0,0 +1,170 @@
+//! Owned extraction definitions.
+//!
+//! A [`ConfigSpec`] holds everything needed to build a [`CoreConfig`] for a field selection as
+//! plain owned values. With the `serde` feature it can be saved to and loaded from any serde
+//! format, ex: a YAML job spec, so an extraction can be defined once and rerun later.
+use regex::bytes::Regex;
+use ripline::LineTerminator;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{CoreConfig, CoreConfigBuilder},
+    field_range::FieldSpec,
+};
+
+/// An owned description of how to split lines and which fields to select.
+///
+/// Unset values use the same defaults as [`CoreConfigBuilder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct ConfigSpec {
+    /// The delimiter to split lines on, escapes like `\t` are resolved.
+    pub delimiter: String,
+    /// Whether `delimiter` is a regex instead of a literal.
+    pub delimiter_is_regex: bool,
+    /// The delimiter to write between selected fields.
+    pub output_delimiter: String,
+    /// Fields to select, by position.
+    pub fields: Option<FieldSpec>,
+    /// Fields to select, by header name.
+    pub header_fields: Vec<String>,
+    /// Fields to leave out, by position.
+    pub exclude: Option<FieldSpec>,
+    /// Fields to leave out, by header name.
+    pub exclude_headers: Vec<String>,
+    /// Whether header names are regexes instead of literals.
+    pub header_is_regex: bool,
+    /// Fixed column widths to split lines on instead of `delimiter`.
+    pub widths: Option<Vec<usize>>,
+    /// Whether lines end with `\r\n`.
+    pub crlf: bool,
+    /// Split records on this instead of newlines.
+    pub record_separator: Option<String>,
+    /// Whether `record_separator` is a regex.
+    pub record_separator_is_regex: bool,
+    /// Written in place of selected fields a line is too short to have.
+    pub fill: Option<String>,
+}
+
+impl Default for ConfigSpec {
+    fn default() -> Self {
+        Self {
+            delimiter: String::from("\t"),
+            delimiter_is_regex: false,
+            output_delimiter: String::from("\t"),
+            fields: None,
+            header_fields: vec![],
+            exclude: None,
+            exclude_headers: vec![],
+            header_is_regex: false,
+            widths: None,
+            crlf: false,
+            record_separator: None,
+            record_separator_is_regex: false,
+            fill: None,
+        }
+    }
+}
+
+impl ConfigSpec {
+    /// A builder with this spec applied, to be adjusted further or built.
+    ///
+    /// Fails if a header name is not a valid regex.
+    pub fn to_builder(&self) -> Result<CoreConfigBuilder<'static>, regex::Error> {
+        let header_fields = compile(&self.header_fields)?;
+        let exclude_headers = compile(&self.exclude_headers)?;
+        let line_terminator = if self.crlf {
+            LineTerminator::crlf()
+        } else {
+            LineTerminator::default()
+        };
+        Ok(CoreConfigBuilder::new()
+            .delimiter(self.delimiter.clone().into_bytes())
+            .is_regex_parser(self.delimiter_is_regex)
+            .output_delimiter(self.output_delimiter.clone().into_bytes())
+            .fields(self.fields.as_ref().map(ToString::to_string))
+            .headers(header_fields)
+            .exclude(self.exclude.as_ref().map(ToString::to_string))
+            .exclude_headers(exclude_headers)
+            .header_is_regex(self.header_is_regex)
+            .widths(self.widths.clone())
+            .line_terminator(line_terminator)
+            .record_separator(self.record_separator.clone().map(String::into_bytes))
+            .record_separator_is_regex(self.record_separator_is_regex)
+            .fill(self.fill.clone().map(String::into_bytes)))
+    }
+
+    /// Build the [`CoreConfig`] this spec describes.
+    pub fn build(&self) -> anyhow::Result<CoreConfig<'static>> {
+        self.to_builder()?.build()
+    }
+}
+
+/// Compile header names, `None` if there are none.
+fn compile(names: &[String]) -> Result<Option<Vec<Regex>>, regex::Error> {
+    if names.is_empty() {
+        return Ok(None);
+    }
+    names
+        .iter()
+        .map(|name| Regex::new(name))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let spec = ConfigSpec {
+            delimiter: String::from(","),
+            fields: Some(FieldSpec::parse("3,1").unwrap()),
+            header_fields: vec![String::from("^b")],
+            header_is_regex: true,
+            fill: Some(String::from("NA")),
+            ..ConfigSpec::default()
+        };
+        let conf = spec.build().unwrap();
+        assert_eq!(conf.fill(), Some(&b"NA"[..]));
+        // The same selection as a borrowed config
+        let headers = vec![Regex::new("^b").unwrap()];
+        let borrowed = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .fields(Some("3,1"))
+            .headers(Some(&headers))
+            .header_is_regex(true)
+            .build()
+            .unwrap();
+        let names = ["a", "b", "c"];
+        assert_eq!(
+            conf.parse_fields_from_names(&names).unwrap(),
+            borrowed.parse_fields_from_names(&names).unwrap()
+        );
+
+        let bad = ConfigSpec {
+            header_fields: vec![String::from("(")],
+            ..ConfigSpec::default()
+        };
+        assert!(bad.build().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let spec: ConfigSpec =
+            serde_json::from_str(r#"{"delimiter": ",", "fields": "1,3-", "fill": "NA"}"#).unwrap();
+        assert_eq!(spec.delimiter, ",");
+        assert_eq!(spec.fields, Some(FieldSpec::parse("1,3-").unwrap()));
+        assert_eq!(spec.output_delimiter, "\t");
+        let json = serde_json::to_string(&spec).unwrap();
+        assert_eq!(serde_json::from_str::<ConfigSpec>(&json).unwrap(), spec);
+        assert!(serde_json::from_str::<ConfigSpec>(r#"{"delim": ","}"#).is_err());
+    }
+}
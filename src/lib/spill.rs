@@ -0,0 +1,262 @@
+//! Spilling keyed records to disk, for operations whose state may not fit in memory.
+//!
+//! This is the partitioning half of the grace hash join in [`join`](crate::join): when the build
+//! side would exceed its [`MemoryBudget`], both sides are split into the same number of
+//! [`Partitions`] by a hash of their keys with a [`Partitioner`]. Matching keys always land in the
+//! same partition, so each pair of partitions can then be joined on its own with a much smaller
+//! hash table. A partition that is still too large can be partitioned again with a different
+//! `level`.
+//!
+//! Partitions are written to a temporary directory that is removed when they are dropped, and are
+//! memory mapped when read back so records can be borrowed without copying.
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::File,
+    hash::Hasher,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use memmap::Mmap;
+use tempfile::TempDir;
+
+/// A limit on the bytes an operation may hold in memory before it must spill to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, used: 0 }
+    }
+
+    /// Account for `bytes` more, returning false if that would go over the limit.
+    #[inline]
+    pub fn try_reserve(&mut self, bytes: usize) -> bool {
+        match self.used.checked_add(bytes) {
+            Some(used) if used <= self.limit => {
+                self.used = used;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The bytes reserved so far.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// The most bytes that may be reserved.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+/// The partition a key belongs to out of `partitions`, at a given level of repartitioning.
+#[inline]
+pub fn partition_of(key: &[u8], partitions: usize, level: u32) -> usize {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u32(level);
+    hasher.write(key);
+    (hasher.finish() % partitions as u64) as usize
+}
+
+/// Writes keyed records to on disk partitions by the hash of their key.
+pub struct Partitioner {
+    dir: TempDir,
+    level: u32,
+    writers: Vec<BufWriter<File>>,
+    paths: Vec<PathBuf>,
+}
+
+impl Partitioner {
+    /// Create `partitions` empty partitions in a new temporary directory.
+    ///
+    /// Inputs that will be matched against each other must use the same `partitions` and `level`.
+    pub fn new(partitions: usize, level: u32) -> io::Result<Self> {
+        Self::new_in(std::env::temp_dir(), partitions, level)
+    }
+
+    /// Like [`Partitioner::new`], with the temporary directory created in `parent`.
+    pub fn new_in(parent: impl Into<PathBuf>, partitions: usize, level: u32) -> io::Result<Self> {
+        let dir = tempfile::Builder::new()
+            .prefix("hck-spill")
+            .tempdir_in(parent.into())?;
+        let paths: Vec<PathBuf> = (0..partitions.max(1))
+            .map(|i| dir.path().join(format!("{}.part", i)))
+            .collect();
+        let writers = paths
+            .iter()
+            .map(|path| File::create(path).map(BufWriter::new))
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            dir,
+            level,
+            writers,
+            paths,
+        })
+    }
+
+    /// Append a record to the partition for its `key`.
+    #[inline]
+    pub fn push(&mut self, key: &[u8], record: &[u8]) -> io::Result<()> {
+        let writer = &mut self.writers[partition_of(key, self.paths.len(), self.level)];
+        writer.write_all(&(key.len() as u64).to_le_bytes())?;
+        writer.write_all(key)?;
+        writer.write_all(&(record.len() as u64).to_le_bytes())?;
+        writer.write_all(record)
+    }
+
+    /// Flush every partition so they can be read back.
+    pub fn finish(self) -> io::Result<Partitions> {
+        for mut writer in self.writers {
+            writer.flush()?;
+        }
+        Ok(Partitions {
+            _dir: self.dir,
+            paths: self.paths,
+        })
+    }
+}
+
+/// The finished partitions written by a [`Partitioner`], removed from disk when dropped.
+pub struct Partitions {
+    _dir: TempDir,
+    paths: Vec<PathBuf>,
+}
+
+impl Partitions {
+    /// The number of partitions.
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Whether there are no partitions, which is never the case.
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// The size in bytes of partition `i` on disk.
+    pub fn size(&self, i: usize) -> io::Result<u64> {
+        Ok(std::fs::metadata(&self.paths[i])?.len())
+    }
+
+    /// Memory map partition `i` to iterate over its records.
+    pub fn open(&self, i: usize) -> io::Result<Partition> {
+        let file = File::open(&self.paths[i])?;
+        let mmap = if file.metadata()?.len() == 0 {
+            None
+        } else {
+            // The partition files are private to this process and never written to again
+            Some(unsafe { Mmap::map(&file)? })
+        };
+        Ok(Partition { mmap })
+    }
+}
+
+/// A memory mapped partition.
+pub struct Partition {
+    mmap: Option<Mmap>,
+}
+
+impl Partition {
+    /// Iterate over the `(key, record)` pairs in the order they were pushed.
+    pub fn records(&self) -> PartitionRecords<'_> {
+        PartitionRecords {
+            bytes: self.mmap.as_deref().unwrap_or_default(),
+        }
+    }
+}
+
+/// An iterator over the `(key, record)` pairs of a [`Partition`].
+pub struct PartitionRecords<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> PartitionRecords<'a> {
+    #[inline]
+    fn take(&mut self) -> Option<&'a [u8]> {
+        let (len, rest) = self.bytes.split_first_chunk::<8>()?;
+        let len = u64::from_le_bytes(*len) as usize;
+        let (value, rest) = rest.split_at_checked(len)?;
+        self.bytes = rest;
+        Some(value)
+    }
+}
+
+impl<'a> Iterator for PartitionRecords<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        Some((self.take()?, self.take()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let mut budget = MemoryBudget::new(10);
+        assert!(budget.try_reserve(6));
+        assert!(!budget.try_reserve(5));
+        assert!(budget.try_reserve(4));
+        assert_eq!(budget.used(), 10);
+        // Overflow is over the limit, not a panic
+        let mut budget = MemoryBudget::new(usize::MAX);
+        assert!(budget.try_reserve(1));
+        assert!(!budget.try_reserve(usize::MAX));
+    }
+
+    #[test]
+    fn test_partitions() {
+        let keys: Vec<String> = (0..100).map(|i| format!("key{}", i % 10)).collect();
+        let mut left = Partitioner::new(4, 0).unwrap();
+        let mut right = Partitioner::new(4, 0).unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            left.push(key.as_bytes(), format!("l{}", i).as_bytes())
+                .unwrap();
+            right
+                .push(key.as_bytes(), format!("r{}", i).as_bytes())
+                .unwrap();
+        }
+        let (left, right) = (left.finish().unwrap(), right.finish().unwrap());
+        assert_eq!(left.len(), 4);
+
+        let mut total = 0;
+        for i in 0..left.len() {
+            let (l, r) = (left.open(i).unwrap(), right.open(i).unwrap());
+            let l: Vec<_> = l.records().collect();
+            let r: Vec<_> = r.records().collect();
+            // The same keys land in the same partition on both sides, in input order
+            assert_eq!(
+                l.iter().map(|(k, _)| *k).collect::<Vec<_>>(),
+                r.iter().map(|(k, _)| *k).collect::<Vec<_>>()
+            );
+            for (key, _) in &l {
+                assert_eq!(partition_of(key, 4, 0), i);
+            }
+            total += l.len();
+        }
+        assert_eq!(total, keys.len());
+        let first = left.open(partition_of(b"key0", 4, 0)).unwrap();
+        assert_eq!(
+            first.records().find(|(k, _)| *k == b"key0"),
+            Some((&b"key0"[..], &b"l0"[..]))
+        );
+    }
+
+    #[test]
+    fn test_levels_differ() {
+        let moved = (0..100)
+            .map(|i| format!("key{}", i))
+            .filter(|key| partition_of(key.as_bytes(), 8, 0) != partition_of(key.as_bytes(), 8, 1))
+            .count();
+        assert!(moved > 50, "{}", moved);
+    }
+}
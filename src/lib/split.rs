@@ -0,0 +1,95 @@
+//! Splitting inputs into chunks of whole records.
+//!
+//! Work on a single large input, like a memory mapped file, can be spread over several workers by
+//! giving each one a chunk of it. [`byte_partitions`] ends chunks on the same `\n` line terminators
+//! that `hck` splits records on, so that no record is split between two chunks.
+use std::ops::Range;
+
+/// Split `bytes` into at most `n` ranges of roughly equal size that each end on a record boundary.
+///
+/// Every range but the last ends just after a `\n`, and the last one ends at the end of `bytes`
+/// whether or not it has a terminator, so `\r\n` terminated records are kept whole too. The ranges
+/// are in order, never empty, and together cover all of `bytes`. Fewer than `n` ranges are returned
+/// when records are too long to fill `n` chunks, and none when `bytes` is empty. An `n` of zero is
+/// treated as one.
+pub fn byte_partitions(bytes: &[u8], n: usize) -> Vec<Range<usize>> {
+    let n = n.max(1);
+    let len = bytes.len();
+    let mut partitions = Vec::with_capacity(n.min(len));
+    let mut start = 0;
+    for i in 1..n {
+        // Widen before multiplying so that huge inputs can't overflow
+        let ideal = (len as u128 * i as u128 / n as u128) as usize;
+        if ideal <= start {
+            continue;
+        }
+        // Search from the byte before the ideal split so that a terminator there splits exactly
+        let end = match memchr::memchr(b'\n', &bytes[ideal - 1..]) {
+            Some(pos) => ideal + pos,
+            None => break,
+        };
+        if end >= len {
+            break;
+        }
+        partitions.push(start..end);
+        start = end;
+    }
+    if start < len {
+        partitions.push(start..len);
+    }
+    partitions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Check the invariants that hold for any input.
+    fn check(bytes: &[u8], n: usize) -> Vec<Range<usize>> {
+        let partitions = byte_partitions(bytes, n);
+        assert!(partitions.len() <= n.max(1));
+        let mut expected_start = 0;
+        for (i, range) in partitions.iter().enumerate() {
+            assert_eq!(range.start, expected_start);
+            assert!(range.start < range.end);
+            if i + 1 < partitions.len() {
+                assert_eq!(bytes[range.end - 1], b'\n');
+            }
+            expected_start = range.end;
+        }
+        assert_eq!(expected_start, bytes.len());
+        partitions
+    }
+
+    #[test]
+    fn test_byte_partitions() {
+        assert!(check(b"", 4).is_empty());
+        assert_eq!(check(b"a\nb\n", 0), vec![0..4]);
+        assert_eq!(check(b"a\nb\nc\nd\n", 2), vec![0..4, 4..8]);
+        assert_eq!(check(b"a\nb\nc\nd\n", 4), vec![0..2, 2..4, 4..6, 6..8]);
+        // More partitions than records
+        assert_eq!(check(b"a\nb\n", 8), vec![0..2, 2..4]);
+        // One long record can't be split
+        assert_eq!(check(b"abcdefgh\n", 4), vec![0..9]);
+        // A long record pushes the boundary past later ideal splits
+        assert_eq!(check(b"abcdef\ng\nh\n", 3), vec![0..7, 7..11]);
+        // The last record doesn't need a terminator
+        assert_eq!(check(b"a\nb\nc\ndd", 2), vec![0..4, 4..8]);
+        assert_eq!(check(b"a\r\nb\r\nc\r\n", 3), vec![0..3, 3..6, 6..9]);
+    }
+
+    #[test]
+    fn test_byte_partitions_cover_records() {
+        let bytes: Vec<u8> = (0..1000)
+            .flat_map(|i| format!("{}\t{}\n", "x".repeat(i % 37), i).into_bytes())
+            .collect();
+        for n in 1..20 {
+            let partitions = check(&bytes, n);
+            let records: usize = partitions
+                .iter()
+                .map(|range| bytes[range.clone()].iter().filter(|&&b| b == b'\n').count())
+                .sum();
+            assert_eq!(records, 1000);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Splitting one field into several, and merging several into one.
+//!
+//! Some columns pack a list into a single field, ex: `a;b;c` in an annotation column. A
+//! [`SplitFieldSpec`] names the field and the separator between its pieces so that each piece can be
+//! written as a column of its own. A [`MergeFieldsSpec`] goes the other way, joining several fields
+//! into one, ex: a `chrom_pos` key.
+use std::{borrow::Cow, ops::Range, str::FromStr};
+
+use memchr::memmem;
+use thiserror::Error;
+
+use crate::field_range::{FieldError, FieldRange};
+
+/// Errors for parsing a [`SplitFieldSpec`].
+#[derive(Error, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SplitFieldError {
+    #[error("Invalid split spec `{0}`, expected FIELD:SEP[:N] like `5:;`")]
+    InvalidSpec(String),
+    #[error("Invalid split field `{0}`, expected a 1-based position")]
+    InvalidField(String),
+    #[error("Invalid split limit `{0}`, expected a number of pieces greater than 0")]
+    InvalidLimit(String),
+}
+
+/// Which output field to split and how, parsed from `FIELD:SEP[:N]`.
+///
+/// A trailing `:N` keeps only the first `N` pieces, so `5::` splits on `:` and `5:::2` splits on `:`
+/// keeping two pieces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitFieldSpec {
+    /// The 0-based output position to split
+    pub index: usize,
+    /// The separator between pieces
+    pub sep: Vec<u8>,
+    /// The most pieces to keep, the rest are dropped
+    pub limit: Option<usize>,
+}
+
+impl SplitFieldSpec {
+    /// Split `field` into its pieces, borrowing from it where possible.
+    pub fn split<'a>(&self, field: Cow<'a, [u8]>) -> Vec<Cow<'a, [u8]>> {
+        match field {
+            Cow::Borrowed(field) => self
+                .pieces(field)
+                .into_iter()
+                .map(|range| Cow::Borrowed(&field[range]))
+                .collect(),
+            Cow::Owned(field) => self
+                .pieces(&field)
+                .into_iter()
+                .map(|range| Cow::Owned(field[range].to_vec()))
+                .collect(),
+        }
+    }
+
+    /// Where the pieces of `field` are, up to the limit.
+    fn pieces(&self, field: &[u8]) -> Vec<Range<usize>> {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let mut pieces = vec![];
+        let mut start = 0;
+        for end in memmem::find_iter(field, &self.sep) {
+            if pieces.len() == limit {
+                return pieces;
+            }
+            pieces.push(start..end);
+            start = end + self.sep.len();
+        }
+        if pieces.len() < limit {
+            pieces.push(start..field.len());
+        }
+        pieces
+    }
+}
+
+impl FromStr for SplitFieldSpec {
+    type Err = SplitFieldError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((field, rest)) = s.split_once(':') else {
+            return Err(SplitFieldError::InvalidSpec(s.to_owned()));
+        };
+        let (sep, limit) = match rest.rsplit_once(':') {
+            Some((sep, limit))
+                if !sep.is_empty()
+                    && !limit.is_empty()
+                    && limit.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                match limit.parse::<usize>() {
+                    Ok(limit) if limit > 0 => (sep, Some(limit)),
+                    _ => return Err(SplitFieldError::InvalidLimit(limit.to_owned())),
+                }
+            }
+            _ => (rest, None),
+        };
+        if sep.is_empty() {
+            return Err(SplitFieldError::InvalidSpec(s.to_owned()));
+        }
+        let index = match field.trim().parse::<usize>() {
+            Ok(position) if position > 0 => position - 1,
+            _ => return Err(SplitFieldError::InvalidField(field.to_owned())),
+        };
+        Ok(Self {
+            index,
+            sep: sep.as_bytes().to_vec(),
+            limit,
+        })
+    }
+}
+
+/// Errors for parsing a [`MergeFieldsSpec`].
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum MergeFieldsError {
+    #[error("Invalid merge spec `{0}`, expected FIELDS:JOINER like `1,2:_`")]
+    InvalidSpec(String),
+    #[error(transparent)]
+    InvalidField(#[from] FieldError),
+}
+
+/// Which output fields to join into one and what goes between them, parsed from `FIELDS:JOINER`.
+///
+/// The joiner is everything after the first `:` and may be empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeFieldsSpec {
+    pub fields: Vec<FieldRange>,
+    pub joiner: Vec<u8>,
+}
+
+impl MergeFieldsSpec {
+    /// Replace the fields of `record` covered by this spec with a single field joining them, in
+    /// their order in the record and at the position of the first. Records that have none of the
+    /// fields are left as is.
+    pub fn merge<'a>(&self, record: &mut Vec<Cow<'a, [u8]>>) {
+        let contains = |i: usize| self.fields.iter().any(|range| range.contains(i));
+        let Some(first) = (0..record.len()).find(|&i| contains(i)) else {
+            return;
+        };
+        let mut merged = record[first].to_vec();
+        let mut i = 0;
+        record.retain(|field| {
+            let keep = i == first || !contains(i);
+            if !keep {
+                merged.extend_from_slice(&self.joiner);
+                merged.extend_from_slice(field);
+            }
+            i += 1;
+            keep
+        });
+        record[first] = Cow::Owned(merged);
+    }
+}
+
+impl FromStr for MergeFieldsSpec {
+    type Err = MergeFieldsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((fields, joiner)) = s.split_once(':') else {
+            return Err(MergeFieldsError::InvalidSpec(s.to_owned()));
+        };
+        Ok(Self {
+            fields: FieldRange::from_list(fields)?,
+            joiner: joiner.as_bytes().to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let spec: SplitFieldSpec = "5:;".parse().unwrap();
+        assert_eq!(
+            spec,
+            SplitFieldSpec {
+                index: 4,
+                sep: b";".to_vec(),
+                limit: None
+            }
+        );
+        let spec: SplitFieldSpec = "1:, :3".parse().unwrap();
+        assert_eq!((spec.sep.as_slice(), spec.limit), (&b", "[..], Some(3)));
+        let spec: SplitFieldSpec = "2::".parse().unwrap();
+        assert_eq!((spec.sep.as_slice(), spec.limit), (&b":"[..], None));
+        let spec: SplitFieldSpec = "2:::2".parse().unwrap();
+        assert_eq!((spec.sep.as_slice(), spec.limit), (&b":"[..], Some(2)));
+        // A separator that ends in a number needs no limit to be taken as a separator
+        let spec: SplitFieldSpec = "2:7".parse().unwrap();
+        assert_eq!((spec.sep.as_slice(), spec.limit), (&b"7"[..], None));
+
+        assert_eq!(
+            "5".parse::<SplitFieldSpec>(),
+            Err(SplitFieldError::InvalidSpec("5".to_owned()))
+        );
+        assert_eq!(
+            "5:".parse::<SplitFieldSpec>(),
+            Err(SplitFieldError::InvalidSpec("5:".to_owned()))
+        );
+        assert_eq!(
+            "0:;".parse::<SplitFieldSpec>(),
+            Err(SplitFieldError::InvalidField("0".to_owned()))
+        );
+        assert_eq!(
+            "1:;:0".parse::<SplitFieldSpec>(),
+            Err(SplitFieldError::InvalidLimit("0".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let merge = |spec: &str, fields: &[&'static str]| -> Vec<String> {
+            let spec: MergeFieldsSpec = spec.parse().unwrap();
+            let mut record: Vec<Cow<[u8]>> =
+                fields.iter().map(|f| Cow::Borrowed(f.as_bytes())).collect();
+            spec.merge(&mut record);
+            record
+                .iter()
+                .map(|f| String::from_utf8(f.to_vec()).unwrap())
+                .collect()
+        };
+        assert_eq!(merge("1,2:_", &["chr1", "100", "x"]), vec!["chr1_100", "x"]);
+        assert_eq!(merge("1,3::", &["a", "b", "c", "d"]), vec!["a:c", "b", "d"]);
+        assert_eq!(merge("2-:", &["a", "b", "c", "d"]), vec!["a", "bcd"]);
+        // Missing fields are left out of the merge
+        assert_eq!(merge("2,4:-", &["a", "b", "c"]), vec!["a", "b", "c"]);
+        assert_eq!(merge("3,4:-", &["a", "b"]), vec!["a", "b"]);
+
+        assert_eq!(
+            "1,2".parse::<MergeFieldsSpec>(),
+            Err(MergeFieldsError::InvalidSpec("1,2".to_owned()))
+        );
+        assert!(matches!(
+            "0,1:_".parse::<MergeFieldsSpec>(),
+            Err(MergeFieldsError::InvalidField(_))
+        ));
+    }
+
+    #[test]
+    fn test_split() {
+        let spec: SplitFieldSpec = "1:;".parse().unwrap();
+        let pieces = spec.split(Cow::Borrowed(b"a;b;;c"));
+        assert_eq!(pieces, vec![&b"a"[..], b"b", b"", b"c"]);
+        assert!(pieces.iter().all(|p| matches!(p, Cow::Borrowed(_))));
+        assert_eq!(spec.split(Cow::Owned(b"a;".to_vec())), vec![&b"a"[..], b""]);
+        assert_eq!(spec.split(Cow::Borrowed(b"")), vec![&b""[..]]);
+
+        let spec: SplitFieldSpec = "1:, ".parse().unwrap();
+        assert_eq!(
+            spec.split(Cow::Borrowed(b"a, b,c, ")),
+            vec![&b"a"[..], b"b,c", b""]
+        );
+        let spec: SplitFieldSpec = "1:::2".parse().unwrap();
+        assert_eq!(spec.split(Cow::Borrowed(b"a:b:c")), vec![&b"a"[..], b"b"]);
+    }
+}
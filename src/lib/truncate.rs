@@ -0,0 +1,186 @@
+//! Dropping the unselected tails of lines while reading.
+//!
+//! A line has to fit in the line buffer before it can be split, so a single multi-gigabyte line
+//! needs that much memory even if only its first few fields are selected. A [`TruncatingReader`]
+//! sits between the input and the line buffer and passes on each line only up to the end of the
+//! last field that can be selected, scanning past the rest without keeping it.
+use std::io::{self, Read};
+
+use ripline::LineTerminator;
+
+/// The size of the buffer reads from the inner reader go through.
+const BUFFER_SIZE: usize = 64 * (1 << 10);
+
+/// A [`Read`] wrapper that cuts every line off after a given number of fields.
+#[derive(Debug)]
+pub struct TruncatingReader<R> {
+    inner: R,
+    delim: u8,
+    newline: u8,
+    crlf: bool,
+    /// The 0-based index of the last field to keep on each line
+    last: usize,
+    buffer: Vec<u8>,
+    /// The start of the unread data in `buffer`
+    pos: usize,
+    /// The end of the data in `buffer`
+    len: usize,
+    /// The 0-based index of the field being read on the current line
+    field: usize,
+    /// Whether the rest of the current line is being dropped
+    skipping: bool,
+    /// Whether a `\r` needs to go before the `\n` that ends a cut short line
+    pending_cr: bool,
+}
+
+impl<R: Read> TruncatingReader<R> {
+    /// Keep fields `0..=last` of each line split on `delim`.
+    ///
+    /// The delimiter after the last kept field is dropped along with everything after it, but the
+    /// line terminator is kept. With a CRLF terminator lines that are cut short always end in
+    /// `\r\n`, since a bare `\n` would otherwise be read as part of the last kept field.
+    pub fn new(inner: R, delim: u8, terminator: LineTerminator, last: usize) -> Self {
+        Self {
+            inner,
+            delim,
+            newline: terminator.as_byte(),
+            crlf: terminator.is_crlf(),
+            last,
+            buffer: vec![0; BUFFER_SIZE],
+            pos: 0,
+            len: 0,
+            field: 0,
+            skipping: false,
+            pending_cr: false,
+        }
+    }
+}
+
+impl<R: Read> Read for TruncatingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.pos == self.len {
+                let n = self.inner.read(&mut self.buffer)?;
+                if n == 0 {
+                    return Ok(0);
+                }
+                self.pos = 0;
+                self.len = n;
+            }
+            let mut written = 0;
+            while self.pos < self.len && written < buf.len() {
+                if self.skipping {
+                    match memchr::memchr(self.newline, &self.buffer[self.pos..self.len]) {
+                        Some(i) => {
+                            self.pos += i;
+                            self.skipping = false;
+                            self.pending_cr = self.crlf;
+                        }
+                        None => self.pos = self.len,
+                    }
+                    continue;
+                }
+                if self.pending_cr {
+                    buf[written] = b'\r';
+                    written += 1;
+                    self.pending_cr = false;
+                    continue;
+                }
+                let limit = (self.len - self.pos).min(buf.len() - written);
+                let chunk = &self.buffer[self.pos..self.pos + limit];
+                let (copy, skip) = match memchr::memchr2(self.delim, self.newline, chunk) {
+                    Some(i) if chunk[i] == self.newline => {
+                        self.field = 0;
+                        (i + 1, 0)
+                    }
+                    Some(i) if self.field == self.last => {
+                        self.skipping = true;
+                        self.field = 0;
+                        (i, 1)
+                    }
+                    Some(i) => {
+                        self.field += 1;
+                        (i + 1, 0)
+                    }
+                    None => (limit, 0),
+                };
+                buf[written..written + copy].copy_from_slice(&chunk[..copy]);
+                written += copy;
+                self.pos += copy + skip;
+            }
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Read everything through a [`TruncatingReader`], `step` bytes at a time.
+    fn truncate(input: &[u8], last: usize, step: usize, terminator: LineTerminator) -> Vec<u8> {
+        let mut reader = TruncatingReader::new(input, b',', terminator, last);
+        let mut out = vec![];
+        let mut chunk = vec![0; step];
+        loop {
+            let n = reader.read(&mut chunk).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&chunk[..n]);
+        }
+        out
+    }
+
+    #[test]
+    fn test_truncating_reader() {
+        let input = b"a,b,c,d\n,,\nlong\r\n1,2\r\n1,2,3\nx,y,z";
+        let lf = LineTerminator::default();
+        let crlf = LineTerminator::crlf();
+        for step in [1, 2, 3, 7, 1000] {
+            assert_eq!(
+                truncate(input, 0, step, lf),
+                b"a\n\nlong\r\n1\n1\nx".to_vec(),
+                "step {}",
+                step
+            );
+            assert_eq!(
+                truncate(input, 1, step, lf),
+                b"a,b\n,\nlong\r\n1,2\r\n1,2\nx,y".to_vec(),
+                "step {}",
+                step
+            );
+            assert_eq!(
+                truncate(input, 5, step, lf),
+                input.to_vec(),
+                "step {}",
+                step
+            );
+            // Lines that are cut short always end in CRLF
+            assert_eq!(
+                truncate(b"a,b\r\nc,d\ne,\r\r\nf\n", 0, step, crlf),
+                b"a\r\nc\r\ne\r\nf\n".to_vec(),
+                "step {}",
+                step
+            );
+        }
+    }
+
+    #[test]
+    fn test_truncating_reader_long_line() {
+        // Lines much longer than the internal buffer
+        let tail = ",x".repeat(3 * BUFFER_SIZE);
+        let input = format!("a,b{}\nc,d{}\ne,f\n", tail, tail);
+        let lf = LineTerminator::default();
+        assert_eq!(truncate(input.as_bytes(), 1, 4096, lf), b"a,b\nc,d\ne,f\n");
+        assert_eq!(truncate(input.as_bytes(), 0, 1, lf), b"a\nc\ne\n");
+        let input = format!("a,b{}\r\nc,d{}\r\n", tail, tail);
+        let crlf = LineTerminator::crlf();
+        assert_eq!(truncate(input.as_bytes(), 1, 4096, crlf), b"a,b\r\nc,d\r\n");
+    }
+}
@@ -0,0 +1,270 @@
+//! Reading files with io_uring, keeping several reads in flight.
+//!
+//! A [`UringReader`] reads a file in chunks of [`URING_CHUNK_SIZE`], queueing up to
+//! [`URING_DEPTH`] reads ahead of the one being consumed so the device stays busy while lines are
+//! parsed. The chunks are copied out through [`Read`], so they feed the same parsers as any other
+//! reader, fast mode included. Buffers are reused once their chunk is consumed.
+//!
+//! io_uring is Linux only and can be disabled, ex: by container seccomp profiles, so [`open`]
+//! falls back to plain reads when a ring can't be set up.
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+/// The number of reads kept queued ahead of the reader.
+pub const URING_DEPTH: usize = 4;
+
+/// The size of each read from the file.
+pub const URING_CHUNK_SIZE: usize = 1 << 20;
+
+/// Open `path` for reading through a [`UringReader`], or as a plain file if io_uring is unavailable.
+#[cfg(target_os = "linux")]
+pub(crate) fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    match UringReader::new(file.try_clone()?) {
+        Ok(reader) => Ok(Box::new(reader)),
+        Err(err) => {
+            log::debug!(
+                "{}: io_uring is unavailable, reading normally: {}",
+                path.display(),
+                err
+            );
+            Ok(Box::new(file))
+        }
+    }
+}
+
+/// io_uring is Linux only, elsewhere files are read normally.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    Ok(Box::new(File::open(path)?))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) use linux::UringReader;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{URING_CHUNK_SIZE, URING_DEPTH};
+    use io_uring::{opcode, types, IoUring};
+    use std::{
+        collections::VecDeque,
+        fs::File,
+        io::{self, Read},
+        mem,
+        os::unix::io::AsRawFd,
+    };
+
+    /// A read of one chunk of the file.
+    struct Chunk {
+        /// Where in the file the read starts
+        offset: u64,
+        buffer: Vec<u8>,
+        /// The bytes read or the error number, once the read completes
+        result: Option<Result<usize, i32>>,
+    }
+
+    /// A [`Read`] over a file that keeps up to [`URING_DEPTH`] reads in flight.
+    pub(crate) struct UringReader {
+        ring: IoUring,
+        file: File,
+        /// Reads in the order they were queued, the front one is being consumed
+        chunks: VecDeque<Chunk>,
+        /// Buffers of consumed chunks, to reuse
+        free: Vec<Vec<u8>>,
+        /// The user data of the front chunk, each chunk after it has the next value
+        first_id: u64,
+        /// Where the next read is queued from
+        next_offset: u64,
+        /// How far into the file has been consumed
+        offset: u64,
+        /// How far into the front chunk has been consumed
+        pos: usize,
+        /// Whether a read hit the end of the file, so no more are queued
+        eof: bool,
+    }
+
+    impl UringReader {
+        pub(crate) fn new(file: File) -> io::Result<Self> {
+            Ok(Self {
+                ring: IoUring::new(URING_DEPTH as u32)?,
+                file,
+                chunks: VecDeque::with_capacity(URING_DEPTH),
+                free: vec![],
+                first_id: 0,
+                next_offset: 0,
+                offset: 0,
+                pos: 0,
+                eof: false,
+            })
+        }
+
+        /// Queue reads until [`URING_DEPTH`] are in flight or waiting to be consumed.
+        fn queue(&mut self) -> io::Result<()> {
+            let mut queued = false;
+            while !self.eof && self.chunks.len() < URING_DEPTH {
+                let mut buffer = self.free.pop().unwrap_or_else(|| vec![0; URING_CHUNK_SIZE]);
+                let id = self.first_id + self.chunks.len() as u64;
+                let entry = opcode::Read::new(
+                    types::Fd(self.file.as_raw_fd()),
+                    buffer.as_mut_ptr(),
+                    buffer.len() as u32,
+                )
+                .offset(self.next_offset)
+                .build()
+                .user_data(id);
+                // SAFETY: The buffer stays in `chunks`, and the file open, until the read completes,
+                // which `Drop` waits for.
+                unsafe { self.ring.submission().push(&entry) }
+                    .map_err(|_| io::Error::other("the io_uring submission queue is full"))?;
+                self.chunks.push_back(Chunk {
+                    offset: self.next_offset,
+                    buffer,
+                    result: None,
+                });
+                self.next_offset += URING_CHUNK_SIZE as u64;
+                queued = true;
+            }
+            if queued {
+                self.ring.submit()?;
+            }
+            Ok(())
+        }
+
+        /// Wait for at least one read to complete, and record the result of every completed read.
+        fn complete(&mut self) -> io::Result<()> {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => (),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => return Ok(()),
+                Err(err) => return Err(err),
+            }
+            for entry in self.ring.completion() {
+                let chunk = &mut self.chunks[(entry.user_data() - self.first_id) as usize];
+                chunk.result = Some(match entry.result() {
+                    len if len >= 0 => Ok(len as usize),
+                    errno => Err(-errno),
+                });
+            }
+            Ok(())
+        }
+
+        /// Drop the front chunk, keeping its buffer for another read.
+        fn recycle(&mut self) {
+            if let Some(chunk) = self.chunks.pop_front() {
+                self.free.push(chunk.buffer);
+                self.first_id += 1;
+                self.pos = 0;
+            }
+        }
+    }
+
+    impl Read for UringReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                self.queue()?;
+                let Some(front) = self.chunks.front() else {
+                    return Ok(0);
+                };
+                let result = match front.result {
+                    Some(result) => result,
+                    None => {
+                        self.complete()?;
+                        continue;
+                    }
+                };
+                // Reads queued before a short read or an error start in the wrong place
+                if front.offset + self.pos as u64 != self.offset {
+                    self.recycle();
+                    continue;
+                }
+                let len = match result {
+                    Ok(len) => len,
+                    Err(errno) => {
+                        self.recycle();
+                        self.next_offset = self.offset;
+                        let err = io::Error::from_raw_os_error(errno);
+                        match err.kind() {
+                            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => continue,
+                            _ => return Err(err),
+                        }
+                    }
+                };
+                if len == 0 {
+                    self.eof = true;
+                    self.recycle();
+                    return Ok(0);
+                }
+                let n = buf.len().min(len - self.pos);
+                buf[..n].copy_from_slice(&front.buffer[self.pos..self.pos + n]);
+                self.pos += n;
+                self.offset += n as u64;
+                if self.pos == len {
+                    self.recycle();
+                    if len < URING_CHUNK_SIZE {
+                        // Likely the end of the file, but a short read can also come before it
+                        self.next_offset = self.offset;
+                    }
+                }
+                return Ok(n);
+            }
+        }
+    }
+
+    impl Drop for UringReader {
+        fn drop(&mut self) {
+            // The kernel may still be writing into the buffers of reads in flight
+            while self.chunks.iter().any(|chunk| chunk.result.is_none()) {
+                if let Err(err) = self.complete() {
+                    log::debug!("failed to wait for io_uring reads: {}", err);
+                    // Leaking the buffers is the only safe option left
+                    mem::forget(mem::take(&mut self.chunks));
+                    break;
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use std::{fs, io::Read};
+        use tempfile::TempDir;
+
+        #[test]
+        fn test_uring_reader() {
+            let dir = TempDir::new().unwrap();
+            let path = dir.path().join("input.txt");
+            // More chunks than are kept in flight, ending partway through one
+            let data = (0..(URING_DEPTH * URING_CHUNK_SIZE + 12345))
+                .map(|i| (i % 251) as u8)
+                .collect::<Vec<_>>();
+            fs::write(&path, &data).unwrap();
+            let mut reader = match UringReader::new(File::open(&path).unwrap()) {
+                Ok(reader) => reader,
+                // Not every kernel or sandbox allows io_uring
+                Err(_) => return,
+            };
+            let mut out = vec![];
+            // Odd sized reads don't line up with the chunks
+            let mut buf = vec![0; 4099];
+            loop {
+                let n = reader.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                out.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(out.len(), data.len());
+            assert!(out == data);
+            assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+            // Dropping a reader with reads in flight waits for them
+            let mut reader = UringReader::new(File::open(&path).unwrap()).unwrap();
+            assert_eq!(reader.read(&mut buf[..10]).unwrap(), 10);
+            assert_eq!(&buf[..10], &data[..10]);
+            drop(reader);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Round trip verification.
+//!
+//! Selecting every field (`-f1-`) and writing it back out with the input delimiter should reproduce
+//! the input byte for byte. [`verify_roundtrip`] does exactly that for one input, hashing the raw
+//! input and the output as they stream by, so users can check that the parsers handle their real
+//! data correctly before trusting a more complex extraction. It also makes a convenient end to end
+//! check of the parsers in tests.
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::Hasher,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use ripline::line_buffer::LineBuffer;
+
+use crate::{
+    core::{Core, CoreConfig, HckInput},
+    field_range::FieldRange,
+    line_parser::LineParser,
+};
+
+/// The size and hash of a byte stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Digest {
+    pub bytes: u64,
+    pub hash: u64,
+}
+
+/// A [`Write`] that discards everything written to it, keeping only a [`Digest`].
+#[derive(Debug, Default)]
+pub struct DigestWriter {
+    hasher: DefaultHasher,
+    bytes: u64,
+}
+
+impl DigestWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The [`Digest`] of everything written so far.
+    pub fn digest(&self) -> Digest {
+        Digest {
+            bytes: self.bytes,
+            hash: self.hasher.finish(),
+        }
+    }
+}
+
+impl Write for DigestWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.write(buf);
+        self.bytes += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The result of [`verify_roundtrip`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Roundtrip {
+    pub input: Digest,
+    pub output: Digest,
+}
+
+impl Roundtrip {
+    /// Whether the output was identical to the input.
+    pub fn matches(&self) -> bool {
+        self.input == self.output
+    }
+}
+
+/// Select every field of `input` using the delimiter and line terminator from `config`, and compare
+/// the output to the input.
+///
+/// The field selection and output settings of `config` are ignored. `Stdin` can only be read once,
+/// so it is buffered in memory.
+pub fn verify_roundtrip<P: AsRef<Path>>(
+    input: HckInput<P>,
+    config: &CoreConfig,
+    line_buffer: &mut LineBuffer,
+) -> Result<Roundtrip> {
+    let config = config
+        .roundtrip_config()
+        .ok_or_else(|| anyhow!("Fixed width inputs can't be round tripped"))?;
+    let fields = FieldRange::from_list("1-")?;
+
+    let parser = config.line_parser(&fields);
+    let (input, output) = roundtrip(Core::new(&config, &fields, parser, line_buffer), input)?;
+    Ok(Roundtrip { input, output })
+}
+
+/// Digest the raw bytes of `input` and the output of running it through `core`.
+fn roundtrip<'a, L, P>(mut core: Core<'a, L>, input: HckInput<P>) -> Result<(Digest, Digest)>
+where
+    L: LineParser<'a>,
+    P: AsRef<Path>,
+{
+    let mut input_digest = DigestWriter::new();
+    let mut output_digest = DigestWriter::new();
+    match input {
+        HckInput::Stdin => {
+            let mut bytes = vec![];
+            core.config()
+                .open_input(&input, None)?
+                .read_to_end(&mut bytes)?;
+            input_digest.write_all(&bytes)?;
+            core.hck_bytes(&bytes, &mut output_digest)?;
+        }
+        HckInput::Path(_) => {
+            io::copy(
+                &mut core.config().open_input(&input, None)?,
+                &mut input_digest,
+            )?;
+            core.hck_input(input, &mut output_digest, None)?;
+        }
+    }
+    Ok((input_digest.digest(), output_digest.digest()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::CoreConfigBuilder;
+    use ripline::{line_buffer::LineBufferBuilder, LineTerminator};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn verify(data: &[u8], builder: CoreConfigBuilder) -> bool {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("input.txt");
+        fs::write(&path, data).unwrap();
+        let config = builder.build().unwrap();
+        let mut line_buffer = LineBufferBuilder::new().build();
+        let result = verify_roundtrip(HckInput::Path(&path), &config, &mut line_buffer).unwrap();
+        assert_eq!(result.input.bytes, data.len() as u64);
+        result.matches()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = b"a,b,,c\n,\n\n1,2,3";
+        // Field selection and output settings don't matter
+        let builder = CoreConfigBuilder::new()
+            .delimiter(b",")
+            .output_delimiter(b"|")
+            .fields(Some("2"));
+        assert!(verify(data, builder.clone()));
+        assert!(verify(data, builder.clone().is_regex_parser(true)));
+        assert!(verify(data, builder.mmap(crate::mmap::MmapChoice::never())));
+
+        let data = b"a\tb\r\nc\td\r\n";
+        assert!(verify(
+            data,
+            CoreConfigBuilder::new().line_terminator(LineTerminator::crlf())
+        ));
+        assert!(verify(data, CoreConfigBuilder::new().delimiter(br"\t")));
+    }
+
+    #[test]
+    fn test_roundtrip_mismatch() {
+        // Only the first delimiter matched on a line is written back out
+        let builder = CoreConfigBuilder::new()
+            .delimiter(br"\s+")
+            .is_regex_parser(true);
+        assert!(verify(b"a b c\n", builder.clone()));
+        assert!(!verify(b"a b  c\n", builder));
+
+        let widths = [1, 1];
+        let config = CoreConfigBuilder::new()
+            .widths(Some(&widths))
+            .build()
+            .unwrap();
+        let mut line_buffer = LineBufferBuilder::new().build();
+        assert!(verify_roundtrip(HckInput::Path("x"), &config, &mut line_buffer).is_err());
+    }
+}
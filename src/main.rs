@@ -1,31 +1,70 @@
-use anyhow::{Context, Error, Result};
-use clap::Parser;
+use anyhow::{anyhow, Context, Error, Result};
+use bstr::ByteVec;
+use clap::{
+    error::ErrorKind, parser::ValueSource, ArgMatches, Args, CommandFactory, FromArgMatches,
+    Parser, Subcommand, ValueEnum,
+};
+use encoding_rs::Encoding;
 use env_logger::Env;
-use flate2::Compression;
+use flate2::{write::GzEncoder, Compression};
 use git_version::git_version;
-use grep_cli::{stdout, unescape};
-use gzp::{deflate::Bgzf, ZBuilder};
+use grep_cli::stdout;
 use hcklib::{
-    core::{Core, CoreConfig, CoreConfigBuilder, HckInput},
-    field_range::RegexOrString,
-    line_parser::{RegexLineParser, SubStrLineParser},
+    codec::{Codec, CodecSpec},
+    core::{
+        Core, CoreConfig, CoreConfigBuilder, FieldCount, HckInput, HeaderNames, RaggedRows,
+        DEFAULT_STDIN_BUFFER_SIZE,
+    },
+    distinct::{DistinctCounter, HyperLogLog, DEFAULT_PRECISION},
+    expr::Expr,
+    field_range::{parse_widths, FieldRange},
+    gen::{generate, ColumnSpec, GenConfig},
+    join::{select_fields, HashJoin, JoinKey, JoinSink},
+    key::{GenKeySpec, Key, KeyNormalize},
+    line_parser::SubStrLineParser,
+    metrics::Metrics,
     mmap::MmapChoice,
+    output::{EdgeSpec, OutputFormat},
+    pattern::ColumnPattern,
+    pipeline::{
+        CaptureStage, Case, CaseStage, CountDistinctStage, EnumerateStage, ExprFilterStage,
+        GenKeyStage, HeadStage, HeaderPolicy, InjectStage, MapFieldsStage, MergeFieldsStage,
+        PadStage, Pipeline, RecodeStage, RequireStage, ReservoirStage, SampleStage, SkipEmptyStage,
+        SplitFieldStage, TailStage, UniqueStage,
+    },
+    recode::{RecodeSpec, Recoder},
+    sink::{BoundedSink, BufferedSink, CompressedSink, FsyncSink, LineBufferedSink, OutputSink},
+    subfield::{MergeFieldsSpec, SplitFieldSpec},
+    verify::verify_roundtrip,
 };
 use lazy_static::lazy_static;
-use log::{error, warn};
+use log::{error, info, warn};
 use regex::bytes::Regex;
 use ripline::{
     line_buffer::{LineBuffer, LineBufferBuilder},
-    LineTerminator,
+    lines, LineTerminator,
 };
+use serde::Deserialize;
 use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ffi::OsString,
     fs::File,
-    io::{self, BufWriter, Write},
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write},
+    mem,
     path::{Path, PathBuf},
-    process::exit,
+    process::{self, exit, Child, Stdio},
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 use termcolor::ColorChoice;
 
+/// Counts the heap allocations reported by `--stats`.
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: hcklib::metrics::CountingAllocator = hcklib::metrics::CountingAllocator;
+
 lazy_static! {
     /// Default number of compression threads to use.
     ///
@@ -57,9 +96,7 @@ fn select_output<P: AsRef<Path>>(output: Option<P>) -> Result<Box<dyn Write + Se
                 // TODO: verify that stdout buffers when writing to a terminal now (this was a bug in Rust at some point).
                 Box::new(stdout(ColorChoice::Never))
             } else {
-                Box::new(File::create(&path).with_context(|| {
-                    format!("Failed to open {} for writing.", path.as_ref().display())
-                })?)
+                Box::new(create_output(path)?)
             }
         }
         None => Box::new(stdout(ColorChoice::Never)),
@@ -67,6 +104,27 @@ fn select_output<P: AsRef<Path>>(output: Option<P>) -> Result<Box<dyn Write + Se
     Ok(writer)
 }
 
+/// The [`MmapChoice`] for `--mmap` and `--no-mmap`.
+fn mmap_choice(opts: &InputOpts) -> MmapChoice {
+    match opts.mmap {
+        _ if opts.no_mmap => MmapChoice::never(),
+        MmapMode::Never => MmapChoice::never(),
+        MmapMode::Auto => unsafe { MmapChoice::auto() },
+        MmapMode::Always => unsafe { MmapChoice::always() },
+    }
+}
+
+/// Whether output to `output`, as given with `-o`, goes to stdout.
+fn is_stdout<P: AsRef<Path>>(output: Option<P>) -> bool {
+    output.map_or(true, |path| path.as_ref().as_os_str() == "-")
+}
+
+/// Create the output file at `path`.
+fn create_output<P: AsRef<Path>>(path: P) -> Result<File> {
+    File::create(&path)
+        .with_context(|| format!("Failed to open {} for writing.", path.as_ref().display()))
+}
+
 /// Check if err is a broken pipe.
 #[inline]
 fn is_broken_pipe(err: &Error) -> bool {
@@ -98,55 +156,286 @@ fn is_broken_pipe(err: &Error) -> bool {
 /// If `field-headers` is used as a regex then the headers will be be grouped together in groups that all matched the
 /// same regex, and in the order of the regex as specified on the CLI.
 #[derive(Debug, Parser)]
-#[clap(author, version = HCK_VERSION)]
+#[clap(author, version = HCK_VERSION, args_conflicts_with_subcommands = true)]
 struct Opts {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Field selection, when no subcommand is given.
+    #[clap(flatten)]
+    select: SelectOpts,
+}
+
+/// Modes of `hck`, field selection is the default when no subcommand is given.
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Select fields from the inputs, the same as running `hck` without a subcommand.
+    Select(Box<SelectOpts>),
+    /// Generate deterministic pseudo-data, for benchmarks and test fixtures.
+    Gen(GenOpts),
+    /// Join two inputs on key fields, writing the selected fields of each matching pair of lines.
+    Join(Box<JoinOpts>),
+    /// Run a cookbook of example commands against a small bundled corpus, printing each command and its
+    /// output. Exits non-zero if any output isn't what it should be.
+    Examples(ExamplesOpts),
+}
+
+/// Options for reading inputs, shared by the subcommands that read them.
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "Input")]
+struct InputOpts {
     /// Input files to parse, defaults to stdin.
     ///
     /// If a file has a recognizable file extension indicating that it is compressed, and a local binary
     /// to perform decompression is found, decompression will occur automagically. This requires with `-z`.
-    input: Vec<PathBuf>,
+    #[clap(value_name = "INPUT")]
+    files: Vec<PathBuf>,
 
-    /// Output file to write to, defaults to stdout
-    #[clap(short, long, allow_hyphen_values = true)]
-    output: Option<PathBuf>,
+    /// Try to find the correct decompression method based on the file extensions
+    #[clap(short = 'z', long, env = "HCK_TRY_DECOMPRESS")]
+    try_decompress: bool,
 
-    /// Delimiter to use on input files, this is a substring literal by default. To treat it as a literal add the `-L` flag.
-    #[clap(short, long, default_value = r"\s+", allow_hyphen_values = true)]
-    delimiter: String,
+    /// Disallow the possibility of using mmap, the same as `--mmap never`
+    #[clap(long, conflicts_with = "mmap")]
+    no_mmap: bool,
 
-    /// Treat the delimiter as a string literal. This can significantly improve performance, especially for single byte delimiters.
-    #[clap(short = 'L', long)]
-    delim_is_literal: bool,
+    /// When to memory map input files. `always` maps even where `auto` wouldn't, like on macOS, and makes inputs
+    /// that can't be mapped, like stdin or compressed files, an error instead of reading them.
+    #[clap(long, value_enum, default_value_t = MmapMode::Auto)]
+    mmap: MmapMode,
+
+    /// Read input files with O_DIRECT, bypassing the page cache, to benchmark throughput on uncached
+    /// inputs. Experimental and Linux only, never uses mmap and doesn't apply to compressed inputs.
+    #[clap(long)]
+    direct_io: bool,
+
+    /// Read input files with io_uring, keeping several reads in flight. Helps on fast storage like
+    /// NVMe drives, never uses mmap and doesn't apply to compressed inputs. Linux only, files are
+    /// read normally where io_uring is unavailable.
+    #[cfg(feature = "uring")]
+    #[clap(long, conflicts_with = "direct_io")]
+    uring: bool,
+
+    /// Don't hint to the OS that input files are read sequentially, with `madvise` for memory maps and
+    /// `posix_fadvise` for files that are read. The hints help on large files that aren't cached yet.
+    #[clap(long)]
+    no_read_hints: bool,
+
+    /// The largest read to make from stdin, ex: 1M. Reads start small and grow while stdin keeps
+    /// up, and output is flushed as it is produced while stdin is only delivering a little at a
+    /// time. Defaults to 64K.
+    #[clap(long, value_parser = parse_byte_size)]
+    stdin_buffer_size: Option<usize>,
+
+    /// The size of the buffer input is read into, ex: 4M. Bigger reads help on storage that is slow
+    /// to answer each request, like NFS or object stores mounted with FUSE. Defaults to 64K, or
+    /// `--stdin-buffer-size` if that is bigger.
+    #[clap(long, value_parser = parse_byte_size)]
+    read_buffer: Option<usize>,
+
+    /// Drop the rest of each line after the last selected field while reading it, so lines of any length can
+    /// be processed without holding them in memory. Needs a single byte literal delimiter and no open ended
+    /// field ranges like `3-`, and only applies to inputs that aren't memory mapped, see `--no-mmap`.
+    #[clap(long)]
+    truncate_lines: bool,
 
-    /// Use the input delimiter as the output delimiter if the input is literal and no other output delimiter has been set.
+    /// Fail on any line longer than this, ex: 64M, naming its line number, instead of growing the
+    /// buffer until the line fits. Only applies to inputs that aren't memory mapped, see `--no-mmap`.
+    #[clap(long, value_name = "BYTES", value_parser = parse_byte_size)]
+    max_line_length: Option<usize>,
+
+    /// Transcode inputs from this encoding to UTF-8 before splitting them, ex: utf-16le, latin1, windows-1252.
+    /// Output is always UTF-8.
+    #[clap(long, value_parser = parse_encoding)]
+    encoding: Option<&'static Encoding>,
+
+    /// Don't strip a UTF-8 byte order mark from the start of each input, or transcode inputs that start with
+    /// a UTF-16 one.
+    #[clap(long, conflicts_with = "encoding")]
+    keep_bom: bool,
+}
+
+/// Options for splitting records into fields and joining them back up.
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "Delimiters")]
+struct DelimOpts {
+    /// Delimiter to use on input files, this is a substring literal by default. To treat it as a literal add the `-L` flag.
+    ///
+    /// Give it once per input to split each input on its own delimiter, ex: `-d , -d '\t' a.csv b.tsv`. The inputs
+    /// still share one output delimiter.
+    ///
+    /// The escapes `\t`, `\r`, `\n`, `\0`, `\\`, and `\xNN` are resolved with `-L` and in `-D`. A regex delimiter that
+    /// is just one escaped byte, ex: `-d '\x1f'` or `-d '\0'`, is split on as that byte, keeping fast mode.
     #[clap(
-        short = 'I',
+        short,
         long,
-        requires("delim-is-literal"),
-        conflicts_with("output-delimiter")
+        env = "HCK_DELIMITER",
+        default_value = r"\s+",
+        number_of_values = 1,
+        allow_hyphen_values = true
     )]
+    delimiter: Vec<String>,
+
+    /// Split and write a common format with a literal delimiter and the same output delimiter. Delimiter options
+    /// given alongside win over the preset's. Quotes in the input aren't parsed, so a quoted field with the
+    /// delimiter in it is still split, and fields are written as they were read, quotes and all, unless
+    /// `--out-quote` is given.
+    #[clap(long, value_enum)]
+    preset: Option<Preset>,
+
+    /// Treat the delimiter as a string literal. This can significantly improve performance, especially for single byte delimiters.
+    #[clap(short = 'L', long, env = "HCK_DELIM_IS_LITERAL")]
+    delim_is_literal: bool,
+
+    /// Use the input delimiter as the output delimiter if no other output delimiter has been set.
+    ///
+    /// With a regex delimiter the first delimiter matched on each line is used, falling back to
+    /// `--input-delim-fallback` for lines with no match.
+    #[clap(short = 'I', long)]
     use_input_delim: bool,
 
+    /// The output delimiter to use with `-I` and a regex delimiter for lines where the delimiter isn't found.
+    #[clap(long, default_value = "\t", requires("use_input_delim"))]
+    input_delim_fallback: String,
+
     /// Delimiter string to use on outputs
-    #[clap(short = 'D', long, default_value = "\t", allow_hyphen_values = true)]
+    #[clap(
+        short = 'D',
+        long,
+        env = "HCK_OUTPUT_DELIMITER",
+        default_value = "\t",
+        allow_hyphen_values = true
+    )]
     output_delimiter: String,
 
+    /// Split lines into columns of fixed byte widths instead of on a delimiter, ex: 10,8,20.
+    /// Bytes past the last width are ignored.
+    #[clap(long)]
+    widths: Option<String>,
+
+    /// Read the fixed column widths from a file, one or more per line separated by commas or whitespace.
+    /// Text after a `#` is ignored.
+    #[clap(long, conflicts_with = "widths")]
+    widths_file: Option<PathBuf>,
+
+    /// Split fields the way awk does by default, on runs of spaces and tabs with leading and trailing
+    /// ones ignored, so `-f` numbers match awk's `$1`, `$2`, ... on space padded input.
+    #[clap(long, conflicts_with_all(["widths", "widths_file"]))]
+    awk_fields: bool,
+
+    /// Treat runs of the literal delimiter as one, skipping empty fields, including any at the start or
+    /// end of a line. Unlike switching to a regex delimiter like `,+`, this keeps fast mode. Requires `-L`.
+    #[clap(long, requires = "delim_is_literal")]
+    squeeze: bool,
+
+    /// Support CRLF newlines
+    #[clap(long)]
+    crlf: bool,
+
+    /// Split records on this instead of newlines, ex: '\x1e'. A single byte keeps fast mode, anything longer
+    /// is handled in slow mode and written back out after each record.
+    #[clap(long, conflicts_with = "crlf", allow_hyphen_values = true)]
+    record_sep: Option<String>,
+
+    /// Treat `--record-sep` as a regex. Records are written out separated by newlines.
+    #[clap(long, requires = "record_sep")]
+    record_sep_is_regex: bool,
+
+    /// Write this after each record instead of the input's newline or record separator, ex: '\n' to turn
+    /// `--crlf` input into plain newlines, or '\0'. Must be a single byte or '\r\n'.
+    #[clap(long, allow_hyphen_values = true)]
+    out_record_sep: Option<String>,
+}
+
+/// Options for writing the output, shared by every subcommand.
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "Output")]
+struct OutputOpts {
+    /// Output file to write to, defaults to stdout
+    #[clap(
+        short = 'o',
+        long = "output",
+        value_name = "OUTPUT",
+        allow_hyphen_values = true
+    )]
+    file: Option<PathBuf>,
+
+    /// Try to gzip compress the output
+    #[clap(short = 'Z', long)]
+    try_compress: bool,
+
+    /// Threads to use for compression, 0 will result in `hck` staying single threaded.
+    #[clap(short = 't', long, env = "HCK_THREADS", default_value=&DEFAULT_CPUS.as_str())]
+    compression_threads: usize,
+
+    /// Compression level
+    #[clap(short = 'l', long, env = "HCK_COMPRESSION_LEVEL", default_value = "6")]
+    compression_level: u32,
+
+    /// Sync the output file, and the directory it is in, to disk before exiting.
+    #[clap(long)]
+    fsync: bool,
+
+    /// The size of the output buffer, ex: 1M, which is written out each time it fills. `0` writes
+    /// every piece of output as it is produced.
+    #[clap(
+        long,
+        visible_alias = "write-buffer",
+        value_name = "BYTES",
+        value_parser = parse_buffer_size,
+        conflicts_with = "try_compress"
+    )]
+    flush_size: Option<usize>,
+
+    /// The most output to hold at once, ex: 4M, defaults to `--flush-size`. Output is still written out once
+    /// `--flush-size` bytes are buffered, but a record that doesn't fit in what's left of it is buffered whole
+    /// rather than written in pieces. Anything larger than this is written as it is produced.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_buffer_size,
+        conflicts_with = "try_compress"
+    )]
+    max_buffer: Option<usize>,
+
+    /// Write out each line as soon as it is selected. This is the default when writing to a terminal.
+    #[clap(long, conflicts_with_all(["try_compress", "flush_size", "max_buffer"]))]
+    line_buffered: bool,
+
+    /// Page output through `less -FRSX` when writing to a terminal, so wide rows don't wrap and output that
+    /// fits on one screen is written as is. Set `HCK_PAGER` to use another pager, or to an empty value to
+    /// turn paging off.
+    #[clap(long, conflicts_with = "try_compress")]
+    pager: bool,
+}
+
+/// Options for `hck select`, which is also what `hck` runs without a subcommand.
+#[derive(Debug, Args)]
+struct SelectOpts {
     /// Fields to keep in the output, ex: 1,2-,-5,2-5. Fields are 1-based and inclusive.
     #[clap(short, long, allow_hyphen_values = true)]
     fields: Option<String>,
 
+    /// Fields to keep from each input in turn, as `;` separated lists like `-f` takes, ex: `1-3;2,5` keeps fields
+    /// 1 to 3 of the first input and fields 2 and 5 of the second. Give one list per input.
+    #[clap(long, conflicts_with_all(["fields", "edge"]), allow_hyphen_values = true)]
+    fields_per_file: Option<String>,
+
     /// Fields to exclude from the output, ex: 3,9-11,15-. Exclude fields are 1 based and inclusive.
     /// Exclude fields take precedence over `fields`.
     #[clap(short = 'e', long, allow_hyphen_values = true)]
     exclude: Option<String>,
 
     /// Headers to exclude from the output, ex: '^badfield.*$`. This is a string literal by default.
-    /// Add the `-r` flag to treat as a regex.
+    /// Add the `-r` flag to treat as a regex. Commas separate several headers, as for `-F`.
     #[clap(short = 'E', long, number_of_values = 1, allow_hyphen_values = true)]
     exclude_header: Option<Vec<Regex>>,
 
     /// A string literal or regex to select headers, ex: '^is_.*$`. This is a string literal
     /// by default. add the `-r` flag to treat it as a regex.
+    ///
+    /// Commas separate several headers, selected in the given order, ex: `chrom,pos,ref`. Use `\,` for a
+    /// literal comma. Regex commas inside `{}`, `[]` or `()` don't separate headers, so `a{1,3}` still works.
     #[clap(short = 'F', long, number_of_values = 1, allow_hyphen_values = true)]
     header_field: Option<Vec<Regex>>,
 
@@ -154,652 +443,4544 @@ struct Opts {
     #[clap(short = 'r', long)]
     header_is_regex: bool,
 
-    /// Try to find the correct decompression method based on the file extensions
-    #[clap(short = 'z', long)]
-    try_decompress: bool,
+    /// Don't write the header line of each input when selecting by `-F` or `-E`, leaving only data rows. The
+    /// header is still used to resolve the fields.
+    #[clap(long, visible_alias = "no-header-out")]
+    no_out_header: bool,
 
-    /// Try to gzip compress the output
-    #[clap(short = 'Z', long)]
-    try_compress: bool,
+    /// Treat the inputs as headerless, naming their columns with these comma separated names, ex: `id,name`.
+    /// `-F` and `-E` select by these names, and the names of the selected fields are written as a header line
+    /// before the output.
+    #[clap(long)]
+    add_header: Option<String>,
 
-    /// Threads to use for compression, 0 will result in `hck` staying single threaded.
-    #[clap(short = 't', long, default_value=&DEFAULT_CPUS.as_str())]
-    compression_threads: usize,
+    /// Like `--add-header`, but name the columns `c1`, `c2`, ... for as many fields as the first line of an
+    /// input has.
+    #[clap(long, conflicts_with = "add_header")]
+    gen_header: bool,
 
-    /// Compression level
-    #[clap(short = 'l', long, default_value = "6")]
-    compression_level: u32,
+    /// Write the selected fields of each chunk of input with vectored writes instead of copying
+    /// them into the output buffer. Only used in fast mode, and mostly helps with wide fields
+    /// written to a file.
+    #[clap(long)]
+    vectored_writes: bool,
 
-    /// Disallow the possibility of using mmap
+    /// Write the record for a final input line that has no line terminator without one too. By default every
+    /// record is terminated.
+    ///
+    /// Ignored when there is more than one input so that records from different inputs are never joined.
     #[clap(long)]
-    no_mmap: bool,
+    mirror_missing_newline: bool,
 
-    /// Support CRLF newlines
+    /// Format to write the selected fields in. `csv` uses a `,` output delimiter, so it can't be given with `-D`
+    /// or `-I`, and quotes fields as needed. `dot` writes a Graphviz digraph using the `--edge` columns.
+    #[clap(long, value_enum, default_value_t = OutFormat::Delimited)]
+    out_format: OutFormat,
+
+    /// Quote output fields that contain the output delimiter, a double quote, or a line break, doubling
+    /// any internal quotes per RFC 4180.
     #[clap(long)]
-    crlf: bool,
-}
+    out_quote: bool,
 
-fn main() -> Result<()> {
-    // TODO: move tests / add more tests
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-    let opts = Opts::parse();
+    /// How to keep output fields that contain the output delimiter or a line break parseable with
+    /// `--out-format delimited`. `quote` is the same as `--out-quote`, `backslash` escapes backslashes and
+    /// output delimiters with a backslash and writes line breaks as `\n` and `\r`.
+    #[clap(long, value_enum, default_value_t = OutEscape::None, conflicts_with = "out_quote")]
+    out_escape: OutEscape,
 
-    let writer = select_output(opts.output.as_ref())?;
-    // TODO: Support all flate2 compression targets via enum on `-Z`
-    let mut writer: Box<dyn Write> = if opts.try_compress {
-        Box::new(
-            ZBuilder::<Bgzf, _>::new()
-                .compression_level(Compression::new(opts.compression_level))
-                .num_threads(opts.compression_threads)
-                .from_writer(writer),
-        )
-    } else {
-        Box::new(BufWriter::new(writer))
-    };
+    /// Columns to use as edges for `--out-format dot`, ex: `1,3` or `1,3:weight=5`. Columns are 1-based.
+    #[clap(
+        long,
+        conflicts_with_all(["fields", "header_field", "exclude", "exclude_header"])
+    )]
+    edge: Option<EdgeSpec>,
 
-    if opts.input.is_empty() && opts.try_decompress && opts.header_field.is_some() {
-        warn!("Selections based on header fields is not currently supported on STDIN compressed data.");
-    }
+    /// Decode selected output fields as `FIELDS:CODEC`, ex: `3:url` or `1,4-5:json`. Codecs are `url` (percent
+    /// encoding), `backslash` (`\t`, `\n`, `\xNN`, ...), `tsv` (only `\t`, `\n`, `\r`, and `\\`), `json` (string
+    /// contents without the quotes), and `base64`.
+    /// May be repeated, and is applied before `--encode` and everything else. Malformed escapes are left as is.
+    #[clap(long)]
+    decode: Vec<CodecSpec>,
 
-    let inputs: Vec<HckInput<PathBuf>> = if opts.input.is_empty() {
-        vec![HckInput::Stdin]
-    } else {
-        opts.input
-            .iter()
-            .map(|p| {
-                if p.as_os_str() == "-" {
-                    HckInput::Stdin
-                } else {
-                    HckInput::Path(p.clone())
-                }
-            })
-            .collect()
-    };
+    /// Encode selected output fields as `FIELDS:CODEC`, with the same codecs as `--decode`. May be repeated.
+    #[clap(long)]
+    encode: Vec<CodecSpec>,
 
-    let mut conf_builder = CoreConfigBuilder::new();
+    /// Percent decode the selected output fields at these positions, ex: `9`, the same as `--decode 9:url`. Handy
+    /// for GFF3 attributes or access log paths. Applied after `--decode`, and may be repeated.
+    #[clap(long, value_name = "LIST", value_parser = parse_url_fields)]
+    url_decode: Vec<CodecSpec>,
 
-    let line_term = if opts.crlf {
-        LineTerminator::crlf()
-    } else {
-        LineTerminator::default()
-    };
-    conf_builder = conf_builder.line_terminator(line_term);
+    /// Base64 decode the selected output fields at these positions, ex: `4`. Applied after `--url-decode`.
+    ///
+    /// Decoded payloads are written as is, and may contain delimiters, newlines, or non-UTF-8 bytes. Add
+    /// `--encode 4:backslash` to keep them on one line and printable, or `--out-quote` to quote them.
+    #[clap(long, value_parser = parse_base64_fields)]
+    b64_decode: Vec<CodecSpec>,
 
-    let mmap = if opts.no_mmap {
-        MmapChoice::never()
-    } else {
-        unsafe { MmapChoice::auto() }
-    };
+    /// Base64 encode the selected output fields at these positions, ex: `4`. Applied before `--encode`.
+    #[clap(long, value_parser = parse_base64_fields)]
+    b64_encode: Vec<CodecSpec>,
 
-    let out_delim = if opts.delim_is_literal && opts.use_input_delim {
-        unescape(&opts.delimiter)
-    } else {
-        unescape(&opts.output_delimiter)
-    };
+    /// Split a selected output field into pieces that each become a column, as `FIELD:SEP[:N]`, ex: `5:;`
+    /// for a semicolon separated annotation column. A trailing `:N` keeps only the first N pieces. Positions
+    /// are counted before any splits, and options applied later, like `--require` or `--filter`, see each
+    /// piece as its own field. A header row isn't split. Applied after `--encode`, and may be repeated.
+    #[clap(long, value_name = "FIELD:SEP[:N]")]
+    split_field: Vec<SplitFieldSpec>,
 
-    let conf = conf_builder
-        .mmap(mmap)
-        .delimiter(opts.delimiter.as_bytes())
-        .output_delimiter(&out_delim)
-        .is_regex_parser(!opts.delim_is_literal)
-        .try_decompress(opts.try_decompress)
-        .fields(opts.fields.as_deref())
-        .headers(opts.header_field.as_deref())
-        .exclude(opts.exclude.as_deref())
-        .exclude_headers(opts.exclude_header.as_deref())
-        .header_is_regex(opts.header_is_regex)
-        .build()?;
+    /// Join selected output fields into one column, as `FIELDS:JOINER`, ex: `1,2:_` for `chrom_pos` keys. The
+    /// joined column takes the place of the first field, and a header is joined the same way. Applied after
+    /// `--split-field`, and may be repeated, where each merge counts positions after the ones before it.
+    #[clap(long, value_name = "FIELDS:JOINER")]
+    merge: Vec<MergeFieldsSpec>,
 
-    let mut line_buffer = LineBufferBuilder::new().build();
+    /// Insert a constant column at an output position on every row, as `POS=VALUE`, ex: `1=batch7`. The header
+    /// gets the value too when there is one, and rows too short to reach the position get it at the end.
+    /// Applied after `--enumerate`, and may be repeated, where positions are counted in the final output.
+    #[clap(long, value_name = "POS=VALUE", value_parser = parse_insert)]
+    insert: Vec<(usize, String)>,
 
-    for input in inputs.into_iter() {
-        if let Err(err) = run(input, &mut writer, &conf, &mut line_buffer) {
-            if is_broken_pipe(&err) {
-                exit(0)
-            }
-            error!("{}", err);
-            exit(1)
-        }
-    }
-    Ok(())
-}
+    /// Read and write fields with TSV escapes, as used by Postgres `COPY`. `\t`, `\n`, `\r`, and `\\` in input
+    /// fields are decoded before anything else, and escaped again just before output, so tabs and newlines in
+    /// values can't break up the columns.
+    #[clap(long)]
+    tsv_escapes: bool,
 
-/// Run the actual parsing and writing
-fn run<W: Write>(
-    input: HckInput<PathBuf>,
-    writer: &mut W,
-    conf: &CoreConfig,
-    line_buffer: &mut LineBuffer,
-) -> Result<()> {
-    let (extra, fields) = conf.parse_fields(&input)?;
-    // No point processing empty fields
-    if fields.is_empty() {
-        return Ok(());
-    }
+    /// Replace values of a selected output field using a mapping file, as `FIELD:PATH[:default=VALUE]`, ex:
+    /// `2:map.tsv:default=UNKNOWN`. Each line of the file is a value and its replacement separated by a tab.
+    /// Values missing from the file become VALUE, or are left as is without a default. Applied after `--decode`
+    /// and `--encode`, and may be repeated.
+    #[clap(long)]
+    recode: Vec<RecodeSpec>,
 
-    match conf.parsed_delim() {
-        RegexOrString::Regex(regex) => {
-            let mut core = Core::new(
-                conf,
-                &fields,
-                RegexLineParser::new(&fields, regex),
-                line_buffer,
-            );
-            core.hck_input(input, writer, extra)?;
-        }
-        RegexOrString::String(s) => {
-            // let s = unescape(s);
-            let mut core = Core::new(
-                conf,
-                &fields,
-                SubStrLineParser::new(&fields, s.as_bytes()),
-                line_buffer,
-            );
-            core.hck_input(input, writer, extra)?;
-        }
-    };
-    Ok(())
-}
+    /// Only output rows whose selected output field matches a regex, as `FIELD:REGEX`, ex: `3:^\d+$`. Rows too
+    /// short to have the field are dropped. Applied after `--recode`, and may be repeated to require several.
+    #[clap(long)]
+    require: Vec<ColumnPattern>,
 
-#[cfg(test)]
-mod test {
+    /// Replace a selected output field with the first capture group of a regex, as `FIELD:REGEX`, ex:
+    /// `3:^(\w+)-`. The whole match is used if the regex has no groups, and fields that don't match are left as
+    /// is. Applied after `--require`, and may be repeated.
+    #[clap(long)]
+    capture: Vec<ColumnPattern>,
 
-    use std::io::BufReader;
+    /// Replace a selected output field with the first capture group of a regex, as `FIELD:/REGEX/`, ex:
+    /// `2:/sample=([A-Z0-9]+)/`. Like `--capture`, but fields that don't match are emptied. The slashes are
+    /// optional. Applied after `--capture`, and may be repeated.
+    #[clap(long, value_name = "FIELD:/REGEX/", value_parser = ColumnPattern::parse_slashed)]
+    extract: Vec<ColumnPattern>,
 
-    use super::*;
-    use bstr::io::BufReadExt;
-    use rstest::rstest;
-    use tempfile::TempDir;
+    /// Upper case every selected output field. Applied after `--extract`.
+    #[clap(long, conflicts_with = "lower")]
+    upper: bool,
 
-    /// Build a set of opts for testing
-    fn build_opts(
-        input_file: impl AsRef<Path>,
-        output_file: impl AsRef<Path>,
-        fields: &str,
-        no_mmap: bool,
-        delimiter: &str,
-    ) -> Opts {
-        Opts {
-            input: vec![input_file.as_ref().to_path_buf()],
-            output: Some(output_file.as_ref().to_path_buf()),
-            delimiter: delimiter.to_string(),
-            delim_is_literal: false,
-            output_delimiter: "\t".to_owned(),
-            use_input_delim: false,
-            fields: Some(fields.to_owned()),
-            header_field: None,
-            header_is_regex: true,
-            try_decompress: false,
-            try_compress: false,
-            no_mmap,
-            crlf: false,
-            exclude: None,
-            exclude_header: None,
-            compression_level: 3,
-            compression_threads: 0,
-        }
-    }
+    /// Upper case the selected output fields at these positions, ex: `2,5`. Applied after `--upper` and
+    /// `--lower`.
+    #[clap(long, value_name = "LIST")]
+    upper_in: Option<String>,
 
-    /// Build a set of opts for testing
-    fn build_opts_not_regex(
-        input_file: impl AsRef<Path>,
-        output_file: impl AsRef<Path>,
-        fields: &str,
-        no_mmap: bool,
-        delimiter: &str,
-    ) -> Opts {
-        Opts {
-            input: vec![input_file.as_ref().to_path_buf()],
-            output: Some(output_file.as_ref().to_path_buf()),
-            delimiter: delimiter.to_string(),
-            delim_is_literal: true,
-            output_delimiter: "\t".to_owned(),
-            use_input_delim: false,
-            fields: Some(fields.to_owned()),
-            header_field: None,
-            header_is_regex: true,
-            try_decompress: false,
-            try_compress: false,
-            no_mmap,
-            crlf: false,
-            exclude: None,
-            exclude_header: None,
-            compression_level: 3,
-            compression_threads: 0,
-        }
-    }
+    /// Lower case every selected output field. Applied after `--extract`.
+    #[clap(long)]
+    lower: bool,
 
-    /// Build a set of opts for testing
-    #[allow(clippy::too_many_arguments)]
-    fn build_opts_generic(
-        input_file: impl AsRef<Path>,
-        output_file: impl AsRef<Path>,
-        fields: Option<&str>,
-        header_field: Option<Vec<Regex>>,
-        exclude: Option<&str>,
-        no_mmap: bool,
-        delimiter: &str,
-        delim_is_literal: bool,
-        header_is_regex: bool,
-    ) -> Opts {
-        Opts {
-            input: vec![input_file.as_ref().to_path_buf()],
-            output: Some(output_file.as_ref().to_path_buf()),
-            delimiter: delimiter.to_string(),
-            delim_is_literal,
-            output_delimiter: "\t".to_owned(),
-            use_input_delim: false,
-            fields: fields.map(|f| f.to_owned()),
-            header_field,
-            header_is_regex,
-            try_decompress: false,
-            try_compress: false,
-            no_mmap,
-            crlf: false,
-            exclude: exclude.map(|e| e.to_owned()),
-            exclude_header: None,
-            compression_threads: 0,
-            compression_level: 3,
-        }
-    }
+    /// Lower case the selected output fields at these positions, ex: `2,5`. Applied after `--upper-in`.
+    #[clap(long, value_name = "LIST")]
+    lower_in: Option<String>,
 
-    /// Simple function to read a tsv into a nested list of lists.
-    fn read_tsv(path: impl AsRef<Path>) -> Vec<Vec<String>> {
-        let reader = BufReader::new(File::open(path).unwrap());
-        let mut result = vec![];
-        let r = Regex::new(r"\s+").unwrap();
+    /// Drop rows where any selected output field is empty. Applied after `--upper` and `--lower`.
+    #[clap(long)]
+    skip_empty: bool,
 
-        for line in reader.byte_lines() {
-            let line = &line.unwrap();
-            result.push(
-                r.split(line)
-                    .map(|s| unsafe { String::from_utf8_unchecked(s.to_vec()) })
-                    .collect(),
-            );
-        }
-        result
-    }
+    /// Drop rows where any of these selected output fields is empty, ex: `2,5`. Rows too short to have a field
+    /// count as empty. Implies `--skip-empty`.
+    #[clap(long, value_name = "LIST")]
+    skip_empty_in: Option<String>,
 
-    /// Write delmited data to a file.
-    fn write_file(path: impl AsRef<Path>, data: Vec<Vec<&str>>, sep: &str) {
-        let mut writer = BufWriter::new(File::create(path).unwrap());
-        for row in data {
-            writeln!(&mut writer, "{}", row.join(sep)).unwrap();
-        }
-        writer.flush().unwrap();
-    }
+    /// Only output rows whose input column matches a regex, as `COLUMN:REGEX`, ex: `3:^chr[0-9]+$` like awk's
+    /// `$3 ~ /^chr[0-9]+$/`. Unlike `--require` the column is numbered in the input, so it needn't be selected.
+    /// Rows too short to have the column are dropped. May be repeated to match several.
+    #[clap(long = "match", value_name = "COLUMN:REGEX")]
+    column_match: Vec<ColumnPattern>,
 
-    // Wrap the run function to create the readers and writers.
-    fn run_wrapper<P: AsRef<Path>>(input: P, output: P, opts: &Opts) {
-        let conf = CoreConfigBuilder::new()
-            .delimiter(opts.delimiter.as_bytes())
-            .is_regex_parser(!opts.delim_is_literal)
-            .mmap(if opts.no_mmap {
-                MmapChoice::never()
-            } else {
-                unsafe { MmapChoice::auto() }
-            })
-            .output_delimiter(opts.output_delimiter.as_bytes())
-            .headers(opts.header_field.as_deref())
-            .fields(opts.fields.as_deref())
-            .exclude(opts.exclude.as_deref())
-            .exclude_headers(opts.exclude_header.as_deref())
-            .header_is_regex(opts.header_is_regex)
-            .build()
-            .unwrap();
-        let mut line_buffer = LineBufferBuilder::new().build();
+    /// Only output the rows that `--match` would drop.
+    #[clap(long, requires = "column_match")]
+    invert_match: bool,
+
+    /// Only output rows matching an expression, ex: `col(3) == "PASS" && col(5) > 30`.
+    ///
+    /// `col(N)` is the Nth selected output field. Comparisons with a number, and `<`, `<=`, `>`, `>=` between
+    /// columns, are numeric and false when a side isn't a number, or an error with `--strict`. Comparisons
+    /// with a quoted string are bytewise. They can be combined with `&&`, `||`, `!`, and parentheses.
+    /// A header row is only skipped when selecting by header with `-F` or `-E`, otherwise it is filtered like
+    /// any other row.
+    #[clap(long)]
+    filter: Option<String>,
+
+    /// Only output the first row for each distinct set of selected fields, after any `--filter`.
+    #[clap(long)]
+    unique: bool,
+
+    /// Like `--unique`, but only compare the given positions of the selected output fields, ex: `1,3`.
+    /// Positions are 1-based and may be ranges.
+    #[clap(long)]
+    unique_on: Option<String>,
+
+    /// Prepend a surrogate key column derived from some of the selected output fields, as `FIELDS:NAME:KIND`,
+    /// ex: `1+2+3:id:uuid5`. FIELDS are `+` separated 1-based positions, NAME is written in place of the key on the
+    /// header line when selecting by `-F`, and KIND is `uuid5` or `xxh3`. Equal fields always get the same key.
+    #[clap(long)]
+    gen_key: Option<GenKeySpec>,
+
+    /// Prepend a row number column, counting the rows written out across all inputs. The header line gets
+    /// `index` when selecting by `-F`. Applied after `--gen-key`.
+    #[clap(long)]
+    enumerate: bool,
+
+    /// The first row number for `--enumerate`.
+    #[clap(long, value_name = "N", default_value_t = 1, requires = "enumerate")]
+    enumerate_start: u64,
+
+    /// How much `--enumerate` counts up by from one row to the next.
+    #[clap(long, value_name = "N", default_value_t = 1, requires = "enumerate")]
+    enumerate_step: u64,
+
+    /// Count `--enumerate` row numbers from the start again for each input.
+    #[clap(long, requires = "enumerate")]
+    restart_per_file: bool,
+
+    /// Normalize key fields before comparing them in `--unique`, `--unique-on`, `--gen-key`, and `--count-unique`, as a comma separated
+    /// list of `lower` and `trim`, ex: `lower,trim`. The fields are still written out as they were read.
+    #[clap(long)]
+    key_normalize: Option<KeyNormalize>,
+
+    /// Randomly keep each row with the given probability, ex: 0.01, after any `--filter`.
+    #[clap(long, value_parser = parse_sample_rate, conflicts_with = "sample_n")]
+    sample: Option<f64>,
+
+    /// Randomly keep exactly N rows, or all rows if there are fewer, after any `--filter`. Rows are
+    /// written in input order once all input is read.
+    #[clap(long)]
+    sample_n: Option<usize>,
+
+    /// Seed for `--sample` and `--sample-n`, the same seed always selects the same rows. Defaults to
+    /// a random seed.
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Only output the first N rows, after any `--filter`. Reading stops as soon as N rows are output.
+    #[clap(long)]
+    head: Option<u64>,
+
+    /// Only output the last N rows, after any `--filter` and `--head`.
+    #[clap(long)]
+    tail: Option<usize>,
+
+    /// Only output the number of distinct rows of selected fields, after any `--filter`, `--head`, and `--tail`.
+    #[clap(long)]
+    count_unique: bool,
+
+    /// Estimate `--count-unique` with a HyperLogLog in 16 KiB of memory instead of remembering every
+    /// distinct row. The standard error of the estimate is reported to stderr.
+    #[clap(long, requires = "count_unique")]
+    approx: bool,
+
+    /// Add fields to the end of rows with fewer than N, so every row has at least N columns, ex: for loaders
+    /// that need rectangular input. Added fields are empty, or the `--fill` value. Applied after `--insert`.
+    #[clap(long, value_name = "N", conflicts_with = "pad_to_header")]
+    pad_to: Option<usize>,
+
+    /// Like `--pad-to` with as many columns as the header of each input, when selecting by `-F` or `-E`.
+    #[clap(long, conflicts_with = "no_out_header")]
+    pad_to_header: bool,
+
+    /// Write this value in place of any selected fields a row is too short to have, instead of leaving them out.
+    /// An open ended range like `3-` only gets a placeholder if the row has no fields in it at all.
+    #[clap(long)]
+    fill: Option<String>,
+
+    /// Report rows whose field count differs from the first row of their input to stderr, with line numbers.
+    /// Counting fields disables fast mode.
+    #[clap(long)]
+    check: bool,
+
+    /// Like `--check`, but exit non-zero on the first row whose field count differs, or that a `--filter`
+    /// compares numerically to a value that isn't a number.
+    #[clap(long)]
+    strict: bool,
+
+    /// Write the number of fields on each row instead of the selected fields, ex: to find ragged rows with
+    /// `--count-fields --filter 'col(1) != 12'`. Keeps fast mode with a single byte literal delimiter.
+    #[clap(long, conflicts_with = "count")]
+    count_fields: bool,
+
+    /// Like `--count-fields`, but write the number of fields as a last column after the selected fields.
+    #[clap(long, conflicts_with_all(["count", "count_fields"]))]
+    count_fields_column: bool,
+
+    /// What to do when exclusions leave no fields to select for an input. `empty-lines` writes a blank
+    /// record per row so row counts are preserved.
+    #[clap(long, value_enum, default_value_t = OnEmptySelection::Skip)]
+    on_empty_selection: OnEmptySelection,
+
+    /// Check that selecting every field with the input delimiter reproduces each input byte for byte,
+    /// reporting the result per input instead of writing any fields. Exits non-zero if any input differs.
+    #[clap(
+        long,
+        conflicts_with_all(["count", "fields_per_file", "no_out_header", "add_header", "gen_header", "filter", "unique", "unique_on", "sample", "sample_n", "head", "tail", "gen_key", "enumerate", "insert", "pad_to", "pad_to_header", "count_unique", "decode", "encode", "url_decode", "b64_decode", "b64_encode", "split_field", "merge", "recode", "require", "capture", "extract", "upper", "upper_in", "lower", "lower_in", "skip_empty", "skip_empty_in"])
+    )]
+    verify_roundtrip: bool,
+
+    /// Don't write any fields, just the number of records and selected fields in each input as
+    /// `input<TAB>records<TAB>fields`.
+    #[clap(
+        short = 'c',
+        long,
+        conflicts_with_all(["no_out_header", "add_header", "gen_header", "filter", "unique", "unique_on", "sample", "sample_n", "head", "tail", "gen_key", "enumerate", "insert", "pad_to", "pad_to_header", "count_unique", "decode", "encode", "url_decode", "b64_decode", "b64_encode", "split_field", "merge", "recode", "require", "capture", "extract", "upper", "upper_in", "lower", "lower_in", "skip_empty", "skip_empty_in"])
+    )]
+    count: bool,
+
+    /// Write line N of every input side by side on one output line, like paste(1), instead of one input after
+    /// another. Combine with `--fields-per-file` to pick different fields from each input. An input that runs
+    /// out of lines before the others leaves an empty column.
+    #[clap(
+        long,
+        conflicts_with_all(["count", "verify_roundtrip", "edge", "record_sep", "no_out_header", "filter", "unique", "unique_on", "sample", "sample_n", "head", "tail", "gen_key", "enumerate", "insert", "pad_to", "pad_to_header", "count_unique", "decode", "encode", "url_decode", "b64_decode", "b64_encode", "split_field", "merge", "recode", "require", "capture", "extract", "upper", "upper_in", "lower", "lower_in", "skip_empty", "skip_empty_in"])
+    )]
+    paste: bool,
+
+    /// Don't select anything, just write the 1 based index and name of each header of the inputs, one per line,
+    /// ex: `3<TAB>ref`. Headers are split by `-d` the same way `-F` sees them. With more than one input, each
+    /// line starts with the name of its input.
+    #[clap(long, conflicts_with_all(["count", "verify_roundtrip", "paste"]))]
+    show_headers: bool,
+
+    /// Don't select anything, just write how each input would be processed: the fields selected after resolving
+    /// headers and exclusions, the parser lines are split with, and whether the input is memory mapped or
+    /// decompressed.
+    #[clap(long, conflicts_with_all(["count", "verify_roundtrip", "show_headers", "paste", "fail_if_empty"]))]
+    explain: bool,
+
+    /// Exit with status 3 if no data rows were written, ex: every row was filtered out or the inputs were empty.
+    /// Header lines don't count as data rows.
+    #[clap(long, conflicts_with_all(["count", "verify_roundtrip", "show_headers", "paste"]))]
+    fail_if_empty: bool,
+
+    /// Print performance counters (records, bytes, compressed bytes read with -z, throughput, and heap allocations when built with the `alloc-stats` feature) for each input to stderr when done.
+    #[clap(long)]
+    stats: bool,
+
+    /// Use the defaults of this named profile from the config file. Options given on the command line win over
+    /// the profile's values, and any field selection given replaces the profile's selection.
+    #[clap(long)]
+    profile: Option<String>,
+
+    /// The config file to read `--profile` from, defaults to `$XDG_CONFIG_HOME/hck/config.toml` or
+    /// `~/.config/hck/config.toml`.
+    #[clap(long, requires = "profile")]
+    config: Option<PathBuf>,
+
+    /// Read inputs through a reader that injects short reads and interrupts, seeded with the given value.
+    #[cfg(feature = "test-util")]
+    #[clap(long, hide = true)]
+    chaos_read: Option<u64>,
+
+    #[clap(flatten)]
+    input: InputOpts,
+
+    #[clap(flatten)]
+    delim: DelimOpts,
+
+    #[clap(flatten)]
+    output: OutputOpts,
+}
+
+/// The config file, a table of named profiles, ex:
+///
+/// ```toml
+/// [profiles.vcf]
+/// delimiter = "\t"
+/// delim-is-literal = true
+/// try-decompress = true
+/// header-field = ["#CHROM", "POS"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    profiles: HashMap<String, Profile>,
+}
+
+/// Defaults for `hck select` from the config file, named after the options they stand in for.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Profile {
+    delimiter: Option<String>,
+    delim_is_literal: bool,
+    output_delimiter: Option<String>,
+    crlf: bool,
+    try_decompress: bool,
+    try_compress: bool,
+    compression_level: Option<u32>,
+    compression_threads: Option<usize>,
+    fields: Option<String>,
+    header_field: Option<Vec<String>>,
+    exclude: Option<String>,
+    exclude_header: Option<Vec<String>>,
+    header_is_regex: bool,
+}
+
+/// Options for `hck gen`.
+#[derive(Debug, Args)]
+struct GenOpts {
+    /// Columns to generate as `[NAME:]KIND[*COUNT]`, ex: 'id:seq,name:word,score:float*3'.
+    /// Kinds are seq, int, float, word, and bool.
+    #[clap(long)]
+    cols: String,
+
+    /// Number of rows to generate, ex: 1000 or 1e6
+    #[clap(long, default_value = "1000", value_parser = parse_row_count)]
+    rows: u64,
+
+    /// Seed for the random number generator, the same seed always generates the same data
+    #[clap(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Delimiter to put between columns
+    #[clap(short, long, default_value = "\t", allow_hyphen_values = true)]
+    delimiter: String,
+
+    /// Write a header line of column names first
+    #[clap(long)]
+    header: bool,
+
+    /// Probability (0-1) that a row is cut short
+    #[clap(long, default_value_t = 0.0)]
+    ragged: f64,
+
+    /// Probability (0-1) that a word column contains the delimiter
+    #[clap(long, default_value_t = 0.0)]
+    collisions: f64,
+
+    #[clap(flatten)]
+    output: OutputOpts,
+}
+
+/// Options for `hck join`.
+///
+/// The left input is loaded into a hash table on its key and the right input is streamed past it, so neither
+/// needs to be sorted. Each right line is written out once for every left line with the same key, as the left
+/// fields followed by the right fields, in the order of the right input. If the left input doesn't fit in
+/// `--memory-limit` both inputs are spilled to disk and joined in parts, and the output is no longer in the
+/// order of the right input.
+#[derive(Debug, Args)]
+struct JoinOpts {
+    /// The left input, which is held in memory, `-` for stdin.
+    left: PathBuf,
+
+    /// The right input, which is streamed, `-` for stdin.
+    right: PathBuf,
+
+    /// Fields to join on, ex: 1 or 2,1. Fields are 1-based, and listed fields are matched up in order.
+    #[clap(short, long, default_value = "1", allow_hyphen_values = true)]
+    key: String,
+
+    /// Fields of the right input to join on, if they differ from `--key`.
+    #[clap(long, allow_hyphen_values = true)]
+    right_key: Option<String>,
+
+    /// Fields of the left input to write out, ex: 2-4.
+    #[clap(long, default_value = "1-", allow_hyphen_values = true)]
+    left_fields: String,
+
+    /// Fields of the right input to write out, ex: 2-4.
+    #[clap(long, default_value = "1-", allow_hyphen_values = true)]
+    right_fields: String,
+
+    /// Normalize key fields before comparing them, as a comma separated list of `lower` and `trim`.
+    #[clap(long)]
+    key_normalize: Option<KeyNormalize>,
+
+    /// Treat the first line of each input as a header, written out once as the left header followed by the
+    /// right one.
+    #[clap(long)]
+    header: bool,
+
+    /// Write the selected fields of left lines that no right line matched to this file.
+    #[clap(long)]
+    unmatched_left: Option<PathBuf>,
+
+    /// Write the selected fields of right lines that no left line matched to this file.
+    #[clap(long)]
+    unmatched_right: Option<PathBuf>,
+
+    /// Print the number of lines read and matched from each input to stderr when done.
+    #[clap(long)]
+    summary: bool,
+
+    /// About how much memory the left input may take before both inputs are spilled to disk, ex: 512M.
+    #[clap(long, default_value = "1G", value_parser = parse_byte_size)]
+    memory_limit: usize,
+
+    /// Delimiter to split both inputs on, a regex unless `-L` is given.
+    #[clap(
+        short,
+        long,
+        env = "HCK_DELIMITER",
+        default_value = r"\s+",
+        allow_hyphen_values = true
+    )]
+    delimiter: String,
+
+    /// Treat the delimiter as a string literal.
+    #[clap(short = 'L', long, env = "HCK_DELIM_IS_LITERAL")]
+    delim_is_literal: bool,
+
+    /// Delimiter string to use on outputs
+    #[clap(
+        short = 'D',
+        long,
+        env = "HCK_OUTPUT_DELIMITER",
+        default_value = "\t",
+        allow_hyphen_values = true
+    )]
+    output_delimiter: String,
+
+    /// Support CRLF newlines
+    #[clap(long)]
+    crlf: bool,
+
+    /// Try to find the correct decompression method based on the file extensions
+    #[clap(short = 'z', long, env = "HCK_TRY_DECOMPRESS")]
+    try_decompress: bool,
+
+    #[clap(flatten)]
+    output: OutputOpts,
+}
+
+/// Options for `hck examples`.
+#[derive(Debug, Args)]
+struct ExamplesOpts {
+    /// Write the example corpus to this directory and keep it, instead of using a temporary directory.
+    #[clap(long)]
+    dir: Option<PathBuf>,
+
+    #[clap(flatten)]
+    output: OutputOpts,
+}
+
+/// The files `hck examples` runs against, `people.csv` is also written gzipped.
+const EXAMPLE_CORPUS: &[(&str, &str)] = &[
+    (
+        "people.csv",
+        "id,name,age,city\n1,ann,34,paris\n2,bob,27,oslo\n3,cat,45,paris\n",
+    ),
+    (
+        "scores.csv",
+        "id,score,subject\n1,90,math\n3,75,art\n4,60,math\n",
+    ),
+    ("spaced.txt", "a  b   c\nd e\tf\n"),
+];
+
+/// A command run by `hck examples`, with the output it should write.
+struct Example {
+    title: &'static str,
+    args: &'static [&'static str],
+    expected: &'static str,
+}
+
+/// The cookbook run by `hck examples`.
+const EXAMPLES: &[Example] = &[
+    Example {
+        title: "Select columns by index",
+        args: &["-d", ",", "-f", "1,3", "people.csv"],
+        expected: "id\tage\n1\t34\n2\t27\n3\t45\n",
+    },
+    Example {
+        title: "Reorder columns",
+        args: &["-d", ",", "-f", "4,2", "people.csv"],
+        expected: "city\tname\nparis\tann\noslo\tbob\nparis\tcat\n",
+    },
+    Example {
+        title: "Split on runs of whitespace, the default",
+        args: &["-f", "3", "spaced.txt"],
+        expected: "c\nf\n",
+    },
+    Example {
+        title: "Select columns by header name",
+        args: &["-d", ",", "-F", "name", "-F", "id", "people.csv"],
+        expected: "name\tid\nann\t1\nbob\t2\ncat\t3\n",
+    },
+    Example {
+        title: "Select columns by header regex",
+        args: &["-d", ",", "-r", "-F", "^s", "scores.csv"],
+        expected: "score\tsubject\n90\tmath\n75\tart\n60\tmath\n",
+    },
+    Example {
+        title: "Exclude columns",
+        args: &["-d", ",", "-e", "1,3", "people.csv"],
+        expected: "name\tcity\nann\tparis\nbob\toslo\ncat\tparis\n",
+    },
+    Example {
+        title: "Keep rows matching an expression",
+        args: &[
+            "-d",
+            ",",
+            "-F",
+            "name,age",
+            "--filter",
+            "col(2) > 30",
+            "people.csv",
+        ],
+        expected: "name\tage\nann\t34\ncat\t45\n",
+    },
+    Example {
+        title: "Distinct values of a column",
+        args: &["-d", ",", "-f", "4", "--unique", "people.csv"],
+        expected: "city\nparis\noslo\n",
+    },
+    Example {
+        title: "Read a compressed input",
+        args: &["-z", "-d", ",", "-f", "2", "people.csv.gz"],
+        expected: "name\nann\nbob\ncat\n",
+    },
+    Example {
+        title: "Paste columns from two inputs side by side",
+        args: &[
+            "-d",
+            ",",
+            "--paste",
+            "--fields-per-file",
+            "2;3",
+            "people.csv",
+            "scores.csv",
+        ],
+        expected: "name\tsubject\nann\tmath\nbob\tart\ncat\tmath\n",
+    },
+    Example {
+        title: "Join two inputs on their first column",
+        args: &[
+            "join",
+            "-d",
+            ",",
+            "--header",
+            "--right-fields",
+            "2",
+            "people.csv",
+            "scores.csv",
+        ],
+        expected: "id\tname\tage\tcity\tscore\n1\tann\t34\tparis\t90\n3\tcat\t45\tparis\t75\n",
+    },
+    Example {
+        title: "Generate test data",
+        args: &[
+            "gen",
+            "--cols",
+            "id:seq,name:word,score:int",
+            "--rows",
+            "3",
+            "--seed",
+            "1",
+        ],
+        expected: "1\ttango\t428519\n2\toscar\t780235\n3\tfoxtrot\t530048\n",
+    },
+];
+
+/// Parse a row count that may be written in scientific notation, ex: `1e6`.
+fn parse_row_count(count: &str) -> Result<u64, String> {
+    if let Ok(count) = count.parse::<u64>() {
+        return Ok(count);
+    }
+    match count.parse::<f64>() {
+        Ok(float) if float >= 0.0 && float.fract() == 0.0 && float <= u64::MAX as f64 => {
+            Ok(float as u64)
+        }
+        _ => Err(format!("Invalid row count: {}", count)),
+    }
+}
+
+/// Parse a size in bytes with an optional binary `K`, `M`, or `G` suffix, ex: 512K.
+fn parse_byte_size(size: &str) -> Result<usize, String> {
+    let (digits, scale) = match size.as_bytes().last().map(u8::to_ascii_uppercase) {
+        Some(b'K') => (&size[..size.len() - 1], 1 << 10),
+        Some(b'M') => (&size[..size.len() - 1], 1 << 20),
+        Some(b'G') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    match digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+    {
+        Some(bytes) if bytes > 0 => Ok(bytes),
+        _ => Err(format!("Invalid size: {}", size)),
+    }
+}
+
+/// Parse a buffer size like [`parse_byte_size`], where `0` means no buffer at all.
+fn parse_buffer_size(size: &str) -> Result<usize, String> {
+    match size {
+        "0" => Ok(0),
+        _ => parse_byte_size(size),
+    }
+}
+
+/// The capacity of the line buffer inputs are read into, see `--read-buffer`.
+fn read_buffer_capacity(opts: &InputOpts) -> usize {
+    opts.read_buffer.unwrap_or_else(|| {
+        opts.stdin_buffer_size
+            .map_or(DEFAULT_STDIN_BUFFER_SIZE, |size| {
+                size.max(DEFAULT_STDIN_BUFFER_SIZE)
+            })
+    })
+}
+
+/// Parse a 1-based output position and the value to insert there from `POS=VALUE`.
+fn parse_insert(spec: &str) -> Result<(usize, String), String> {
+    let (pos, value) = spec.split_once('=').ok_or_else(|| {
+        format!(
+            "Invalid insert `{}`, expected POS=VALUE like `1=batch7`",
+            spec
+        )
+    })?;
+    match pos.trim().parse::<usize>() {
+        Ok(pos) if pos > 0 => Ok((pos - 1, value.to_owned())),
+        _ => Err(format!(
+            "Invalid insert position `{}`, expected a 1-based position",
+            pos
+        )),
+    }
+}
+
+/// Parse a list of positions to apply `codec` to.
+fn parse_codec_fields(list: &str, codec: Codec) -> Result<CodecSpec, String> {
+    Ok(CodecSpec {
+        fields: FieldRange::from_list(list).map_err(|e| e.to_string())?,
+        codec,
+    })
+}
+
+/// Parse a list of positions to apply the base64 codec to.
+fn parse_base64_fields(list: &str) -> Result<CodecSpec, String> {
+    parse_codec_fields(list, Codec::Base64)
+}
+
+/// Parse a list of positions to percent decode.
+fn parse_url_fields(list: &str) -> Result<CodecSpec, String> {
+    parse_codec_fields(list, Codec::Url)
+}
+
+/// Parse an encoding label as understood by the WHATWG Encoding Standard.
+fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("Unknown encoding: {}", label))
+}
+
+/// Parse a sampling rate, which must be in `[0, 1]`.
+fn parse_sample_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("Sample rate must be between 0 and 1: {}", rate)),
+    }
+}
+
+/// The output formats selectable from the CLI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutFormat {
+    /// Fields joined by the output delimiter
+    Delimited,
+    /// RFC 4180 style CSV, comma separated with quoting as needed
+    Csv,
+    /// A Graphviz digraph built from `--edge`
+    Dot,
+}
+
+/// How delimited output fields are escaped, see `--out-escape`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutEscape {
+    /// Write fields as they are
+    None,
+    /// Quote fields as needed per RFC 4180
+    Quote,
+    /// Escape delimiters, backslashes, and line breaks with a backslash
+    Backslash,
+}
+
+/// When to memory map inputs, see `--mmap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum MmapMode {
+    /// Map files, failing if they can't be
+    Always,
+    /// Map files where it is believed to be faster
+    Auto,
+    /// Never map files
+    Never,
+}
+
+/// Common delimited formats for `--preset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Preset {
+    /// Comma separated
+    Csv,
+    /// Tab separated
+    Tsv,
+    /// Pipe separated
+    Psv,
+    /// Separated by single spaces
+    Ssv,
+}
+
+impl Preset {
+    /// The delimiter of the format, for both input and output.
+    fn delimiter(self) -> &'static str {
+        match self {
+            Preset::Csv => ",",
+            Preset::Tsv => "\t",
+            Preset::Psv => "|",
+            Preset::Ssv => " ",
+        }
+    }
+}
+
+/// What to do with an input when no fields are left to select.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OnEmptySelection {
+    /// Write nothing for the input
+    Skip,
+    /// Write an empty record for every row of the input
+    EmptyLines,
+    /// Fail with an error
+    Error,
+}
+
+fn main() -> Result<()> {
+    // TODO: move tests / add more tests
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let matches = Opts::command().get_matches();
+    if let Err(err) = check_conflicts(&matches) {
+        err.exit()
+    }
+    let opts = opts_from_matches(&matches)?;
+
+    match &opts.command {
+        Some(Command::Select(select_opts)) => run_select(select_opts),
+        Some(Command::Gen(gen_opts)) => exit_on_error(run_gen(gen_opts)),
+        Some(Command::Join(join_opts)) => exit_on_error(run_join(join_opts)),
+        Some(Command::Examples(examples_opts)) => exit_on_error(run_examples(examples_opts)),
+        None => run_select(&opts.select),
+    }
+}
+
+/// Options of `hck select` that can't be given together.
+///
+/// These aren't clap conflicts since the first of each pair can take a default from the environment, see
+/// [`check_conflicts`].
+const CONFLICTS: &[(&str, &str)] = &[
+    ("output_delimiter", "use_input_delim"),
+    ("delimiter", "awk_fields"),
+    ("delim_is_literal", "awk_fields"),
+    ("delim_is_literal", "widths"),
+    ("delim_is_literal", "widths_file"),
+];
+
+/// Fail on any [`CONFLICTS`] given on the command line. A default from the environment doesn't conflict
+/// with an option given on the command line, which just wins over it.
+fn check_conflicts(matches: &ArgMatches) -> Result<(), clap::Error> {
+    let matches = matches.subcommand_matches("select").unwrap_or(matches);
+    let given = |id: &str| given(matches, id);
+    for (first, second) in CONFLICTS {
+        if given(first) && given(second) {
+            return Err(Opts::command().error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "the argument '--{}' cannot be used with '--{}'",
+                    second.replace('_', "-"),
+                    first.replace('_', "-")
+                ),
+            ));
+        }
+    }
+    // CSV output always uses a `,` delimiter
+    if matches.get_one::<OutFormat>("out_format") == Some(&OutFormat::Csv) {
+        if let Some(id) = ["output_delimiter", "use_input_delim"]
+            .into_iter()
+            .find(|id| given(id))
+        {
+            return Err(Opts::command().error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "the argument '--{}' cannot be used with '--out-format csv'",
+                    id.replace('_', "-")
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Build the [`Opts`] from parsed arguments, applying any `--profile` under the options given.
+fn opts_from_matches(matches: &ArgMatches) -> Result<Opts> {
+    let mut opts = Opts::from_arg_matches(matches)?;
+    let (select, matches) = match &mut opts.command {
+        Some(Command::Select(select)) => (select.as_mut(), matches.subcommand_matches("select")),
+        Some(_) => return Ok(opts),
+        None => (&mut opts.select, Some(matches)),
+    };
+    let Some(matches) = matches else {
+        return Ok(opts);
+    };
+    if let Some(name) = select.profile.clone() {
+        let path = match &select.config {
+            Some(path) => path.clone(),
+            None => default_config_path()
+                .ok_or_else(|| anyhow!("No config file for `--profile`, set `--config`"))?,
+        };
+        let config: ConfigFile = toml::from_str(
+            &std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read config file {}", path.display()))?,
+        )
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        let profile = config
+            .profiles
+            .get(&name)
+            .ok_or_else(|| anyhow!("No profile named `{}` in {}", name, path.display()))?;
+        apply_profile(select, matches, profile)?;
+    }
+    // The preset is given on the command line, so it wins over the profile
+    if let Some(preset) = select.delim.preset {
+        apply_preset(select, matches, preset);
+    }
+    Ok(opts)
+}
+
+/// Whether the option `id` was given on the command line, rather than coming from the environment or
+/// a default.
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches.try_contains_id(id).unwrap_or(false)
+        && matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+/// Set the delimiters of `preset` where the command line didn't.
+fn apply_preset(opts: &mut SelectOpts, matches: &ArgMatches, preset: Preset) {
+    let given = |id: &str| given(matches, id);
+    if !["delimiter", "awk_fields", "widths", "widths_file"]
+        .iter()
+        .any(|id| given(id))
+    {
+        opts.delim.delimiter = vec![preset.delimiter().to_owned()];
+        opts.delim.delim_is_literal = true;
+    }
+    if !given("output_delimiter") {
+        opts.delim.output_delimiter = preset.delimiter().to_owned();
+    }
+}
+
+/// Where the config file is by default, under `$XDG_CONFIG_HOME` or `~/.config`.
+fn default_config_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("hck").join("config.toml"))
+}
+
+/// Fill in the options that weren't given on the command line from `profile`.
+///
+/// A field selection is all or nothing, so any of `-f`, `-F`, `-e` or `-E` on the command line
+/// replaces the profile's selection instead of adding to it.
+fn apply_profile(opts: &mut SelectOpts, matches: &ArgMatches, profile: &Profile) -> Result<()> {
+    let given = |id: &str| given(matches, id);
+    let compile = |headers: &Option<Vec<String>>| {
+        headers
+            .as_ref()
+            .map(|headers| {
+                headers
+                    .iter()
+                    .map(|h| Regex::new(h))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()
+    };
+
+    if !given("delimiter") && !given("awk_fields") {
+        if let Some(delimiter) = &profile.delimiter {
+            opts.delim.delimiter = vec![delimiter.clone()];
+        }
+        opts.delim.delim_is_literal |= profile.delim_is_literal;
+    }
+    if let Some(output_delimiter) = &profile.output_delimiter {
+        if !given("output_delimiter") && !opts.delim.use_input_delim {
+            opts.delim.output_delimiter = output_delimiter.clone();
+        }
+    }
+    opts.delim.crlf |= profile.crlf && opts.delim.record_sep.is_none();
+    opts.input.try_decompress |= profile.try_decompress;
+    opts.output.try_compress |= profile.try_compress;
+    if let Some(level) = profile
+        .compression_level
+        .filter(|_| !given("compression_level"))
+    {
+        opts.output.compression_level = level;
+    }
+    if let Some(threads) = profile
+        .compression_threads
+        .filter(|_| !given("compression_threads"))
+    {
+        opts.output.compression_threads = threads;
+    }
+
+    let selections = ["fields", "header_field", "exclude", "exclude_header"];
+    if !selections.iter().any(|id| given(id)) {
+        opts.fields = profile.fields.clone();
+        opts.header_field = compile(&profile.header_field)?;
+        opts.exclude = profile.exclude.clone();
+        opts.exclude_header = compile(&profile.exclude_header)?;
+        opts.header_is_regex |= profile.header_is_regex;
+    }
+    Ok(())
+}
+
+/// Log the error from a subcommand and exit, treating a broken pipe as success.
+fn exit_on_error(result: Result<()>) -> Result<()> {
+    if let Err(err) = result {
+        if is_broken_pipe(&err) {
+            exit(0)
+        }
+        error!("{}", err);
+        exit(1)
+    }
+    Ok(())
+}
+
+/// The pager for `--pager` when `HCK_PAGER` isn't set.
+const DEFAULT_PAGER: &str = "less -FRSX";
+
+/// Start `command`, split on whitespace, as a pager reading from a pipe.
+///
+/// Returns `None` for an empty command, or one that can't be started, so output goes straight to
+/// stdout instead.
+fn start_pager(command: &str) -> Option<Child> {
+    let mut args = command.split_whitespace();
+    let program = args.next()?;
+    match process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => Some(child),
+        Err(err) => {
+            warn!("Failed to start pager `{}`, not paging: {}", command, err);
+            None
+        }
+    }
+}
+
+/// A sink that writes to a pager, and waits for the pager to exit once finished.
+///
+/// A pager that exits early, ex: when `less` is quit, closes the pipe, so writes fail with a broken pipe
+/// like they do for any other closed stdout.
+struct PagerSink {
+    /// The sink writing to the stdin of `pager`, dropped to close the pipe once finished
+    inner: Option<Box<dyn OutputSink>>,
+    pager: Child,
+}
+
+impl PagerSink {
+    fn inner(&mut self) -> io::Result<&mut Box<dyn OutputSink>> {
+        self.inner
+            .as_mut()
+            .ok_or_else(|| io::Error::other("write to a finished pager"))
+    }
+}
+
+impl Write for PagerSink {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner()?.write(buf)
+    }
+
+    #[inline]
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.inner()?.write_vectored(bufs)
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner()?.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner()?.flush()
+    }
+}
+
+impl OutputSink for PagerSink {
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(mut inner) = self.inner.take() {
+            inner.finish()?;
+        }
+        self.pager.wait()?;
+        Ok(())
+    }
+}
+
+/// Open the output sink described by `opts`.
+fn open_output(opts: &OutputOpts) -> Result<Box<dyn OutputSink>> {
+    if let (Some(flush_size), Some(max_buffer)) = (opts.flush_size, opts.max_buffer) {
+        if flush_size > max_buffer {
+            return Err(anyhow!(
+                "`--flush-size` can't be larger than `--max-buffer`"
+            ));
+        }
+    }
+    // With `--fsync` keep a handle on the output file to sync it once everything is written
+    let (writer, synced): (Box<dyn Write + Send + 'static>, _) = match opts.file.as_ref() {
+        Some(path) if opts.fsync && path.as_os_str() != "-" => {
+            let file = create_output(path)?;
+            (Box::new(file.try_clone()?), Some((file, path.clone())))
+        }
+        output => {
+            if opts.fsync {
+                warn!("`--fsync` only applies to an output file given with `-o`, ignoring it.");
+            }
+            (select_output(output)?, None)
+        }
+    };
+    let mut pager = if opts.pager && is_stdout(opts.file.as_ref()) && io::stdout().is_terminal() {
+        let command = std::env::var("HCK_PAGER").unwrap_or_else(|_| DEFAULT_PAGER.to_owned());
+        start_pager(&command)
+    } else {
+        None
+    };
+    let writer = match pager.as_mut().and_then(|pager| pager.stdin.take()) {
+        Some(stdin) => Box::new(stdin),
+        None => writer,
+    };
+    // TODO: Support all flate2 compression targets via enum on `-Z`
+    let mut writer: Box<dyn OutputSink> = if opts.try_compress {
+        Box::new(CompressedSink::bgzf(
+            writer,
+            opts.compression_level,
+            opts.compression_threads,
+        ))
+    } else if opts.line_buffered || (is_stdout(opts.file.as_ref()) && io::stdout().is_terminal()) {
+        Box::new(LineBufferedSink::new(writer))
+    } else if let Some(max_buffer) = opts.max_buffer {
+        let flush_size = opts.flush_size.unwrap_or(max_buffer);
+        Box::new(BoundedSink::new(flush_size, max_buffer, writer))
+    } else if let Some(size) = opts.flush_size {
+        Box::new(BufferedSink::with_capacity(size, writer))
+    } else {
+        Box::new(BufferedSink::new(writer))
+    };
+    if let Some((file, path)) = synced {
+        writer = Box::new(FsyncSink::new(writer, file, path));
+    }
+    if let Some(pager) = pager {
+        writer = Box::new(PagerSink {
+            inner: Some(writer),
+            pager,
+        });
+    }
+    Ok(writer)
+}
+
+/// Run `hck select`, which is also what `hck` runs without a subcommand.
+fn run_select(opts: &SelectOpts) -> Result<()> {
+    if opts.no_out_header && header_policy(opts) == HeaderPolicy::None {
+        return Err(anyhow!(
+            "`--no-out-header` needs a header, select fields with `-F` or `-E`"
+        ));
+    }
+    if opts.edge.is_some() && opts.out_format != OutFormat::Dot {
+        return Err(anyhow!("`--edge` is only used with `--out-format dot`"));
+    }
+
+    let mut writer = open_output(&opts.output)?;
+
+    if opts.input.files.is_empty() && opts.input.try_decompress && opts.header_field.is_some() {
+        warn!("Selections based on header fields is not currently supported on STDIN compressed data.");
+    }
+
+    let inputs: Vec<HckInput<PathBuf>> = if opts.input.files.is_empty() {
+        vec![HckInput::Stdin]
+    } else {
+        opts.input
+            .files
+            .iter()
+            .map(|p| {
+                if p.as_os_str() == "-" {
+                    HckInput::Stdin
+                } else {
+                    HckInput::Path(p.clone())
+                }
+            })
+            .collect()
+    };
+
+    let mut conf_builder = CoreConfigBuilder::new();
+
+    let line_term = if opts.delim.crlf {
+        LineTerminator::crlf()
+    } else {
+        LineTerminator::default()
+    };
+    conf_builder = conf_builder
+        .line_terminator(line_term)
+        .record_separator(opts.delim.record_sep.as_deref().map(str::as_bytes))
+        .record_separator_is_regex(opts.delim.record_sep_is_regex)
+        .output_record_separator(opts.delim.out_record_sep.as_deref().map(str::as_bytes))
+        .encoding(opts.input.encoding)
+        .keep_bom(opts.input.keep_bom);
+
+    let mmap = mmap_choice(&opts.input);
+
+    let output_format = match opts.out_format {
+        OutFormat::Delimited if opts.out_quote => OutputFormat::Quoted,
+        OutFormat::Delimited => match opts.out_escape {
+            OutEscape::None => OutputFormat::Delimited,
+            OutEscape::Quote => OutputFormat::Quoted,
+            OutEscape::Backslash => OutputFormat::Escaped,
+        },
+        OutFormat::Csv => OutputFormat::Quoted,
+        OutFormat::Dot => {
+            let edge = opts
+                .edge
+                .ok_or_else(|| anyhow!("`--out-format dot` requires `--edge`"))?;
+            OutputFormat::Dot {
+                weighted: edge.weight.is_some(),
+            }
+        }
+    };
+    let edge_fields = opts.edge.as_ref().map(EdgeSpec::field_list);
+    let header_fields = opts
+        .header_field
+        .as_deref()
+        .map(|headers| split_header_selections(headers, opts.header_is_regex))
+        .transpose()?;
+    let exclude_headers = opts
+        .exclude_header
+        .as_deref()
+        .map(|headers| split_header_selections(headers, opts.header_is_regex))
+        .transpose()?;
+    let widths = read_widths(
+        opts.delim.widths.as_deref(),
+        opts.delim.widths_file.as_deref(),
+    )?;
+
+    #[cfg(feature = "test-util")]
+    {
+        conf_builder = conf_builder.chaos_read(opts.chaos_read);
+    }
+    #[cfg(feature = "uring")]
+    {
+        conf_builder = conf_builder.uring(opts.input.uring);
+    }
+
+    let delimiters = input_delimiters(opts, inputs.len())?;
+    let conf_builder = conf_builder
+        .mmap(mmap)
+        .output_delimiter(output_delimiter(opts))
+        .is_regex_parser(!opts.delim.delim_is_literal)
+        .reuse_input_delim(opts.delim.use_input_delim)
+        .mirror_missing_terminator(opts.mirror_missing_newline && inputs.len() == 1)
+        .try_decompress(opts.input.try_decompress)
+        .fields(edge_fields.as_deref().or(opts.fields.as_deref()))
+        .headers(header_fields.as_deref())
+        .exclude(opts.exclude.as_deref())
+        .exclude_headers(exclude_headers.as_deref())
+        .header_is_regex(opts.header_is_regex)
+        .header_names(header_names(opts))
+        .widths(widths.as_deref())
+        .awk_fields(opts.delim.awk_fields)
+        .squeeze(opts.delim.squeeze)
+        .stdin_buffer_size(opts.input.stdin_buffer_size)
+        .read_buffer_size(opts.input.read_buffer)
+        .truncate_lines(opts.input.truncate_lines)
+        .max_line_length(opts.input.max_line_length)
+        .vectored_writes(opts.vectored_writes)
+        .direct_io(opts.input.direct_io)
+        .read_hints(!opts.input.no_read_hints)
+        .output_format(output_format)
+        .count_only(opts.count)
+        .fill(opts.fill.as_deref().map(str::as_bytes))
+        .ragged_rows(ragged_rows(opts))
+        .field_count(field_count(opts))
+        .column_matches(opts.column_match.clone())
+        .invert_match(opts.invert_match);
+    let field_lists = input_fields(opts, inputs.len())?;
+    // One config per input when each has its own delimiter or fields, otherwise one for all of them
+    let confs = (0..delimiters.len().max(field_lists.len()))
+        .map(|i| {
+            let delimiter = &delimiters[i.min(delimiters.len() - 1)];
+            let mut builder = conf_builder.clone().delimiter(delimiter.as_bytes());
+            if let Some(fields) = field_lists.get(i) {
+                builder = builder.fields(Some(*fields));
+            }
+            builder.build()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let conf_for = |i: usize| &confs[i.min(confs.len() - 1)];
+
+    let mut line_buffer = LineBufferBuilder::new()
+        .capacity(read_buffer_capacity(&opts.input))
+        .build();
+    if opts.verify_roundtrip {
+        return run_verify(inputs, &mut writer, &confs, &mut line_buffer);
+    }
+    if opts.show_headers {
+        return run_show_headers(opts, inputs, &mut writer, &confs);
+    }
+    let mut pipeline = build_pipeline(opts)?;
+    if opts.explain {
+        return run_explain(
+            opts,
+            inputs,
+            &mut writer,
+            &confs,
+            &mut pipeline,
+            &mut line_buffer,
+        );
+    }
+
+    if !opts.count {
+        output_format.write_prologue(&mut writer)?;
+    }
+    if opts.paste {
+        return run_paste(opts, inputs, &confs, writer);
+    }
+
+    let mut total = Metrics::default();
+    for (i, input) in inputs.into_iter().enumerate() {
+        let name = input_name(&input);
+        match run(
+            input,
+            &mut writer,
+            conf_for(i),
+            &mut line_buffer,
+            Some(&mut pipeline),
+            opts.on_empty_selection,
+            i == 0,
+        ) {
+            Ok(metrics) => {
+                if opts.count {
+                    writeln!(writer, "{}\t{}\t{}", name, metrics.records, metrics.fields)?;
+                }
+                if opts.stats {
+                    eprintln!("{}: {}", name, metrics);
+                }
+                total += metrics;
+            }
+            Err(err) => {
+                if is_broken_pipe(&err) {
+                    exit(0)
+                }
+                error!("{}", err);
+                exit(1)
+            }
+        }
+    }
+    // Records a stage held on to until now, which `Metrics::written` doesn't count
+    let mut emitted = 0;
+    pipeline.finish(|record| {
+        emitted += 1;
+        output_format.write_record(
+            &mut writer,
+            confs[0].output_delimiter(),
+            record.iter().map(|field| field.as_ref()),
+            &confs[0].output_line_terminator(),
+        )
+    })?;
+    if !opts.count {
+        output_format.write_epilogue(&mut writer)?;
+    }
+    if let Err(err) = writer.finish() {
+        if err.kind() == io::ErrorKind::BrokenPipe {
+            exit(0)
+        }
+        return Err(err.into());
+    }
+    if opts.stats {
+        eprintln!("total: {}", total);
+    }
+    if opts.fail_if_empty && total.written + emitted == 0 {
+        exit(EMPTY_OUTPUT_EXIT_CODE)
+    }
+    Ok(())
+}
+
+/// The exit status of `--fail-if-empty` when no data rows were written.
+const EMPTY_OUTPUT_EXIT_CODE: i32 = 3;
+
+/// The number of chunks of selected output each input of `--paste` can get ahead by.
+const PASTE_CHUNKS: usize = 4;
+
+/// The size of the chunks of selected output sent from each input of `--paste`.
+const PASTE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A [`Write`] that sends everything written to it down a channel.
+struct ChannelWriter(SyncSender<io::Result<Vec<u8>>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A [`Read`] over the chunks sent by a [`ChannelWriter`], which ends when the writer is dropped.
+struct ChannelReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Run `--paste`, selecting from every input at once on its own thread and writing their lines side by side.
+///
+/// `confs` has a config per input, or a single config shared by all of them.
+fn run_paste(
+    opts: &SelectOpts,
+    inputs: Vec<HckInput<PathBuf>>,
+    confs: &[CoreConfig],
+    mut writer: Box<dyn OutputSink>,
+) -> Result<()> {
+    let delimiter = confs[0].output_delimiter();
+    let terminator = confs[0].output_line_terminator();
+    let results = thread::scope(|scope| {
+        let mut readers = vec![];
+        let mut handles = vec![];
+        for (i, input) in inputs.into_iter().enumerate() {
+            let conf = &confs[i.min(confs.len() - 1)];
+            let (sender, receiver) = mpsc::sync_channel(PASTE_CHUNKS);
+            let failed = sender.clone();
+            handles.push(scope.spawn(move || -> Result<(String, Metrics)> {
+                let name = input_name(&input);
+                let mut line_buffer = LineBufferBuilder::new()
+                    .capacity(read_buffer_capacity(&opts.input))
+                    .build();
+                let mut output = BufWriter::with_capacity(PASTE_CHUNK_SIZE, ChannelWriter(sender));
+                let result = run(
+                    input,
+                    &mut output,
+                    conf,
+                    &mut line_buffer,
+                    None,
+                    opts.on_empty_selection,
+                    true,
+                )
+                .and_then(|metrics| Ok(output.flush().map(|_| metrics)?));
+                // Stop the paste instead of leaving an empty column for the rest of the lines
+                if let Err(err) = &result {
+                    let _ = failed.send(Err(io::Error::other(format!("{}: {}", name, err))));
+                }
+                Ok((name, result?))
+            }));
+            // Each input's records end with its output terminator
+            let reader = BufReader::new(ChannelReader {
+                receiver,
+                chunk: vec![],
+                pos: 0,
+            });
+            readers.push((reader, conf.output_line_terminator()));
+        }
+
+        let mut pasted = || -> Result<()> {
+            let (mut line, mut out) = (vec![], vec![]);
+            loop {
+                out.clear();
+                let mut any = false;
+                for (i, (reader, record_terminator)) in readers.iter_mut().enumerate() {
+                    if i > 0 {
+                        out.extend_from_slice(delimiter);
+                    }
+                    line.clear();
+                    if reader.read_until(record_terminator.as_byte(), &mut line)? > 0 {
+                        any = true;
+                        out.extend_from_slice(lines::without_terminator(&line, *record_terminator));
+                    }
+                }
+                if !any {
+                    return Ok(());
+                }
+                out.extend_from_slice(terminator.as_bytes());
+                writer.write_all(&out)?;
+            }
+        };
+        let pasted = pasted();
+        // Stop any inputs that are still going
+        drop(readers);
+        let joined: Vec<_> = handles
+            .into_iter()
+            .map(|handle| handle.join().expect("paste input thread panicked"))
+            .collect();
+        (pasted, joined)
+    });
+
+    let (pasted, joined) = results;
+    let mut total = Metrics::default();
+    for result in joined {
+        match result {
+            Ok((name, metrics)) => {
+                if opts.stats {
+                    eprintln!("{}: {}", name, metrics);
+                }
+                total += metrics;
+            }
+            // Inputs are cut off when the paste itself stops
+            Err(err) if is_broken_pipe(&err) => (),
+            Err(err) => {
+                error!("{}", err);
+                exit(1)
+            }
+        }
+    }
+    match pasted.and_then(|_| Ok(writer.finish()?)) {
+        Err(err) if is_broken_pipe(&err) => exit(0),
+        result => result?,
+    }
+    if opts.stats {
+        eprintln!("total: {}", total);
+    }
+    Ok(())
+}
+
+/// Run `--verify-roundtrip` over each input, exiting non-zero if any don't round trip.
+///
+/// `confs` has a config per input, or a single config shared by all of them.
+fn run_verify(
+    inputs: Vec<HckInput<PathBuf>>,
+    writer: &mut Box<dyn OutputSink>,
+    confs: &[CoreConfig],
+    line_buffer: &mut LineBuffer,
+) -> Result<()> {
+    let mut failed = false;
+    for (i, input) in inputs.into_iter().enumerate() {
+        let conf = &confs[i.min(confs.len() - 1)];
+        let name = input_name(&input);
+        let roundtrip = verify_roundtrip(input, conf, line_buffer)?;
+        if roundtrip.matches() {
+            writeln!(writer, "{}: ok, {} bytes", name, roundtrip.input.bytes)?;
+        } else {
+            failed = true;
+            writeln!(
+                writer,
+                "{}: output differs from input, {} bytes in, {} bytes out",
+                name, roundtrip.input.bytes, roundtrip.output.bytes
+            )?;
+        }
+    }
+    writer.finish()?;
+    if failed {
+        exit(1)
+    }
+    Ok(())
+}
+
+/// Run `--show-headers`, writing the index and name of each header of each input.
+///
+/// `confs` has a config per input, or a single config shared by all of them.
+fn run_show_headers(
+    opts: &SelectOpts,
+    inputs: Vec<HckInput<PathBuf>>,
+    writer: &mut Box<dyn OutputSink>,
+    confs: &[CoreConfig],
+) -> Result<()> {
+    let named = inputs.len() > 1;
+    for (i, input) in inputs.iter().enumerate() {
+        if matches!(input, HckInput::Stdin)
+            && (opts.input.try_decompress || opts.input.encoding.is_some())
+        {
+            return Err(anyhow!(
+                "`--show-headers` can't read compressed or transcoded stdin, pass it as a file instead"
+            ));
+        }
+        let conf = &confs[i.min(confs.len() - 1)];
+        let name = input_name(input);
+        let first_line = conf
+            .peek_first_line(input)
+            .map_err(|err| anyhow!("{}: {}", name, err))?;
+        // An empty input has no headers to show
+        if first_line.is_empty() {
+            continue;
+        }
+        for (index, header) in conf.header_fields(&first_line).iter().enumerate() {
+            if named {
+                writer.write_all(name.as_bytes())?;
+                writer.write_all(conf.output_delimiter())?;
+            }
+            write!(writer, "{}", index + 1)?;
+            writer.write_all(conf.output_delimiter())?;
+            writer.write_all(header)?;
+            writer.write_all(conf.line_terminator().as_bytes())?;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Run `--explain`, writing how each input would be processed without processing it.
+fn run_explain(
+    opts: &SelectOpts,
+    inputs: Vec<HckInput<PathBuf>>,
+    writer: &mut Box<dyn OutputSink>,
+    confs: &[CoreConfig],
+    pipeline: &mut Pipeline,
+    line_buffer: &mut LineBuffer,
+) -> Result<()> {
+    for (i, input) in inputs.iter().enumerate() {
+        let conf = &confs[i.min(confs.len() - 1)];
+        let name = input_name(input);
+        writeln!(writer, "input: {}", name)?;
+        #[cfg(feature = "parquet")]
+        if let HckInput::Path(path) = input {
+            if hcklib::parquet_input::is_parquet(path) {
+                writeln!(writer, "parser: parquet")?;
+                continue;
+            }
+        }
+        if matches!(input, HckInput::Stdin)
+            && (opts.header_field.is_some() || opts.exclude_header.is_some() || opts.gen_header)
+            && (opts.input.try_decompress || opts.input.encoding.is_some())
+        {
+            return Err(anyhow!(
+                "`--explain` can't read headers from compressed or transcoded stdin, pass it as a file instead"
+            ));
+        }
+        let (_, fields) = conf
+            .parse_fields(input)
+            .map_err(|err| anyhow!("{}: {}", name, err))?;
+        // The fields are kept sorted for parsing, `pos` is their place in the output
+        let mut ordered = fields.clone();
+        ordered.sort_by_key(|field| field.pos);
+        let list = ordered
+            .iter()
+            .map(FieldRange::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        writeln!(
+            writer,
+            "fields: {}",
+            if list.is_empty() { "none" } else { &list }
+        )?;
+        // The line parser plays no part in how an input is processed
+        let core = Core::new(
+            conf,
+            &fields,
+            SubStrLineParser::new(&fields, b""),
+            line_buffer,
+        )
+        .pipeline(Some(&mut *pipeline));
+        let explanation = core
+            .explain(input)
+            .map_err(|err| anyhow!("{}: {}", name, err))?;
+        writeln!(writer, "parser: {}", explanation.parser)?;
+        writeln!(
+            writer,
+            "mmap: {}",
+            if explanation.mmap { "yes" } else { "no" }
+        )?;
+        writeln!(
+            writer,
+            "decompress: {}",
+            explanation.decompressor.as_deref().unwrap_or("no")
+        )?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Run `hck examples`.
+fn run_examples(opts: &ExamplesOpts) -> Result<()> {
+    let tmp;
+    let dir = match &opts.dir {
+        Some(dir) => dir.as_path(),
+        None => {
+            tmp = tempfile::tempdir()?;
+            tmp.path()
+        }
+    };
+    write_example_corpus(dir)?;
+
+    let mut writer = open_output(&opts.output)?;
+    let mut failed = 0;
+    for example in EXAMPLES {
+        let command: Vec<Cow<str>> = example.args.iter().map(|arg| shell_quote(arg)).collect();
+        writeln!(writer, "# {}", example.title)?;
+        writeln!(writer, "❯ hck {}", command.join(" "))?;
+        match run_example(dir, example) {
+            Ok(output) if output == example.expected => writer.write_all(output.as_bytes())?,
+            Ok(output) => {
+                failed += 1;
+                writer.write_all(output.as_bytes())?;
+                writeln!(writer, "! Expected:")?;
+                writer.write_all(example.expected.as_bytes())?;
+            }
+            Err(err) => {
+                failed += 1;
+                writeln!(writer, "! Failed: {}", err)?;
+            }
+        }
+        writeln!(writer)?;
+    }
+    writer.finish()?;
+    if failed > 0 {
+        return Err(anyhow!("{} of {} examples failed", failed, EXAMPLES.len()));
+    }
+    Ok(())
+}
+
+/// Write the [`EXAMPLE_CORPUS`] to `dir`, creating it if needed.
+fn write_example_corpus(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for (name, contents) in EXAMPLE_CORPUS {
+        std::fs::write(dir.join(name), contents)?;
+    }
+    let mut encoder = GzEncoder::new(
+        create_output(dir.join("people.csv.gz"))?,
+        Compression::default(),
+    );
+    encoder.write_all(EXAMPLE_CORPUS[0].1.as_bytes())?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Run an example against the corpus in `dir`, returning what it wrote.
+fn run_example(dir: &Path, example: &Example) -> Result<String> {
+    let output_file = dir.join("example.out");
+    let mut args = vec![OsString::from("hck")];
+    for arg in example.args {
+        let path = dir.join(arg);
+        if path.is_file() {
+            args.push(path.into_os_string());
+        } else {
+            args.push(OsString::from(arg));
+        }
+    }
+    args.extend([OsString::from("-o"), output_file.clone().into_os_string()]);
+    let opts = Opts::try_parse_from(args)?;
+    match &opts.command {
+        None => run_select(&opts.select)?,
+        Some(Command::Select(select_opts)) => run_select(select_opts)?,
+        Some(Command::Gen(gen_opts)) => run_gen(gen_opts)?,
+        Some(Command::Join(join_opts)) => run_join(join_opts)?,
+        Some(Command::Examples(_)) => return Err(anyhow!("Examples can't run `hck examples`")),
+    }
+    let output = std::fs::read_to_string(&output_file)?;
+    std::fs::remove_file(&output_file)?;
+    Ok(output)
+}
+
+/// Quote `arg` for a POSIX shell if it needs it.
+fn shell_quote(arg: &str) -> Cow<'_, str> {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.,:/=+".contains(c));
+    if plain {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', r"'\''")))
+    }
+}
+
+/// Run `hck gen`.
+fn run_gen(opts: &GenOpts) -> Result<()> {
+    let config = GenConfig {
+        columns: ColumnSpec::from_list(&opts.cols)?,
+        rows: opts.rows,
+        seed: opts.seed,
+        delimiter: Vec::unescape_bytes(&opts.delimiter),
+        header: opts.header,
+        ragged: opts.ragged,
+        collisions: opts.collisions,
+        ..GenConfig::default()
+    };
+    let mut writer = open_output(&opts.output)?;
+    generate(&config, &mut writer)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes the results of `hck join`.
+struct JoinWriter<'a> {
+    output: &'a mut dyn Write,
+    unmatched_left: Option<Box<dyn OutputSink>>,
+    unmatched_right: Option<Box<dyn OutputSink>>,
+    delimiter: &'a [u8],
+    terminator: LineTerminator,
+}
+
+impl JoinSink for JoinWriter<'_> {
+    fn matched(&mut self, left: &[u8], right: &[u8]) -> io::Result<()> {
+        self.output.write_all(left)?;
+        self.output.write_all(self.delimiter)?;
+        self.output.write_all(right)?;
+        self.output.write_all(self.terminator.as_bytes())
+    }
+
+    fn unmatched_left(&mut self, left: &[u8]) -> io::Result<()> {
+        match self.unmatched_left.as_mut() {
+            Some(output) => write_line(output, left, self.terminator),
+            None => Ok(()),
+        }
+    }
+
+    fn unmatched_right(&mut self, right: &[u8]) -> io::Result<()> {
+        match self.unmatched_right.as_mut() {
+            Some(output) => write_line(output, right, self.terminator),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write `bytes` followed by `terminator`.
+fn write_line<W: Write + ?Sized>(
+    output: &mut W,
+    bytes: &[u8],
+    terminator: LineTerminator,
+) -> io::Result<()> {
+    output.write_all(bytes)?;
+    output.write_all(terminator.as_bytes())
+}
+
+/// Open an unmatched lines file for `hck join`.
+fn open_unmatched(path: Option<&Path>) -> Result<Option<Box<dyn OutputSink>>> {
+    path.map(|path| -> Result<Box<dyn OutputSink>> {
+        Ok(Box::new(BufferedSink::new(Box::new(create_output(path)?))))
+    })
+    .transpose()
+}
+
+/// Run `hck join`.
+fn run_join(opts: &JoinOpts) -> Result<()> {
+    let input = |path: &PathBuf| {
+        if path.as_os_str() == "-" {
+            HckInput::Stdin
+        } else {
+            HckInput::Path(path.clone())
+        }
+    };
+    let (left, right) = (input(&opts.left), input(&opts.right));
+    if matches!((&left, &right), (HckInput::Stdin, HckInput::Stdin)) {
+        return Err(anyhow!("Only one of the join inputs can be stdin"));
+    }
+    let conf = CoreConfigBuilder::new()
+        .delimiter(opts.delimiter.as_bytes())
+        .is_regex_parser(!opts.delim_is_literal)
+        .output_delimiter(opts.output_delimiter.as_bytes())
+        .line_terminator(if opts.crlf {
+            LineTerminator::crlf()
+        } else {
+            LineTerminator::default()
+        })
+        .try_decompress(opts.try_decompress)
+        .fields(Some("1-"))
+        .build()?;
+    let normalize = opts.key_normalize.unwrap_or_default();
+    let left_key = JoinKey::new(FieldRange::from_list(&opts.key)?).normalize(normalize);
+    let right_key = JoinKey::new(FieldRange::from_list(
+        opts.right_key.as_deref().unwrap_or(&opts.key),
+    )?)
+    .normalize(normalize);
+    let fields_of = |list: &str| -> Result<Vec<FieldRange>> {
+        let mut fields = FieldRange::from_list(list)?;
+        fields.sort_by_key(|range| range.pos);
+        Ok(fields)
+    };
+    let (left_fields, right_fields) = (
+        fields_of(&opts.left_fields)?,
+        fields_of(&opts.right_fields)?,
+    );
+    let delimiter = conf.output_delimiter();
+    let join_fields = |fields: &[FieldRange], row: &[&[u8]], record: &mut Vec<u8>| {
+        record.clear();
+        for (i, field) in select_fields(fields, row).enumerate() {
+            if i > 0 {
+                record.extend_from_slice(delimiter);
+            }
+            record.extend_from_slice(field);
+        }
+    };
+
+    let mut writer = open_output(&opts.output)?;
+    let mut sink = JoinWriter {
+        output: &mut writer,
+        unmatched_left: open_unmatched(opts.unmatched_left.as_deref())?,
+        unmatched_right: open_unmatched(opts.unmatched_right.as_deref())?,
+        delimiter,
+        terminator: conf.output_line_terminator(),
+    };
+    let mut join = HashJoin::new(opts.memory_limit);
+    let (mut key, mut record) = (vec![], vec![]);
+    let missing_key = |input: &HckInput<PathBuf>, line: u64| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Line {} of {} is missing key fields",
+                line,
+                input_name(input)
+            ),
+        )
+    };
+    let mut left_header = None;
+    let mut line = 0;
+    for_each_row(&left, &conf, |row| {
+        line += 1;
+        join_fields(&left_fields, row, &mut record);
+        if opts.header && line == 1 {
+            left_header = Some(record.clone());
+            return Ok(());
+        }
+        if !left_key.extract_into(row, &mut key) {
+            return Err(missing_key(&left, line));
+        }
+        join.build(&key, &record)
+    })?;
+    let mut line = 0;
+    for_each_row(&right, &conf, |row| {
+        line += 1;
+        join_fields(&right_fields, row, &mut record);
+        if opts.header && line == 1 {
+            let left_header = left_header.take().unwrap_or_default();
+            if let Some(output) = sink.unmatched_left.as_mut() {
+                write_line(output, &left_header, sink.terminator)?;
+            }
+            if let Some(output) = sink.unmatched_right.as_mut() {
+                write_line(output, &record, sink.terminator)?;
+            }
+            return sink.matched(&left_header, &record);
+        }
+        if !right_key.extract_into(row, &mut key) {
+            return Err(missing_key(&right, line));
+        }
+        join.probe(&key, &record, &mut sink)
+    })?;
+    let summary = join.finish(&mut sink)?;
+    for output in [sink.unmatched_left, sink.unmatched_right]
+        .iter_mut()
+        .flatten()
+    {
+        output.finish()?;
+    }
+    writer.finish()?;
+    if opts.summary {
+        eprintln!("{}", summary);
+    }
+    Ok(())
+}
+
+/// Call `f` with every field of each line of `input`.
+fn for_each_row<F>(input: &HckInput<PathBuf>, conf: &CoreConfig, f: F) -> Result<()>
+where
+    F: FnMut(&[&[u8]]) -> io::Result<()>,
+{
+    let input = match input {
+        HckInput::Stdin => HckInput::Stdin,
+        HckInput::Path(path) => HckInput::Path(path.as_path()),
+    };
+    let fields = FieldRange::from_list("1-")?;
+    let mut line_buffer = LineBufferBuilder::new().build();
+    Core::new(conf, &fields, conf.line_parser(&fields), &mut line_buffer).for_each_row(input, f)?;
+    Ok(())
+}
+
+/// Parse the fixed column widths from `--widths` or `--widths-file`.
+fn read_widths(widths: Option<&str>, widths_file: Option<&Path>) -> Result<Option<Vec<usize>>> {
+    if let Some(widths) = widths {
+        return Ok(Some(parse_widths(widths)?));
+    }
+    if let Some(path) = widths_file {
+        let spec = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read widths from {}", path.display()))?;
+        let widths: Vec<&str> = spec
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or(""))
+            .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+            .filter(|width| !width.is_empty())
+            .collect();
+        return Ok(Some(parse_widths(&widths.join(","))?));
+    }
+    Ok(None)
+}
+
+/// The delimiter to split each input on, either one shared by all of them or one per input.
+fn input_delimiters(opts: &SelectOpts, inputs: usize) -> Result<&[String]> {
+    let delimiters = opts.delim.delimiter.as_slice();
+    if delimiters.len() > 1 {
+        if delimiters.len() != inputs {
+            return Err(anyhow!(
+                "Got {} delimiters for {} inputs, give `-d` once or once per input",
+                delimiters.len(),
+                inputs
+            ));
+        }
+        if opts.delim.use_input_delim {
+            return Err(anyhow!(
+                "`-I` needs a single input delimiter to use as the output delimiter"
+            ));
+        }
+    }
+    Ok(delimiters)
+}
+
+/// The fields to select from each input given with `--fields-per-file`, if any.
+fn input_fields(opts: &SelectOpts, inputs: usize) -> Result<Vec<&str>> {
+    let field_lists: Vec<&str> = match &opts.fields_per_file {
+        Some(spec) => spec.split(';').collect(),
+        None => return Ok(vec![]),
+    };
+    if field_lists.len() != inputs {
+        return Err(anyhow!(
+            "Got {} field lists for {} inputs, give `--fields-per-file` one list per input",
+            field_lists.len(),
+            inputs
+        ));
+    }
+    Ok(field_lists)
+}
+
+/// The output delimiter to use given `opts`, escapes are resolved by [`CoreConfigBuilder`].
+fn output_delimiter(opts: &SelectOpts) -> &[u8] {
+    if opts.out_format == OutFormat::Csv {
+        b","
+    } else if opts.delim.delim_is_literal && opts.delim.use_input_delim {
+        opts.delim.delimiter[0].as_bytes()
+    } else if opts.delim.use_input_delim {
+        opts.delim.input_delim_fallback.as_bytes()
+    } else {
+        opts.delim.output_delimiter.as_bytes()
+    }
+}
+
+/// Build the [`Pipeline`] of record stages requested in `opts`.
+fn build_pipeline(opts: &SelectOpts) -> Result<Pipeline> {
+    let mut pipeline = Pipeline::new();
+    pipeline.header_policy(header_policy(opts));
+    let tsv_escapes = opts.tsv_escapes.then(|| CodecSpec {
+        fields: vec![FieldRange::default()],
+        codec: Codec::Tsv,
+    });
+    let decodes = tsv_escapes
+        .iter()
+        .chain(&opts.decode)
+        .chain(&opts.url_decode)
+        .chain(&opts.b64_decode);
+    let encodes = opts.b64_encode.iter().chain(&opts.encode);
+    let codecs: Vec<(CodecSpec, bool)> = decodes
+        .map(|spec| (spec.clone(), true))
+        .chain(encodes.map(|spec| (spec.clone(), false)))
+        .collect();
+    if !codecs.is_empty() {
+        pipeline.push(MapFieldsStage(move |i: usize, field: &[u8]| {
+            let mut value: Option<Vec<u8>> = None;
+            for (spec, decode) in codecs.iter().filter(|(spec, _)| spec.contains(i)) {
+                let mut out = Vec::with_capacity(field.len());
+                let input = value.as_deref().unwrap_or(field);
+                if *decode {
+                    spec.codec.decode_into(input, &mut out);
+                } else {
+                    spec.codec.encode_into(input, &mut out);
+                }
+                value = Some(out);
+            }
+            value
+        }));
+    }
+    // Split from the last position back so each position is counted before any splits
+    let mut splits = opts.split_field.clone();
+    splits.sort_by_key(|spec| std::cmp::Reverse(spec.index));
+    for spec in splits {
+        pipeline.push(SplitFieldStage::new(spec));
+    }
+    for spec in &opts.merge {
+        pipeline.push(MergeFieldsStage::new(spec.clone()));
+    }
+    for spec in &opts.recode {
+        let recoder = Recoder::load(spec)?;
+        pipeline.push(RecodeStage::new(spec.index, recoder));
+    }
+    for pattern in &opts.require {
+        pipeline.push(RequireStage::new(pattern.clone()));
+    }
+    for pattern in &opts.capture {
+        pipeline.push(CaptureStage::new(pattern.clone()));
+    }
+    for pattern in &opts.extract {
+        pipeline.push(CaptureStage::new(pattern.clone()).clear_unmatched(true));
+    }
+    if opts.upper || opts.lower {
+        let case = if opts.upper { Case::Upper } else { Case::Lower };
+        pipeline.push(CaseStage::new(case, None));
+    }
+    let cases = [
+        (Case::Upper, &opts.upper_in, "Invalid --upper-in"),
+        (Case::Lower, &opts.lower_in, "Invalid --lower-in"),
+    ];
+    for (case, list, context) in cases {
+        if let Some(list) = list {
+            let fields = FieldRange::from_list(list).context(context)?;
+            pipeline.push(CaseStage::new(case, Some(fields)));
+        }
+    }
+    if opts.skip_empty || opts.skip_empty_in.is_some() {
+        let fields = opts
+            .skip_empty_in
+            .as_deref()
+            .map(FieldRange::from_list)
+            .transpose()
+            .context("Invalid --skip-empty-in")?;
+        pipeline.push(SkipEmptyStage::new(fields));
+    }
+    if let Some(filter) = &opts.filter {
+        let expr = Expr::parse(filter).with_context(|| format!("Invalid --filter: {}", filter))?;
+        pipeline.push(ExprFilterStage::new(expr).strict(opts.strict));
+    }
+    if opts.unique || opts.unique_on.is_some() {
+        let key = opts
+            .unique_on
+            .as_deref()
+            .map(FieldRange::from_list)
+            .transpose()
+            .context("Invalid --unique-on")?;
+        let key = Key::new(key).normalize(opts.key_normalize.unwrap_or_default());
+        pipeline.push(UniqueStage::new(key));
+    }
+    let seed = opts.seed.unwrap_or_else(random_seed);
+    if let Some(rate) = opts.sample {
+        pipeline.push(SampleStage::new(rate, seed));
+    }
+    if let Some(n) = opts.sample_n {
+        pipeline.push(ReservoirStage::new(n, seed));
+    }
+    if let Some(n) = opts.head {
+        pipeline.push(HeadStage::new(n));
+    }
+    if let Some(n) = opts.tail {
+        pipeline.push(TailStage::new(n));
+    }
+    if let Some(spec) = &opts.gen_key {
+        let key =
+            Key::new(Some(spec.fields.clone())).normalize(opts.key_normalize.unwrap_or_default());
+        pipeline.push(GenKeyStage::new(key, spec.kind).header(spec.name.clone().into_bytes()));
+    }
+    if opts.enumerate {
+        let stage = EnumerateStage::new(opts.enumerate_start, opts.enumerate_step)
+            .restart_per_input(opts.restart_per_file)
+            .header(b"index".to_vec());
+        pipeline.push(stage);
+    }
+    // Insert from the first position on so each lands where it was asked for
+    let mut inserts = opts.insert.clone();
+    inserts.sort_by_key(|(index, _)| *index);
+    for (index, value) in inserts {
+        pipeline.push(InjectStage::new(index, value.into_bytes()));
+    }
+    let pad = opts.fill.clone().unwrap_or_default().into_bytes();
+    if let Some(width) = opts.pad_to {
+        pipeline.push(PadStage::new(width, pad));
+    } else if opts.pad_to_header {
+        if header_policy(opts) != HeaderPolicy::Keep {
+            return Err(anyhow!(
+                "--pad-to-header needs a header, select fields by -F or -E"
+            ));
+        }
+        pipeline.push(PadStage::to_header(pad));
+    }
+    if opts.tsv_escapes {
+        pipeline.push(MapFieldsStage(|_, field: &[u8]| {
+            let escape = |byte: &u8| matches!(byte, b'\t' | b'\n' | b'\r' | b'\\');
+            field.iter().any(escape).then(|| {
+                let mut out = Vec::with_capacity(field.len() + 1);
+                Codec::Tsv.encode_into(field, &mut out);
+                out
+            })
+        }));
+    }
+    if opts.count_unique {
+        let counter = if opts.approx {
+            let counter = HyperLogLog::new(DEFAULT_PRECISION);
+            info!(
+                "Approximate distinct count, standard error {:.2}%",
+                counter.standard_error() * 100.0
+            );
+            DistinctCounter::Approx(counter)
+        } else {
+            DistinctCounter::exact()
+        };
+        let key = Key::new(None).normalize(opts.key_normalize.unwrap_or_default());
+        pipeline.push(CountDistinctStage::new(key, counter));
+    }
+    Ok(pipeline)
+}
+
+/// A seed for sampling when none is given.
+fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default()
+        ^ u64::from(std::process::id())
+}
+
+/// How the first line of each input is handled. Selecting by header means it is a header, which is
+/// written out unless `--no-out-header` is set, while `--add-header` and `--gen-header` mean there is none.
+fn header_policy(opts: &SelectOpts) -> HeaderPolicy {
+    if opts.header_field.is_none() && opts.exclude_header.is_none() || header_names(opts).is_some()
+    {
+        HeaderPolicy::None
+    } else if opts.no_out_header {
+        HeaderPolicy::Drop
+    } else {
+        HeaderPolicy::Keep
+    }
+}
+
+/// Split each `-F` or `-E` value on its commas into separate header selections, keeping their order.
+///
+/// `\,` is a literal comma. Regexes don't split on commas inside `{}`, `[]` or `()`, or on any
+/// escaped character, so repetitions like `a{1,3}` and classes like `[,;]` keep working.
+fn split_header_selections(selections: &[Regex], is_regex: bool) -> Result<Vec<Regex>> {
+    let mut split = vec![];
+    for selection in selections {
+        let mut part = String::new();
+        let mut depth = 0_usize;
+        let mut chars = selection.as_str().chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(',') if !is_regex => part.push(','),
+                    Some(escaped) => {
+                        part.push(c);
+                        part.push(escaped);
+                    }
+                    None => part.push(c),
+                },
+                '{' | '[' | '(' if is_regex => {
+                    depth += 1;
+                    part.push(c);
+                }
+                '}' | ']' | ')' if is_regex => {
+                    depth = depth.saturating_sub(1);
+                    part.push(c);
+                }
+                ',' if depth == 0 => split.push(Regex::new(&mem::take(&mut part))?),
+                _ => part.push(c),
+            }
+        }
+        split.push(Regex::new(&part)?);
+    }
+    Ok(split)
+}
+
+/// The column names of headerless inputs, from `--add-header` or `--gen-header`.
+fn header_names(opts: &SelectOpts) -> Option<HeaderNames> {
+    match &opts.add_header {
+        Some(names) => Some(HeaderNames::Names(
+            names.split(',').map(String::from).collect(),
+        )),
+        None if opts.gen_header => Some(HeaderNames::Generated),
+        None => None,
+    }
+}
+
+/// How to handle ragged rows, `--strict` wins over `--check`.
+fn ragged_rows(opts: &SelectOpts) -> RaggedRows {
+    if opts.strict {
+        RaggedRows::Error
+    } else if opts.check {
+        RaggedRows::Report
+    } else {
+        RaggedRows::Ignore
+    }
+}
+
+/// Whether the number of fields on each row is written, see `--count-fields`.
+fn field_count(opts: &SelectOpts) -> Option<FieldCount> {
+    if opts.count_fields {
+        Some(FieldCount::Only)
+    } else if opts.count_fields_column {
+        Some(FieldCount::Column)
+    } else {
+        None
+    }
+}
+
+/// A display name for an input.
+fn input_name(input: &HckInput<PathBuf>) -> String {
+    match input {
+        HckInput::Stdin => String::from("stdin"),
+        HckInput::Path(path) => path.display().to_string(),
+    }
+}
+
+/// Run the actual parsing and writing, returning the [`Metrics`] collected along the way.
+///
+/// If `header` is set, the header line of headerless inputs is written first, see
+/// [`CoreConfig::header_line`].
+fn run<W: Write>(
+    input: HckInput<PathBuf>,
+    writer: &mut W,
+    conf: &CoreConfig,
+    line_buffer: &mut LineBuffer,
+    mut pipeline: Option<&mut Pipeline>,
+    on_empty: OnEmptySelection,
+    header: bool,
+) -> Result<Metrics> {
+    #[cfg(feature = "parquet")]
+    if let HckInput::Path(path) = &input {
+        if hcklib::parquet_input::is_parquet(path) {
+            return hcklib::parquet_input::hck_parquet(path, conf, writer);
+        }
+    }
+
+    let (extra, fields) = conf.parse_fields(&input)?;
+    if fields.is_empty() {
+        match on_empty {
+            // No point processing empty fields
+            OnEmptySelection::Skip => return Ok(Metrics::default()),
+            OnEmptySelection::Error => {
+                return Err(anyhow!(
+                    "No fields left to select in {}",
+                    input_name(&input)
+                ))
+            }
+            // Every line parses to an empty record
+            OnEmptySelection::EmptyLines => (),
+        }
+    }
+    if header && !conf.count_only() {
+        if let Some(line) = conf.header_line(extra.as_deref().unwrap_or_default(), &fields) {
+            writer.write_all(&line)?;
+            writer.write_all(conf.output_line_terminator().as_bytes())?;
+        }
+    }
+
+    let mut core =
+        Core::new(conf, &fields, conf.line_parser(&fields), line_buffer).pipeline(pipeline.take());
+    core.hck_input(input, writer, extra)?;
+    Ok(*core.metrics())
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::{BufReader, BufWriter};
+
+    use super::*;
+    use bstr::io::BufReadExt;
+    use rstest::rstest;
+    use tempfile::TempDir;
+
+    /// The opts `hck` runs with when given no arguments.
+    fn default_opts() -> SelectOpts {
+        Opts::parse_from(["hck"]).select
+    }
+
+    /// Build a set of opts for testing
+    fn build_opts(
+        input_file: impl AsRef<Path>,
+        output_file: impl AsRef<Path>,
+        fields: &str,
+        no_mmap: bool,
+        delimiter: &str,
+    ) -> SelectOpts {
+        build_opts_generic(
+            input_file,
+            output_file,
+            Some(fields),
+            None,
+            None,
+            no_mmap,
+            delimiter,
+            false,
+            true,
+        )
+    }
+
+    /// Build a set of opts for testing
+    fn build_opts_not_regex(
+        input_file: impl AsRef<Path>,
+        output_file: impl AsRef<Path>,
+        fields: &str,
+        no_mmap: bool,
+        delimiter: &str,
+    ) -> SelectOpts {
+        build_opts_generic(
+            input_file,
+            output_file,
+            Some(fields),
+            None,
+            None,
+            no_mmap,
+            delimiter,
+            true,
+            true,
+        )
+    }
+
+    /// Build a set of opts for testing
+    #[allow(clippy::too_many_arguments)]
+    fn build_opts_generic(
+        input_file: impl AsRef<Path>,
+        output_file: impl AsRef<Path>,
+        fields: Option<&str>,
+        header_field: Option<Vec<Regex>>,
+        exclude: Option<&str>,
+        no_mmap: bool,
+        delimiter: &str,
+        delim_is_literal: bool,
+        header_is_regex: bool,
+    ) -> SelectOpts {
+        let mut opts = SelectOpts {
+            fields: fields.map(|f| f.to_owned()),
+            header_field,
+            header_is_regex,
+            exclude: exclude.map(|e| e.to_owned()),
+            ..default_opts()
+        };
+        opts.input.files = vec![input_file.as_ref().to_path_buf()];
+        opts.input.no_mmap = no_mmap;
+        opts.delim.delimiter = vec![delimiter.to_string()];
+        opts.delim.delim_is_literal = delim_is_literal;
+        opts.output.file = Some(output_file.as_ref().to_path_buf());
+        opts
+    }
+
+    /// Simple function to read a tsv into a nested list of lists.
+    fn read_tsv(path: impl AsRef<Path>) -> Vec<Vec<String>> {
+        let reader = BufReader::new(File::open(path).unwrap());
+        let mut result = vec![];
+        let r = Regex::new(r"\s+").unwrap();
+
+        for line in reader.byte_lines() {
+            let line = &line.unwrap();
+            result.push(
+                r.split(line)
+                    .map(|s| unsafe { String::from_utf8_unchecked(s.to_vec()) })
+                    .collect(),
+            );
+        }
+        result
+    }
+
+    /// Write delmited data to a file.
+    fn write_file(path: impl AsRef<Path>, data: Vec<Vec<&str>>, sep: &str) {
+        let mut writer = BufWriter::new(File::create(path).unwrap());
+        for row in data {
+            writeln!(&mut writer, "{}", row.join(sep)).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    // Wrap the run function to create the readers and writers.
+    fn run_wrapper<P: AsRef<Path>>(input: P, output: P, opts: &SelectOpts) -> Metrics {
+        try_run_wrapper(input, output, opts).unwrap()
+    }
+
+    fn try_run_wrapper<P: AsRef<Path>>(input: P, output: P, opts: &SelectOpts) -> Result<Metrics> {
+        let widths = read_widths(
+            opts.delim.widths.as_deref(),
+            opts.delim.widths_file.as_deref(),
+        )
+        .unwrap();
+
+        let conf_builder = CoreConfigBuilder::new()
+            .delimiter(opts.delim.delimiter[0].as_bytes())
+            .is_regex_parser(!opts.delim.delim_is_literal)
+            .mmap(mmap_choice(&opts.input))
+            .output_delimiter(output_delimiter(opts))
+            .reuse_input_delim(opts.delim.use_input_delim)
+            .mirror_missing_terminator(opts.mirror_missing_newline)
+            .headers(opts.header_field.as_deref())
+            .fields(opts.fields.as_deref())
+            .exclude(opts.exclude.as_deref())
+            .exclude_headers(opts.exclude_header.as_deref())
+            .header_is_regex(opts.header_is_regex)
+            .widths(widths.as_deref())
+            .awk_fields(opts.delim.awk_fields)
+            .squeeze(opts.delim.squeeze)
+            .vectored_writes(opts.vectored_writes)
+            .direct_io(opts.input.direct_io)
+            .read_hints(!opts.input.no_read_hints)
+            .truncate_lines(opts.input.truncate_lines)
+            .max_line_length(opts.input.max_line_length)
+            .read_buffer_size(opts.input.read_buffer)
+            .count_only(opts.count)
+            .fill(opts.fill.as_deref().map(str::as_bytes))
+            .ragged_rows(ragged_rows(opts))
+            .field_count(field_count(opts))
+            .column_matches(opts.column_match.clone())
+            .invert_match(opts.invert_match)
+            .record_separator(opts.delim.record_sep.as_deref().map(str::as_bytes))
+            .record_separator_is_regex(opts.delim.record_sep_is_regex)
+            .output_record_separator(opts.delim.out_record_sep.as_deref().map(str::as_bytes))
+            .encoding(opts.input.encoding)
+            .keep_bom(opts.input.keep_bom);
+        #[cfg(feature = "uring")]
+        let conf_builder = conf_builder.uring(opts.input.uring);
+        let conf = conf_builder.build().unwrap();
+        let mut line_buffer = LineBufferBuilder::new()
+            .capacity(read_buffer_capacity(&opts.input))
+            .build();
+        let mut pipeline = build_pipeline(opts).unwrap();
         let mut writer = BufWriter::new(File::create(output).unwrap());
-        run(
+        let metrics = run(
             HckInput::Path(input.as_ref().to_owned()),
             &mut writer,
             &conf,
             &mut line_buffer,
+            Some(&mut pipeline),
+            opts.on_empty_selection,
+            false,
+        )?;
+        pipeline.finish(|record| {
+            conf.output_format().write_record(
+                &mut writer,
+                conf.output_delimiter(),
+                record.iter().map(|field| field.as_ref()),
+                &conf.output_line_terminator(),
+            )
+        })?;
+        Ok(metrics)
+    }
+
+    /// Run `hck` with `args` over a file holding `input`, the way `main` would, returning what it wrote.
+    fn run_hck(args: &[&str], input: &str) -> String {
+        try_run_hck(args, &[input]).unwrap()
+    }
+
+    /// Run `hck` with `args` over one file per entry of `inputs`, returning what it wrote.
+    fn try_run_hck(args: &[&str], inputs: &[&str]) -> Result<String> {
+        let tmp = TempDir::new().unwrap();
+        let output_file = tmp.path().join("output.txt");
+        let mut argv = vec![String::from("hck")];
+        argv.extend(args.iter().map(|arg| arg.to_string()));
+        argv.extend([String::from("-o"), output_file.display().to_string()]);
+        for (i, input) in inputs.iter().enumerate() {
+            let input_file = tmp.path().join(format!("input{}.txt", i));
+            std::fs::write(&input_file, input).unwrap();
+            argv.push(input_file.display().to_string());
+        }
+        let matches = Opts::command().try_get_matches_from(argv)?;
+        check_conflicts(&matches)?;
+        let opts = opts_from_matches(&matches)?;
+        match &opts.command {
+            Some(Command::Select(select_opts)) => run_select(select_opts)?,
+            _ => run_select(&opts.select)?,
+        }
+        Ok(std::fs::read_to_string(output_file).unwrap())
+    }
+
+    const FOURSPACE: &str = "    ";
+
+    #[test]
+    #[cfg(unix)]
+    fn test_pager_sink() {
+        let tmp = TempDir::new().unwrap();
+        let paged = tmp.path().join("paged.txt");
+        let command = format!("cp /dev/stdin {}", paged.display());
+        let mut pager = start_pager(&command).unwrap();
+        let stdin = pager.stdin.take().unwrap();
+        let mut sink = PagerSink {
+            inner: Some(Box::new(BufferedSink::new(stdin))),
+            pager,
+        };
+        sink.write_all(b"a\tb\n").unwrap();
+        sink.finish().unwrap();
+        // The pager has exited once the sink is finished
+        assert_eq!(std::fs::read_to_string(&paged).unwrap(), "a\tb\n");
+        assert!(sink.write_all(b"c\n").is_err());
+
+        // A pager that quits early closes the pipe
+        let mut pager = start_pager("true").unwrap();
+        let stdin = pager.stdin.take().unwrap();
+        pager.wait().unwrap();
+        let mut sink = PagerSink {
+            inner: Some(Box::new(BufferedSink::new(stdin))),
+            pager,
+        };
+        let err = sink
+            .write_all(b"a\n")
+            .and_then(|_| sink.finish())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        assert!(start_pager("").is_none());
+        assert!(start_pager("hck-no-such-pager -S").is_none());
+    }
+
+    #[rstest]
+    fn test_run_hck(#[values(true, false)] no_mmap: bool) {
+        let mut args = vec!["-f", "3,1"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, "a\tb\tc\n1\t2\t3\n"), "c\ta\n3\t1\n");
+        assert_eq!(
+            try_run_hck(&args, &["a\tb\tc\n", "1\t2\t3\n"]).unwrap(),
+            "c\ta\n3\t1\n"
+        );
+    }
+
+    #[rstest]
+    fn test_exclude_one(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("1,3"),
+            None,
+            Some("3"),
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            false,
+        );
+        let data = vec![vec!["a", "b", "c"], vec!["1", "2", "3"]];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["a",], vec!["1"]]);
+    }
+
+    #[rstest]
+    fn test_exclude_range_overlap_front(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("3-"),
+            None,
+            Some("-5"),
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            false,
+        );
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f"],
+            vec!["1", "2", "3", "4", "5", "6"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["f",], vec!["6"]]);
+    }
+
+    #[rstest]
+    fn test_exclude_range_overlap_back(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("2-5"),
+            None,
+            Some("3-"),
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            false,
+        );
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f"],
+            vec!["1", "2", "3", "4", "5", "6"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["b",], vec!["2"]]);
+    }
+
+    #[rstest]
+    fn test_exclude_range_split_fields(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("1-"),
+            None,
+            Some("3-5"),
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            false,
+        );
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f"],
+            vec!["1", "2", "3", "4", "5", "6"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["a", "b", "f"], vec!["1", "2", "6"]]);
+    }
+
+    #[rstest]
+    fn test_exclude_range_all(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("4,3"),
+            None,
+            Some("2-5"),
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            false,
+        );
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f"],
+            vec!["1", "2", "3", "4", "5", "6"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert!(filtered.is_empty());
+    }
+
+    #[rstest]
+    fn test_exclude_range_split_fields_reorder(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("4-6,1-3"),
+            None,
+            Some("3-5"),
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            false,
+        );
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f"],
+            vec!["1", "2", "3", "4", "5", "6"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["f", "a", "b"], vec!["6", "1", "2"]]);
+    }
+
+    #[rstest]
+    fn test_headers_simple(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(true, false)] header_is_regex: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            None,
+            Some(vec![Regex::new("a").unwrap()]),
+            None,
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            header_is_regex,
+        );
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f"],
+            vec!["1", "2", "3", "4", "5", "6"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["a"], vec!["1"]]);
+    }
+
+    #[rstest]
+    fn test_headers_simple2(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(true, false)] header_is_regex: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            None,
+            Some(vec![Regex::new("a").unwrap(), Regex::new("c").unwrap()]),
+            None,
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            header_is_regex,
+        );
+        let data = vec![vec!["a", "b", "c"], vec!["1", "2", "3"]];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["a", "c"], vec!["1", "3"]]);
+    }
+
+    #[rstest]
+    fn test_duplicate_field_selection_more(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(true, false)] header_is_regex: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("3,3,1,2"),
+            None,
+            None,
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            header_is_regex,
+        );
+        let data = vec![vec!["a", "b", "c", "d", "e"], vec!["1", "2", "3", "4", "5"]];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["c", "a", "b"], vec!["3", "1", "2"]]);
+    }
+
+    #[rstest]
+    fn test_duplicate_field_selection_range(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(true, false)] header_is_regex: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("2-3,5,1,2-4"),
+            None,
+            None,
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            header_is_regex,
+        );
+        let data = vec![vec!["a", "b", "c", "d", "e"], vec!["1", "2", "3", "4", "5"]];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(
+            filtered,
+            vec![vec!["b", "c", "e", "a", "d"], vec!["2", "3", "5", "1", "4"]]
+        );
+    }
+
+    #[rstest]
+    fn test_headers_and_fields(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(true, false)] header_is_regex: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("3"),
+            Some(vec![Regex::new("b").unwrap(), Regex::new("a").unwrap()]),
+            None,
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            header_is_regex,
+        );
+        let data = vec![vec!["a", "b", "c", "d", "e"], vec!["1", "2", "3", "4", "5"]];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["b", "c", "a"], vec!["2", "3", "1"]]);
+    }
+
+    #[rstest]
+    fn test_duplicate_field_selection(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r" ", "  ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(true, false)] header_is_regex: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("3,1,3"),
+            None,
+            None,
+            no_mmap,
+            hck_delim,
+            delim_is_literal,
+            header_is_regex,
+        );
+        let data = vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3", "4"]];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["c", "a"], vec!["3", "1"]]);
+    }
+    #[rstest]
+    #[rustfmt::skip::macros(vec)]
+    fn test_read_single_values(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "1", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c"],
+            vec!["1", "2", "3"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["a"], vec!["1"]]);
+    }
+
+    #[rstest]
+    fn test_read_several_single_values(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r"\s+")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "1,3", no_mmap, hck_delim);
+        let data = vec![vec!["a", "b", "c"], vec!["1", "2", "3"]];
+        write_file(&input_file, data, FOURSPACE);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["a", "c"], vec!["1", "3"]]);
+    }
+
+    #[rstest]
+    fn test_read_several_single_values_with_invalid_utf8(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r"\s+")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "1,3", no_mmap, hck_delim);
+        let bad_str = unsafe { String::from_utf8_unchecked(b"a\xED\xA0\x80z".to_vec()) };
+        let data = vec![vec![bad_str.as_str(), "b", "c"], vec!["1", "2", "3"]];
+        write_file(&input_file, data, FOURSPACE);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec![bad_str.as_str(), "c"], vec!["1", "3"]]);
+    }
+
+    #[rstest]
+    fn test_read_single_range(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r"\s+")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "2-", no_mmap, hck_delim);
+        let data = vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3", "4"]];
+        write_file(&input_file, data, FOURSPACE);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["b", "c", "d"], vec!["2", "3", "4"]]);
+    }
+
+    #[rstest]
+    fn test_read_serveral_range(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r"\s+")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "2-4,6-", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, FOURSPACE);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(
+            filtered,
+            vec![vec!["b", "c", "d", "f", "g"], vec!["2", "3", "4", "6", "7"]]
+        );
+    }
+
+    #[rstest]
+    fn test_read_mixed_fields1(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r"\s+")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "2,4-", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, FOURSPACE);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(
+            filtered,
+            vec![vec!["b", "d", "e", "f", "g"], vec!["2", "4", "5", "6", "7"]]
+        );
+    }
+
+    #[rstest]
+    fn test_read_mixed_fields2(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r"\s+")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "-4,7", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, FOURSPACE);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(
+            filtered,
+            vec![vec!["a", "b", "c", "d", "g"], vec!["1", "2", "3", "4", "7"]]
+        );
+    }
+
+    #[rstest]
+    fn test_read_no_delimis_found(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r"\s+")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "-4,7", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, "-");
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // We hae no concept of only-delimited, so if no delim is found the whole line
+        // is treated as column 1.
+        assert_eq!(filtered, vec![vec!["a-b-c-d-e-f-g"], vec!["1-2-3-4-5-6-7"]]);
+    }
+
+    #[rstest]
+    fn test_read_over_end(#[values(true, false)] no_mmap: bool, #[values(r"\s+")] hck_delim: &str) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "-4,8,11-", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, FOURSPACE);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3", "4"]]
+        );
+    }
+
+    #[rstest]
+    fn test_reorder1(#[values(true, false)] no_mmap: bool, #[values(r"\s+")] hck_delim: &str) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "6,-4", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, FOURSPACE);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![vec!["f", "a", "b", "c", "d"], vec!["6", "1", "2", "3", "4"]]
+        );
+    }
+
+    #[rstest]
+    fn test_reorder_merged_range(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r"\s+")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts(&input_file, &output_file, "1,3,2,7,6", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, FOURSPACE);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![vec!["a", "c", "b", "g", "f"], vec!["1", "3", "2", "7", "6"]]
+        );
+    }
+
+    #[rstest]
+    fn test_reorder2(#[values(true, false)] no_mmap: bool, #[values(r"\s+")] hck_delim: &str) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        // 4-5 should not be repeated at the end and only written once.
+        let opts = build_opts(&input_file, &output_file, "3-,1,4-5", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, FOURSPACE);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![
+                vec!["c", "d", "e", "f", "g", "a"],
+                vec!["3", "4", "5", "6", "7", "1"]
+            ]
+        );
+    }
+
+    #[rstest]
+    #[rustfmt::skip::macros(vec)]
+    fn test_read_single_values_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_not_regex(&input_file, &output_file, "1", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c"],
+            vec!["1", "2", "3"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["a"], vec!["1"]]);
+    }
+
+    #[rstest]
+    fn test_read_several_single_values_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_not_regex(&input_file, &output_file, "1,3", no_mmap, hck_delim);
+        let data = vec![vec!["a", "b", "c"], vec!["1", "2", "3"]];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["a", "c"], vec!["1", "3"]]);
+    }
+
+    #[rstest]
+    fn test_read_several_single_values_with_invalid_utf8_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_not_regex(&input_file, &output_file, "1,3", no_mmap, hck_delim);
+        let bad_str = unsafe { String::from_utf8_unchecked(b"a\xED\xA0\x80z".to_vec()) };
+        let data = vec![vec![bad_str.as_str(), "b", "c"], vec!["1", "2", "3"]];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec![bad_str.as_str(), "c"], vec!["1", "3"]]);
+    }
+
+    #[rstest]
+    fn test_read_single_range_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_not_regex(&input_file, &output_file, "2-", no_mmap, hck_delim);
+        let data = vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3", "4"]];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(filtered, vec![vec!["b", "c", "d"], vec!["2", "3", "4"]]);
+    }
+
+    #[rstest]
+    fn test_read_serveral_range_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_not_regex(&input_file, &output_file, "2-4,6-", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(
+            filtered,
+            vec![vec!["b", "c", "d", "f", "g"], vec!["2", "3", "4", "6", "7"]]
+        );
+    }
+
+    #[rstest]
+    fn test_read_mixed_fields1_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_not_regex(&input_file, &output_file, "2,4-", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(
+            filtered,
+            vec![vec!["b", "d", "e", "f", "g"], vec!["2", "4", "5", "6", "7"]]
+        );
+    }
+
+    #[rstest]
+    fn test_read_mixed_fields2_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_not_regex(&input_file, &output_file, "-4,7", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        assert_eq!(
+            filtered,
+            vec![vec!["a", "b", "c", "d", "g"], vec!["1", "2", "3", "4", "7"]]
+        );
+    }
+
+    #[rstest]
+    fn test_read_no_delimis_found_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_not_regex(&input_file, &output_file, "-4,7", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, "-");
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // We hae no concept of only-delimited, so if no delim is found the whole line
+        // is treated as column 1.
+        assert_eq!(filtered, vec![vec!["a-b-c-d-e-f-g"], vec!["1-2-3-4-5-6-7"]]);
+    }
+
+    #[rstest]
+    fn test_read_over_end_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_not_regex(&input_file, &output_file, "-4,8,11-", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3", "4"]]
+        );
+    }
+
+    #[rstest]
+    fn test_reorder1_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_not_regex(&input_file, &output_file, "6,-4", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![vec!["f", "a", "b", "c", "d"], vec!["6", "1", "2", "3", "4"]]
+        );
+    }
+
+    #[rstest]
+    fn test_reorder2_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        // 4-5 should not be repeated at the end and only written once.
+        let opts = build_opts_not_regex(&input_file, &output_file, "3-,1,4-5", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![
+                vec!["c", "d", "e", "f", "g", "a"],
+                vec!["3", "4", "5", "6", "7", "1"]
+            ]
+        );
+    }
+
+    /// Tests from users
+    #[rstest]
+    fn test_reorder_no_split_found(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        // 4-5 should not be repeated at the end and only written once.
+        let opts = build_opts_not_regex(&input_file, &output_file, "3-,1,4-5", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, "-");
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(filtered, vec![vec!["a-b-c-d-e-f-g"], vec!["1-2-3-4-5-6-7"]]);
+    }
+
+    /// Tests from users
+    #[rstest]
+    fn test_reorder_no_split_found_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        // 4-5 should not be repeated at the end and only written once.
+        let opts = build_opts(&input_file, &output_file, "3-,1,4-5", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, "---");
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![
+                vec!["a---b---c---d---e---f---g"],
+                vec!["1---2---3---4---5---6---7"]
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_issue_12_with_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values(r"\s+")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        // 4-5 should not be repeated at the end and only written once.
+        let opts = build_opts(&input_file, &output_file, "2,3,4-", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, "  ");
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![
+                vec!["b", "c", "d", "e", "f", "g"],
+                vec!["2", "3", "4", "5", "6", "7"]
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_issue_12_no_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        // 4-5 should not be repeated at the end and only written once.
+        let opts = build_opts(&input_file, &output_file, "2,3,4-", no_mmap, hck_delim);
+        let data = vec![
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![
+                vec!["b", "c", "d", "e", "f", "g"],
+                vec!["2", "3", "4", "5", "6", "7"]
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_issue_38_not_regex(
+        #[values(true, false)] no_mmap: bool,
+        #[values("    ", " ")] hck_delim: &str,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        // 4-5 should not be repeated at the end and only written once.
+        let opts = build_opts_not_regex(&input_file, &output_file, "1,2", no_mmap, hck_delim);
+        let data = vec![
+            vec![""],
+            vec![""],
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            vec![""],
+            vec![""],
+            vec!["1", "2", "3", "4", "5", "6", "7"],
+        ];
+        write_file(&input_file, data, hck_delim);
+        run_wrapper(&input_file, &output_file, &opts);
+        let filtered = read_tsv(output_file);
+
+        // columns past end in fields are ignored
+        assert_eq!(
+            filtered,
+            vec![
+                vec![""],
+                vec![""],
+                vec!["a", "b"],
+                vec![""],
+                vec![""],
+                vec!["1", "2"]
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_metrics_counts_records(
+        #[values(true, false)] no_mmap: bool,
+        #[values(true, false)] delim_is_literal: bool,
+    ) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let output_file = tmp.path().join("output.txt");
+        let opts = build_opts_generic(
+            &input_file,
+            &output_file,
+            Some("1"),
+            None,
+            None,
+            no_mmap,
+            ",",
+            delim_is_literal,
+            false,
+        );
+        let data = vec![vec!["a", "b"], vec!["1", "2"], vec!["3", "4"]];
+        write_file(&input_file, data, ",");
+        let metrics = run_wrapper(&input_file, &output_file, &opts);
+
+        assert_eq!(metrics.records, 3);
+        assert_eq!(metrics.bytes, 12);
+    }
+
+    #[rstest]
+    fn test_use_input_delim_with_regex(#[values(true, false)] no_mmap: bool) {
+        let mut args = vec!["-f", "1,3", "-I", "--input-delim-fallback", "|"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, "a  b  c\nd\te\tf\ng\n"), "a  c\nd\tf\ng\n");
+    }
+
+    #[rstest]
+    fn test_head_tail(
+        #[values(true, false)] no_mmap: bool,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(
+            (vec!["--head", "2"], "0\n1\n"),
+            (vec!["--tail", "2"], "8\n9\n"),
+            (vec!["--head", "5", "--tail", "2"], "3\n4\n")
+        )]
+        limits: (Vec<&str>, &str),
+    ) {
+        let (mut args, expected) = limits;
+        args.extend(["-f", "2", "-d", "\t"]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        let input: String = (0..10).map(|i| format!("r{}\t{}\n", i, i)).collect();
+        assert_eq!(run_hck(&args, &input), expected);
+    }
+
+    #[rstest]
+    fn test_header_policy(
+        #[values(true, false)] no_mmap: bool,
+        #[values(
+            (vec!["-f", "2", "--head", "1"], "n\n"),
+            (vec!["-F", "n", "--head", "1"], "n\na\n"),
+            (vec!["-F", "n", "--tail", "1"], "n\nc\n"),
+            (vec!["-F", "n", "--unique"], "n\na\nb\nc\n"),
+            (vec!["-F", "n", "--sample-n", "9"], "n\na\nb\na\nc\n"),
+            (vec!["-F", "n", "--count-unique"], "3\n"),
+            (vec!["-E", "id", "--require", "1:^[ab]$"], "n\na\nb\na\n"),
+            (vec!["-F", "n", "--no-out-header", "--head", "1"], "a\n"),
+            (vec!["-E", "id", "--no-header-out"], "a\nb\na\nc\n"),
+            (vec!["-F", "name", "--add-header", "id,name", "--tail", "1"], "name\nc\n"),
+            (vec!["-f", "2,1", "--add-header", "x,y", "--head", "1"], "y\tx\nn\tid\n"),
+            (vec!["-E", "x", "--add-header", "x,y", "--unique"], "y\nn\na\nb\nc\n"),
+            (vec!["-F", "c2", "--gen-header", "--head", "2"], "c2\nn\na\n"),
+            (vec!["--gen-header", "--head", "1"], "c1\tc2\nid\tn\n"),
+        )]
+        case: (Vec<&str>, &str),
+    ) {
+        let (mut args, expected) = case;
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, "id\tn\n1\ta\n2\tb\n3\ta\n4\tc\n"), expected);
+
+        // There is no header to leave out without selecting by header
+        assert!(try_run_hck(&["-f", "2", "--no-out-header"], &["id\tn\n"]).is_err());
+    }
+
+    #[rstest]
+    #[case(vec!["chrom,pos"], false, vec!["chrom", "pos"])]
+    #[case(vec!["a\\,b", "c"], false, vec!["a,b", "c"])]
+    #[case(vec!["a,", "b"], false, vec!["a", "", "b"])]
+    #[case(vec!["^a{1,3}$,[,;]"], true, vec!["^a{1,3}$", "[,;]"])]
+    #[case(vec!["(x|y),z\\,"], true, vec!["(x|y)", "z\\,"])]
+    fn test_split_header_selections(
+        #[case] selections: Vec<&str>,
+        #[case] is_regex: bool,
+        #[case] expected: Vec<&str>,
+    ) {
+        let selections: Vec<Regex> = selections
+            .into_iter()
+            .map(|s| Regex::new(s).unwrap())
+            .collect();
+        let split = split_header_selections(&selections, is_regex).unwrap();
+        assert_eq!(
+            split.iter().map(Regex::as_str).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[rstest]
+    fn test_comma_separated_headers(#[values(true, false)] no_mmap: bool) {
+        for (mut args, expected) in [
+            (vec!["-F", "ref,chrom"], "ref\tchrom\nA\tchr1\n"),
+            (vec!["-F", "pos", "-F", "a\\,b"], "pos\ta,b\n10\tx\n"),
+            (vec!["-E", "pos,ref"], "chrom\ta,b\nchr1\tx\n"),
+            (
+                vec!["-r", "-F", "^(ref|pos)$,^c"],
+                "pos\tref\tchrom\n10\tA\tchr1\n",
+            ),
+        ] {
+            if no_mmap {
+                args.push("--no-mmap");
+            }
+            assert_eq!(
+                run_hck(&args, "chrom\tpos\tref\ta,b\nchr1\t10\tA\tx\n"),
+                expected
+            );
+        }
+    }
+
+    #[rstest]
+    #[case(vec!["-L", "-d", ",", "-f3,1"], "fields: 3,1\nparser: fast\nmmap: yes\n")]
+    #[case(vec!["-d", ",", "-F", "c", "-f", "1"], "fields: 1,3\nparser: regex\nmmap: yes\n")]
+    #[case(vec!["-L", "-d", ",", "-E", "b", "--head", "1"], "fields: 1,3-\nparser: substr\nmmap: yes\n")]
+    #[case(vec!["-L", "-d", ",", "-f2", "-e2"], "fields: none\nparser: fast\nmmap: yes\n")]
+    #[case(vec!["--widths", "1,1", "-f2", "--no-mmap"], "fields: 2\nparser: fixed-width\nmmap: no\n")]
+    #[case(vec!["-f2"], "fields: 2\nparser: whitespace\nmmap: yes\n")]
+    fn test_explain(#[case] args: Vec<&str>, #[case] expected: &str) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.csv");
+        let output_file = tmp.path().join("output.txt");
+        std::fs::write(&input_file, "a,b,c\n1,2,3\n").unwrap();
+        let input = input_file.to_str().unwrap();
+        let mut argv = vec!["hck", "--explain", "-o", output_file.to_str().unwrap()];
+        argv.extend(args);
+        argv.push(input);
+        run_select(&Opts::try_parse_from(argv).unwrap().select).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&output_file).unwrap(),
+            format!("input: {}\n{}decompress: no\n", input, expected)
+        );
+    }
+
+    #[rstest]
+    fn test_mmap_mode(#[values("always", "auto", "never")] mode: &str) {
+        assert_eq!(
+            run_hck(&["--mmap", mode, "-f3,1"], "a\tb\tc\n1\t2\t3\n"),
+            "c\ta\n3\t1\n"
+        );
+        assert!(Opts::try_parse_from(["hck", "--mmap", mode, "--no-mmap"]).is_err());
+    }
+
+    #[rstest]
+    #[case(vec!["--line-buffered"])]
+    #[case(vec!["--flush-size", "1"])]
+    #[case(vec!["--flush-size", "0"])]
+    #[case(vec!["--write-buffer", "4K"])]
+    #[case(vec!["--max-buffer", "8"])]
+    #[case(vec!["--flush-size", "1", "--max-buffer", "1M"])]
+    #[case(vec!["--flush-size", "0", "--max-buffer", "0"])]
+    fn test_output_buffering(#[case] mut args: Vec<&str>) {
+        args.push("-f3,1");
+        assert_eq!(run_hck(&args, "a\tb\tc\n1\t2\t3\n"), "c\ta\n3\t1\n");
+        // The compressed output has its own buffering
+        args.push("-Z");
+        assert!(try_run_hck(&args, &["a\tb\tc\n"]).is_err());
+    }
+
+    #[test]
+    fn test_max_buffer_below_flush_size() {
+        let args = ["--flush-size", "1M", "--max-buffer", "4K"];
+        assert!(try_run_hck(&args, &["a\tb\n"]).is_err());
+        assert!(Opts::try_parse_from(["hck", "--line-buffered", "--max-buffer", "4K"]).is_err());
+    }
+
+    #[rstest]
+    fn test_fail_if_empty(#[values(true, false)] no_mmap: bool) {
+        // Output with data rows runs as usual, the exit status for no data rows ends the process
+        for (mut args, expected) in [
+            (vec!["-F", "a"], "a\n1\n"),
+            (
+                vec!["-F", "a", "--filter", "col(1) == 1", "--tail", "1"],
+                "a\n1\n",
+            ),
+        ] {
+            args.push("--fail-if-empty");
+            if no_mmap {
+                args.push("--no-mmap");
+            }
+            assert_eq!(run_hck(&args, "a\tb\n1\t2\n"), expected);
+        }
+        for conflict in ["--count", "--show-headers", "--verify-roundtrip"] {
+            assert!(Opts::try_parse_from(["hck", "--fail-if-empty", conflict]).is_err());
+        }
+    }
+
+    #[rstest]
+    #[case(vec!["-d", ","], "1\tid\n2\tname\n3\tscore\n")]
+    #[case(vec!["-d", ",", "-D", ":"], "1:id\n2:name\n3:score\n")]
+    #[case(vec!["-d", ",", "--gen-header"], "1\tc1\n2\tc2\n3\tc3\n")]
+    #[case(vec!["-d", ",", "--add-header", "x,y"], "1\tx\n2\ty\n")]
+    #[case(vec!["-d", "\\s+"], "1\tid,name,score\n")]
+    fn test_show_headers(#[case] args: Vec<&str>, #[case] expected: &str) {
+        let tmp = TempDir::new().unwrap();
+        let input_file = tmp.path().join("input.txt");
+        let empty_file = tmp.path().join("empty.txt");
+        let output_file = tmp.path().join("output.txt");
+        std::fs::write(&input_file, "id,name,score\n1,a,2\n").unwrap();
+        std::fs::write(&empty_file, "").unwrap();
+        let (input, out) = (input_file.to_str().unwrap(), output_file.to_str().unwrap());
+
+        let mut argv = vec!["hck", "--show-headers", "-o", out];
+        argv.extend(args.iter());
+        argv.push(input);
+        run_select(&Opts::try_parse_from(&argv).unwrap().select).unwrap();
+        assert_eq!(std::fs::read_to_string(&output_file).unwrap(), expected);
+
+        // Each line is prefixed with its input when there are several, and empty inputs have no headers
+        argv.push(empty_file.to_str().unwrap());
+        argv.push(input);
+        run_select(&Opts::try_parse_from(&argv).unwrap().select).unwrap();
+        let prefixed: String = expected
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .map(|line| {
+                let delim = if args.contains(&":") { ":" } else { "\t" };
+                let end = if args.contains(&"--crlf") {
+                    "\r\n"
+                } else {
+                    "\n"
+                };
+                format!("{}{}{}{}", input, delim, line, end)
+            })
+            .collect();
+        assert_eq!(
+            std::fs::read_to_string(&output_file).unwrap(),
+            prefixed.repeat(2)
+        );
+    }
+
+    #[rstest]
+    #[case(vec![], "b|a\n2|1\n")]
+    #[case(vec!["-f", "3"], "c\n3\n")]
+    #[case(vec!["-D", ":", "-E", "a"], "b:c\n2:3\n")]
+    #[case(vec!["select", "-d", ";", "-f", "1"], "a,b,c\n1,2,3\n")]
+    fn test_profile(#[case] args: Vec<&str>, #[case] expected: &str) {
+        let tmp = TempDir::new().unwrap();
+        let config = tmp.path().join("config.toml");
+        let not_toml = tmp.path().join("input.txt");
+        std::fs::write(
+            &config,
+            "[profiles.csv]\ndelimiter = ','\ndelim-is-literal = true\noutput-delimiter = '|'\nheader-field = ['b', 'a']\n",
         )
         .unwrap();
+        std::fs::write(&not_toml, "a,b,c\n1,2,3\n").unwrap();
+        let (config, not_toml) = (config.to_str().unwrap(), not_toml.to_str().unwrap());
+        let args = [&args[..], &["--profile", "csv", "--config", config]].concat();
+        assert_eq!(run_hck(&args, "a,b,c\n1,2,3\n"), expected);
+
+        for profile in ["missing", "csv"] {
+            let config = if profile == "csv" { not_toml } else { config };
+            let args = ["--profile", profile, "--config", config];
+            assert!(try_run_hck(&args, &["a,b,c\n"]).is_err());
+        }
     }
 
-    const FOURSPACE: &str = "    ";
+    #[test]
+    fn test_env_defaults() {
+        // Point the options at variables only this test sets, so no other test sees them
+        let command = || {
+            Opts::command()
+                .mut_arg("delimiter", |arg| arg.env("HCK_TEST_DELIMITER"))
+                .mut_arg("output_delimiter", |arg| {
+                    arg.env("HCK_TEST_OUTPUT_DELIMITER")
+                })
+                .mut_arg("compression_threads", |arg| arg.env("HCK_TEST_THREADS"))
+        };
+        let parse = |args: &[&str]| {
+            let matches = command().try_get_matches_from(args).unwrap();
+            check_conflicts(&matches).map(|_| opts_from_matches(&matches).unwrap().select)
+        };
+
+        let opts = parse(&["hck"]).unwrap();
+        assert_eq!(opts.delim.delimiter, [r"\s+"]);
+        assert_eq!(opts.delim.output_delimiter, "\t");
+
+        std::env::set_var("HCK_TEST_DELIMITER", ",");
+        std::env::set_var("HCK_TEST_OUTPUT_DELIMITER", ":");
+        std::env::set_var("HCK_TEST_THREADS", "3");
+        // The environment wins over the defaults
+        let opts = parse(&["hck"]).unwrap();
+        assert_eq!(opts.delim.delimiter, [","]);
+        assert_eq!(opts.delim.output_delimiter, ":");
+        assert_eq!(opts.output.compression_threads, 3);
+        // And flags win over the environment
+        let opts = parse(&["hck", "-d", ";", "-D", "|", "-t", "1"]).unwrap();
+        assert_eq!(opts.delim.delimiter, [";"]);
+        assert_eq!(opts.delim.output_delimiter, "|");
+        assert_eq!(opts.output.compression_threads, 1);
+        // Even flags that can't be given with the options the environment set
+        let opts = parse(&["hck", "--awk-fields", "-I"]).unwrap();
+        assert!(opts.delim.awk_fields && opts.delim.use_input_delim);
+        assert!(parse(&["hck", "-d", ";", "--awk-fields"]).is_err());
+        assert!(parse(&["hck", "select", "-I", "-D", ","]).is_err());
+        std::env::remove_var("HCK_TEST_DELIMITER");
+        std::env::remove_var("HCK_TEST_OUTPUT_DELIMITER");
+        std::env::remove_var("HCK_TEST_THREADS");
+
+        let command = Opts::command();
+        for (id, env) in [
+            ("delimiter", "HCK_DELIMITER"),
+            ("delim_is_literal", "HCK_DELIM_IS_LITERAL"),
+            ("output_delimiter", "HCK_OUTPUT_DELIMITER"),
+            ("try_decompress", "HCK_TRY_DECOMPRESS"),
+            ("compression_threads", "HCK_THREADS"),
+            ("compression_level", "HCK_COMPRESSION_LEVEL"),
+        ] {
+            let arg = command.get_arguments().find(|arg| arg.get_id() == id);
+            assert_eq!(arg.and_then(|arg| arg.get_env()), Some(env.as_ref()));
+        }
+    }
 
     #[rstest]
-    fn test_exclude_one(
+    #[case(vec!["--preset", "csv", "-f", "3,1"], "a,\"b\",c\n", "c,a\n")]
+    #[case(vec!["--preset", "tsv", "-f", "2"], "a b\tc\n", "c\n")]
+    #[case(vec!["--preset", "psv", "-f", "2-"], "a|b|c\n", "b|c\n")]
+    #[case(vec!["--preset", "ssv", "-f", "3"], "a  b\n", "b\n")]
+    #[case(vec!["--preset", "csv", "-D", "\t", "-f", "2,1"], "a,b\n", "b\ta\n")]
+    #[case(vec!["--preset", "psv", "-d", ";", "-f", "2,1"], "a;b\n", "b|a\n")]
+    #[case(vec!["--preset", "csv", "--out-quote", "-f", "2"], "a,\"b\"\n", "\"\"\"b\"\"\"\n")]
+    #[case(vec!["select", "--preset", "tsv", "--awk-fields", "-f", "2"], " a  b\n", "b\n")]
+    #[case(vec!["--preset", "psv", "-D", ",", "--out-escape", "quote", "-f", "2,1"], "a,b|c\n", "c,\"a,b\"\n")]
+    #[case(vec!["--preset", "psv", "-D", ",", "--out-escape", "backslash", "-f", "2,1"], "a,b\\|c\n", "c,a\\,b\\\\\n")]
+    #[case(vec!["--preset", "psv", "--out-escape", "none", "-f", "2,1"], "a,b|c\n", "c|a,b\n")]
+    fn test_preset(
+        #[values(true, false)] no_mmap: bool,
+        #[case] mut args: Vec<&str>,
+        #[case] input: &str,
+        #[case] expected: &str,
+    ) {
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, input), expected);
+    }
+
+    #[rstest]
+    fn test_fields_per_file(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
         #[values(true, false)] delim_is_literal: bool,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
-            &input_file,
-            &output_file,
-            Some("1,3"),
-            None,
-            Some("3"),
-            no_mmap,
-            hck_delim,
-            delim_is_literal,
-            false,
+        let inputs = ["id,name,x\n1,a,x\n", "y,name,z,id\ny,b,z,2\n"];
+        let mut args = vec!["-d", ",", "--fields-per-file", "1-2;4,2"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        assert_eq!(
+            try_run_hck(&args, &inputs).unwrap(),
+            "id\tname\n1\ta\nid\tname\n2\tb\n"
         );
-        let data = vec![vec!["a", "b", "c"], vec!["1", "2", "3"]];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
 
-        assert_eq!(filtered, vec![vec!["a",], vec!["1"]]);
+        // Each input needs its own list, and they replace -f
+        assert!(try_run_hck(&["--fields-per-file", "1;2;3"], &inputs).is_err());
+        assert!(Opts::try_parse_from(["hck", "-f", "1", "--fields-per-file", "1;2"]).is_err());
     }
 
     #[rstest]
-    fn test_exclude_range_overlap_front(
+    fn test_paste(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
         #[values(true, false)] delim_is_literal: bool,
+        #[values(true, false)] crlf: bool,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
-            &input_file,
-            &output_file,
-            Some("3-"),
-            None,
-            Some("-5"),
-            no_mmap,
-            hck_delim,
-            delim_is_literal,
-            false,
+        let newline = if crlf { "\r\n" } else { "\n" };
+        let first = ["a,b,c", "1,2,3", "4,5,6", ""].join(newline);
+        let second = ["x,y", "7,8"].join(newline);
+        let (first, second, third) = (first.as_str(), second.as_str(), "");
+        let run_paste = |extra: &[&str], inputs: &[&str]| {
+            let mut args = vec!["--paste", "-d", ","];
+            if no_mmap {
+                args.push("--no-mmap");
+            }
+            if delim_is_literal {
+                args.push("-L");
+            }
+            if crlf {
+                args.push("--crlf");
+            }
+            args.extend(extra);
+            try_run_hck(&args, inputs).unwrap()
+        };
+
+        // Inputs that run out of lines leave empty columns
+        let expected = ["c\ta\ty", "3\t1\t8", "6\t4\t", ""].join(newline);
+        assert_eq!(
+            run_paste(&["--fields-per-file", "3,1;2"], &[first, second]),
+            expected
         );
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f"],
-            vec!["1", "2", "3", "4", "5", "6"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let expected = ["a|x|", "1|7|", "4||", ""].join(newline);
+        assert_eq!(
+            run_paste(&["-f", "1", "-D", "|"], &[first, second, third]),
+            expected
+        );
+        assert_eq!(
+            run_paste(&["-F", "c", "-F", "a"], &[first, first]),
+            ["c\ta\tc\ta", "3\t1\t3\t1", "6\t4\t6\t4", ""].join(newline)
+        );
+        assert_eq!(run_paste(&["-f", "1"], &[third]), "");
+        // Pasted lines are split and joined on the output record separator
+        assert_eq!(
+            run_paste(
+                &["-f", "1", "-D", "|", "--out-record-sep", "\\0"],
+                &[first, second]
+            ),
+            ["a|x", "1|7", "4|", ""].join("\0")
+        );
+        assert!(Opts::try_parse_from(["hck", "--paste", "--count"]).is_err());
+    }
 
-        assert_eq!(filtered, vec![vec!["f",], vec!["6"]]);
+    #[rstest]
+    fn test_truncate_lines(
+        #[values(true, false)] no_mmap: bool,
+        #[values("1", "3,1", "2-3", "2-")] fields: &str,
+        #[values(true, false)] crlf: bool,
+    ) {
+        let mut args = vec!["-L", "-d", ",", "-f", fields];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if crlf {
+            args.push("--crlf");
+        }
+        let wide = format!(",{}", "x".repeat(100_000)).repeat(20);
+        let data = format!("a,b,c{}\r\n1,2{}\n,\r\nx,y,z\n", wide, wide);
+        let expected = run_hck(&args, &data);
+
+        args.push("--truncate-lines");
+        assert_eq!(run_hck(&args, &data), expected);
     }
 
     #[rstest]
-    fn test_exclude_range_overlap_back(
+    fn test_per_input_delimiters(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
         #[values(true, false)] delim_is_literal: bool,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
-            &input_file,
-            &output_file,
-            Some("2-5"),
-            None,
-            Some("3-"),
-            no_mmap,
-            hck_delim,
-            delim_is_literal,
-            false,
+        let inputs = ["a,b c,d\n1,2 3,4\n", "e\tf g\th\n5\t6 7\t8\n"];
+        let mut args = vec!["-d", ",", "-d", r"\t", "-D", "|", "-f", "3,2"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        assert_eq!(
+            try_run_hck(&args, &inputs).unwrap(),
+            "d|b c\n4|2 3\nh|f g\n8|6 7\n"
         );
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f"],
-            vec!["1", "2", "3", "4", "5", "6"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
 
-        assert_eq!(filtered, vec![vec!["b",], vec!["2"]]);
+        // Delimiters pair up with inputs, and -I needs just one to write out
+        assert!(try_run_hck(&["-d", ",", "-d", r"\t"], &inputs[..1]).is_err());
+        assert!(try_run_hck(&["-I", "-d", ",", "-d", r"\t"], &inputs).is_err());
     }
 
     #[rstest]
-    fn test_exclude_range_split_fields(
+    fn test_final_line_without_newline(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
         #[values(true, false)] delim_is_literal: bool,
+        #[values(true, false)] mirror_missing_newline: bool,
+        #[values(("1,3", "a\tc\n1\t3"), ("2", "b\n2"), ("3-", "c\td\n3"))] case: (&str, &str),
     ) {
         let tmp = TempDir::new().unwrap();
         let input_file = tmp.path().join("input.txt");
         let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
-            &input_file,
-            &output_file,
-            Some("1-"),
-            None,
-            Some("3-5"),
-            no_mmap,
-            hck_delim,
-            delim_is_literal,
-            false,
-        );
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f"],
-            vec!["1", "2", "3", "4", "5", "6"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let (fields, expected) = case;
+        let opts = SelectOpts {
+            mirror_missing_newline,
+            ..build_opts_generic(
+                &input_file,
+                &output_file,
+                Some(fields),
+                None,
+                None,
+                no_mmap,
+                "\t",
+                delim_is_literal,
+                false,
+            )
+        };
+        std::fs::write(&input_file, "a\tb\tc\td\n1\t2\t3").unwrap();
+        let metrics = run_wrapper(&input_file, &output_file, &opts);
 
-        assert_eq!(filtered, vec![vec!["a", "b", "f"], vec!["1", "2", "6"]]);
+        let mut expected = expected.to_owned();
+        if !mirror_missing_newline {
+            expected.push('\n');
+        }
+        assert_eq!(metrics.records, 2);
+        assert_eq!(std::fs::read_to_string(output_file).unwrap(), expected);
     }
 
     #[rstest]
-    fn test_exclude_range_all(
+    fn test_mirror_missing_newline(
+        #[values(true, false)] no_mmap: bool,
+        #[values(
+            vec!["-L", "-f", "3,1"],
+            vec!["-f", "3,1"],
+            vec!["-L", "-f", "3,1", "--head", "5"],
+            vec!["-F", "c", "-F", "a"]
+        )]
+        selection: Vec<&str>,
+    ) {
+        let mut args = vec!["-d", ",", "-D", ","];
+        args.extend(selection);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        let input = "a,b,c\n1,2,3";
+        assert_eq!(run_hck(&args, input), "c,a\n3,1\n");
+        // Records used to mirror the input, and still can
+        args.push("--mirror-missing-newline");
+        assert_eq!(run_hck(&args, input), "c,a\n3,1");
+    }
+
+    #[rstest]
+    fn test_fill(
+        #[values(true, false)] no_mmap: bool,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(
+            ("1,3", "a\tc\n1\tNA\nx\tNA\n"),
+            ("2-3", "b\tc\n2\tNA\nNA\tNA\n"),
+            ("3,1", "c\ta\nNA\t1\nNA\tx\n"),
+            ("2-", "b\tc\td\n2\nNA\n")
+        )]
+        case: (&str, &str),
+    ) {
+        let (fields, expected) = case;
+        let mut args = vec!["-f", fields, "-d", "\t", "--fill", "NA"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        assert_eq!(run_hck(&args, "a\tb\tc\td\n1\t2\nx\n"), expected);
+    }
+
+    #[rstest]
+    fn test_strict(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
         #[values(true, false)] delim_is_literal: bool,
+        #[values(true, false)] ragged: bool,
     ) {
         let tmp = TempDir::new().unwrap();
         let input_file = tmp.path().join("input.txt");
         let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
-            &input_file,
-            &output_file,
-            Some("4,3"),
-            None,
-            Some("2-5"),
-            no_mmap,
-            hck_delim,
-            delim_is_literal,
-            false,
-        );
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f"],
-            vec!["1", "2", "3", "4", "5", "6"],
-        ];
-        write_file(&input_file, data, hck_delim);
+        let opts = SelectOpts {
+            strict: true,
+            ..build_opts_generic(
+                &input_file,
+                &output_file,
+                Some("1"),
+                None,
+                None,
+                no_mmap,
+                "\t",
+                delim_is_literal,
+                false,
+            )
+        };
+        let mut data = vec![vec!["a", "b"], vec!["1", "2"], vec!["3", "4"]];
+        if ragged {
+            data[2].push("5");
+        }
+        write_file(&input_file, data, "\t");
+        let result = try_run_wrapper(&input_file, &output_file, &opts);
+        if ragged {
+            let err = result.unwrap_err().to_string();
+            assert!(err.ends_with(":3: expected 2 fields, found 3"), "{}", err);
+        } else {
+            assert_eq!(result.unwrap().records, 3);
+        }
+
+        // `--check` only reports, so everything is still written
+        let opts = SelectOpts {
+            strict: false,
+            check: true,
+            ..opts
+        };
         run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        assert_eq!(read_tsv(output_file), vec![vec!["a"], vec!["1"], vec!["3"]]);
+    }
+
+    #[rstest]
+    fn test_record_sep(
+        #[values(true, false)] no_mmap: bool,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(
+            ("\\x1e", false, "a\tb\x1e1\t2\x1e", "b\x1e2\x1e"),
+            ("\\x1e", false, "a\tb\x1e1\t2", "b\x1e2\x1e"),
+            ("||", false, "a\tb||1\t2||3", "b||2||||"),
+            ("||", false, "a\t|b||", "|b||"),
+            ("\\r\\n", false, "a\tb\r\n1\t2\r\n", "b\r\n2\r\n"),
+            ("\\n{2,}", true, "a\tb\n1\n\n\n1\t2\n\n", "b\n1\n2\n"),
+            ("[;|]", true, "a\tb;1\t2|", "b\n2\n")
+        )]
+        case: (&str, bool, &str, &str),
+    ) {
+        let (sep, is_regex, input, expected) = case;
+        let mut args = vec!["-f", "2", "-d", "\t", "--record-sep", sep];
+        if is_regex {
+            args.push("--record-sep-is-regex");
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        assert_eq!(run_hck(&args, input), expected);
+    }
+
+    #[rstest]
+    fn test_column_match(
+        #[values(true, false)] no_mmap: bool,
+        #[values(
+            (vec!["-f", "2", "--match", "1:^chr[0-9]+$"], "a\nc\n"),
+            (vec!["-f", "2", "--match", "1:^chr[0-9]+$", "--invert-match"], "name\nb\n\n"),
+            (vec!["-f", "2", "--match", "1:^chr", "--match", "3:^[0-9]+$"], "a\nb\n"),
+            (vec!["-F", "name", "--match", "1:^chrX$"], "name\nb\n"),
+            (vec!["-F", "name", "--match", "3:.", "--invert-match"], "name\nc\n\n"),
+            (vec!["-f", "2", "--awk-fields", "--match", "2:^[ab]$"], "a\nb\n")
+        )]
+        case: (Vec<&str>, &str),
+    ) {
+        let (mut args, expected) = case;
+        if !args.contains(&"--awk-fields") {
+            args.extend(["-L", "-d", "\t"]);
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(
+            run_hck(
+                &args,
+                "chrom\tname\tpos\nchr1\ta\t10\nchrX\tb\t20\nchr22\tc\nscaffold\n"
+            ),
+            expected
+        );
+    }
+
+    #[rstest]
+    fn test_count_fields(
+        #[values(true, false)] no_mmap: bool,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(
+            (vec!["--count-fields"], "3\n2\n1\n"),
+            (vec!["--count-fields-column"], "b\t3\n\t2\n1\n"),
+            (vec!["--count-fields", "--filter", "col(1) != 3"], "2\n1\n"),
+            (vec!["--count-fields-column", "--filter", "col(2) == 3"], "b\t3\n")
+        )]
+        case: (Vec<&str>, &str),
+    ) {
+        let (mut args, expected) = case;
+        args.extend(["-f", "2", "-d", "\t"]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        assert_eq!(run_hck(&args, "a\tb\tc\n1\t\n\n"), expected);
+    }
+
+    #[rstest]
+    fn test_out_record_sep(
+        #[values(true, false)] no_mmap: bool,
+        #[values(
+            (None, "\\0", "2,1", "a\tb\n1\t2", "b\ta\x002\t1\x00"),
+            (Some("\\r\\n"), "\\n", "2", "a\tb\r\n1\t2\r\n", "b\n2\n"),
+            (Some("||"), "\\n", "1-", "a\tb||1\t2||", "a\tb\n1\t2\n"),
+            (None, "\\r\\n", "1-", "a\tb\n1\t2\n", "a\tb\r\n1\t2\r\n")
+        )]
+        case: (Option<&str>, &str, &str, &str, &str),
+    ) {
+        let (sep, out, fields, input, expected) = case;
+        let mut args = vec!["-f", fields, "-d", "\t", "--out-record-sep", out];
+        if let Some(sep) = sep {
+            args.extend(["--record-sep", sep]);
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, input), expected);
+    }
+
+    #[rstest]
+    fn test_out_record_sep_added_header(
+        #[values(true, false)] no_mmap: bool,
+        #[values(
+            (vec!["--add-header", "x,y"], "y\tx\x002\t1\x004\t3\x00"),
+            (vec!["--gen-header"], "c2\tc1\x002\t1\x004\t3\x00")
+        )]
+        case: (Vec<&str>, &str),
+    ) {
+        let (header, expected) = case;
+        let mut args = vec!["-f", "2,1", "--out-record-sep", "\\0"];
+        args.extend(header);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, "1\t2\n3\t4\n"), expected);
+    }
+
+    #[rstest]
+    fn test_record_sep_header(#[values(true, false)] no_mmap: bool) {
+        let mut args = vec!["-L", "-d", "\t", "-r", "-F", "^b$", "--record-sep", "||"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, "a\tb||1\t2||"), "b||2||");
+    }
 
-        assert!(filtered.is_empty());
+    #[test]
+    fn test_record_sep_errors() {
+        for (sep, is_regex) in [("", false), ("x*", true), ("(", true)] {
+            let conf = CoreConfigBuilder::new()
+                .record_separator(Some(sep.as_bytes()))
+                .record_separator_is_regex(is_regex)
+                .build();
+            assert!(conf.is_err(), "{}", sep);
+        }
     }
 
     #[rstest]
-    fn test_exclude_range_split_fields_reorder(
+    fn test_count(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
         #[values(true, false)] delim_is_literal: bool,
+        #[values(("1,3", 5), ("2-", 5), ("3,1", 5), ("9", 0))] case: (&str, u64),
     ) {
         let tmp = TempDir::new().unwrap();
         let input_file = tmp.path().join("input.txt");
         let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
-            &input_file,
-            &output_file,
-            Some("4-6,1-3"),
-            None,
-            Some("3-5"),
-            no_mmap,
-            hck_delim,
-            delim_is_literal,
-            false,
-        );
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f"],
-            vec!["1", "2", "3", "4", "5", "6"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let (fields, expected) = case;
+        let opts = SelectOpts {
+            count: true,
+            ..build_opts_generic(
+                &input_file,
+                &output_file,
+                Some(fields),
+                None,
+                None,
+                no_mmap,
+                "\t",
+                delim_is_literal,
+                false,
+            )
+        };
+        let data = vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3"], vec!["x"]];
+        write_file(&input_file, data, "\t");
+        let metrics = run_wrapper(&input_file, &output_file, &opts);
 
-        assert_eq!(filtered, vec![vec!["f", "a", "b"], vec!["6", "1", "2"]]);
+        assert_eq!(metrics.records, 3);
+        assert_eq!(metrics.fields, expected);
+        assert_eq!(std::fs::read_to_string(output_file).unwrap(), "");
     }
 
     #[rstest]
-    fn test_headers_simple(
+    fn test_on_empty_selection(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
         #[values(true, false)] delim_is_literal: bool,
-        #[values(true, false)] header_is_regex: bool,
+        #[values(
+            OnEmptySelection::Skip,
+            OnEmptySelection::EmptyLines,
+            OnEmptySelection::Error
+        )]
+        on_empty_selection: OnEmptySelection,
     ) {
         let tmp = TempDir::new().unwrap();
         let input_file = tmp.path().join("input.txt");
         let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
-            &input_file,
-            &output_file,
-            None,
-            Some(vec![Regex::new("a").unwrap()]),
-            None,
-            no_mmap,
-            hck_delim,
-            delim_is_literal,
-            header_is_regex,
-        );
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f"],
-            vec!["1", "2", "3", "4", "5", "6"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let opts = SelectOpts {
+            on_empty_selection,
+            ..build_opts_generic(
+                &input_file,
+                &output_file,
+                Some("1,2"),
+                None,
+                Some("1-2"),
+                no_mmap,
+                "\t",
+                delim_is_literal,
+                false,
+            )
+        };
+        write_file(&input_file, vec![vec!["a", "b"], vec!["1", "2"]], "\t");
+        let result = try_run_wrapper(&input_file, &output_file, &opts);
 
-        assert_eq!(filtered, vec![vec!["a"], vec!["1"]]);
+        match on_empty_selection {
+            OnEmptySelection::Skip => {
+                assert_eq!(result.unwrap().records, 0);
+                assert_eq!(std::fs::read_to_string(output_file).unwrap(), "");
+            }
+            OnEmptySelection::EmptyLines => {
+                assert_eq!(result.unwrap().records, 2);
+                assert_eq!(std::fs::read_to_string(output_file).unwrap(), "\n\n");
+            }
+            OnEmptySelection::Error => {
+                let err = result.unwrap_err().to_string();
+                assert!(err.starts_with("No fields left to select"), "{}", err);
+            }
+        }
     }
 
     #[rstest]
-    fn test_headers_simple2(
+    fn test_escaped_delimiters(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
-        #[values(true, false)] delim_is_literal: bool,
-        #[values(true, false)] header_is_regex: bool,
+        #[values(r"\t\t", r"\x1f", "::")] output_delimiter: &str,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
-            &input_file,
-            &output_file,
-            None,
-            Some(vec![Regex::new("a").unwrap(), Regex::new("c").unwrap()]),
-            None,
-            no_mmap,
-            hck_delim,
-            delim_is_literal,
-            header_is_regex,
+        let mut args = vec!["-L", "-d", r"\x1f", "-D", output_delimiter, "-f", "1,3"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        let sep = String::from_utf8(Vec::unescape_bytes(output_delimiter)).unwrap();
+        assert_eq!(
+            run_hck(&args, "a\x1fb\x1fc\n1\x1f2\x1f3\n"),
+            format!("a{sep}c\n1{sep}3\n")
         );
-        let data = vec![vec!["a", "b", "c"], vec!["1", "2", "3"]];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        assert_eq!(filtered, vec![vec!["a", "c"], vec!["1", "3"]]);
     }
 
     #[rstest]
-    fn test_duplicate_field_selection_more(
+    fn test_control_character_delimiters(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
-        #[values(true, false)] delim_is_literal: bool,
-        #[values(true, false)] header_is_regex: bool,
+        #[values(true, false)] literal: bool,
+        #[values((r"\x1f", "\x1f"), (r"\0", "\0"), (r"\x1e", "\x1e"))] delimiter: (&str, &str),
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
-            &input_file,
-            &output_file,
-            Some("3,3,1,2"),
-            None,
-            None,
-            no_mmap,
-            hck_delim,
-            delim_is_literal,
-            header_is_regex,
-        );
-        let data = vec![vec!["a", "b", "c", "d", "e"], vec!["1", "2", "3", "4", "5"]];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        assert_eq!(filtered, vec![vec!["c", "a", "b"], vec!["3", "1", "2"]]);
+        let (escaped, d) = delimiter;
+        let input = format!("x{d}y{d}z\n1{d}2{d}3\n");
+        let run = |extra: &[&str]| {
+            let mut args = vec!["-d", escaped];
+            if literal {
+                args.push("-L");
+            }
+            if no_mmap {
+                args.push("--no-mmap");
+            }
+            args.extend(extra);
+            run_hck(&args, &input)
+        };
+        assert_eq!(run(&["-f", "3,1", "-D", r"\x1e"]), "z\x1ex\n3\x1e1\n");
+        assert_eq!(run(&["-f", "1,3", "-D", r"\0"]), "x\0z\n1\x003\n");
+        assert_eq!(run(&["-f", "1,3", "-I"]), format!("x{d}z\n1{d}3\n"));
+        // Header names are split on the same delimiter
+        assert_eq!(run(&["-F", "y"]), "y\n2\n");
+        assert_eq!(run(&["-E", "y", "-D", r"\x1e"]), "x\x1ez\n1\x1e3\n");
     }
 
     #[rstest]
-    fn test_duplicate_field_selection_range(
+    fn test_unique(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
         #[values(true, false)] delim_is_literal: bool,
-        #[values(true, false)] header_is_regex: bool,
+        #[values(
+            (vec!["--unique"], "a\t1\nb\t1\na\t2\n"),
+            (vec!["--unique-on", "1"], "a\t1\nb\t1\n"),
+            (vec!["--unique-on", "2"], "a\t1\na\t2\n")
+        )]
+        case: (Vec<&str>, &str),
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
-            &input_file,
-            &output_file,
-            Some("2-3,5,1,2-4"),
-            None,
-            None,
-            no_mmap,
-            hck_delim,
-            delim_is_literal,
-            header_is_regex,
-        );
-        let data = vec![vec!["a", "b", "c", "d", "e"], vec!["1", "2", "3", "4", "5"]];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
+        let (mut args, expected) = case;
+        args.extend(["-f", "3,1", "-d", "\t"]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
         assert_eq!(
-            filtered,
-            vec![vec!["b", "c", "e", "a", "d"], vec!["2", "3", "5", "1", "4"]]
+            run_hck(&args, "1\tx\ta\n1\ty\tb\n1\tz\ta\n2\tx\ta\n2\ty\ta\n"),
+            expected
         );
     }
 
     #[rstest]
-    fn test_headers_and_fields(
-        #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
+    fn test_encoding(
         #[values(true, false)] delim_is_literal: bool,
-        #[values(true, false)] header_is_regex: bool,
+        #[values(None, Some("b"))] header: Option<&str>,
+        #[values("utf-16le", "utf-16be", "latin1")] encoding: &str,
     ) {
         let tmp = TempDir::new().unwrap();
         let input_file = tmp.path().join("input.txt");
         let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_generic(
+        let mut opts = build_opts_generic(
             &input_file,
             &output_file,
-            Some("3"),
-            Some(vec![Regex::new("b").unwrap(), Regex::new("a").unwrap()]),
+            header.is_none().then_some("2"),
+            header.map(|h| vec![Regex::new(h).unwrap()]),
             None,
-            no_mmap,
-            hck_delim,
+            false,
+            "\t",
             delim_is_literal,
-            header_is_regex,
+            false,
         );
-        let data = vec![vec!["a", "b", "c", "d", "e"], vec!["1", "2", "3", "4", "5"]];
-        write_file(&input_file, data, hck_delim);
+        opts.input.encoding = Some(parse_encoding(encoding).unwrap());
+        let text = "a\tb\ncafé\tnaïve\n";
+        let bytes: Vec<u8> = match encoding {
+            "utf-16le" => [0xFF, 0xFE]
+                .into_iter()
+                .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+                .collect(),
+            "utf-16be" => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            _ => text.chars().map(|c| c as u8).collect(),
+        };
+        std::fs::write(&input_file, bytes).unwrap();
         run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        assert_eq!(filtered, vec![vec!["b", "c", "a"], vec!["2", "3", "1"]]);
+        assert_eq!(std::fs::read_to_string(output_file).unwrap(), "b\nnaïve\n");
     }
 
     #[rstest]
-    fn test_duplicate_field_selection(
+    fn test_bom(
         #[values(true, false)] no_mmap: bool,
-        #[values(r" ", "  ")] hck_delim: &str,
         #[values(true, false)] delim_is_literal: bool,
-        #[values(true, false)] header_is_regex: bool,
+        #[values(None, Some("id"))] header: Option<&str>,
+        #[values("utf-8", "utf-16le", "utf-16be")] encoding: &str,
     ) {
         let tmp = TempDir::new().unwrap();
         let input_file = tmp.path().join("input.txt");
@@ -807,657 +4988,1020 @@ mod test {
         let opts = build_opts_generic(
             &input_file,
             &output_file,
-            Some("3,1,3"),
-            None,
+            header.is_none().then_some("1"),
+            header.map(|h| vec![Regex::new(h).unwrap()]),
             None,
             no_mmap,
-            hck_delim,
+            "\t",
             delim_is_literal,
-            header_is_regex,
+            false,
         );
-        let data = vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3", "4"]];
-        write_file(&input_file, data, hck_delim);
+        let text = "\u{feff}id\tname\n1\tcafé\n";
+        let bytes: Vec<u8> = match encoding {
+            "utf-16le" => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            "utf-16be" => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            _ => text.as_bytes().to_vec(),
+        };
+        std::fs::write(&input_file, &bytes).unwrap();
         run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        assert_eq!(std::fs::read_to_string(&output_file).unwrap(), "id\n1\n");
 
-        assert_eq!(filtered, vec![vec!["c", "a"], vec!["3", "1"]]);
+        // Only a BOM at the very start of the input is special
+        if encoding == "utf-8" {
+            std::fs::write(&input_file, "id\tname\n\u{feff}1\tcafé\n").unwrap();
+            run_wrapper(&input_file, &output_file, &opts);
+            assert_eq!(
+                std::fs::read_to_string(&output_file).unwrap(),
+                "id\n\u{feff}1\n"
+            );
+        }
     }
-    #[rstest]
-    #[rustfmt::skip::macros(vec)]
-    fn test_read_single_values(
-        #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
-    ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "1", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c"],
-            vec!["1", "2", "3"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
 
-        assert_eq!(filtered, vec![vec!["a"], vec!["1"]]);
+    #[rstest]
+    fn test_keep_bom(#[values(true, false)] no_mmap: bool) {
+        let mut args = vec!["-L", "-d", "\t", "-f", "1", "--keep-bom"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(
+            run_hck(&args, "\u{feff}id\tname\n1\tcafé\n"),
+            "\u{feff}id\n1\n"
+        );
     }
 
     #[rstest]
-    fn test_read_several_single_values(
+    fn test_gen_key(
         #[values(true, false)] no_mmap: bool,
-        #[values(r"\s+")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(None, Some("b"))] header: Option<&str>,
+        #[values("uuid5", "xxh3")] kind: &str,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "1,3", no_mmap, hck_delim);
-        let data = vec![vec!["a", "b", "c"], vec!["1", "2", "3"]];
-        write_file(&input_file, data, FOURSPACE);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        assert_eq!(filtered, vec![vec!["a", "c"], vec!["1", "3"]]);
+        let spec = format!("1:id:{}", kind);
+        let mut args = vec!["-d", "\t", "--gen-key", &spec];
+        match header {
+            Some(header) => args.extend(["-F", header]),
+            None => args.extend(["-f", "2"]),
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        let out = run_hck(&args, "a\tb\n1\tx\n2\ty\n3\tx\n");
+        let (keys, values): (Vec<&str>, Vec<&str>) = out
+            .lines()
+            .map(|line| line.split_once('\t').unwrap())
+            .unzip();
+        assert_eq!(values, vec!["b", "x", "y", "x"]);
+        if header.is_some() {
+            assert_eq!(keys[0], "id");
+        }
+        assert_eq!(keys[1], keys[3]);
+        assert_ne!(keys[1], keys[2]);
+        assert_eq!(keys[1].len(), if kind == "uuid5" { 36 } else { 16 });
     }
 
     #[rstest]
-    fn test_read_several_single_values_with_invalid_utf8(
+    fn test_codecs(
         #[values(true, false)] no_mmap: bool,
-        #[values(r"\s+")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "1,3", no_mmap, hck_delim);
-        let bad_str = unsafe { String::from_utf8_unchecked(b"a\xED\xA0\x80z".to_vec()) };
-        let data = vec![vec![bad_str.as_str(), "b", "c"], vec!["1", "2", "3"]];
-        write_file(&input_file, data, FOURSPACE);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        assert_eq!(filtered, vec![vec![bad_str.as_str(), "c"], vec!["1", "3"]]);
+        let mut args = vec![
+            "-d",
+            "\t",
+            "-f",
+            "2,3,4",
+            "--decode",
+            "1:url",
+            "--decode",
+            "2-3:backslash",
+            "--encode",
+            "2:json",
+            "--filter",
+            r#"col(1) == "a b""#,
+        ];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        assert_eq!(
+            run_hck(&args, "0\ta%20b\tsay\\t\\x22hi\\x22\tx\\ny\n0\tc\td\te\n"),
+            "a b\tsay\\t\\\"hi\\\"\tx\ny\n"
+        );
     }
 
     #[rstest]
-    fn test_read_single_range(
-        #[values(true, false)] no_mmap: bool,
-        #[values(r"\s+")] hck_delim: &str,
-    ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "2-", no_mmap, hck_delim);
-        let data = vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3", "4"]];
-        write_file(&input_file, data, FOURSPACE);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+    fn test_tsv_escapes(#[values(true, false)] no_mmap: bool) {
+        let mut args = vec!["-L", "-d", "\t", "-f", "3,2", "--tsv-escapes"];
+        args.extend(["--filter", "col(2) != \"b\tc\""]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        // The escaped tab in the second record is decoded for the filter, and a backslash that doesn't
+        // start an escape is taken as is, so it is escaped on the way out
+        assert_eq!(
+            run_hck(&args, "0\ta\\tb\tc\\\\d\\ne\n1\tb\\tc\tf\n2\t\\x41\\q\tg\n"),
+            "c\\\\d\\ne\ta\\tb\ng\t\\\\x41\\\\q\n"
+        );
+    }
 
-        assert_eq!(filtered, vec![vec!["b", "c", "d"], vec!["2", "3", "4"]]);
+    #[rstest]
+    fn test_base64(#[values(true, false)] no_mmap: bool) {
+        let mut args = vec!["-L", "-d", "\t", "-f", "2,3"];
+        args.extend(["--b64-decode", "1", "--b64-encode", "2"]);
+        args.extend(["--encode", "1,2:backslash"]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        // `YQli` is `a\tb`, which is escaped to stay a single field, `bm90IGJhc2U2NA` isn't valid
+        assert_eq!(
+            run_hck(&args, "0\tYQli\tfoo\n0\tbm90IGJhc2U2NA!\tfo\n"),
+            "a\\tb\tZm9v\nbm90IGJhc2U2NA!\tZm8=\n"
+        );
     }
 
     #[rstest]
-    fn test_read_serveral_range(
+    fn test_split_field(
         #[values(true, false)] no_mmap: bool,
-        #[values(r"\s+")] hck_delim: &str,
+        #[values(
+            (vec!["--split-field", "2:;"], "1\ta\tb\tc\tx\n2\t\tx,y\n3\n"),
+            (vec!["--split-field", "2:;:2"], "1\ta\tb\tx\n2\t\tx,y\n3\n"),
+            (vec!["--split-field", "3:,", "--split-field", "2:;"], "1\ta\tb\tc\tx\n2\t\tx\ty\n3\n"),
+            (vec!["--split-field", "2:;", "--require", "4:^c$"], "1\ta\tb\tc\tx\n"),
+        )]
+        case: (Vec<&str>, &str),
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "2-4,6-", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, FOURSPACE);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        assert_eq!(
-            filtered,
-            vec![vec!["b", "c", "d", "f", "g"], vec!["2", "3", "4", "6", "7"]]
-        );
+        let (mut args, expected) = case;
+        args.extend(["-d", "\t"]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, "1\ta;b;c\tx\n2\t\tx,y\n3\n"), expected);
+        assert!(Opts::try_parse_from(["hck", "--split-field", "0:;"]).is_err());
     }
 
     #[rstest]
-    fn test_read_mixed_fields1(
+    fn test_merge(
         #[values(true, false)] no_mmap: bool,
-        #[values(r"\s+")] hck_delim: &str,
+        #[values(
+            (vec!["--merge", "1,2:_"], "1_a;b;c\tx\n2_\tx,y\n3\n"),
+            (vec!["-f", "3,1", "--merge", "1-:: "], "x: 1\nx,y: 2\n3\n"),
+            (vec!["-F", "chrom", "-F", "pos", "--merge", "1,2:_"], "chrom_pos\n1_a\n2_\n"),
+            (vec!["--split-field", "2:;", "--merge", "1,3:-"], "1-b\ta\tc\tx\n2-x,y\t\n3\n"),
+        )]
+        case: (Vec<&str>, &str),
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "2,4-", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, FOURSPACE);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        assert_eq!(
-            filtered,
-            vec![vec!["b", "d", "e", "f", "g"], vec!["2", "4", "5", "6", "7"]]
-        );
+        let (mut args, expected) = case;
+        let input = if args.contains(&"-F") {
+            "chrom\tpos\n1\ta\n2\t\n"
+        } else {
+            "1\ta;b;c\tx\n2\t\tx,y\n3\n"
+        };
+        args.extend(["-d", "\t"]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, input), expected);
     }
 
     #[rstest]
-    fn test_read_mixed_fields2(
+    fn test_enumerate(
         #[values(true, false)] no_mmap: bool,
-        #[values(r"\s+")] hck_delim: &str,
+        #[values(
+            (vec!["-f", "2"], "1\ta\n2\tb\n3\tc\n4\td\n"),
+            (vec!["-f", "2", "--enumerate-start", "0", "--enumerate-step", "10"], "0\ta\n10\tb\n20\tc\n30\td\n"),
+            (vec!["-f", "2", "--restart-per-file"], "1\ta\n2\tb\n1\tc\n2\td\n"),
+            (vec!["-F", "n", "--restart-per-file"], "index\tn\n1\ta\n2\tb\nindex\tn\n1\tc\n2\td\n"),
+            (vec!["-f", "2", "--match", "2:[bd]"], "1\tb\n2\td\n"),
+        )]
+        case: (Vec<&str>, &str),
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "-4,7", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
+        let (mut args, expected) = case;
+        let header = if args.contains(&"-F") { "id\tn\n" } else { "" };
+        let inputs = [
+            format!("{}1\ta\n2\tb\n", header),
+            format!("{}3\tc\n4\td\n", header),
         ];
-        write_file(&input_file, data, FOURSPACE);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
+        args.push("--enumerate");
+        if no_mmap {
+            args.push("--no-mmap");
+        }
         assert_eq!(
-            filtered,
-            vec![vec!["a", "b", "c", "d", "g"], vec!["1", "2", "3", "4", "7"]]
+            try_run_hck(&args, &[&inputs[0], &inputs[1]]).unwrap(),
+            expected
         );
+        assert!(Opts::try_parse_from(["hck", "--restart-per-file"]).is_err());
     }
 
     #[rstest]
-    fn test_read_no_delimis_found(
+    fn test_pad_to(
         #[values(true, false)] no_mmap: bool,
-        #[values(r"\s+")] hck_delim: &str,
+        #[values(
+            (vec!["-f", "1-", "--pad-to", "3"], "id\tn\t\n1\ta\tb\tc\n2\t\t\n"),
+            (vec!["-f", "1,2", "--pad-to", "2", "--fill", "NA"], "id\tn\n1\ta\n2\tNA\n"),
+            (vec!["-E", "x", "--pad-to-header"], "id\tn\n1\ta\tb\tc\n2\t\n"),
+            (vec!["-F", "id", "--insert", "2=z", "--pad-to", "3", "--fill", "-"], "id\tz\t-\n1\tz\t-\n2\tz\t-\n"),
+        )]
+        case: (Vec<&str>, &str),
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "-4,7", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, "-");
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let (mut args, expected) = case;
+        args.extend(["-d", "\t"]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, "id\tn\n1\ta\tb\tc\n2\n"), expected);
 
-        // We hae no concept of only-delimited, so if no delim is found the whole line
-        // is treated as column 1.
-        assert_eq!(filtered, vec![vec!["a-b-c-d-e-f-g"], vec!["1-2-3-4-5-6-7"]]);
+        // Without a header there is nothing to pad to
+        assert!(try_run_hck(&["-f", "1", "--pad-to-header"], &["id\tn\n"]).is_err());
     }
 
     #[rstest]
-    fn test_read_over_end(#[values(true, false)] no_mmap: bool, #[values(r"\s+")] hck_delim: &str) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "-4,8,11-", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, FOURSPACE);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+    fn test_insert(
+        #[values(true, false)] no_mmap: bool,
+        #[values(
+            (vec!["-f", "1,2", "--insert", "2=b7"], "id\tb7\tn\n1\tb7\ta\n2\tb7\n"),
+            (vec!["-F", "n", "--insert", "1=b7"], "b7\tn\nb7\ta\nb7\n"),
+            (vec!["-f", "1", "--insert", "3=z", "--insert", "1=a"], "a\tid\tz\na\t1\tz\na\t2\tz\n"),
+            (vec!["-f", "1", "--insert", "2=="], "id\t=\n1\t=\n2\t=\n"),
+        )]
+        case: (Vec<&str>, &str),
+    ) {
+        let (mut args, expected) = case;
+        args.extend(["-d", "\t"]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, "id\tn\n1\ta\n2\n"), expected);
+        assert!(Opts::try_parse_from(["hck", "--insert", "0=a"]).is_err());
+        assert!(Opts::try_parse_from(["hck", "--insert", "a"]).is_err());
+    }
 
-        // columns past end in fields are ignored
+    #[rstest]
+    fn test_url_decode(#[values(true, false)] no_mmap: bool) {
+        let mut args = vec!["-f", "1,3", "--url-decode", "2"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        // Malformed escapes and `+` are left as is
         assert_eq!(
-            filtered,
-            vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3", "4"]]
+            run_hck(
+                &args,
+                "/a%20b\t1\tID=gene%3B1;Name=x%2Cy\n/c\t2\t100%+%zz\n"
+            ),
+            "/a%20b\tID=gene;1;Name=x,y\n/c\t100%+%zz\n"
         );
     }
 
     #[rstest]
-    fn test_reorder1(#[values(true, false)] no_mmap: bool, #[values(r"\s+")] hck_delim: &str) {
+    fn test_recode(
+        #[values(true, false)] no_mmap: bool,
+        #[values(None, Some("c"))] header: Option<&str>,
+        #[values(None, Some("?"))] default: Option<&str>,
+    ) {
         let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "6,-4", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, FOURSPACE);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        // columns past end in fields are ignored
-        assert_eq!(
-            filtered,
-            vec![vec!["f", "a", "b", "c", "d"], vec!["6", "1", "2", "3", "4"]]
-        );
+        let map_file = tmp.path().join("map.tsv");
+        std::fs::write(&map_file, "A\tApple\nB\tBanana\nc\tnot_a_header\n").unwrap();
+        let mut spec = format!("2:{}", map_file.display());
+        if let Some(default) = default {
+            spec.push_str(&format!(":default={}", default));
+        }
+        let mut args = vec!["-L", "-d", "\t", "--recode", &spec];
+        match header {
+            Some(header) => args.extend(["-f", "1", "-F", header]),
+            None => args.extend(["-f", "1,2"]),
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        let out = run_hck(&args, "n\tc\n1\tA\n2\tZ\n3\tB\n");
+        let values: Vec<&str> = out
+            .lines()
+            .map(|line| line.split_once('\t').unwrap().1)
+            .collect();
+        let missing = default.unwrap_or("Z");
+        // The header line is only left alone when selecting by header
+        let first = if header.is_some() {
+            "c"
+        } else {
+            "not_a_header"
+        };
+        assert_eq!(values, vec![first, "Apple", missing, "Banana"]);
     }
 
     #[rstest]
-    fn test_reorder_merged_range(
+    fn test_require_and_capture(
         #[values(true, false)] no_mmap: bool,
-        #[values(r"\s+")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(None, Some("name"))] header: Option<&str>,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts(&input_file, &output_file, "1,3,2,7,6", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
+        let mut args = vec![
+            "-d",
+            "\t",
+            "--require",
+            r"1:^\d+$",
+            "--capture",
+            r"2:^(\w+)-",
         ];
-        write_file(&input_file, data, FOURSPACE);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        // columns past end in fields are ignored
+        match header {
+            Some(header) => args.extend(["-f", "1", "-F", header]),
+            None => args.extend(["-f", "1,2"]),
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        let mut expected = String::from("1\tabc\n3\tghi\n4\n");
+        if header.is_some() {
+            expected.insert_str(0, "id\tname\n");
+        }
         assert_eq!(
-            filtered,
-            vec![vec!["a", "c", "b", "g", "f"], vec!["1", "3", "2", "7", "6"]]
+            run_hck(&args, "id\tname\n1\tabc-1\nx\tdef-2\n3\tghi\n4\n"),
+            expected
         );
     }
 
     #[rstest]
-    fn test_reorder2(#[values(true, false)] no_mmap: bool, #[values(r"\s+")] hck_delim: &str) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        // 4-5 should not be repeated at the end and only written once.
-        let opts = build_opts(&input_file, &output_file, "3-,1,4-5", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, FOURSPACE);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        // columns past end in fields are ignored
+    fn test_extract(#[values(true, false)] no_mmap: bool) {
+        let mut args = vec!["-f", "1,3", "--extract", "2:/sample=([A-Z0-9]+)/"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
         assert_eq!(
-            filtered,
-            vec![
-                vec!["c", "d", "e", "f", "g", "a"],
-                vec!["3", "4", "5", "6", "7", "1"]
-            ]
+            run_hck(
+                &args,
+                "a\tx\tid=1;sample=S01;q=2\nb\ty\tsample=lower\nc\tz\n"
+            ),
+            "a\tS01\nb\t\nc\n"
         );
+        assert!(Opts::try_parse_from(["hck", "--extract", "2:/(/"]).is_err());
     }
 
     #[rstest]
-    #[rustfmt::skip::macros(vec)]
-    fn test_read_single_values_not_regex(
+    fn test_upper_lower(
         #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_not_regex(&input_file, &output_file, "1", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c"],
-            vec!["1", "2", "3"],
+        let cases = [
+            (vec!["--upper"], "ID\tNAME\tÜNIT\nA1\tBOB\tKG\n"),
+            (vec!["--lower-in", "2-"], "Id\tname\tünit\na1\tbob\tkg\n"),
+            // Positions are changed after the whole row
+            (
+                vec!["--lower", "--upper-in", "3"],
+                "id\tname\tÜNIT\na1\tbob\tKG\n",
+            ),
+            (
+                vec!["--upper", "--lower-in", "1"],
+                "id\tNAME\tÜNIT\na1\tBOB\tKG\n",
+            ),
         ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        for (mut args, expected) in cases {
+            args.extend(["-f", "1-", "-d", "\t"]);
+            if no_mmap {
+                args.push("--no-mmap");
+            }
+            if delim_is_literal {
+                args.push("-L");
+            }
+            assert_eq!(run_hck(&args, "Id\tName\tÜnit\na1\tBob\tkg\n"), expected);
+        }
+    }
 
-        assert_eq!(filtered, vec![vec!["a"], vec!["1"]]);
+    #[rstest]
+    fn test_skip_empty(
+        #[values(true, false)] no_mmap: bool,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(
+            (vec!["--skip-empty"], "1\tb\tc\n"),
+            (vec!["--skip-empty-in", "2"], "1\tb\tc\n2\tb\t\n"),
+            (vec!["--skip-empty-in", "3"], "1\tb\tc\n3\t\tc\n")
+        )]
+        case: (Vec<&str>, &str),
+    ) {
+        let (mut args, expected) = case;
+        args.extend(["-f", "1,3,4", "-d", "\t"]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        assert_eq!(
+            run_hck(&args, "1\ta\tb\tc\n2\ta\tb\t\n3\ta\t\tc\n"),
+            expected
+        );
     }
 
     #[rstest]
-    fn test_read_several_single_values_not_regex(
+    fn test_count_unique(
         #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
+        #[values(true, false)] approx: bool,
+        #[values(("1", "2\n"), ("2", "3\n"), ("1-", "4\n"))] case: (&str, &str),
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_not_regex(&input_file, &output_file, "1,3", no_mmap, hck_delim);
-        let data = vec![vec!["a", "b", "c"], vec!["1", "2", "3"]];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let (fields, expected) = case;
+        let mut args = vec!["-f", fields, "-d", "\t", "--count-unique"];
+        if approx {
+            args.push("--approx");
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        assert_eq!(run_hck(&args, "a\t1\nb\t1\na\t2\na\t1\nb\t3\n"), expected);
+    }
 
-        assert_eq!(filtered, vec![vec!["a", "c"], vec!["1", "3"]]);
+    #[rstest]
+    fn test_key_normalize(
+        #[values(true, false)] no_mmap: bool,
+        #[values(
+            ("lower", "Foo\t1\n foo\t3\nFoo \t4\n"),
+            ("trim", "Foo\t1\nfoo\t2\n"),
+            ("lower,trim", "Foo\t1\n")
+        )]
+        case: (&str, &str),
+    ) {
+        let (normalize, expected) = case;
+        let mut args = vec!["-L", "-d", ",", "-f", "1-", "--unique-on", "1"];
+        args.extend(["--key-normalize", normalize]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        assert_eq!(run_hck(&args, "Foo,1\nfoo,2\n foo,3\nFoo ,4\n"), expected);
     }
 
     #[rstest]
-    fn test_read_several_single_values_with_invalid_utf8_not_regex(
+    fn test_sample(
         #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_not_regex(&input_file, &output_file, "1,3", no_mmap, hck_delim);
-        let bad_str = unsafe { String::from_utf8_unchecked(b"a\xED\xA0\x80z".to_vec()) };
-        let data = vec![vec![bad_str.as_str(), "b", "c"], vec!["1", "2", "3"]];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let input: String = (0..1000).map(|i| format!("r{}\t{}\n", i, i)).collect();
+        let sample = |how: &[&str], seed: &str| {
+            let mut args = vec!["-f", "2", "-d", "\t", "--seed", seed];
+            args.extend(how);
+            if no_mmap {
+                args.push("--no-mmap");
+            }
+            if delim_is_literal {
+                args.push("-L");
+            }
+            run_hck(&args, &input)
+                .lines()
+                .map(|line| line.parse::<u64>().unwrap())
+                .collect::<Vec<_>>()
+        };
 
-        assert_eq!(filtered, vec![vec![bad_str.as_str(), "c"], vec!["1", "3"]]);
+        let bernoulli = sample(&["--sample", "0.1"], "1");
+        assert!((50..150).contains(&bernoulli.len()));
+        assert_eq!(bernoulli, sample(&["--sample", "0.1"], "1"));
+        assert_ne!(bernoulli, sample(&["--sample", "0.1"], "2"));
+        assert!(sample(&["--sample", "0"], "1").is_empty());
+        assert_eq!(sample(&["--sample", "1"], "1").len(), 1000);
+
+        let reservoir = sample(&["--sample-n", "25"], "1");
+        assert_eq!(reservoir.len(), 25);
+        assert!(reservoir.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(reservoir, sample(&["--sample-n", "25"], "1"));
+        assert_eq!(sample(&["--sample-n", "2000"], "1").len(), 1000);
+    }
+
+    #[test]
+    fn test_sample_args() {
+        assert!(Opts::try_parse_from(["hck", "--sample", "1.5"]).is_err());
+        assert!(Opts::try_parse_from(["hck", "--sample", "0.1", "--sample-n", "3"]).is_err());
+        let opts = Opts::try_parse_from(["hck", "--sample", "0.25", "--seed", "3"]).unwrap();
+        assert_eq!(opts.select.sample, Some(0.25));
+        assert_eq!(opts.select.seed, Some(3));
     }
 
     #[rstest]
-    fn test_read_single_range_not_regex(
+    fn test_filter(
         #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
+        #[values(true, false)] delim_is_literal: bool,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_not_regex(&input_file, &output_file, "2-", no_mmap, hck_delim);
-        let data = vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3", "4"]];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let mut args = vec!["-f", "1,3,5", "-d", "\t"];
+        args.extend(["--filter", "col(2) == 'PASS' && col(3) > 30"]);
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        if delim_is_literal {
+            args.push("-L");
+        }
+        let input = "chr1\tx\tPASS\tx\t31\nchr2\tx\tFAIL\tx\t99\nchr3\tx\tPASS\tx\t4\nchr4\tx\tPASS\tx\t100\nchr5\tx\tPASS\n";
+        assert_eq!(run_hck(&args, input), "chr1\tPASS\t31\nchr4\tPASS\t100\n");
+    }
 
-        assert_eq!(filtered, vec![vec!["b", "c", "d"], vec!["2", "3", "4"]]);
+    #[test]
+    fn test_filter_needs_numbers() {
+        let select = |args: &[&str]| {
+            let args = [&["-d", "\t"], args].concat();
+            try_run_hck(&args, &["name\tscore\na\t31\nb\tNA\nc\t4\nd\t40\n"])
+        };
+
+        // The header is a row like any other unless selecting by header
+        assert_eq!(
+            select(&["--filter", "col(2) > 30"]).unwrap(),
+            "a\t31\nd\t40\n"
+        );
+        assert_eq!(
+            select(&["-F", "name,score", "--filter", "col(2) > 30"]).unwrap(),
+            "name\tscore\na\t31\nd\t40\n"
+        );
+        let err = select(&["-F", "name,score", "--filter", "col(2) > 30", "--strict"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "record 3: Expected a number for col(2), found `NA`"
+        );
+    }
+
+    #[test]
+    fn test_gen_opts() {
+        let opts = Opts::try_parse_from([
+            "hck",
+            "gen",
+            "--cols",
+            "id:seq,name:word",
+            "--rows",
+            "1e3",
+            "--seed",
+            "7",
+        ])
+        .unwrap();
+        match opts.command {
+            Some(Command::Gen(gen_opts)) => {
+                assert_eq!(gen_opts.rows, 1000);
+                assert_eq!(gen_opts.seed, 7);
+            }
+            _ => panic!("Expected the gen subcommand"),
+        }
+        assert!(parse_row_count("1.5").is_err());
+        assert!(parse_row_count("-1").is_err());
+        assert!(Opts::try_parse_from(["hck", "-f1", "gen", "--cols", "int"]).is_err());
+    }
+
+    /// Run `hck join` with `args`, returning the output.
+    fn join_output(tmp: &TempDir, args: &[&str]) -> Result<String> {
+        let output_file = tmp.path().join("joined.txt");
+        let out = output_file.to_str().unwrap();
+        let opts = Opts::try_parse_from(["hck", "join", "-o", out].iter().chain(args)).unwrap();
+        match opts.command {
+            Some(Command::Join(join_opts)) => run_join(&join_opts)?,
+            _ => panic!("Expected the join subcommand"),
+        }
+        Ok(std::fs::read_to_string(&output_file).unwrap())
     }
 
     #[rstest]
-    fn test_read_serveral_range_not_regex(
-        #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
+    fn test_join(
+        #[values(true, false)] delim_is_literal: bool,
+        #[values("1G", "1")] memory_limit: &str,
     ) {
         let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_not_regex(&input_file, &output_file, "2-4,6-", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
+        let left = tmp.path().join("left.csv");
+        let right = tmp.path().join("right.csv");
+        std::fs::write(&left, "id,name\n1,ann\n2,bob\n3,cat\n1,al\n").unwrap();
+        std::fs::write(&right, "score,id\n10,1\n20,4\n30,2\n").unwrap();
+        let (left, right) = (left.to_str().unwrap(), right.to_str().unwrap());
+        let unmatched_left = tmp.path().join("unmatched_left.csv");
+        let unmatched_right = tmp.path().join("unmatched_right.csv");
+        let mut args = vec![
+            "-d",
+            ",",
+            "-D",
+            ",",
+            "--right-key",
+            "2",
+            "--right-fields",
+            "1",
+            "--header",
+            "--memory-limit",
+            memory_limit,
+            "--unmatched-left",
+            unmatched_left.to_str().unwrap(),
+            "--unmatched-right",
+            unmatched_right.to_str().unwrap(),
+            left,
+            right,
         ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
+        if delim_is_literal {
+            args.push("-L");
+        }
+        let joined = join_output(&tmp, &args).unwrap();
+        let mut lines: Vec<&str> = joined.lines().collect();
+        assert_eq!(lines[0], "id,name,score");
+        // Spilling to disk changes the order
+        lines[1..].sort_unstable();
         assert_eq!(
-            filtered,
-            vec![vec!["b", "c", "d", "f", "g"], vec!["2", "3", "4", "6", "7"]]
+            lines,
+            vec!["id,name,score", "1,al,10", "1,ann,10", "2,bob,30"]
+        );
+        assert_eq!(
+            std::fs::read_to_string(&unmatched_left).unwrap(),
+            "id,name\n3,cat\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&unmatched_right).unwrap(),
+            "score\n20\n"
         );
     }
 
-    #[rstest]
-    fn test_read_mixed_fields1_not_regex(
-        #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
-    ) {
+    #[test]
+    fn test_join_keys() {
         let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_not_regex(&input_file, &output_file, "2,4-", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let left = tmp.path().join("left.tsv");
+        let right = tmp.path().join("right.tsv");
+        std::fs::write(&left, "Chr1\t10\ta\nchr1\t20\tb\n").unwrap();
+        std::fs::write(&right, "20\tCHR1 \tx\n10\tchr1\ty\n").unwrap();
+        let (left, right) = (left.to_str().unwrap(), right.to_str().unwrap());
 
+        // Keys are matched up in the order they're listed, and are in the right input's order
+        let args = [
+            "-L",
+            "-d",
+            "\\t",
+            "-k",
+            "1,2",
+            "--right-key",
+            "2,1",
+            "--key-normalize",
+            "lower,trim",
+        ];
+        let joined = join_output(&tmp, &[&args[..], &[left, right]].concat()).unwrap();
         assert_eq!(
-            filtered,
-            vec![vec!["b", "d", "e", "f", "g"], vec!["2", "4", "5", "6", "7"]]
+            joined,
+            "chr1\t20\tb\t20\tCHR1 \tx\nChr1\t10\ta\t10\tchr1\ty\n"
         );
+        let args = [
+            "-k",
+            "2",
+            "--right-key",
+            "1",
+            "--left-fields",
+            "3",
+            "--right-fields",
+            "3",
+        ];
+        let joined = join_output(&tmp, &[&args[..], &[left, right]].concat()).unwrap();
+        assert_eq!(joined, "b\tx\na\ty\n");
+        // Keys are compared as is by default
+        let joined = join_output(&tmp, &["-k", "1", "--right-key", "2", left, right]).unwrap();
+        assert_eq!(joined, "chr1\t20\tb\t10\tchr1\ty\n");
+        // Every line needs the key fields
+        assert!(join_output(&tmp, &["-k", "4", left, right]).is_err());
+        assert!(join_output(&tmp, &["-", "-"]).is_err());
     }
 
-    #[rstest]
-    fn test_read_mixed_fields2_not_regex(
-        #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
-    ) {
+    #[test]
+    fn test_join_crlf() {
         let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_not_regex(&input_file, &output_file, "-4,7", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let left = tmp.path().join("left.csv");
+        let right = tmp.path().join("right.csv");
+        std::fs::write(&left, "id,name\r\n1,ann\r\n3,cat\r\n").unwrap();
+        std::fs::write(&right, "id,score\r\n1,10\r\n4,20\r\n").unwrap();
+        let (left, right) = (left.to_str().unwrap(), right.to_str().unwrap());
+        let unmatched_left = tmp.path().join("unmatched_left.csv");
+        let unmatched_right = tmp.path().join("unmatched_right.csv");
 
+        // Every output, not just the joined records, keeps the CRLF terminators
+        let args = [
+            "--crlf",
+            "-d",
+            ",",
+            "-D",
+            ",",
+            "--header",
+            "--unmatched-left",
+            unmatched_left.to_str().unwrap(),
+            "--unmatched-right",
+            unmatched_right.to_str().unwrap(),
+            left,
+            right,
+        ];
         assert_eq!(
-            filtered,
-            vec![vec!["a", "b", "c", "d", "g"], vec!["1", "2", "3", "4", "7"]]
+            join_output(&tmp, &args).unwrap(),
+            "id,name,id,score\r\n1,ann,1,10\r\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&unmatched_left).unwrap(),
+            "id,name\r\n3,cat\r\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&unmatched_right).unwrap(),
+            "id,score\r\n4,20\r\n"
         );
     }
 
-    #[rstest]
-    fn test_read_no_delimis_found_not_regex(
-        #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
-    ) {
+    #[test]
+    fn test_examples() {
         let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_not_regex(&input_file, &output_file, "-4,7", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, "-");
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        write_example_corpus(tmp.path()).unwrap();
+        for example in EXAMPLES {
+            assert_eq!(
+                run_example(tmp.path(), example).unwrap(),
+                example.expected,
+                "{}",
+                example.title
+            );
+        }
+        assert_eq!(shell_quote("1,3"), "1,3");
+        assert_eq!(shell_quote("col(2) > 30"), "'col(2) > 30'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
 
-        // We hae no concept of only-delimited, so if no delim is found the whole line
-        // is treated as column 1.
-        assert_eq!(filtered, vec![vec!["a-b-c-d-e-f-g"], vec!["1-2-3-4-5-6-7"]]);
+    #[test]
+    fn test_select_subcommand() {
+        let args = ["-f", "2", "-d", ",", "-o", "out.txt", "in.txt"];
+        let implicit = Opts::try_parse_from(["hck"].iter().chain(&args)).unwrap();
+        assert!(implicit.command.is_none());
+        let explicit = Opts::try_parse_from(["hck", "select"].iter().chain(&args)).unwrap();
+        let select = match explicit.command {
+            Some(Command::Select(select)) => select,
+            _ => panic!("Expected the select subcommand"),
+        };
+        for opts in [&implicit.select, &*select] {
+            assert_eq!(opts.fields.as_deref(), Some("2"));
+            assert_eq!(opts.delim.delimiter, vec![","]);
+            assert_eq!(opts.input.files, vec![PathBuf::from("in.txt")]);
+            assert_eq!(opts.output.file, Some(PathBuf::from("out.txt")));
+        }
+        assert!(Opts::try_parse_from(["hck", "-f1", "select", "-f2"]).is_err());
+
+        // Output options are shared with the other subcommands
+        let opts =
+            Opts::try_parse_from(["hck", "gen", "--cols", "int", "-Z", "-o", "x.gz"]).unwrap();
+        match opts.command {
+            Some(Command::Gen(gen_opts)) => {
+                assert!(gen_opts.output.try_compress);
+                assert_eq!(gen_opts.output.file, Some(PathBuf::from("x.gz")));
+            }
+            _ => panic!("Expected the gen subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_stdin_buffer_size_opts() {
+        let opts = Opts::try_parse_from(["hck", "--stdin-buffer-size", "1M"]).unwrap();
+        assert_eq!(opts.select.input.stdin_buffer_size, Some(1 << 20));
+        assert_eq!(parse_byte_size("512k"), Ok(512 << 10));
+        assert_eq!(parse_byte_size("4096"), Ok(4096));
+        for invalid in ["", "0", "M", "-1K", "1.5M"] {
+            assert!(parse_byte_size(invalid).is_err(), "{}", invalid);
+        }
     }
 
     #[rstest]
-    fn test_read_over_end_not_regex(
-        #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
+    fn test_max_line_length(
+        #[values(None, Some("||"))] record_sep: Option<&str>,
+        #[values(false, true)] truncate_lines: bool,
     ) {
         let tmp = TempDir::new().unwrap();
         let input_file = tmp.path().join("input.txt");
         let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_not_regex(&input_file, &output_file, "-4,8,11-", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
+        let sep = record_sep.unwrap_or("\n");
+        let long = "x".repeat(100);
+        std::fs::write(&input_file, ["a\tb", "c\td", &long, "e\tf"].join(sep) + sep).unwrap();
+        let argv = [
+            "hck",
+            "--no-mmap",
+            "-f1",
+            "--max-line-length",
+            "64",
+            "-d",
+            "\t",
+            "-L",
         ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        // columns past end in fields are ignored
+        let mut opts = Opts::try_parse_from(argv).unwrap().select;
+        opts.delim.record_sep = record_sep.map(String::from);
+        opts.input.truncate_lines = truncate_lines;
+        let err = try_run_wrapper(&input_file, &output_file, &opts).unwrap_err();
         assert_eq!(
-            filtered,
-            vec![vec!["a", "b", "c", "d"], vec!["1", "2", "3", "4"]]
+            err.to_string(),
+            "line 3 is longer than the maximum line length of 64 bytes"
         );
+
+        // Lines that fit, or that were cut short while reading, are fine
+        opts.input.max_line_length = Some(100);
+        run_wrapper(&input_file, &output_file, &opts);
+        let expected = ["a", "c", &long, "e"].join(sep) + sep;
+        assert_eq!(std::fs::read_to_string(&output_file).unwrap(), expected);
+        if truncate_lines && record_sep.is_none() {
+            std::fs::write(&input_file, format!("a\t{}\n", long)).unwrap();
+            opts.input.max_line_length = Some(1);
+            run_wrapper(&input_file, &output_file, &opts);
+            assert_eq!(std::fs::read_to_string(&output_file).unwrap(), "a\n");
+        }
     }
 
     #[rstest]
-    fn test_reorder1_not_regex(
+    fn test_read_buffer(
+        #[values("1", "7", "1M")] size: &str,
+        #[values(None, Some("||"))] record_sep: Option<&str>,
         #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        let opts = build_opts_not_regex(&input_file, &output_file, "6,-4", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
-
-        // columns past end in fields are ignored
+        let mut args = vec!["--read-buffer", size, "-f3,1"];
+        if let Some(record_sep) = record_sep {
+            args.extend(["--record-sep", record_sep]);
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        let sep = record_sep.unwrap_or("\n");
+        let data = (0..1000)
+            .map(|i| format!("{}\tb\tc{}{}", i, i, sep))
+            .collect::<String>();
+        let expected = (0..1000)
+            .map(|i| format!("c{}\t{}{}", i, i, sep))
+            .collect::<String>();
+        assert_eq!(run_hck(&args, &data), expected);
+        let opts = Opts::try_parse_from(["hck", "--read-buffer", size]).unwrap();
         assert_eq!(
-            filtered,
-            vec![vec!["f", "a", "b", "c", "d"], vec!["6", "1", "2", "3", "4"]]
+            read_buffer_capacity(&opts.select.input),
+            parse_byte_size(size).unwrap()
         );
     }
 
     #[rstest]
-    fn test_reorder2_not_regex(
+    fn test_fixed_widths(
         #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
+        #[values(true, false)] use_widths_file: bool,
     ) {
         let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        // 4-5 should not be repeated at the end and only written once.
-        let opts = build_opts_not_regex(&input_file, &output_file, "3-,1,4-5", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let widths_file = tmp.path().join("widths.txt");
+        std::fs::write(&widths_file, "# id, name\n3 5\n4 # score\n").unwrap();
+        let mut args = vec!["-f", "3,1", "-F", "name"];
+        if use_widths_file {
+            args.extend(["--widths-file", widths_file.to_str().unwrap()]);
+        } else {
+            args.extend(["--widths", "3,5,4"]);
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
 
-        // columns past end in fields are ignored
+        // Header names are trimmed for matching, but values are written as is
         assert_eq!(
-            filtered,
-            vec![
-                vec!["c", "d", "e", "f", "g", "a"],
-                vec!["3", "4", "5", "6", "7", "1"]
-            ]
+            run_hck(&args, "id name scor\n001alice0.50\n002bob  1.25\n"),
+            "name \tscor\tid \nalice\t0.50\t001\nbob  \t1.25\t002\n"
         );
     }
 
-    /// Tests from users
     #[rstest]
-    fn test_reorder_no_split_found(
+    fn test_squeeze(
         #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
+        #[values(None, Some("b"))] header: Option<&str>,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        // 4-5 should not be repeated at the end and only written once.
-        let opts = build_opts_not_regex(&input_file, &output_file, "3-,1,4-5", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, "-");
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let mut args = vec!["-L", "-d", " ", "--squeeze"];
+        match header {
+            Some(header) => args.extend(["-f", "1", "-F", header]),
+            None => args.extend(["-f", "3,1"]),
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
 
-        // columns past end in fields are ignored
-        assert_eq!(filtered, vec![vec!["a-b-c-d-e-f-g"], vec!["1-2-3-4-5-6-7"]]);
+        let expected = match header {
+            None => "c\ta\n3\t1\n\n6\t4\n",
+            Some(_) => "a\tb\n1\t2\n\n4\t5\n",
+        };
+        assert_eq!(run_hck(&args, "  a  b c\n1 2   3  \n\n4 5 6\n"), expected);
+        assert!(Opts::try_parse_from(["hck", "--squeeze", "-d", ","]).is_err());
     }
 
-    /// Tests from users
     #[rstest]
-    fn test_reorder_no_split_found_regex(
+    fn test_awk_fields(
         #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
+        #[values(None, Some("b"))] header: Option<&str>,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        // 4-5 should not be repeated at the end and only written once.
-        let opts = build_opts(&input_file, &output_file, "3-,1,4-5", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, "---");
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let mut args = vec!["--awk-fields"];
+        match header {
+            Some(header) => args.extend(["-f", "1", "-F", header]),
+            None => args.extend(["-f", "3,1"]),
+        }
+        if no_mmap {
+            args.push("--no-mmap");
+        }
 
-        // columns past end in fields are ignored
+        // Leading blanks don't make an empty first field, and a blank line has no fields
+        let expected = match header {
+            None => "c\ta\n3\t1\n\n6\t4\n",
+            Some(_) => "a\tb\n1\t2\n\n4\t5\n",
+        };
         assert_eq!(
-            filtered,
-            vec![
-                vec!["a---b---c---d---e---f---g"],
-                vec!["1---2---3---4---5---6---7"]
-            ]
+            run_hck(&args, "  a  b\tc\n   1 2  3  \n\t\n4 5 6\n"),
+            expected
         );
+        let matches = Opts::command().get_matches_from(["hck", "--awk-fields", "-d", ","]);
+        assert!(check_conflicts(&matches).is_err());
+    }
+
+    #[test]
+    fn test_csv_output_delimiter_conflicts() {
+        let check = |args: &[&str]| check_conflicts(&Opts::command().get_matches_from(args));
+        assert!(check(&["hck", "--out-format", "csv", "-D", "|"]).is_err());
+        assert!(check(&["hck", "--out-format", "csv", "-I"]).is_err());
+        assert!(check(&["hck", "select", "--out-format", "csv", "-D", "|"]).is_err());
+        assert!(check(&["hck", "--out-format", "csv"]).is_ok());
+        assert!(check(&["hck", "--out-quote", "-D", "|"]).is_ok());
     }
 
     #[rstest]
-    fn test_issue_12_with_regex(
+    fn test_vectored_writes(
         #[values(true, false)] no_mmap: bool,
-        #[values(r"\s+")] hck_delim: &str,
+        #[values("1-", "3,1")] fields: &str,
     ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        // 4-5 should not be repeated at the end and only written once.
-        let opts = build_opts(&input_file, &output_file, "2,3,4-", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, "  ");
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let mut args = vec!["-L", "-d", "\t", "-f", fields, "--vectored-writes"];
+        if no_mmap {
+            args.push("--no-mmap");
+        }
+        let wide = "x".repeat(10_000);
+        let data = format!("a\tb\tc\n{}\t1\t{}\n4\t\t6", wide, wide);
 
-        // columns past end in fields are ignored
-        assert_eq!(
-            filtered,
-            vec![
-                vec!["b", "c", "d", "e", "f", "g"],
-                vec!["2", "3", "4", "5", "6", "7"]
-            ]
-        );
+        let expected = match fields {
+            "1-" => format!("{}\n", data),
+            _ => format!("c\ta\n{}\t{}\n6\t4\n", wide, wide),
+        };
+        assert_eq!(run_hck(&args, &data), expected);
     }
 
-    #[rstest]
-    fn test_issue_12_no_regex(
-        #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
-    ) {
-        let tmp = TempDir::new().unwrap();
-        let input_file = tmp.path().join("input.txt");
-        let output_file = tmp.path().join("output.txt");
-        // 4-5 should not be repeated at the end and only written once.
-        let opts = build_opts(&input_file, &output_file, "2,3,4-", no_mmap, hck_delim);
-        let data = vec![
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, hck_delim);
-        run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+    #[cfg(target_os = "linux")]
+    fn supports_direct_io(path: &Path) -> bool {
+        use std::{fs::OpenOptions, os::unix::fs::OpenOptionsExt};
 
-        // columns past end in fields are ignored
-        assert_eq!(
-            filtered,
-            vec![
-                vec!["b", "c", "d", "e", "f", "g"],
-                vec!["2", "3", "4", "5", "6", "7"]
-            ]
-        );
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+            .is_ok()
     }
 
-    #[rstest]
-    fn test_issue_38_not_regex(
-        #[values(true, false)] no_mmap: bool,
-        #[values("    ", " ")] hck_delim: &str,
-    ) {
+    #[cfg(not(target_os = "linux"))]
+    fn supports_direct_io(_path: &Path) -> bool {
+        false
+    }
+
+    #[test]
+    fn test_direct_io() {
         let tmp = TempDir::new().unwrap();
         let input_file = tmp.path().join("input.txt");
         let output_file = tmp.path().join("output.txt");
-        // 4-5 should not be repeated at the end and only written once.
-        let opts = build_opts_not_regex(&input_file, &output_file, "1,2", no_mmap, hck_delim);
-        let data = vec![
-            vec![""],
-            vec![""],
-            vec!["a", "b", "c", "d", "e", "f", "g"],
-            vec![""],
-            vec![""],
-            vec!["1", "2", "3", "4", "5", "6", "7"],
-        ];
-        write_file(&input_file, data, hck_delim);
+        let mut opts = build_opts(&input_file, &output_file, "2", false, "\t");
+        opts.input.direct_io = true;
+        let data = "a\tb\n".repeat(5000);
+        std::fs::write(&input_file, &data).unwrap();
+        // Not every filesystem supports O_DIRECT
+        if !supports_direct_io(&input_file) {
+            return;
+        }
         run_wrapper(&input_file, &output_file, &opts);
-        let filtered = read_tsv(output_file);
+        let filtered = std::fs::read_to_string(output_file).unwrap();
+        assert_eq!(filtered, "b\n".repeat(5000));
+    }
 
-        // columns past end in fields are ignored
+    #[cfg(feature = "uring")]
+    #[rstest]
+    fn test_uring(#[values("1-", "2", "3,1")] fields: &str) {
+        // Spans several io_uring reads, with lines split across them
+        let data = (0..200_000)
+            .map(|i| format!("{}\tb{}\tc\n", i, i))
+            .collect::<String>();
+        let expected = (0..200_000)
+            .map(|i| match fields {
+                "1-" => format!("{}\tb{}\tc\n", i, i),
+                "2" => format!("b{}\n", i),
+                _ => format!("c\t{}\n", i),
+            })
+            .collect::<String>();
         assert_eq!(
-            filtered,
-            vec![
-                vec![""],
-                vec![""],
-                vec!["a", "b"],
-                vec![""],
-                vec![""],
-                vec!["1", "2"]
-            ]
+            run_hck(&["-f", fields, "-d", "\t", "--uring"], &data),
+            expected
         );
+        assert!(Opts::try_parse_from(["hck", "--uring", "--direct-io"]).is_err());
+    }
+
+    #[test]
+    fn test_dot_edge_opts() {
+        let opts =
+            Opts::try_parse_from(["hck", "--out-format", "dot", "--edge", "1,3:weight=5"]).unwrap();
+        assert_eq!(opts.select.out_format, OutFormat::Dot);
+        assert_eq!(opts.select.edge.unwrap().field_list(), "1,3,5");
+        assert!(Opts::try_parse_from(["hck", "--edge", "1,3", "-f", "2"]).is_err());
+        assert!(Opts::try_parse_from(["hck", "--out-format", "dot", "--edge", "1,1"]).is_err());
+        // Edges don't stand in for `-f` with other output formats
+        for format in ["delimited", "csv"] {
+            let opts =
+                Opts::try_parse_from(["hck", "--out-format", format, "--edge", "1,3"]).unwrap();
+            let err = run_select(&opts.select).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "`--edge` is only used with `--out-format dot`"
+            );
+        }
     }
 }